@@ -1,3 +1,8 @@
 fn main() {
+    if std::env::var_os("CARGO_FEATURE_WAVPACK").is_some() {
+        if let Err(e) = pkg_config::probe_library("wavpack") {
+            panic!("libwavpack not found ({}); install it or build without the wavpack feature", e);
+        }
+    }
     tauri_build::build()
 }
@@ -1,45 +1,106 @@
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
 use std::time::{Duration, Instant};
-use rodio::{Decoder, OutputStreamHandle, Sink, Source};
-use std::fs::File;
-use std::io::BufReader;
-use once_cell::sync::Lazy;
-use crate::models::{AudioState, MusicFile};
-
-static STREAM_HANDLE: Lazy<Mutex<Option<&'static OutputStreamHandle>>> = Lazy::new(|| Mutex::new(None));
-
-pub fn get_stream_handle() -> Result<&'static OutputStreamHandle, String> {
-    let mut handle_opt = STREAM_HANDLE.lock().unwrap();
-    if handle_opt.is_none() {
-        let (stream, handle) = rodio::OutputStream::try_default()
-            .map_err(|e| format!("Failed to create output stream: {}", e))?;
-        Box::leak(Box::new(stream));
-        let handle_ref = Box::leak(Box::new(handle));
-        *handle_opt = Some(handle_ref);
-        Ok(handle_ref)
-    } else {
-        Ok(handle_opt.unwrap())
-    }
-}
+use chrono::{DateTime, Days, Local, NaiveTime};
+use rodio::{Sink, Source};
+use crate::audiobook;
+use crate::backend::Backend;
+use crate::decoder::{self, AudioDecoder};
+use crate::equalizer::{EqControl, Equalizer};
+use crate::output;
+use crate::queue::PlayQueue;
+use crate::models::{
+    Alarm, AlarmSettings, AlarmStatus, AlarmTarget, AudioState, CrossfadeSettings,
+    EqualizerSettings, ExportPlan, ExportTrack, MusicFile, OutputSettings, PlaybackErrorEvent,
+    PlaybackRate, PlaybackRateMode, PlaybackStatus, QueueEntry, QueueSnapshot, QueuedTrack,
+    RepeatMode, ReplayGainMode, ReplayGainSettings, Session, SessionState, ShuffleMode, SleepAfter,
+    SleepTimer, SleepTimerSettings, SleepTimerStatus, Silence, TrackControls, TrackOverrides,
+    TrackRange, TrackSource,
+};
+use crate::replaygain;
+use crate::sources::{Fade, Fader, Gain, Loop, LoopControl, Position, StopAt, TrackStart};
+use crate::timestretch::TimeStretch;
+
+const MAX_CROSSFADE_SECS: f32 = 12.0;
+const MIN_PLAYBACK_RATE: f32 = 0.5;
+const MAX_PLAYBACK_RATE: f32 = 3.0;
+// How far ahead of the end of the current track the next one is handed to the
+// sink. Until then it is held back so queue edits can still replace it.
+const GAPLESS_LEAD: Duration = Duration::from_secs(2);
+// How quickly a track faded out by a cancelled sleep timer comes back up.
+const SLEEP_CANCEL_FADE: Duration = Duration::from_millis(500);
+const MAX_TRANSITION_FADE: Duration = Duration::from_secs(1);
+// Extra time allowed for a fade to be played out before giving up on it, e.g.
+// when the output has stopped pulling samples.
+const FADE_WAIT_SLACK: Duration = Duration::from_millis(200);
 
 static AUDIO_STATE: Mutex<Option<Arc<Mutex<AudioState>>>> = Mutex::new(None);
 
-pub fn get_audio_state() -> Arc<Mutex<AudioState>> {
-    let mut state = AUDIO_STATE.lock().unwrap();
-    if state.is_none() {
-        let audio_state = Arc::new(Mutex::new(AudioState {
+impl AudioState {
+    // A stopped engine with an empty library, playing through the app's
+    // shared output stream.
+    pub fn new() -> Self {
+        AudioState {
             sink: None,
+            controls: None,
+            fading_sink: None,
+            fading_fader: None,
             current_track: None,
             tracks: Vec::new(),
+            queue: PlayQueue::default(),
             volume: 0.5,
-            playback_start: None,
-            paused_elapsed: Duration::ZERO,
             total_duration: None,
-        }));
-        *state = Some(audio_state.clone());
-        audio_state
-    } else {
-        state.as_ref().unwrap().clone()
+            queued_next: None,
+            crossfade: CrossfadeSettings {
+                duration_secs: 0.0,
+                on_manual_skip: false,
+            },
+            replay_gain: ReplayGainSettings {
+                mode: ReplayGainMode::Off,
+                preamp_db: 0.0,
+                prevent_clipping: true,
+            },
+            equalizer: EqControl::new(EqualizerSettings {
+                enabled: false,
+                preamp_db: 0.0,
+                bands: Vec::new(),
+            }),
+            playback_rate: PlaybackRate {
+                rate: 1.0,
+                mode: PlaybackRateMode::Stretch,
+            },
+            stretch_rate: Arc::new(AtomicU32::new(1f32.to_bits())),
+            sleep_timer: None,
+            alarm: None,
+            transition_fade: Duration::from_millis(30),
+            skip_silence: false,
+            output: None,
+        }
+    }
+
+    // An engine with an output stream of its own on `backend`, so it can be
+    // driven apart from the app's, e.g. headless with `NullBackend`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_backend(backend: Arc<dyn Backend>) -> Result<Self, String> {
+        Ok(AudioState {
+            output: Some(output::Dedicated::open(backend, OutputSettings::default())?),
+            ..AudioState::new()
+        })
+    }
+}
+
+// The app's engine, playing through the shared output stream.
+pub fn get_audio_state() -> Arc<Mutex<AudioState>> {
+    let mut state = AUDIO_STATE.lock().unwrap();
+    match state.as_ref() {
+        Some(audio_state) => audio_state.clone(),
+        None => {
+            let audio_state = Arc::new(Mutex::new(AudioState::new()));
+            *state = Some(audio_state.clone());
+            audio_state
+        }
     }
 }
 
@@ -48,247 +109,2034 @@ pub fn set_tracks(tracks: Vec<MusicFile>) {
     state.lock().unwrap().tracks = tracks;
 }
 
-pub fn play_music(path: String) -> Result<(), String> {
+pub fn get_track_overrides(path: &str) -> Result<TrackOverrides, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    overrides(&audio_state, path)
+        .cloned()
+        .ok_or_else(|| format!("Track not found: {}", path))
+}
+
+// The part of a file a library track covers, if a CUE sheet cut it from one.
+pub fn track_range(path: &str) -> Option<TrackRange> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    range(&audio_state, path).cloned()
+}
+
+// Takes effect the next time the track starts, except for the gain, which
+// also changes on the playing track straight away.
+pub fn set_track_overrides(path: &str, overrides: TrackOverrides) -> Result<(), String> {
+    let valid_time = |t: Option<f64>| t.is_none_or(|t| t.is_finite() && t >= 0.0);
+    if !valid_time(overrides.start) || !valid_time(overrides.stop) || !overrides.gain_db.is_finite() {
+        return Err("Invalid track overrides".to_string());
+    }
+    if let (Some(start), Some(stop)) = (overrides.start, overrides.stop) {
+        if stop <= start {
+            return Err(format!("Stop time {:.1}s is not after start time {:.1}s", stop, start));
+        }
+    }
+
     let state = get_audio_state();
     let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    let audio_state = &mut *audio_state;
 
-    if let Some(sink) = audio_state.sink.take() {
-        sink.stop();
+    let track = audio_state
+        .tracks
+        .iter_mut()
+        .find(|t| t.path == path)
+        .ok_or_else(|| format!("Track not found: {}", path))?;
+    track.overrides = overrides;
+    apply_replay_gain(audio_state);
+
+    // A next track that is lined up but not yet handed over is opened again
+    // with the new start and stop times.
+    let lined_up = audio_state
+        .queued_next
+        .as_ref()
+        .is_some_and(|next| next.path == path && next.source.is_some());
+    if lined_up {
+        queue_next_track(audio_state);
     }
 
-    let stream_handle = get_stream_handle()?;
+    audio_state.queue.update_exclusions(&audio_state.tracks);
+    replan_next(audio_state)
+}
 
-    let file = File::open(&path)
-        .map_err(|e| format!("Failed to open file: {}", e))?;
-    let source = Decoder::new(BufReader::new(file))
-        .map_err(|e| format!("Failed to decode audio: {}", e))?;
+fn track_gain(audio_state: &AudioState, path: &str) -> f32 {
+    audio_state
+        .tracks
+        .iter()
+        .find(|t| t.path == path)
+        .map_or(1.0, |t| {
+            replaygain::gain_factor(&t.replay_gain, &t.loudness, &audio_state.replay_gain)
+                * 10f32.powf(t.overrides.gain_db / 20.0)
+        })
+}
 
-    let total_duration = source.total_duration();
-    let volume = audio_state.volume;
+fn overrides<'a>(audio_state: &'a AudioState, path: &str) -> Option<&'a TrackOverrides> {
+    audio_state.tracks.iter().find(|t| t.path == path).map(|t| &t.overrides)
+}
 
-    let sink = Sink::try_new(stream_handle)
-        .map_err(|e| format!("Failed to create sink: {}", e))?;
+fn range<'a>(audio_state: &'a AudioState, path: &str) -> Option<&'a TrackRange> {
+    audio_state.tracks.iter().find(|t| t.path == path).and_then(|t| t.range.as_ref())
+}
 
-    sink.set_volume(volume);
-    sink.append(source);
-    sink.play();
+fn silence<'a>(audio_state: &'a AudioState, path: &str) -> Option<&'a Silence> {
+    if !audio_state.skip_silence {
+        return None;
+    }
+    audio_state.tracks.iter().find(|t| t.path == path).map(|t| &t.silence)
+}
 
-    audio_state.sink = Some(sink);
-    audio_state.current_track = Some(path);
-    audio_state.playback_start = Some(Instant::now());
-    audio_state.paused_elapsed = Duration::ZERO;
-    audio_state.total_duration = total_duration;
+// The custom stop time, or where trailing silence starts when it is skipped,
+// whichever comes first.
+fn stop_time(audio_state: &AudioState, path: &str) -> Option<Duration> {
+    let stop = overrides(audio_state, path).and_then(|o| o.stop);
+    let silence = silence(audio_state, path).and_then(|s| s.end);
+    let stop = match (stop, silence) {
+        (Some(stop), Some(silence)) => Some(stop.min(silence)),
+        (stop, silence) => stop.or(silence),
+    };
+    stop.map(Duration::from_secs_f64)
+}
 
-    Ok(())
+// Where a track starts when it is played from the top: an audiobook where it
+// was left, otherwise the track's custom start time. A track that follows on
+// from the previous one also skips its leading silence when that is on.
+fn start_position(audio_state: &AudioState, path: &str, following: bool) -> Duration {
+    if let Some(resume) = audiobook::resume_position(path) {
+        return resume;
+    }
+    let start = overrides(audio_state, path).and_then(|o| o.start).unwrap_or(0.0);
+    let silence = silence(audio_state, path).filter(|_| following).map_or(0.0, |s| s.start);
+    Duration::from_secs_f64(start.max(silence))
 }
 
-pub fn seek(position_secs: f64) -> Result<(), String> {
-    let state = get_audio_state();
-    let mut audio_state = state.lock().unwrap();
+// Builds the playback chain for a decoded track and returns the handles used
+// to control it while it plays.
+fn wrap_track(
+    audio_state: &AudioState,
+    path: &str,
+    source: AudioDecoder,
+    initial_fade: f32,
+    start: Duration,
+) -> Result<(TrackSource, TrackControls), String> {
+    let controls = TrackControls {
+        started: Arc::new(OnceLock::new()),
+        fader: Fader::new(initial_fade),
+        ramp: Fader::new(1.0),
+        gain: Arc::new(AtomicU32::new(track_gain(audio_state, path).to_bits())),
+        position: Arc::new(AtomicU64::new(0)),
+        looping: LoopControl::new(),
+    };
+
+    let source = StopAt::new(source.convert_samples::<f32>(), stop_time(audio_state, path));
+    let mut source = Position::new(source, controls.position.clone());
+    if !start.is_zero() {
+        source
+            .seek_or_skip(start)
+            .map_err(|e| format!("Failed to seek: {}", e))?;
+    }
+    let source = Loop::new(source, controls.looping.clone());
+    let source = Gain::new(source, controls.gain.clone());
+    let source = Equalizer::new(source, audio_state.equalizer.clone());
+    let source = TimeStretch::new(source, audio_state.stretch_rate.clone());
+    let source = TrackStart::new(source, controls.started.clone());
+    let source = Fade::new(source, controls.fader.clone());
+    let source = Fade::new(source, controls.ramp.clone());
+
+    Ok((Box::new(source), controls))
+}
+
+fn open_track(
+    audio_state: &AudioState,
+    path: &str,
+    initial_fade: f32,
+    start: Duration,
+) -> Result<(TrackSource, Option<Duration>, TrackControls), String> {
+    let source = decoder::open_track(path, range(audio_state, path))?;
+    let total_duration = match (source.total_duration(), stop_time(audio_state, path)) {
+        (Some(total), Some(stop)) => Some(total.min(stop)),
+        (total, stop) => total.or(stop),
+    };
+    let (source, controls) = wrap_track(audio_state, path, source, initial_fade, start)?;
+    Ok((source, total_duration, controls))
+}
+
+// Only ever attaches to a stream that is already open: opening one waits on
+// the output thread, which must not happen with the engine locked. Anything
+// that can start playback calls `open_output` before taking the lock.
+fn new_sink(audio_state: &AudioState) -> Result<Sink, String> {
+    let mixer = match &audio_state.output {
+        Some(output) => output.mixer(),
+        None => output::current().ok_or_else(|| "Output stream is not available".to_string())?,
+    };
+    let (sink, queue) = Sink::new_idle();
+    mixer.add(queue);
+
+    sink.set_volume(audio_state.volume);
+    if audio_state.playback_rate.mode == PlaybackRateMode::Tape {
+        sink.set_speed(audio_state.playback_rate.rate);
+    }
+    Ok(sink)
+}
+
+// Opens the shared output stream, when the engine plays through it, ahead of
+// locking the engine.
+fn open_output(state: &Mutex<AudioState>) -> Result<(), String> {
+    if state.lock().unwrap().output.is_some() {
+        return Ok(());
+    }
+    output::mixer().map(|_| ())
+}
+
+fn elapsed(audio_state: &AudioState) -> Duration {
+    audio_state
+        .controls
+        .as_ref()
+        .map_or(Duration::ZERO, |c| Duration::from_nanos(c.position.load(Ordering::Relaxed)))
+}
+
+fn is_running(audio_state: &AudioState) -> bool {
+    audio_state.sink.as_ref().is_some_and(|sink| !sink.is_paused() && !sink.empty())
+}
+
+fn loop_region(audio_state: &AudioState) -> Option<(Duration, Duration)> {
+    audio_state.controls.as_ref().and_then(|c| c.looping.times())
+}
+
+fn loop_frames(audio_state: &AudioState) -> Option<(u64, u64)> {
+    audio_state.controls.as_ref().and_then(|c| c.looping.region())
+}
+
+fn crossfade_duration(audio_state: &AudioState) -> Option<Duration> {
+    let secs = audio_state.crossfade.duration_secs;
+    if secs > 0.0 {
+        Some(Duration::from_secs_f32(secs))
+    } else {
+        None
+    }
+}
+
+// Tracks from the same album are meant to run into each other, so they are
+// always joined gaplessly.
+fn should_crossfade(audio_state: &AudioState, from: &str, to: &str) -> bool {
+    if crossfade_duration(audio_state).is_none() {
+        return false;
+    }
+
+    let album_of = |path: &str| {
+        audio_state
+            .tracks
+            .iter()
+            .find(|t| t.path == path)
+            .and_then(|t| t.album.clone())
+    };
+
+    match (album_of(from), album_of(to)) {
+        (Some(a), Some(b)) => a != b,
+        _ => true,
+    }
+}
+
+fn end_fading_sink(audio_state: &mut AudioState) {
+    if let Some(sink) = audio_state.fading_sink.take() {
+        sink.stop();
+    }
+    audio_state.fading_fader = None;
+}
+
+// Hands the live sink over to the fade-out slot so a new track can start on a
+// fresh sink while the old one ramps down underneath it.
+fn fade_out_current(audio_state: &mut AudioState, over: Duration) {
+    end_fading_sink(audio_state);
+    if let (Some(sink), Some(controls)) = (audio_state.sink.take(), audio_state.controls.take()) {
+        controls.fader.ramp_to(0.0, over);
+        audio_state.fading_sink = Some(sink);
+        audio_state.fading_fader = Some(controls.fader);
+    }
+}
+
+// True once a sleep timer that counts tracks has reached its final track.
+fn stops_after_current(audio_state: &AudioState) -> bool {
+    audio_state
+        .sleep_timer
+        .as_ref()
+        .is_some_and(|timer| timer.deadline.is_none() && timer.tracks_left == 0)
+}
 
-    if let Some(path) = audio_state.current_track.clone() {
-        let was_playing = audio_state.sink.as_ref().map_or(false, |s| !s.is_paused());
+fn next_to_play(audio_state: &AudioState) -> Option<QueueEntry> {
+    if stops_after_current(audio_state) {
+        None
+    } else {
+        audio_state.queue.upcoming()
+    }
+}
 
+// Gets the live sink out of the way: a playing track is ramped out in the
+// fade-out slot, anything else is stopped outright.
+fn release_sink(audio_state: &mut AudioState) {
+    if is_running(audio_state) && audio_state.controls.is_some() {
+        fade_out_current(audio_state, audio_state.transition_fade);
+    } else {
+        end_fading_sink(audio_state);
         if let Some(sink) = audio_state.sink.take() {
             sink.stop();
         }
+    }
+}
 
-        let stream_handle = get_stream_handle()?;
-        let file = File::open(&path)
-            .map_err(|e| format!("Failed to open file: {}", e))?;
-        let mut source = Decoder::new(BufReader::new(file))
-            .map_err(|e| format!("Failed to decode audio: {}", e))?;
+// Ramps the playing track down to silence and waits, without holding the
+// engine lock, until the ramp has been played out. Returns straight away when
+// nothing is playing.
+fn fade_to_silence(state: &Mutex<AudioState>) {
+    let (ramp, over) = {
+        let mut audio_state = state.lock().unwrap();
+        sync_queued_track(&mut audio_state);
+        if !is_running(&audio_state) {
+            return;
+        }
 
-        let seek_duration = Duration::from_secs_f64(position_secs);
-        if source.try_seek(seek_duration).is_err() {
-            // if seek fails, we effectively restart the track.
+        let over = audio_state.transition_fade;
+        if let Some(fader) = &audio_state.fading_fader {
+            fader.ramp_to(0.0, over);
+        }
+        match &audio_state.controls {
+            Some(controls) => {
+                controls.ramp.ramp_to(0.0, over);
+                (controls.ramp.clone(), over)
+            }
+            None => return,
         }
+    };
 
-        let total_duration = source.total_duration();
-        let volume = audio_state.volume;
+    let give_up = Instant::now() + over + FADE_WAIT_SLACK;
+    while !(ramp.is_settled() && ramp.gain() == 0.0) && Instant::now() < give_up {
+        thread::sleep(Duration::from_millis(5));
+    }
+}
 
-        let sink = Sink::try_new(stream_handle)
-            .map_err(|e| format!("Failed to create sink: {}", e))?;
+// Decodes the entry following the current one in the queue. The source is
+// held back and handed to the live sink shortly before the current track ends,
+// so the sink runs straight from one track into the next without a gap; for a
+// crossfade it is kept until the fade starts. Streams of unknown length are
+// appended right away since there is no way to tell when they end.
+fn queue_next_track(audio_state: &mut AudioState) {
+    audio_state.queued_next = None;
 
-        sink.set_volume(volume);
-        sink.append(source);
+    let next = match next_to_play(audio_state) {
+        Some(next) => next,
+        None => return,
+    };
+
+    if let Some(sink) = &audio_state.sink {
+        let crossfade = audio_state.total_duration.is_some()
+            && audio_state
+                .current_track
+                .as_deref()
+                .is_some_and(|current| should_crossfade(audio_state, current, &next.path));
+
+        let initial_fade = if crossfade { 0.0 } else { 1.0 };
+        let start = start_position(audio_state, &next.path, true);
+        if let Ok((source, total_duration, controls)) = open_track(audio_state, &next.path, initial_fade, start) {
+            let source = if !crossfade && audio_state.total_duration.is_none() {
+                sink.append(source);
+                None
+            } else {
+                Some(source)
+            };
+
+            audio_state.queued_next = Some(QueuedTrack {
+                entry: next.id,
+                path: next.path,
+                total_duration,
+                controls,
+                crossfade,
+                source,
+            });
+        }
+    }
+}
+
+// Called after the queue was edited: makes sure the track lined up to follow
+// is still the queue's next entry.
+fn replan_next(audio_state: &mut AudioState) -> Result<(), String> {
+    if audio_state.controls.is_none() {
+        return Ok(());
+    }
 
-        if was_playing {
-            sink.play();
-            audio_state.playback_start = Some(Instant::now());
-        } else {
-            audio_state.playback_start = None;
+    let wanted = next_to_play(audio_state).map(|e| e.id);
+    match &audio_state.queued_next {
+        Some(next) if Some(next.entry) == wanted => Ok(()),
+        Some(next) if next.source.is_none() => {
+            // Already handed to the sink, which can't take it back; rebuild
+            // the sink at the current position instead.
+            if let Some(path) = audio_state.current_track.clone() {
+                let position = elapsed(audio_state);
+                let playing = is_running(audio_state);
+                restart_at(audio_state, path, position, playing)?;
+            }
+            Ok(())
+        }
+        _ => {
+            queue_next_track(audio_state);
+            Ok(())
         }
+    }
+}
+
+fn start_crossfade(audio_state: &mut AudioState) -> Result<(), String> {
+    let over = crossfade_duration(audio_state).unwrap_or_default();
+    let source = audio_state.queued_next.as_mut().and_then(|next| next.source.take());
+
+    if let (Some(source), Some(next)) = (source, &audio_state.queued_next) {
+        let sink = new_sink(audio_state)?;
 
+        let controls = next.controls.clone();
+        controls.fader.ramp_to(1.0, over);
+        sink.append(source);
+        sink.play();
+
+        let remaining = audio_state
+            .total_duration
+            .map_or(over, |total| total.saturating_sub(elapsed(audio_state)));
+        fade_out_current(audio_state, remaining.min(over));
         audio_state.sink = Some(sink);
-        audio_state.paused_elapsed = seek_duration;
-        audio_state.total_duration = total_duration;
+        audio_state.controls = Some(controls);
     }
 
     Ok(())
 }
 
-pub fn pause_music() -> Result<(), String> {
-    let state = get_audio_state();
-    let mut audio_state = state.lock().unwrap();
-    if let Some(sink) = &audio_state.sink {
-        sink.pause();
-        if let Some(start) = audio_state.playback_start {
-            audio_state.paused_elapsed += start.elapsed();
-            audio_state.playback_start = None;
+// Switches the position bookkeeping over to the queued track once the sink has
+// pulled its first sample, starts a pending crossfade when the current track
+// reaches its fade point and releases the previous sink once it has faded out.
+fn sync_queued_track(audio_state: &mut AudioState) {
+    let faded_out = audio_state.fading_sink.as_ref().is_some_and(|sink| sink.empty())
+        || audio_state
+            .fading_fader
+            .as_ref()
+            .is_some_and(|fader| fader.is_settled() && fader.gain() == 0.0);
+    if faded_out {
+        end_fading_sink(audio_state);
+    }
+
+    // A looping track never reaches its end, so nothing is handed over while
+    // the loop is on.
+    let looping = loop_region(audio_state).is_some();
+    let crossfade_due = match (&audio_state.queued_next, audio_state.total_duration) {
+        (Some(next), Some(total)) if next.source.is_some() && !looping && is_running(audio_state) => {
+            let over = crossfade_duration(audio_state).unwrap_or_default();
+            elapsed(audio_state) + over >= total
         }
+        _ => false,
+    };
+    if crossfade_due && start_crossfade(audio_state).is_err() {
+        audio_state.queued_next = None;
+    }
+
+    let append_due = match (&audio_state.queued_next, audio_state.total_duration) {
+        (Some(next), Some(total)) if next.source.is_some() && !next.crossfade && !looping => {
+            elapsed(audio_state) + GAPLESS_LEAD >= total
+        }
+        _ => false,
+    };
+    if append_due {
+        let source = audio_state.queued_next.as_mut().and_then(|next| next.source.take());
+        if let (Some(source), Some(sink)) = (source, &audio_state.sink) {
+            sink.append(source);
+        }
+    }
+
+    let started = audio_state
+        .queued_next
+        .as_ref()
+        .is_some_and(|next| next.controls.started.get().is_some());
+
+    if started {
+        count_finished_track(audio_state);
+        if let Some(next) = audio_state.queued_next.take() {
+            audio_state.queue.set_current(Some(next.entry), &audio_state.tracks);
+            audio_state.current_track = Some(next.path);
+            audio_state.total_duration = next.total_duration;
+            audio_state.controls = Some(next.controls);
+        }
+        queue_next_track(audio_state);
     }
-    Ok(())
 }
 
-pub fn resume_music() -> Result<(), String> {
-    let state = get_audio_state();
-    let mut audio_state = state.lock().unwrap();
-    if let Some(sink) = &audio_state.sink {
-        if sink.empty() {
-            if let Some(path) = audio_state.current_track.clone() {
-                drop(audio_state);
-                return play_music(path);
-            }
-        } else {
-            sink.play();
-            audio_state.playback_start = Some(Instant::now());
+// `fade_in` brings the new track up from silence; a crossfade always fades in
+// over the crossfade duration.
+fn start_track(
+    audio_state: &mut AudioState,
+    entry: QueueEntry,
+    crossfade: bool,
+    fade_in: Option<Duration>,
+) -> Result<(), String> {
+    let start = start_position(audio_state, &entry.path, false);
+    start_track_at(audio_state, entry, crossfade, fade_in, start)
+}
+
+fn start_track_at(
+    audio_state: &mut AudioState,
+    entry: QueueEntry,
+    crossfade: bool,
+    fade_in: Option<Duration>,
+    start: Duration,
+) -> Result<(), String> {
+    let path = entry.path;
+    audio_state.queue.set_current(Some(entry.id), &audio_state.tracks);
+
+    let crossfading = match crossfade_duration(audio_state) {
+        Some(over) if crossfade && is_running(audio_state) => {
+            fade_out_current(audio_state, over);
+            true
+        }
+        _ => {
+            release_sink(audio_state);
+            false
         }
+    };
+    audio_state.queued_next = None;
+
+    let fade_in = if crossfading {
+        crossfade_duration(audio_state)
+    } else {
+        fade_in
+    };
+    let initial_fade = if fade_in.is_some() { 0.0 } else { 1.0 };
+    // A saved position past the end of a file that has since changed falls
+    // back to the start.
+    let (source, total_duration, controls) = open_track(audio_state, &path, initial_fade, start)
+        .or_else(|e| {
+            if start.is_zero() {
+                Err(e)
+            } else {
+                open_track(audio_state, &path, initial_fade, Duration::ZERO)
+            }
+        })?;
+    if let Some(over) = fade_in {
+        controls.fader.ramp_to(1.0, over);
     }
+
+    let sink = new_sink(audio_state)?;
+    sink.append(source);
+    sink.play();
+
+    audio_state.sink = Some(sink);
+    audio_state.controls = Some(controls);
+    audio_state.current_track = Some(path);
+    audio_state.total_duration = total_duration;
+    queue_next_track(audio_state);
+
     Ok(())
 }
 
-pub fn stop_music() -> Result<(), String> {
-    let state = get_audio_state();
-    let mut audio_state = state.lock().unwrap();
-    if let Some(sink) = &audio_state.sink {
-        sink.stop();
+fn play_paths(audio_state: &mut AudioState, paths: Vec<String>, start: Option<usize>) -> Result<(), String> {
+    match audio_state.queue.replace(paths, start, &audio_state.tracks) {
+        Some(entry) => start_track(audio_state, entry, false, None),
+        None => Err("Nothing to play".to_string()),
     }
-    audio_state.playback_start = None;
-    audio_state.paused_elapsed = Duration::ZERO;
-    Ok(())
 }
 
-pub fn get_current_track() -> Result<Option<String>, String> {
-    let state = get_audio_state();
-    let audio_state = state.lock().unwrap();
-    Ok(audio_state.current_track.clone())
+// Playing a single library track queues the whole library from that track on,
+// matching the order the library is listed in.
+pub fn play_music(path: String) -> Result<(), String> {
+    play_music_on(&get_audio_state(), path)
 }
 
-pub fn get_current_track_info() -> Result<Option<MusicFile>, String> {
-    let state = get_audio_state();
-    let audio_state = state.lock().unwrap();
+fn play_music_on(state: &Mutex<AudioState>, path: String) -> Result<(), String> {
+    open_output(state)?;
+    let mut audio_state = state.lock().unwrap();
 
-    if let Some(current_path) = &audio_state.current_track {
-        if let Some(track) = audio_state.tracks.iter().find(|t| &t.path == current_path) {
-            return Ok(Some(track.clone()));
-        }
+    let paths: Vec<String> = audio_state.tracks.iter().map(|t| t.path.clone()).collect();
+    match paths.iter().position(|p| *p == path) {
+        Some(start) => play_paths(&mut audio_state, paths, Some(start)),
+        None => play_paths(&mut audio_state, vec![path], Some(0)),
     }
-
-    Ok(None)
 }
 
-pub fn is_playing() -> Result<bool, String> {
+// Plays `path` as `play_music` does, starting `position_secs` into it.
+pub fn play_music_at(path: String, position_secs: f64) -> Result<(), String> {
+    if !position_secs.is_finite() || position_secs < 0.0 {
+        return Err(format!("Invalid seek position: {}", position_secs));
+    }
+
     let state = get_audio_state();
-    let audio_state = state.lock().unwrap();
-    if let Some(sink) = &audio_state.sink {
-        Ok(!sink.is_paused() && sink.len() > 0)
-    } else {
-        Ok(false)
+    open_output(&state)?;
+    let mut audio_state = state.lock().unwrap();
+    let audio_state = &mut *audio_state;
+
+    let paths: Vec<String> = audio_state.tracks.iter().map(|t| t.path.clone()).collect();
+    let entry = match paths.iter().position(|p| *p == path) {
+        Some(start) => audio_state.queue.replace(paths, Some(start), &audio_state.tracks),
+        None => audio_state.queue.replace(vec![path], Some(0), &audio_state.tracks),
+    };
+    match entry {
+        Some(entry) => start_track_at(audio_state, entry, false, None, Duration::from_secs_f64(position_secs)),
+        None => Err("Nothing to play".to_string()),
     }
 }
 
-pub fn list_music() -> Result<Vec<MusicFile>, String> {
+pub fn play_selection(paths: Vec<String>, start: usize) -> Result<(), String> {
     let state = get_audio_state();
-    let tracks = state.lock().unwrap().tracks.clone();
-    Ok(tracks)
+    open_output(&state)?;
+    let mut audio_state = state.lock().unwrap();
+    play_paths(&mut audio_state, paths, Some(start))
 }
 
-pub fn set_volume(volume: f32) -> Result<(), String> {
+fn library_paths<F>(audio_state: &AudioState, matches: F) -> Vec<String>
+where
+    F: Fn(&MusicFile) -> bool,
+{
+    audio_state
+        .tracks
+        .iter()
+        .filter(|t| matches(t))
+        .map(|t| t.path.clone())
+        .collect()
+}
+
+fn in_folder(track: &MusicFile, folder: &std::path::Path) -> bool {
+    std::path::Path::new(&track.path).starts_with(folder)
+}
+
+fn play_library_matching<F>(matches: F) -> Result<(), String>
+where
+    F: Fn(&MusicFile) -> bool,
+{
     let state = get_audio_state();
+    open_output(&state)?;
     let mut audio_state = state.lock().unwrap();
-    audio_state.volume = volume;
-    if let Some(sink) = &audio_state.sink {
-        sink.set_volume(volume);
-    }
-    Ok(())
+    let paths = library_paths(&audio_state, matches);
+    play_paths(&mut audio_state, paths, None)
 }
 
-pub fn get_volume() -> Result<f32, String> {
+pub fn play_album(album: String, artist: Option<String>) -> Result<(), String> {
+    play_library_matching(|t| {
+        t.album.as_deref() == Some(album.as_str())
+            && artist.as_ref().is_none_or(|a| t.artist.as_ref() == Some(a))
+    })
+}
+
+pub fn play_artist(artist: String) -> Result<(), String> {
+    play_library_matching(|t| t.artist.as_deref() == Some(artist.as_str()))
+}
+
+pub fn play_folder(folder: String) -> Result<(), String> {
+    let folder = std::path::PathBuf::from(folder);
+    play_library_matching(|t| in_folder(t, &folder))
+}
+
+pub fn play_queue_entry(id: u64) -> Result<(), String> {
     let state = get_audio_state();
-    let audio_state = state.lock().unwrap();
-    Ok(audio_state.volume)
+    open_output(&state)?;
+    let mut audio_state = state.lock().unwrap();
+    let entry = audio_state
+        .queue
+        .entry(id)
+        .ok_or_else(|| format!("Queue entry not found: {}", id))?;
+    start_track(&mut audio_state, entry, false, None)
 }
 
-pub fn get_playback_position() -> Result<(f64, Option<f64>), String> {
+pub fn get_queue() -> Result<QueueSnapshot, String> {
     let state = get_audio_state();
     let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    Ok(audio_state.queue.snapshot())
+}
 
-    let mut elapsed = audio_state.paused_elapsed.as_secs_f64();
-    if let Some(start) = audio_state.playback_start {
-        elapsed += start.elapsed().as_secs_f64();
-    }
+pub fn enqueue(paths: Vec<String>) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    let audio_state = &mut *audio_state;
+    audio_state.queue.append(paths, &audio_state.tracks);
+    replan_next(audio_state)
+}
 
-    let total = audio_state.total_duration.map(|d| d.as_secs_f64());
+pub fn enqueue_next(paths: Vec<String>) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    audio_state.queue.insert_next(paths);
+    replan_next(&mut audio_state)
+}
 
-    let mut next_track_path: Option<String> = None;
+// Removing the entry that is playing moves on to the one after it.
+pub fn remove_from_queue(id: u64) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
 
-    if let Some(sink) = &audio_state.sink {
-        if sink.empty() {
-            if let Some(current_path) = &audio_state.current_track {
-                if audio_state.playback_start.is_some() {
-                    let tracks = &audio_state.tracks;
-                    if let Some(current_index) = tracks.iter().position(|t| t.path == *current_path) {
-                        if current_index < tracks.len() - 1 {
-                            next_track_path = Some(tracks[current_index + 1].path.clone());
-                            audio_state.playback_start = None;
-                        }
+    if audio_state.queue.current().is_some_and(|e| e.id == id) {
+        match audio_state.queue.next_entry() {
+            Some(next) if audio_state.controls.is_some() => {
+                let playing = is_running(&audio_state);
+                start_track(&mut audio_state, next, false, None)?;
+                if !playing {
+                    if let Some(sink) = &audio_state.sink {
+                        sink.pause();
                     }
                 }
             }
+            _ => {
+                release_sink(&mut audio_state);
+                audio_state.queued_next = None;
+                audio_state.controls = None;
+                audio_state.current_track = None;
+                audio_state.total_duration = None;
+            }
         }
     }
 
-    if let Some(path) = next_track_path {
-        drop(audio_state);
-        let _ = play_music(path);
-    }
+    audio_state.queue.remove(id)?;
+    replan_next(&mut audio_state)
+}
 
-    Ok((elapsed, total))
+pub fn move_in_queue(id: u64, to: usize) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    audio_state.queue.move_to(id, to)?;
+    replan_next(&mut audio_state)
 }
 
-pub fn play_next() -> Result<(), String> {
+pub fn clear_queue() -> Result<(), String> {
     let state = get_audio_state();
-    let audio_state = state.lock().unwrap();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    audio_state.queue.clear();
+    replan_next(&mut audio_state)
+}
 
-    if let Some(current_path) = audio_state.current_track.clone() {
-        let tracks = audio_state.tracks.clone();
-        if let Some(current_index) = tracks.iter().position(|t| t.path == current_path) {
-            if current_index < tracks.len() - 1 {
-                let next_track_path = tracks[current_index + 1].path.clone();
-                drop(audio_state);
-                play_music(next_track_path)?;
-            }
-        }
+// Reopens `path` on a fresh sink at `position`, e.g. when the output stream
+// has been rebuilt or the live source couldn't seek. The current track keeps
+// playing if the new one can't be opened at that position.
+fn restart_at(audio_state: &mut AudioState, path: String, position: Duration, playing: bool) -> Result<(), String> {
+    let (source, total_duration, controls) = open_track(audio_state, &path, 1.0, position)?;
+    controls.ramp.ramp_from(0.0, 1.0, audio_state.transition_fade);
+    if audio_state.current_track.as_deref() == Some(path.as_str()) {
+        controls.looping.set(loop_frames(audio_state));
+    }
+
+    release_sink(audio_state);
+    audio_state.queued_next = None;
+
+    let sink = new_sink(audio_state)?;
+    sink.append(source);
+    if playing {
+        sink.play();
+    } else {
+        sink.pause();
     }
 
+    audio_state.sink = Some(sink);
+    audio_state.controls = Some(controls);
+    audio_state.current_track = Some(path);
+    audio_state.total_duration = total_duration;
+    queue_next_track(audio_state);
+
     Ok(())
 }
 
-pub fn play_previous() -> Result<(), String> {
-    let state = get_audio_state();
-    let audio_state = state.lock().unwrap();
-
-    if let Some(current_path) = audio_state.current_track.clone() {
-        let tracks = audio_state.tracks.clone();
-        if let Some(current_index) = tracks.iter().position(|t| t.path == current_path) {
-            if current_index > 0 {
-                let prev_track_path = tracks[current_index - 1].path.clone();
-                drop(audio_state);
-                play_music(prev_track_path)?;
-            }
-        }
+fn fade_back_in(audio_state: &AudioState) {
+    if let Some(controls) = &audio_state.controls {
+        controls.ramp.ramp_to(1.0, audio_state.transition_fade);
     }
+}
 
+pub fn seek(position_secs: f64) -> Result<(), String> {
+    seek_on(&get_audio_state(), position_secs)
+}
+
+fn seek_on(state: &Mutex<AudioState>, position_secs: f64) -> Result<(), String> {
+    if !position_secs.is_finite() || position_secs < 0.0 {
+        return Err(format!("Invalid seek position: {}", position_secs));
+    }
+    let position = Duration::from_secs_f64(position_secs);
+
+    fade_to_silence(state);
+    let mut audio_state = state.lock().unwrap();
+
+    sync_queued_track(&mut audio_state);
+
+    let Some(path) = audio_state.current_track.clone() else {
+        return Ok(());
+    };
+    if let Some(total) = audio_state.total_duration {
+        if position > total {
+            fade_back_in(&audio_state);
+            return Err(format!(
+                "Cannot seek to {:.1}s, the track is {:.1}s long",
+                position_secs,
+                total.as_secs_f64()
+            ));
+        }
+    }
+
+    seek_to(&mut audio_state, path, position)
+}
+
+// Moves playback of `path`, the current track, to `position`, and leaves it
+// fading back in whether or not that worked.
+fn seek_to(audio_state: &mut AudioState, path: String, position: Duration) -> Result<(), String> {
+    end_fading_sink(audio_state);
+
+    // Seeking out of the loop region ends the loop.
+    if let (Some((start, end)), Some(controls)) = (loop_region(audio_state), &audio_state.controls) {
+        if position < start || position >= end {
+            controls.looping.set(None);
+        }
+    }
+
+    // Seek in place where the decoder supports it. The sink only answers
+    // while its output is being pulled, so a lost stream skips straight to
+    // reopening the track.
+    let output_open = audio_state.output.is_some() || output::is_open();
+    if output_open && audio_state.controls.is_some() {
+        if let Some(sink) = audio_state.sink.as_ref().filter(|s| !s.empty()) {
+            if sink.try_seek(position).is_ok() {
+                fade_back_in(audio_state);
+                return Ok(());
+            }
+        }
+    }
+
+    let was_playing = audio_state.sink.as_ref().map_or(false, |s| !s.is_paused());
+    let result = restart_at(audio_state, path, position, was_playing);
+    if result.is_err() {
+        fade_back_in(audio_state);
+    }
+    result
+}
+
+// Where playback has to jump to for the loop from frame `start` to `end`:
+// its start when the current position lies outside it.
+fn loop_entry(audio_state: &AudioState, start: u64, end: u64) -> Result<Option<Duration>, String> {
+    let Some(controls) = &audio_state.controls else {
+        return Err("Nothing is playing".to_string());
+    };
+    let start_time = controls.looping.to_time(start);
+    if let Some(total) = audio_state.total_duration {
+        if start_time >= total {
+            return Err(format!(
+                "Cannot loop from {:.1}s, the track is {:.1}s long",
+                start_time.as_secs_f64(),
+                total.as_secs_f64()
+            ));
+        }
+    }
+    let position = elapsed(audio_state);
+    Ok((position < start_time || position >= controls.looping.to_time(end)).then_some(start_time))
+}
+
+// Loops the current track between sample frames `start` and `end`, counted
+// at the track's own sample rate. Playback outside the region jumps to its
+// start; inside it carries on and wraps at the end. If playback can't get to
+// the start, the loop set before stays.
+pub fn set_loop(start: u64, end: u64) -> Result<(), String> {
+    set_loop_on(&get_audio_state(), start, end)
+}
+
+fn set_loop_on(state: &Mutex<AudioState>, start: u64, end: u64) -> Result<(), String> {
+    if end <= start {
+        return Err(format!("Invalid loop region: {} to {}", start, end));
+    }
+
+    // Fade out ahead of a likely jump. Whether to jump is decided again below,
+    // with the region checked, the jump made and the loop set under one lock.
+    let jump_likely = {
+        let mut audio_state = state.lock().unwrap();
+        sync_queued_track(&mut audio_state);
+        matches!(loop_entry(&audio_state, start, end), Ok(Some(_)))
+    };
+    if jump_likely {
+        fade_to_silence(state);
+    }
+
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    let jump = match loop_entry(&audio_state, start, end) {
+        Ok(jump) => jump,
+        Err(e) => {
+            fade_back_in(&audio_state);
+            return Err(e);
+        }
+    };
+    let previous = loop_frames(&audio_state);
+    if let Some(controls) = &audio_state.controls {
+        controls.looping.set(Some((start, end)));
+    }
+
+    match (jump, audio_state.current_track.clone()) {
+        (Some(start_time), Some(path)) => seek_to(&mut audio_state, path, start_time).map_err(|e| {
+            if let Some(controls) = &audio_state.controls {
+                controls.looping.set(previous);
+            }
+            format!("Failed to start loop: {}", e)
+        }),
+        _ => {
+            fade_back_in(&audio_state);
+            Ok(())
+        }
+    }
+}
+
+// Ends the loop; playback carries on from where it is in the region.
+pub fn clear_loop() -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    if let Some(controls) = &audio_state.controls {
+        controls.looping.set(None);
+    }
     Ok(())
 }
+
+pub fn get_loop() -> Result<Option<(u64, u64)>, String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    Ok(loop_frames(&audio_state))
+}
+
+// A loop whose source couldn't seek back to its start is picked up by
+// reopening the track there. If even that fails the loop is dropped, so the
+// track plays on rather than staying silent.
+fn reopen_stalled_loop(audio_state: &mut AudioState) -> Result<(), String> {
+    let Some(controls) = audio_state.controls.as_ref().filter(|c| c.looping.stalled()) else {
+        return Ok(());
+    };
+    let (Some(path), Some((start, _))) = (audio_state.current_track.clone(), controls.looping.times()) else {
+        return Ok(());
+    };
+    let playing = is_running(audio_state);
+    restart_at(audio_state, path, start, playing).map_err(|e| {
+        if let Some(controls) = &audio_state.controls {
+            controls.looping.set(None);
+        }
+        format!("Failed to loop: {}", e)
+    })
+}
+
+// Moves playback onto a rebuilt output stream, picking the current track up
+// where it had got to.
+pub fn reattach_output() -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+
+    let playing = is_running(&audio_state);
+    let position = elapsed(&audio_state);
+    match audio_state.current_track.clone() {
+        Some(path) if audio_state.controls.is_some() => restart_at(&mut audio_state, path, position, playing),
+        _ => {
+            end_fading_sink(&mut audio_state);
+            audio_state.sink = None;
+            Ok(())
+        }
+    }
+}
+
+// Returns once the fade-out has been played and the sink is paused.
+pub fn pause_music() -> Result<(), String> {
+    pause_music_on(&get_audio_state())
+}
+
+fn pause_music_on(state: &Mutex<AudioState>) -> Result<(), String> {
+    fade_to_silence(state);
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    end_fading_sink(&mut audio_state);
+    if let Some(sink) = &audio_state.sink {
+        sink.pause();
+    }
+    Ok(())
+}
+
+pub fn resume_music() -> Result<(), String> {
+    resume_music_on(&get_audio_state())
+}
+
+fn resume_music_on(state: &Mutex<AudioState>) -> Result<(), String> {
+    open_output(state)?;
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+
+    if is_running(&audio_state) {
+        return Ok(());
+    }
+
+    // A sleep timer leaves the track faded out; bring it back before the
+    // sink starts pulling again, then ramp in from the pause.
+    if let Some(controls) = &audio_state.controls {
+        controls.fader.ramp_to(1.0, Duration::ZERO);
+        controls.ramp.ramp_from(0.0, 1.0, audio_state.transition_fade);
+    }
+    if let Some(timer) = audio_state.sleep_timer.as_mut() {
+        timer.fading = None;
+    }
+
+    match &audio_state.sink {
+        Some(sink) if !sink.empty() => sink.play(),
+        _ => {
+            if let Some(entry) = audio_state.queue.current() {
+                return start_track(&mut audio_state, entry, false, None);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn stop_music() -> Result<(), String> {
+    stop_music_on(&get_audio_state())
+}
+
+fn stop_music_on(state: &Mutex<AudioState>) -> Result<(), String> {
+    fade_to_silence(state);
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    end_fading_sink(&mut audio_state);
+    // Dropped rather than left to drain, so the engine reads as stopped
+    // straight away.
+    if let Some(sink) = audio_state.sink.take() {
+        sink.stop();
+    }
+    audio_state.queued_next = None;
+    audio_state.controls = None;
+    Ok(())
+}
+
+pub fn get_current_track() -> Result<Option<String>, String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    Ok(audio_state.current_track.clone())
+}
+
+pub fn get_current_track_info() -> Result<Option<MusicFile>, String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+
+    if let Some(current_path) = &audio_state.current_track {
+        if let Some(track) = audio_state.tracks.iter().find(|t| &t.path == current_path) {
+            return Ok(Some(track.clone()));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn is_playing() -> Result<bool, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    if let Some(sink) = &audio_state.sink {
+        Ok(!sink.is_paused() && sink.len() > 0)
+    } else {
+        Ok(false)
+    }
+}
+
+pub fn list_music() -> Result<Vec<MusicFile>, String> {
+    let state = get_audio_state();
+    let tracks = state.lock().unwrap().tracks.clone();
+    Ok(tracks)
+}
+
+pub fn set_volume(volume: f32) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    audio_state.volume = volume;
+    if let Some(sink) = &audio_state.sink {
+        sink.set_volume(volume);
+    }
+    if let Some(sink) = &audio_state.fading_sink {
+        sink.set_volume(volume);
+    }
+    Ok(())
+}
+
+pub fn get_volume() -> Result<f32, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    Ok(audio_state.volume)
+}
+
+pub fn get_playback_position() -> Result<(f64, Option<f64>), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+
+    let elapsed = elapsed(&audio_state).as_secs_f64();
+    let total = audio_state.total_duration.map(|d| d.as_secs_f64());
+
+    Ok((elapsed, total))
+}
+
+fn fade_secs(secs: f32) -> Result<Duration, String> {
+    if secs.is_finite() && secs >= 0.0 {
+        Ok(Duration::from_secs_f32(secs))
+    } else {
+        Err(format!("Invalid fade duration: {}", secs))
+    }
+}
+
+// Fades out whichever track is current; a track that started mid-fade is
+// faded over the time that is left.
+fn fade_out_for_sleep(audio_state: &mut AudioState, remaining: Duration) {
+    let fader = audio_state.controls.as_ref().map(|c| c.fader.clone());
+    if let (Some(timer), Some(fader)) = (audio_state.sleep_timer.as_mut(), fader) {
+        if !timer.fading.as_ref().is_some_and(|f| Arc::ptr_eq(f, &fader)) {
+            fader.ramp_to(0.0, remaining);
+            timer.fading = Some(fader);
+        }
+    }
+}
+
+// A track played out to its end and the next one took over. Counted here
+// rather than by watching the current entry, which stays the same under
+// repeat-one. Called before the track after the new one is lined up, so that
+// none is once the new one is the timer's last.
+fn count_finished_track(audio_state: &mut AudioState) {
+    if let Some(timer) = audio_state.sleep_timer.as_mut() {
+        if timer.deadline.is_none() && timer.tracks_left > 0 {
+            timer.tracks_left -= 1;
+        }
+    }
+}
+
+fn run_sleep_timer(audio_state: &mut AudioState) {
+    let Some(timer) = audio_state.sleep_timer.as_ref() else {
+        return;
+    };
+    let deadline = timer.deadline;
+    let tracks_left = timer.tracks_left;
+    let fade = Duration::from_secs_f32(timer.settings.fade_secs);
+
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                end_fading_sink(audio_state);
+                if let Some(sink) = &audio_state.sink {
+                    sink.pause();
+                }
+                audio_state.sleep_timer = None;
+            } else if is_running(audio_state) && now + fade >= deadline {
+                fade_out_for_sleep(audio_state, deadline - now);
+            }
+        }
+        None if tracks_left == 0 => {
+            if let Some(total) = audio_state.total_duration {
+                let elapsed = elapsed(audio_state);
+                if is_running(audio_state) && elapsed + fade >= total {
+                    fade_out_for_sleep(audio_state, total.saturating_sub(elapsed));
+                }
+            }
+        }
+        None => {}
+    }
+}
+
+// The final track of a sleep timer has ended: stop there, with the queue
+// moved on so playing again continues where the timer left off.
+fn finish_sleep_timer(audio_state: &mut AudioState) {
+    audio_state.sleep_timer = None;
+    audio_state.controls = None;
+    if let Some(next) = audio_state.queue.upcoming() {
+        audio_state.queue.set_current(Some(next.id), &audio_state.tracks);
+        audio_state.current_track = Some(next.path);
+        audio_state.total_duration = None;
+    }
+}
+
+fn clear_sleep_timer(audio_state: &mut AudioState) {
+    if let Some(fader) = audio_state.sleep_timer.take().and_then(|timer| timer.fading) {
+        fader.ramp_to(1.0, SLEEP_CANCEL_FADE);
+    }
+}
+
+// Next time after `after` that the local clock shows `time`.
+fn next_alarm(time: &str, after: DateTime<Local>) -> Result<DateTime<Local>, String> {
+    let time = NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|e| format!("Invalid alarm time: {}", e))?;
+
+    let mut date = after.date_naive();
+    // A time skipped by a daylight saving change moves on to the next day.
+    for _ in 0..3 {
+        if let Some(at) = date.and_time(time).and_local_timezone(Local).earliest() {
+            if at > after {
+                return Ok(at);
+            }
+        }
+        date = date + Days::new(1);
+    }
+    Err("Failed to schedule alarm".to_string())
+}
+
+fn alarm_due(audio_state: &AudioState) -> bool {
+    audio_state.alarm.as_ref().is_some_and(|alarm| alarm.next <= Local::now())
+}
+
+fn run_alarm(audio_state: &mut AudioState) -> Result<(), String> {
+    let now = Local::now();
+    let alarm = match audio_state.alarm.take() {
+        Some(alarm) if alarm.next <= now => alarm,
+        alarm => {
+            audio_state.alarm = alarm;
+            return Ok(());
+        }
+    };
+
+    if alarm.settings.repeat_daily {
+        if let Ok(next) = next_alarm(&alarm.settings.time, now) {
+            audio_state.alarm = Some(Alarm {
+                settings: alarm.settings.clone(),
+                next,
+            });
+        }
+    }
+
+    let paths = match &alarm.settings.target {
+        AlarmTarget::Folder { path } => {
+            let folder = std::path::PathBuf::from(path);
+            library_paths(audio_state, |t| in_folder(t, &folder))
+        }
+        AlarmTarget::Tracks { paths } => paths.clone(),
+    };
+    let fade_in = Some(Duration::from_secs_f32(alarm.settings.fade_in_secs)).filter(|d| !d.is_zero());
+
+    match audio_state.queue.replace(paths, None, &audio_state.tracks) {
+        Some(entry) => start_track(audio_state, entry, false, fade_in),
+        None => Err("Nothing to play for the alarm".to_string()),
+    }
+}
+
+// Driven by the playback supervisor: runs pending transitions, moves on to the
+// next track once the sink has run dry and reports where playback stands.
+// Failures to start a track are returned alongside the status.
+pub fn poll_playback() -> (PlaybackStatus, Vec<PlaybackErrorEvent>) {
+    poll_playback_on(&get_audio_state())
+}
+
+fn poll_playback_on(state: &Mutex<AudioState>) -> (PlaybackStatus, Vec<PlaybackErrorEvent>) {
+    // An alarm can go off before anything has played; if the stream can't be
+    // opened, starting the alarm's track reports it.
+    if alarm_due(&state.lock().unwrap()) {
+        let _ = open_output(state);
+    }
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+
+    let finished = audio_state.controls.is_some()
+        && audio_state.sink.as_ref().is_some_and(|sink| sink.empty());
+
+    let mut errors: Vec<PlaybackErrorEvent> = [reopen_stalled_loop(&mut audio_state), run_alarm(&mut audio_state)]
+        .into_iter()
+        .filter_map(Result::err)
+        .map(|message| PlaybackErrorEvent {
+            path: audio_state.current_track.clone(),
+            message,
+        })
+        .collect();
+    if finished && stops_after_current(&audio_state) {
+        finish_sleep_timer(&mut audio_state);
+    } else if finished {
+        count_finished_track(&mut audio_state);
+        advance_past_failures(&mut audio_state, &mut errors);
+    }
+    run_sleep_timer(&mut audio_state);
+
+    let status = PlaybackStatus {
+        track: audio_state.current_track.clone(),
+        queue_version: audio_state.queue.version(),
+        playing: is_running(&audio_state),
+        position: elapsed(&audio_state).as_secs_f64(),
+        duration: audio_state.total_duration.map(|d| d.as_secs_f64()),
+    };
+    (status, errors)
+}
+
+// Starts the entry after the one that finished. An entry that can't be played
+// is reported and passed over, so one bad file doesn't hold up the rest of
+// the queue; playback stops once the queue runs out, or when every entry has
+// been tried.
+fn advance_past_failures(audio_state: &mut AudioState, errors: &mut Vec<PlaybackErrorEvent>) {
+    let mut tried = HashSet::new();
+    let mut failed = false;
+    while let Some(next) = audio_state.queue.upcoming() {
+        if !tried.insert(next.id) {
+            break;
+        }
+        let start = start_position(audio_state, &next.path, true);
+        match start_track_at(audio_state, next.clone(), false, None, start) {
+            Ok(()) => return,
+            Err(message) => {
+                failed = true;
+                errors.push(PlaybackErrorEvent {
+                    path: Some(next.path),
+                    message,
+                });
+            }
+        }
+    }
+
+    if failed {
+        release_sink(audio_state);
+        audio_state.queued_next = None;
+        audio_state.controls = None;
+        audio_state.current_track = None;
+        audio_state.total_duration = None;
+    }
+}
+
+fn skip_to(audio_state: &mut AudioState, entry: QueueEntry) -> Result<(), String> {
+    let crossfade = audio_state.crossfade.on_manual_skip
+        && audio_state
+            .current_track
+            .as_deref()
+            .is_some_and(|current| should_crossfade(audio_state, current, &entry.path));
+    start_track(audio_state, entry, crossfade, None)
+}
+
+pub fn play_next() -> Result<(), String> {
+    play_next_on(&get_audio_state())
+}
+
+fn play_next_on(state: &Mutex<AudioState>) -> Result<(), String> {
+    open_output(state)?;
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+
+    if let Some(next) = audio_state.queue.next_entry() {
+        skip_to(&mut audio_state, next)?;
+    }
+
+    Ok(())
+}
+
+pub fn play_previous() -> Result<(), String> {
+    play_previous_on(&get_audio_state())
+}
+
+fn play_previous_on(state: &Mutex<AudioState>) -> Result<(), String> {
+    open_output(state)?;
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+
+    if let Some(previous) = audio_state.queue.step_back() {
+        skip_to(&mut audio_state, previous)?;
+    }
+
+    Ok(())
+}
+
+pub fn set_repeat(mode: RepeatMode) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    audio_state.queue.set_repeat(mode);
+    replan_next(&mut audio_state)
+}
+
+pub fn set_shuffle(mode: ShuffleMode) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    let audio_state = &mut *audio_state;
+    audio_state.queue.set_shuffle(mode, &audio_state.tracks);
+    replan_next(audio_state)
+}
+
+pub fn set_crossfade(settings: CrossfadeSettings) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    audio_state.crossfade = CrossfadeSettings {
+        duration_secs: settings.duration_secs.clamp(0.0, MAX_CROSSFADE_SECS),
+        on_manual_skip: settings.on_manual_skip,
+    };
+
+    // Re-plan the upcoming transition so the new setting applies to it.
+    if audio_state.queued_next.as_ref().is_some_and(|next| next.source.is_some()) {
+        queue_next_track(&mut audio_state);
+    }
+    Ok(())
+}
+
+pub fn get_crossfade() -> Result<CrossfadeSettings, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    Ok(audio_state.crossfade.clone())
+}
+
+fn apply_replay_gain(audio_state: &AudioState) {
+    if let (Some(path), Some(controls)) = (&audio_state.current_track, &audio_state.controls) {
+        let gain = track_gain(audio_state, path);
+        controls.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+    if let Some(next) = &audio_state.queued_next {
+        let gain = track_gain(audio_state, &next.path);
+        next.controls.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+}
+
+pub fn set_replay_gain(settings: ReplayGainSettings) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    audio_state.replay_gain = settings;
+    apply_replay_gain(&audio_state);
+    Ok(())
+}
+
+pub fn set_silence(path: &str, silence: Silence) {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    if let Some(track) = audio_state.tracks.iter_mut().find(|t| t.path == path) {
+        track.silence = silence;
+    }
+}
+
+pub fn update_loudness(tracks: Vec<MusicFile>) {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    for track in audio_state.tracks.iter_mut() {
+        if let Some(updated) = tracks.iter().find(|t| t.path == track.path) {
+            track.loudness = updated.loudness.clone();
+        }
+    }
+    apply_replay_gain(&audio_state);
+}
+
+pub fn set_skip_silence(skip: bool) -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    audio_state.skip_silence = skip;
+
+    // Re-open a next track that is still held back, so the setting applies to
+    // the upcoming transition.
+    if audio_state.queued_next.as_ref().is_some_and(|next| next.source.is_some()) {
+        queue_next_track(&mut audio_state);
+    }
+    Ok(())
+}
+
+pub fn get_replay_gain() -> Result<ReplayGainSettings, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    Ok(audio_state.replay_gain.clone())
+}
+
+pub fn set_equalizer(settings: EqualizerSettings) -> Result<(), String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    audio_state.equalizer.update(settings);
+    Ok(())
+}
+
+pub fn get_equalizer() -> Result<EqualizerSettings, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    Ok(audio_state.equalizer.settings())
+}
+
+pub fn set_playback_rate(rate: f32, mode: PlaybackRateMode) -> Result<(), String> {
+    if !(MIN_PLAYBACK_RATE..=MAX_PLAYBACK_RATE).contains(&rate) {
+        return Err(format!(
+            "Playback rate must be between {} and {}",
+            MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE
+        ));
+    }
+
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    apply_playback_rate(&mut audio_state, PlaybackRate { rate, mode });
+    Ok(())
+}
+
+fn apply_playback_rate(audio_state: &mut AudioState, playback_rate: PlaybackRate) {
+    audio_state.playback_rate = playback_rate;
+
+    let PlaybackRate { rate, mode } = playback_rate;
+    let (stretch, speed) = match mode {
+        PlaybackRateMode::Stretch => (rate, 1.0),
+        PlaybackRateMode::Tape => (1.0, rate),
+    };
+    audio_state.stretch_rate.store(stretch.to_bits(), Ordering::Relaxed);
+    if let Some(sink) = &audio_state.sink {
+        sink.set_speed(speed);
+    }
+    if let Some(sink) = &audio_state.fading_sink {
+        sink.set_speed(speed);
+    }
+}
+
+pub fn get_playback_rate() -> Result<PlaybackRate, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    Ok(audio_state.playback_rate)
+}
+
+pub fn set_transition_fade(millis: u32) -> Result<(), String> {
+    let fade = Duration::from_millis(millis as u64);
+    if fade > MAX_TRANSITION_FADE {
+        return Err(format!(
+            "Transition fade must be at most {} ms",
+            MAX_TRANSITION_FADE.as_millis()
+        ));
+    }
+    let state = get_audio_state();
+    state.lock().unwrap().transition_fade = fade;
+    Ok(())
+}
+
+pub fn get_transition_fade() -> Result<u32, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    Ok(audio_state.transition_fade.as_millis() as u32)
+}
+
+pub fn set_sleep_timer(settings: SleepTimerSettings) -> Result<(), String> {
+    set_sleep_timer_on(&get_audio_state(), settings)
+}
+
+fn set_sleep_timer_on(state: &Mutex<AudioState>, settings: SleepTimerSettings) -> Result<(), String> {
+    fade_secs(settings.fade_secs)?;
+    let (deadline, tracks_left) = match settings.after {
+        SleepAfter::Minutes { minutes } if minutes.is_finite() && minutes > 0.0 => {
+            (Some(Instant::now() + Duration::from_secs_f32(minutes * 60.0)), 0)
+        }
+        SleepAfter::Minutes { minutes } => return Err(format!("Invalid sleep timer length: {}", minutes)),
+        SleepAfter::EndOfTrack => (None, 0),
+        SleepAfter::Tracks { count } => (None, count),
+    };
+
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    clear_sleep_timer(&mut audio_state);
+
+    audio_state.sleep_timer = Some(SleepTimer {
+        settings,
+        deadline,
+        tracks_left,
+        fading: None,
+    });
+    replan_next(&mut audio_state)
+}
+
+pub fn cancel_sleep_timer() -> Result<(), String> {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+    clear_sleep_timer(&mut audio_state);
+    replan_next(&mut audio_state)
+}
+
+pub fn get_sleep_timer() -> Result<Option<SleepTimerStatus>, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    Ok(audio_state.sleep_timer.as_ref().map(|timer| SleepTimerStatus {
+        settings: timer.settings.clone(),
+        remaining_secs: timer
+            .deadline
+            .map(|d| d.saturating_duration_since(Instant::now()).as_secs_f64()),
+        remaining_tracks: timer.deadline.is_none().then_some(timer.tracks_left),
+    }))
+}
+
+fn alarm_status(alarm: &Alarm) -> AlarmStatus {
+    AlarmStatus {
+        settings: alarm.settings.clone(),
+        next: alarm.next.to_rfc3339(),
+    }
+}
+
+pub fn set_alarm(settings: AlarmSettings) -> Result<AlarmStatus, String> {
+    fade_secs(settings.fade_in_secs)?;
+    let next = next_alarm(&settings.time, Local::now())?;
+
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    let alarm = Alarm { settings, next };
+    let status = alarm_status(&alarm);
+    audio_state.alarm = Some(alarm);
+    Ok(status)
+}
+
+pub fn cancel_alarm() -> Result<(), String> {
+    let state = get_audio_state();
+    state.lock().unwrap().alarm = None;
+    Ok(())
+}
+
+pub fn get_alarm() -> Result<Option<AlarmStatus>, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+    Ok(audio_state.alarm.as_ref().map(alarm_status))
+}
+
+// Snapshot of what playing `paths`, or the queue when there are none, would
+// go through, for rendering offline.
+pub fn export_plan(paths: Option<Vec<String>>) -> Result<ExportPlan, String> {
+    let state = get_audio_state();
+    let audio_state = state.lock().unwrap();
+
+    let paths = paths.unwrap_or_else(|| {
+        audio_state
+            .queue
+            .play_order()
+            .into_iter()
+            .map(|e| e.path)
+            .collect()
+    });
+    if paths.is_empty() {
+        return Err("Nothing to export".to_string());
+    }
+
+    let tracks = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| ExportTrack {
+            path: path.clone(),
+            range: range(&audio_state, path).cloned(),
+            gain: track_gain(&audio_state, path),
+            crossfade_in: i > 0 && should_crossfade(&audio_state, &paths[i - 1], path),
+        })
+        .collect();
+
+    Ok(ExportPlan {
+        tracks,
+        crossfade: crossfade_duration(&audio_state),
+        equalizer: audio_state.equalizer.settings(),
+    })
+}
+
+pub fn session() -> Session {
+    let state = get_audio_state();
+    let mut audio_state = state.lock().unwrap();
+    sync_queued_track(&mut audio_state);
+
+    let (queue, current, shuffle_order) = audio_state.queue.export();
+    Session {
+        queue,
+        state: SessionState {
+            current,
+            position: elapsed(&audio_state).as_secs_f64(),
+            volume: audio_state.volume,
+            repeat: audio_state.queue.repeat(),
+            shuffle: audio_state.queue.shuffle(),
+            shuffle_order,
+            crossfade: audio_state.crossfade.clone(),
+            replay_gain: audio_state.replay_gain.clone(),
+            equalizer: audio_state.equalizer.settings(),
+            playback_rate: audio_state.playback_rate,
+        },
+    }
+}
+
+// Brings back a saved session: settings first, then the queue, with the
+// current track loaded paused at the saved position.
+pub fn restore_session(session: Session) -> Result<(), String> {
+    let state = get_audio_state();
+    // Without a stream the queue still comes back; loading the track below
+    // reports the failure.
+    if session.state.current.is_some() {
+        let _ = open_output(&state);
+    }
+    let mut audio_state = state.lock().unwrap();
+    let saved = session.state;
+
+    audio_state.volume = saved.volume;
+    audio_state.crossfade = saved.crossfade;
+    audio_state.replay_gain = saved.replay_gain;
+    audio_state.equalizer.update(saved.equalizer);
+    let playback_rate = PlaybackRate {
+        rate: saved.playback_rate.rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE),
+        mode: saved.playback_rate.mode,
+    };
+    apply_playback_rate(&mut audio_state, playback_rate);
+
+    end_fading_sink(&mut audio_state);
+    if let Some(sink) = audio_state.sink.take() {
+        sink.stop();
+    }
+    audio_state.queued_next = None;
+    audio_state.controls = None;
+    audio_state.current_track = None;
+    audio_state.total_duration = None;
+
+    let current = audio_state.queue.restore(
+        session.queue,
+        saved.current,
+        saved.repeat,
+        saved.shuffle,
+        saved.shuffle_order,
+    );
+    match current {
+        Some(entry) => {
+            let position = Duration::from_secs_f64(saved.position.max(0.0));
+            restart_at(&mut audio_state, entry.path, position, false)
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tempfile::TempDir;
+    use crate::backend::ManualBackend;
+    use crate::models::{Loudness, ReplayGain};
+
+    const SAMPLE_RATE: u32 = 44100;
+    // Output pulled between polls: what a device takes in 10 ms.
+    const STEP: usize = ManualBackend::SAMPLE_RATE as usize / 100;
+    // How far a reported position may be from the output pulled: the sink
+    // applies pause, play and seek every 5 ms of output, and resampling holds
+    // back a frame.
+    const TOLERANCE: f64 = 0.006;
+
+    fn output_frames(secs: f64) -> usize {
+        (secs * ManualBackend::SAMPLE_RATE as f64) as usize
+    }
+
+    // Writes a stereo tone `secs` long into `dir`.
+    fn tone_file(dir: &TempDir, name: &str, secs: f32) -> String {
+        let path = dir.path().join(format!("{}.wav", name));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..(secs * SAMPLE_RATE as f32) as usize {
+            let sample = ((i as f32 * 440.0 * std::f32::consts::TAU / SAMPLE_RATE as f32).sin() * 8000.0) as i16;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    // A tone whose decoder can't seek.
+    fn unseekable_file(dir: &TempDir, name: &str, secs: f32) -> String {
+        let path = tone_file(dir, name, secs);
+        decoder::UNSEEKABLE.lock().unwrap().insert(path.clone());
+        path
+    }
+
+    fn missing_file(dir: &TempDir, name: &str) -> String {
+        let path = dir.path().join(format!("{}.wav", name));
+        assert!(!Path::new(&path).exists());
+        path.to_string_lossy().into_owned()
+    }
+
+    fn track(path: &str) -> MusicFile {
+        MusicFile {
+            path: path.to_string(),
+            name: path.to_string(),
+            artist: None,
+            album: None,
+            title: None,
+            thumbnail: None,
+            replay_gain: ReplayGain::default(),
+            loudness: Loudness::default(),
+            overrides: TrackOverrides::default(),
+            silence: Silence::default(),
+            range: None,
+        }
+    }
+
+    // A headless engine with `paths` as its library, and the output it plays
+    // into, which only moves when the test pulls from it.
+    fn engine(paths: &[&str]) -> (Mutex<AudioState>, ManualBackend) {
+        let output = ManualBackend::default();
+        let mut audio_state = AudioState::with_backend(Arc::new(output.clone())).unwrap();
+        audio_state.tracks = paths.iter().map(|p| track(p)).collect();
+        (Mutex::new(audio_state), output)
+    }
+
+    // Plays output a step at a time, polling after each step the way the
+    // supervisor does, until `done` holds. Returns the status then, the errors
+    // reported on the way and how many frames were played. Fails once `limit`
+    // frames have been played.
+    fn play_until(
+        state: &Mutex<AudioState>,
+        output: &ManualBackend,
+        limit: usize,
+        done: impl Fn(&PlaybackStatus) -> bool,
+    ) -> (PlaybackStatus, Vec<PlaybackErrorEvent>, usize) {
+        let mut errors = Vec::new();
+        let mut played = 0;
+        loop {
+            let (status, new_errors) = poll_playback_on(state);
+            errors.extend(new_errors);
+            if done(&status) {
+                return (status, errors, played);
+            }
+            assert!(played < limit, "still at {:?} {}s after {} frames", status.track, status.position, played);
+            output.pull(STEP);
+            played += STEP;
+        }
+    }
+
+    // Plays `frames` frames of output, polling as it goes.
+    fn play_for(
+        state: &Mutex<AudioState>,
+        output: &ManualBackend,
+        frames: usize,
+    ) -> (PlaybackStatus, Vec<PlaybackErrorEvent>) {
+        let mut errors = Vec::new();
+        for _ in 0..frames.div_ceil(STEP) {
+            output.pull(STEP);
+            errors.extend(poll_playback_on(state).1);
+        }
+        let (status, new_errors) = poll_playback_on(state);
+        errors.extend(new_errors);
+        (status, errors)
+    }
+
+    // Runs `action`, which waits for output to be played (a fade, or a seek
+    // in the sink), while pulling output until it returns.
+    fn while_playing<T: Send>(output: &ManualBackend, action: impl FnOnce() -> T + Send) -> T {
+        thread::scope(|scope| {
+            let action = scope.spawn(action);
+            while !action.is_finished() {
+                output.pull(64);
+                thread::yield_now();
+            }
+            action.join().unwrap()
+        })
+    }
+
+    fn status(state: &Mutex<AudioState>) -> PlaybackStatus {
+        poll_playback_on(state).0
+    }
+
+    fn current_path(state: &Mutex<AudioState>) -> Option<String> {
+        state.lock().unwrap().queue.current().map(|e| e.path)
+    }
+
+    #[test]
+    fn play_pause_resume_and_seek() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tone_file(&dir, "a", 3.0);
+        let (state, output) = engine(&[&a]);
+
+        play_music_on(&state, a.clone()).unwrap();
+        let (playing, _) = play_for(&state, &output, output_frames(0.5));
+        assert!(playing.playing);
+        assert_eq!(playing.track.as_deref(), Some(a.as_str()));
+        assert!((playing.position - 0.5).abs() < TOLERANCE, "{}", playing.position);
+        assert!((playing.duration.unwrap() - 3.0).abs() < 0.01);
+
+        while_playing(&output, || pause_music_on(&state)).unwrap();
+        assert!(!status(&state).playing);
+        let (paused, _) = play_for(&state, &output, STEP);
+        let (still, _) = play_for(&state, &output, output_frames(0.5));
+        assert_eq!(still.position, paused.position);
+
+        while_playing(&output, || seek_on(&state, 1.5)).unwrap();
+        let seeked = status(&state);
+        assert!(!seeked.playing);
+        assert!((seeked.position - 1.5).abs() < 0.001, "{}", seeked.position);
+
+        resume_music_on(&state).unwrap();
+        let (resumed, _) = play_for(&state, &output, output_frames(0.25));
+        assert!(resumed.playing);
+        assert!((resumed.position - 1.75).abs() < TOLERANCE, "{}", resumed.position);
+
+        assert!(while_playing(&output, || seek_on(&state, 10.0)).is_err());
+        assert!(seek_on(&state, -1.0).is_err());
+        while_playing(&output, || stop_music_on(&state)).unwrap();
+        assert!(!status(&state).playing);
+    }
+
+    #[test]
+    fn next_and_previous_move_through_the_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tone_file(&dir, "a", 3.0);
+        let b = tone_file(&dir, "b", 3.0);
+        let (state, output) = engine(&[&a, &b]);
+
+        play_music_on(&state, a.clone()).unwrap();
+        play_for(&state, &output, output_frames(0.3));
+
+        while_playing(&output, || play_next_on(&state)).unwrap();
+        let (next, _) = play_for(&state, &output, output_frames(0.1));
+        assert!(next.playing);
+        assert_eq!(next.track.as_deref(), Some(b.as_str()));
+        assert!((next.position - 0.1).abs() < TOLERANCE, "{}", next.position);
+        assert_eq!(current_path(&state), Some(b.clone()));
+
+        // Nothing follows the last entry.
+        while_playing(&output, || play_next_on(&state)).unwrap();
+        assert_eq!(current_path(&state), Some(b.clone()));
+
+        while_playing(&output, || play_previous_on(&state)).unwrap();
+        assert_eq!(status(&state).track.as_deref(), Some(a.as_str()));
+        assert_eq!(current_path(&state), Some(a));
+    }
+
+    #[test]
+    fn tracks_advance_on_their_own_and_stop_at_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tone_file(&dir, "a", 0.5);
+        let b = tone_file(&dir, "b", 0.5);
+        let (state, output) = engine(&[&a, &b]);
+
+        play_music_on(&state, a.clone()).unwrap();
+        let (advanced, errors, played) =
+            play_until(&state, &output, output_frames(1.0), |s| s.track.as_deref() == Some(b.as_str()));
+        assert!(errors.is_empty());
+        // Noticed within a poll of the switch, with no gap in between.
+        assert!(played.abs_diff(output_frames(0.5)) <= STEP + output_frames(TOLERANCE), "{}", played);
+        assert!(advanced.position < 0.02, "{}", advanced.position);
+        assert_eq!(current_path(&state), Some(b.clone()));
+
+        let (ended, _, played) = play_until(&state, &output, output_frames(1.0), |s| !s.playing);
+        assert!(played <= output_frames(0.5) + STEP, "{}", played);
+        assert_eq!(ended.track.as_deref(), Some(b.as_str()));
+        assert_eq!(current_path(&state), Some(b));
+    }
+
+    #[test]
+    fn repeat_one_plays_the_track_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tone_file(&dir, "a", 0.4);
+        let b = tone_file(&dir, "b", 0.4);
+        let (state, output) = engine(&[&a, &b]);
+        state.lock().unwrap().queue.set_repeat(RepeatMode::One);
+
+        play_music_on(&state, a.clone()).unwrap();
+        play_for(&state, &output, output_frames(0.3));
+        // Back near the start of the same track once it has played out.
+        let (again, _, played) = play_until(&state, &output, output_frames(0.5), |s| s.position < 0.2);
+        assert!(played.abs_diff(output_frames(0.1)) <= STEP + output_frames(TOLERANCE), "{}", played);
+        assert_eq!(again.track.as_deref(), Some(a.as_str()));
+        assert_eq!(current_path(&state), Some(a));
+    }
+
+    #[test]
+    fn sleep_timers_count_repeats_of_the_same_track() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tone_file(&dir, "a", 0.4);
+        let b = tone_file(&dir, "b", 0.4);
+        let (state, output) = engine(&[&a, &b]);
+        state.lock().unwrap().queue.set_repeat(RepeatMode::One);
+
+        play_music_on(&state, a.clone()).unwrap();
+        let settings = SleepTimerSettings {
+            after: SleepAfter::Tracks { count: 1 },
+            fade_secs: 0.0,
+        };
+        set_sleep_timer_on(&state, settings).unwrap();
+
+        // This play and one more, then it stops.
+        let (ended, _, played) = play_until(&state, &output, output_frames(1.2), |s| !s.playing);
+        assert!(played.abs_diff(output_frames(0.8)) <= STEP + output_frames(TOLERANCE), "{}", played);
+        assert_eq!(ended.track.as_deref(), Some(a.as_str()));
+        assert!(state.lock().unwrap().sleep_timer.is_none());
+    }
+
+    #[test]
+    fn entries_that_fail_to_open_are_passed_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tone_file(&dir, "a", 0.4);
+        let missing = missing_file(&dir, "missing");
+        let c = tone_file(&dir, "c", 0.4);
+        let (state, output) = engine(&[&a, &missing, &c]);
+
+        play_music_on(&state, a).unwrap();
+        let (advanced, errors, _) =
+            play_until(&state, &output, output_frames(1.0), |s| s.track.as_deref() == Some(c.as_str()));
+        assert!(advanced.playing);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path.as_deref(), Some(missing.as_str()));
+        assert_eq!(current_path(&state), Some(c));
+    }
+
+    #[test]
+    fn playback_stops_when_nothing_left_can_be_opened() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tone_file(&dir, "a", 0.4);
+        let missing = missing_file(&dir, "gone");
+        let (state, output) = engine(&[&a, &missing]);
+
+        play_music_on(&state, a).unwrap();
+        let (stopped, errors, _) = play_until(&state, &output, output_frames(1.0), |s| s.track.is_none());
+        assert!(!stopped.playing);
+        assert_eq!(errors.len(), 1);
+
+        // And it stays stopped rather than retrying on every poll.
+        let (_, errors) = play_for(&state, &output, output_frames(0.1));
+        assert!(errors.is_empty());
+    }
+
+    fn frames(secs: f64) -> u64 {
+        (secs * SAMPLE_RATE as f64) as u64
+    }
+
+    fn current_loop(state: &Mutex<AudioState>) -> Option<(u64, u64)> {
+        loop_frames(&state.lock().unwrap())
+    }
+
+    #[test]
+    fn loops_in_unseekable_tracks_wrap_by_reopening_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = unseekable_file(&dir, "a", 2.0);
+        let (state, output) = engine(&[&a]);
+        assert!(decoder::open_track(&a, None).unwrap().try_seek(Duration::ZERO).is_err());
+
+        play_music_on(&state, a.clone()).unwrap();
+        play_for(&state, &output, output_frames(0.5));
+
+        // Inside the region: nothing moves until playback reaches its end.
+        let region = (frames(0.25), frames(0.75));
+        while_playing(&output, || set_loop_on(&state, region.0, region.1)).unwrap();
+        assert_eq!(current_loop(&state), Some(region));
+        let set = status(&state).position;
+        assert!((set - 0.5).abs() < TOLERANCE, "{}", set);
+
+        // The decoder can't go back, so the track is opened again at the start.
+        let (wrapped, errors, played) =
+            play_until(&state, &output, output_frames(1.0), |s| s.position < set);
+        assert!(errors.is_empty());
+        assert!(played.abs_diff(output_frames(0.25)) <= STEP + output_frames(TOLERANCE), "{}", played);
+        assert!(wrapped.playing);
+        assert_eq!(wrapped.track.as_deref(), Some(a.as_str()));
+        assert!((0.25..0.25 + 0.02).contains(&wrapped.position), "{}", wrapped.position);
+        assert_eq!(current_loop(&state), Some(region));
+    }
+
+    #[test]
+    fn loops_starting_ahead_of_an_unseekable_track_reopen_it_at_the_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = unseekable_file(&dir, "a", 2.0);
+        let (state, output) = engine(&[&a]);
+
+        play_music_on(&state, a).unwrap();
+        play_for(&state, &output, output_frames(0.5));
+
+        assert!(set_loop_on(&state, frames(0.2), frames(0.2)).is_err());
+        let region = (frames(0.1), frames(0.3));
+        while_playing(&output, || set_loop_on(&state, region.0, region.1)).unwrap();
+        assert_eq!(current_loop(&state), Some(region));
+        let (jumped, _) = play_for(&state, &output, output_frames(0.05));
+        assert!(jumped.playing);
+        assert!((jumped.position - 0.15).abs() < TOLERANCE, "{}", jumped.position);
+
+        // Loops twice more, each time from the start.
+        let (_, errors) = play_for(&state, &output, output_frames(0.4));
+        assert!(errors.is_empty());
+        let position = status(&state).position;
+        assert!((0.1..0.3).contains(&position), "{}", position);
+    }
+}
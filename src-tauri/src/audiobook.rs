@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use tauri::AppHandle;
+use crate::audio;
+use crate::db;
+use crate::models::{Bookmark, PlaybackStatus};
+
+// Closer than this to the end counts as finished, so the next listen starts
+// from the beginning rather than a few seconds before the end.
+const FINISHED_MARGIN: f64 = 5.0;
+
+#[derive(Default)]
+struct Books {
+    // Files and folders flagged as audiobooks.
+    roots: Vec<String>,
+    // Where listening got to in each audiobook file, in seconds.
+    resume: HashMap<String, f64>,
+    // Files whose resume position changed since it was last saved.
+    unsaved: HashSet<String>,
+}
+
+static BOOKS: Lazy<Mutex<Books>> = Lazy::new(|| Mutex::new(Books::default()));
+
+// CUE tracks' paths carry on from the file they are cut from, so they count
+// as flagged along with it.
+fn flagged(books: &Books, path: &str) -> bool {
+    books.roots.iter().any(|root| Path::new(path).starts_with(root))
+}
+
+pub fn restore(app: &AppHandle) -> Result<(), String> {
+    let conn = db::get_db_connection(app)?;
+    let roots = db::load_audiobooks(&conn)?;
+    let resume = db::load_resume_positions(&conn)?;
+
+    let mut books = BOOKS.lock().unwrap();
+    books.roots = roots;
+    books.resume = resume;
+    books.unsaved.clear();
+    Ok(())
+}
+
+// Flags a file, or every file under a folder, as an audiobook.
+pub fn set_flag(app: &AppHandle, path: &str, enabled: bool) -> Result<(), String> {
+    let conn = db::get_db_connection(app)?;
+    db::set_audiobook(&conn, path, enabled)?;
+
+    let mut books = BOOKS.lock().unwrap();
+    books.roots.retain(|root| root != path);
+    if enabled {
+        books.roots.push(path.to_string());
+    }
+    Ok(())
+}
+
+pub fn flags() -> Vec<String> {
+    BOOKS.lock().unwrap().roots.clone()
+}
+
+// Where an audiobook file should start playing; `None` for anything else.
+pub fn resume_position(path: &str) -> Option<Duration> {
+    let books = BOOKS.lock().unwrap();
+    if !flagged(&books, path) {
+        return None;
+    }
+    books.resume.get(path).map(|&secs| Duration::from_secs_f64(secs.max(0.0)))
+}
+
+// Called by the playback supervisor on every tick. Only a playing track moves
+// the resume position, so a stopped or failed track doesn't reset it to 0.
+pub fn track_progress(status: &PlaybackStatus) {
+    let Some(path) = status.track.as_deref().filter(|_| status.playing) else {
+        return;
+    };
+    let mut books = BOOKS.lock().unwrap();
+    if !flagged(&books, path) {
+        return;
+    }
+
+    let finished = status.duration.is_some_and(|d| status.position >= d - FINISHED_MARGIN);
+    let changed = if finished {
+        books.resume.remove(path).is_some()
+    } else {
+        books.resume.insert(path.to_string(), status.position) != Some(status.position)
+    };
+    if changed {
+        books.unsaved.insert(path.to_string());
+    }
+}
+
+// Writes out the resume positions that changed. Called periodically by the
+// playback supervisor.
+pub fn persist(app: &AppHandle) -> Result<(), String> {
+    let changes: Vec<(String, Option<f64>)> = {
+        let mut books = BOOKS.lock().unwrap();
+        let unsaved: Vec<String> = books.unsaved.drain().collect();
+        unsaved
+            .into_iter()
+            .map(|path| {
+                let position = books.resume.get(&path).copied();
+                (path, position)
+            })
+            .collect()
+    };
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let conn = db::get_db_connection(app)?;
+    for (path, position) in changes {
+        db::save_resume_position(&conn, &path, position)?;
+    }
+    Ok(())
+}
+
+// Bookmarks the current track at `position`, or where it is now.
+pub fn add_bookmark(app: &AppHandle, note: Option<String>, position: Option<f64>) -> Result<Bookmark, String> {
+    let path = audio::get_current_track()?.ok_or_else(|| "Nothing is playing".to_string())?;
+    let position = match position {
+        Some(position) => position,
+        None => audio::get_playback_position()?.0,
+    };
+    if !position.is_finite() || position < 0.0 {
+        return Err(format!("Invalid bookmark position: {}", position));
+    }
+
+    let conn = db::get_db_connection(app)?;
+    db::add_bookmark(&conn, &path, position, note.as_deref())
+}
+
+// Seeks if the bookmark is in the current track, otherwise plays its track
+// from the bookmarked position.
+pub fn jump_to_bookmark(app: &AppHandle, id: i64) -> Result<(), String> {
+    let conn = db::get_db_connection(app)?;
+    let bookmark = db::get_bookmark(&conn, id)?.ok_or_else(|| format!("Bookmark not found: {}", id))?;
+
+    if audio::get_current_track()?.as_deref() == Some(bookmark.path.as_str()) {
+        audio::seek(bookmark.position)
+    } else {
+        audio::play_music_at(bookmark.path, bookmark.position)
+    }
+}
@@ -0,0 +1,301 @@
+use std::any::Any;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rodio::cpal::{self, BufferSize, FromSample, SampleFormat, SizedSample, SupportedBufferSize};
+use rodio::dynamic_mixer::{self, DynamicMixer, DynamicMixerController};
+use crate::models::{OutputDevice, OutputSettings};
+use crate::visualizer::Tap;
+
+// Selects the backend at startup: `null`, `wav:<path>`, or anything else for
+// the sound card.
+const BACKEND_VAR: &str = "YAMPLAYER_OUTPUT";
+// Format of the simulated devices.
+const SIMULATED_CHANNELS: u16 = 2;
+const SIMULATED_SAMPLE_RATE: u32 = 48000;
+const PUMP_INTERVAL: Duration = Duration::from_millis(10);
+
+pub type Mixer = Arc<DynamicMixerController<f32>>;
+// What a backend plays: the final mix, tapped for the visualizer since that is
+// what is actually heard.
+pub type MixSource = Tap<DynamicMixer<f32>>;
+// Keeps an open output running until it is dropped.
+pub type Stream = Box<dyn Any>;
+// Called from the backend when the device goes away under an open stream.
+pub type LostCallback = Box<dyn Fn() + Send>;
+
+// Where the mixed output ends up. Opening a stream creates the mixer in the
+// format the device wants and starts pulling from it.
+pub trait Backend: Send + Sync {
+    fn open(&self, settings: &OutputSettings, lost: LostCallback) -> Result<(Stream, Mixer), String>;
+
+    fn devices(&self) -> Result<Vec<OutputDevice>, String>;
+
+    // Fails if `device` can't be opened by this backend.
+    fn check_device(&self, device: Option<&str>) -> Result<(), String> {
+        match device {
+            Some(name) if !self.devices()?.iter().any(|d| d.name == name) => {
+                Err(format!("Output device not found: {}", name))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn from_env() -> Arc<dyn Backend> {
+    match std::env::var(BACKEND_VAR) {
+        Ok(value) if value == "null" => Arc::new(NullBackend),
+        Ok(value) if value.starts_with("wav:") => Arc::new(WavBackend {
+            path: value["wav:".len()..].to_string(),
+        }),
+        _ => Arc::new(RodioBackend),
+    }
+}
+
+fn new_mixer(channels: u16, sample_rate: u32) -> (Mixer, MixSource) {
+    let (controller, mixer) = dynamic_mixer::mixer::<f32>(channels, sample_rate);
+    (controller, Tap::new(mixer))
+}
+
+// The sound card, through the cpal host rodio ships with.
+pub struct RodioBackend;
+
+fn find_device(name: Option<&str>) -> Result<cpal::Device, String> {
+    let host = cpal::default_host();
+    match name {
+        Some(name) => host
+            .output_devices()
+            .map_err(|e| format!("Failed to list output devices: {}", e))?
+            .find(|d| d.name().is_ok_and(|n| n == name))
+            .ok_or_else(|| format!("Output device not found: {}", name)),
+        None => host
+            .default_output_device()
+            .ok_or_else(|| "No output device available".to_string()),
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut mixer: MixSource,
+    lost: LostCallback,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    device.build_output_stream::<T, _, _>(
+        config,
+        move |data: &mut [T], _| {
+            for sample in data.iter_mut() {
+                *sample = T::from_sample(mixer.next().unwrap_or(0.0));
+            }
+        },
+        move |err| {
+            if let cpal::StreamError::DeviceNotAvailable = err {
+                lost();
+            }
+        },
+        None,
+    )
+}
+
+impl Backend for RodioBackend {
+    // A remembered device that is no longer present falls back to the system
+    // default rather than leaving the player without output.
+    fn open(&self, settings: &OutputSettings, lost: LostCallback) -> Result<(Stream, Mixer), String> {
+        let device = find_device(settings.device.as_deref()).or_else(|_| find_device(None))?;
+        let supported = device
+            .default_output_config()
+            .map_err(|e| format!("Failed to get output config: {}", e))?;
+
+        let mut config = supported.config();
+        if let Some(frames) = settings.buffer_size {
+            if let SupportedBufferSize::Range { min, max } = supported.buffer_size() {
+                if !(*min..=*max).contains(&frames) {
+                    return Err(format!("Buffer size must be between {} and {} frames", min, max));
+                }
+            }
+            config.buffer_size = BufferSize::Fixed(frames);
+        }
+
+        let (controller, mixer) = new_mixer(config.channels, config.sample_rate.0);
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, mixer, lost),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, mixer, lost),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, mixer, lost),
+            SampleFormat::I32 => build_stream::<i32>(&device, &config, mixer, lost),
+            SampleFormat::U8 => build_stream::<u8>(&device, &config, mixer, lost),
+            format => return Err(format!("Unsupported sample format: {:?}", format)),
+        }
+        .map_err(|e| format!("Failed to create output stream: {}", e))?;
+
+        stream
+            .play()
+            .map_err(|e| format!("Failed to start output stream: {}", e))?;
+        Ok((Box::new(stream), controller))
+    }
+
+    fn devices(&self) -> Result<Vec<OutputDevice>, String> {
+        let host = cpal::default_host();
+        let default_name = host.default_output_device().and_then(|d| d.name().ok());
+        let devices = host
+            .output_devices()
+            .map_err(|e| format!("Failed to list output devices: {}", e))?;
+
+        Ok(devices
+            .filter_map(|d| d.name().ok())
+            .map(|name| OutputDevice {
+                is_default: default_name.as_deref() == Some(name.as_str()),
+                name,
+            })
+            .collect())
+    }
+
+    fn check_device(&self, device: Option<&str>) -> Result<(), String> {
+        find_device(device).map(|_| ())
+    }
+}
+
+// Pulls from the mixer on its own thread at the pace a device would, handing
+// each block to `sink`. Stops when dropped.
+struct Pump {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Pump {
+    fn start(mut mixer: MixSource, mut sink: impl FnMut(&[f32]) + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let samples_per_sec = SIMULATED_SAMPLE_RATE as f64 * SIMULATED_CHANNELS as f64;
+
+        let thread = thread::spawn(move || {
+            let started = Instant::now();
+            let mut pulled = 0u64;
+            let mut block = Vec::new();
+            while !stopped.load(Ordering::SeqCst) {
+                // Catch up with the clock in whole frames, so a late wakeup
+                // doesn't slow playback down.
+                let due = (started.elapsed().as_secs_f64() * samples_per_sec) as u64;
+                let due = due - due % SIMULATED_CHANNELS as u64;
+                block.clear();
+                block.extend((pulled..due).map(|_| mixer.next().unwrap_or(0.0)));
+                pulled = pulled.max(due);
+                sink(&block);
+                thread::sleep(PUMP_INTERVAL);
+            }
+        });
+
+        Pump {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Pump {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn simulated_device(name: &str) -> Vec<OutputDevice> {
+    vec![OutputDevice {
+        name: name.to_string(),
+        is_default: true,
+    }]
+}
+
+// Discards the output, but consumes it in real time so playback, seeking and
+// auto-advance behave as they would with a sound card.
+pub struct NullBackend;
+
+impl Backend for NullBackend {
+    fn open(&self, _settings: &OutputSettings, _lost: LostCallback) -> Result<(Stream, Mixer), String> {
+        let (controller, mixer) = new_mixer(SIMULATED_CHANNELS, SIMULATED_SAMPLE_RATE);
+        let pump = Pump::start(mixer, |_| {});
+        Ok((Box::new(pump), controller))
+    }
+
+    fn devices(&self) -> Result<Vec<OutputDevice>, String> {
+        Ok(simulated_device("Null output"))
+    }
+}
+
+// Plays only as much as it is told to through `pull`, so tests can step
+// playback a number of frames at a time instead of waiting on the clock.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct ManualBackend {
+    mix: Arc<std::sync::Mutex<Option<MixSource>>>,
+}
+
+#[cfg(test)]
+impl ManualBackend {
+    pub const SAMPLE_RATE: u32 = SIMULATED_SAMPLE_RATE;
+
+    // Plays `frames` frames of the open stream, if there is one.
+    pub fn pull(&self, frames: usize) {
+        if let Some(mix) = self.mix.lock().unwrap().as_mut() {
+            for _ in 0..frames * SIMULATED_CHANNELS as usize {
+                mix.next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Backend for ManualBackend {
+    fn open(&self, _settings: &OutputSettings, _lost: LostCallback) -> Result<(Stream, Mixer), String> {
+        let (controller, mixer) = new_mixer(SIMULATED_CHANNELS, SIMULATED_SAMPLE_RATE);
+        *self.mix.lock().unwrap() = Some(mixer);
+        Ok((Box::new(()), controller))
+    }
+
+    fn devices(&self) -> Result<Vec<OutputDevice>, String> {
+        Ok(simulated_device("Manual output"))
+    }
+}
+
+// Plays in real time like `NullBackend`, recording everything into a 32-bit
+// float WAV file. Each stream opened starts the file over.
+pub struct WavBackend {
+    path: String,
+}
+
+impl Backend for WavBackend {
+    fn open(&self, _settings: &OutputSettings, _lost: LostCallback) -> Result<(Stream, Mixer), String> {
+        let spec = hound::WavSpec {
+            channels: SIMULATED_CHANNELS,
+            sample_rate: SIMULATED_SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let writer = hound::WavWriter::create(&self.path, spec)
+            .map_err(|e| format!("Failed to create capture file: {}", e))?;
+        // Finalized when the pump thread ends and drops the closure.
+        let mut writer: Option<hound::WavWriter<BufWriter<File>>> = Some(writer);
+
+        let (controller, mixer) = new_mixer(SIMULATED_CHANNELS, SIMULATED_SAMPLE_RATE);
+        let pump = Pump::start(mixer, move |block| {
+            let failed = writer
+                .as_mut()
+                .is_some_and(|w| block.iter().any(|&s| w.write_sample(s).is_err()));
+            if failed {
+                writer = None;
+            }
+        });
+        Ok((Box::new(pump), controller))
+    }
+
+    fn devices(&self) -> Result<Vec<OutputDevice>, String> {
+        Ok(simulated_device(&format!("WAV capture ({})", self.path)))
+    }
+}
@@ -0,0 +1,150 @@
+use std::f64::consts::PI;
+
+// Second-order IIR section in transposed direct form II, with coefficients
+// normalised so that a0 == 1.
+#[derive(Clone, Copy, Default)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad { b, a, z: [0.0; 2] }
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad::new(
+            [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            [1.0, a[1] / a[0], a[2] / a[0]],
+        )
+    }
+
+    // The formulas below follow the RBJ audio EQ cookbook.
+    pub fn peaking(sample_rate: f64, frequency: f64, gain_db: f64, q: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+
+        Biquad::normalized(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    pub fn low_shelf(sample_rate: f64, frequency: f64, gain_db: f64, q: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let sqrt_a = 2.0 * a.sqrt() * alpha;
+
+        Biquad::normalized(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + sqrt_a,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sqrt_a,
+            ],
+        )
+    }
+
+    pub fn high_shelf(sample_rate: f64, frequency: f64, gain_db: f64, q: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let sqrt_a = 2.0 * a.sqrt() * alpha;
+
+        Biquad::normalized(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a,
+            ],
+        )
+    }
+
+    // Swaps in new coefficients but keeps the filter state, so parameter
+    // changes don't reset the signal path.
+    pub fn retune(&mut self, other: &Biquad) {
+        self.b = other.b;
+        self.a = other.a;
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48000.0;
+
+    // Steady-state gain of `filter` at `frequency`, measured by running a
+    // sine through it and comparing peaks once the filter has settled.
+    fn gain_db(mut filter: Biquad, frequency: f64) -> f64 {
+        let w = 2.0 * PI * frequency / RATE;
+        let settle = RATE as usize;
+        let peak = (0..settle * 2)
+            .map(|i| filter.process((w * i as f64).sin()))
+            .skip(settle)
+            .fold(0.0f64, |peak, y| peak.max(y.abs()));
+        20.0 * peak.log10()
+    }
+
+    #[test]
+    fn peaking_filters_reach_their_gain_at_the_centre() {
+        for gain in [6.0, -6.0, 12.0] {
+            let filter = Biquad::peaking(RATE, 1000.0, gain, 1.0);
+            assert!((gain_db(filter, 1000.0) - gain).abs() < 0.01, "{} dB", gain);
+            // Far from the centre the signal is left alone.
+            assert!(gain_db(filter, 20.0).abs() < 0.05, "{} dB", gain);
+        }
+    }
+
+    #[test]
+    fn shelves_are_half_way_at_the_corner() {
+        let gain = 6.0;
+        let low = Biquad::low_shelf(RATE, 200.0, gain, 0.707);
+        assert!((gain_db(low, 200.0) - gain / 2.0).abs() < 0.01);
+        assert!((gain_db(low, 10.0) - gain).abs() < 0.05);
+        assert!(gain_db(low, 10000.0).abs() < 0.05);
+
+        let high = Biquad::high_shelf(RATE, 5000.0, gain, 0.707);
+        assert!((gain_db(high, 5000.0) - gain / 2.0).abs() < 0.01);
+        assert!((gain_db(high, 20000.0) - gain).abs() < 0.1);
+        assert!(gain_db(high, 100.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn zero_gain_filters_pass_samples_through_exactly() {
+        let filters = [
+            Biquad::peaking(RATE, 1000.0, 0.0, 1.0),
+            Biquad::low_shelf(RATE, 200.0, 0.0, 0.707),
+            Biquad::high_shelf(RATE, 5000.0, 0.0, 0.707),
+        ];
+        for mut filter in filters {
+            for i in 0..1000 {
+                let x = (i as f64 * 0.37).sin() * 0.8;
+                assert_eq!(filter.process(x), x);
+            }
+        }
+    }
+}
@@ -0,0 +1,745 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use lofty::file::TaggedFileExt;
+use crate::formats::{self, Format};
+use crate::models::Chapter;
+
+// Nero chapter times count 100 ns units.
+const NERO_TIMESCALE: f64 = 10_000_000.0;
+// Sample counts in a chapter track are taken from the file; past this many,
+// the table is treated as damaged rather than allocated for.
+const MAX_SAMPLES: usize = 100_000;
+// Longest chapter title sample that is read.
+const MAX_TEXT_SAMPLE: u32 = 65536;
+
+// A chapter as the file gives it: start, end if stated, and title.
+type Marker = (f64, Option<f64>, Option<String>);
+
+// Chapter markers from whichever form the container carries them in: MP4
+// chapter tracks or Nero chapters, ID3v2 CHAP frames, or Vorbis CHAPTERxxx
+// comments. Files without chapters give an empty list.
+pub fn read(path: &str) -> Result<Vec<Chapter>, String> {
+    let starts = match formats::detect(path) {
+        Some(Format::Mp4) => mp4_chapters(path)?,
+        Some(Format::Mp3) => id3_chapters(path)?,
+        Some(Format::Flac | Format::Vorbis | Format::Opus) => vorbis_chapters(path),
+        _ => Vec::new(),
+    };
+    Ok(number(starts))
+}
+
+// Sorts the markers and lets each chapter run to the start of the next.
+fn number(mut starts: Vec<Marker>) -> Vec<Chapter> {
+    starts.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let next_starts: Vec<f64> = starts.iter().skip(1).map(|c| c.0).collect();
+
+    starts
+        .into_iter()
+        .enumerate()
+        .map(|(index, (start, end, title))| Chapter {
+            index,
+            title: title.filter(|t| !t.trim().is_empty()),
+            start,
+            end: end.or_else(|| next_starts.get(index).copied()),
+        })
+        .collect()
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+// The boxes directly inside `data`, as (type, contents).
+fn mp4_boxes(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut boxes = Vec::new();
+    let mut at = 0;
+    while let Some(size) = be_u32(data, at) {
+        let kind = &data[at + 4..(at + 8).min(data.len())];
+        let (header, size) = match size {
+            0 => (8, data.len() - at),
+            1 => match be_u64(data, at + 8) {
+                Some(size) => (16, size as usize),
+                None => break,
+            },
+            size => (8, size as usize),
+        };
+        if size < header || size > data.len() - at {
+            break;
+        }
+        boxes.push((kind, &data[at + header..at + size]));
+        at += size;
+    }
+    boxes
+}
+
+fn mp4_child<'a>(data: &'a [u8], path: &[&[u8]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| {
+        mp4_boxes(data).into_iter().find(|(k, _)| k == kind).map(|(_, body)| body)
+    })
+}
+
+// Reads the `moov` box, which can sit at either end of the file.
+fn read_moov(file: &mut File) -> Result<Option<Vec<u8>>, String> {
+    let length = file
+        .metadata()
+        .map_err(|e| format!("Failed to read file: {}", e))?
+        .len();
+    let mut at = 0u64;
+    let mut header = [0u8; 16];
+
+    while at + 8 <= length {
+        file.seek(SeekFrom::Start(at))
+            .and_then(|_| file.read_exact(&mut header[..8]))
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        let (header_len, size) = match be_u32(&header, 0) {
+            Some(0) => (8, length - at),
+            Some(1) => {
+                file.read_exact(&mut header[8..])
+                    .map_err(|e| format!("Failed to read file: {}", e))?;
+                (16, be_u64(&header, 8).unwrap_or(0))
+            }
+            Some(size) => (8, size as u64),
+            None => break,
+        };
+        if size < header_len || size > length - at {
+            break;
+        }
+
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![0u8; (size - header_len) as usize];
+            file.read_exact(&mut moov)
+                .map_err(|e| format!("Failed to read file: {}", e))?;
+            return Ok(Some(moov));
+        }
+        at += size;
+    }
+    Ok(None)
+}
+
+// Full boxes start with a version byte and three bytes of flags.
+fn track_id(trak: &[u8]) -> Option<u32> {
+    let tkhd = mp4_child(trak, &[b"tkhd"])?;
+    match tkhd.first()? {
+        1 => be_u32(tkhd, 20),
+        _ => be_u32(tkhd, 12),
+    }
+}
+
+fn timescale(trak: &[u8]) -> Option<u32> {
+    let mdhd = mp4_child(trak, &[b"mdia", b"mdhd"])?;
+    match mdhd.first()? {
+        1 => be_u32(mdhd, 20),
+        _ => be_u32(mdhd, 12),
+    }
+    .filter(|&t| t > 0)
+}
+
+// The tracks a track points to as its chapter list.
+fn chapter_refs(trak: &[u8]) -> Vec<u32> {
+    mp4_child(trak, &[b"tref", b"chap"])
+        .map(|chap| chap.chunks_exact(4).filter_map(|id| be_u32(id, 0)).collect())
+        .unwrap_or_default()
+}
+
+// Entries of a sample table box: the count, then fixed-size records.
+fn table<'a>(stbl: &'a [u8], kind: &[u8], offset: usize, record: usize) -> Vec<&'a [u8]> {
+    let Some(body) = mp4_child(stbl, &[kind]) else {
+        return Vec::new();
+    };
+    let count = be_u32(body, offset - 4).unwrap_or(0) as usize;
+    body.get(offset..)
+        .unwrap_or_default()
+        .chunks_exact(record)
+        .take(count)
+        .collect()
+}
+
+// Where each sample of a track starts in the file and how long it is.
+fn sample_locations(stbl: &[u8]) -> Vec<(u64, u32)> {
+    let (fixed, count, sizes) = match mp4_child(stbl, &[b"stsz"]) {
+        Some(stsz) => {
+            let fixed = be_u32(stsz, 4).unwrap_or(0);
+            let count = (be_u32(stsz, 8).unwrap_or(0) as usize).min(MAX_SAMPLES);
+            let sizes: Vec<u32> = match fixed {
+                0 => table(stbl, b"stsz", 12, 4).iter().filter_map(|e| be_u32(e, 0)).collect(),
+                _ => Vec::new(),
+            };
+            (fixed, count, sizes)
+        }
+        None => (0, 0, Vec::new()),
+    };
+    let size_of = |sample: usize| match fixed {
+        0 => sizes.get(sample).copied(),
+        _ => Some(fixed).filter(|_| sample < count),
+    };
+
+    let chunks: Vec<u64> = if mp4_child(stbl, &[b"co64"]).is_some() {
+        table(stbl, b"co64", 8, 8).iter().filter_map(|e| be_u64(e, 0)).collect()
+    } else {
+        table(stbl, b"stco", 8, 4).iter().filter_map(|e| be_u32(e, 0).map(u64::from)).collect()
+    };
+
+    // (first chunk, samples per chunk), with chunks numbered from 1.
+    let runs: Vec<(usize, usize)> = table(stbl, b"stsc", 8, 12)
+        .iter()
+        .filter_map(|e| Some((be_u32(e, 0)? as usize, be_u32(e, 4)? as usize)))
+        .collect();
+
+    let mut locations = Vec::new();
+    for (i, &chunk_start) in chunks.iter().enumerate() {
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= i + 1)
+            .map_or(0, |run| run.1);
+        let mut at = chunk_start;
+        for _ in 0..per_chunk {
+            let Some(size) = size_of(locations.len()) else {
+                return locations;
+            };
+            locations.push((at, size));
+            at = at.saturating_add(size as u64);
+        }
+    }
+    locations
+}
+
+// Start of each sample in timescale units.
+fn sample_times(stbl: &[u8]) -> Vec<u64> {
+    let mut time = 0u64;
+    let mut times = Vec::new();
+    for entry in table(stbl, b"stts", 8, 8) {
+        let (count, delta) = (be_u32(entry, 0).unwrap_or(0), be_u32(entry, 4).unwrap_or(0));
+        for _ in 0..count {
+            if times.len() == MAX_SAMPLES {
+                return times;
+            }
+            times.push(time);
+            time += delta as u64;
+        }
+    }
+    times
+}
+
+// Text samples are a 16-bit length followed by the text, UTF-8 unless it
+// starts with a UTF-16 byte order mark.
+fn text_sample(sample: &[u8]) -> Option<String> {
+    let len = be_u16(sample, 0)? as usize;
+    let text = sample.get(2..2 + len)?;
+    match text {
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        _ => Some(String::from_utf8_lossy(text).into_owned()),
+    }
+}
+
+fn utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> Option<String> {
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|c| unit([c[0], c[1]])).collect();
+    Some(String::from_utf16_lossy(&units).trim_end_matches('\0').to_string())
+}
+
+// QuickTime chapters: a text track that the audio track references, one
+// sample per chapter holding its title.
+fn quicktime_chapters(file: &mut File, moov: &[u8]) -> Option<Vec<Marker>> {
+    let traks: Vec<&[u8]> = mp4_boxes(moov)
+        .into_iter()
+        .filter(|(kind, _)| *kind == b"trak")
+        .map(|(_, body)| body)
+        .collect();
+    let id = traks.iter().flat_map(|t| chapter_refs(t)).next()?;
+    let trak = traks.iter().find(|t| track_id(t) == Some(id))?;
+
+    let scale = timescale(trak)? as f64;
+    let stbl = mp4_child(trak, &[b"mdia", b"minf", b"stbl"])?;
+    let times = sample_times(stbl);
+    let locations = sample_locations(stbl);
+
+    let chapters = times
+        .iter()
+        .zip(&locations)
+        .map(|(&time, &(at, size))| {
+            let mut sample = vec![0u8; size.min(MAX_TEXT_SAMPLE) as usize];
+            let title = file
+                .seek(SeekFrom::Start(at))
+                .and_then(|_| file.read_exact(&mut sample))
+                .ok()
+                .and_then(|_| text_sample(&sample));
+            (time as f64 / scale, None, title)
+        })
+        .collect();
+    Some(chapters)
+}
+
+// Nero chapters in `moov/udta/chpl`: a start time and a title per chapter.
+fn nero_chapters(moov: &[u8]) -> Option<Vec<Marker>> {
+    let chpl = mp4_child(moov, &[b"udta", b"chpl"])?;
+    let mut at = if chpl.first()? == &1 { 8 } else { 4 };
+    let count = *chpl.get(at)? as usize;
+    at += 1;
+
+    let mut chapters = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = be_u64(chpl, at)?;
+        let len = *chpl.get(at + 8)? as usize;
+        let title = chpl.get(at + 9..at + 9 + len)?;
+        chapters.push((
+            start as f64 / NERO_TIMESCALE,
+            None,
+            Some(String::from_utf8_lossy(title).into_owned()),
+        ));
+        at += 9 + len;
+    }
+    Some(chapters)
+}
+
+fn mp4_chapters(path: &str) -> Result<Vec<Marker>, String> {
+    let mut file = File::open(path).map_err(|e| format!("Failed to open file: {}", e))?;
+    let Some(moov) = read_moov(&mut file)? else {
+        return Ok(Vec::new());
+    };
+
+    Ok(quicktime_chapters(&mut file, &moov)
+        .filter(|c| !c.is_empty())
+        .or_else(|| nero_chapters(&moov))
+        .unwrap_or_default())
+}
+
+fn syncsafe(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 4)?;
+    Some(bytes.iter().fold(0, |size, &b| (size << 7) | (b & 0x7F) as u32))
+}
+
+// Text frame contents: an encoding byte, then the text.
+fn id3_text(body: &[u8]) -> Option<String> {
+    let (&encoding, text) = body.split_first()?;
+    let text = match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 => match text {
+            [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes)?,
+            [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes)?,
+            _ => utf16(text, u16::from_le_bytes)?,
+        },
+        2 => utf16(text, u16::from_be_bytes)?,
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+    Some(text.trim_end_matches('\0').to_string())
+}
+
+// The frames in `data` as (id, contents). Version 2.4 gives frame sizes as
+// syncsafe integers, 2.3 as plain ones.
+fn id3_frames(data: &[u8], version: u8) -> Vec<(&[u8], &[u8])> {
+    let mut frames = Vec::new();
+    let mut at = 0;
+    while at + 10 <= data.len() && data[at] != 0 {
+        let size = match version {
+            4 => syncsafe(data, at + 4),
+            _ => be_u32(data, at + 4),
+        };
+        let Some(size) = size.map(|s| s as usize) else {
+            break;
+        };
+        let Some(body) = data.get(at + 10..at + 10 + size) else {
+            break;
+        };
+        frames.push((&data[at..at + 4], body));
+        at += 10 + size;
+    }
+    frames
+}
+
+// CHAP frames: an element id, start and end in milliseconds, byte offsets
+// that are ignored, then subframes with the title in TIT2.
+fn id3_chapters(path: &str) -> Result<Vec<Marker>, String> {
+    let mut file = File::open(path).map_err(|e| format!("Failed to open file: {}", e))?;
+    let mut header = [0u8; 10];
+    if file.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return Ok(Vec::new());
+    }
+    let (version, flags) = (header[3], header[5]);
+    if !(3..=4).contains(&version) {
+        return Ok(Vec::new());
+    }
+
+    // A damaged size reads up to the end of the file rather than failing.
+    let size = syncsafe(&header, 6).unwrap_or(0) as u64;
+    let mut tag = Vec::new();
+    file.take(size)
+        .read_to_end(&mut tag)
+        .map_err(|e| format!("Failed to read ID3 tag: {}", e))?;
+
+    // Whole-tag unsynchronisation inserts a zero after every 0xFF.
+    if flags & 0x80 != 0 && version == 3 {
+        let mut plain = Vec::with_capacity(tag.len());
+        for (i, &b) in tag.iter().enumerate() {
+            if !(b == 0 && i > 0 && tag[i - 1] == 0xFF) {
+                plain.push(b);
+            }
+        }
+        tag = plain;
+    }
+
+    let mut frames_start = 0;
+    if flags & 0x40 != 0 {
+        frames_start = match version {
+            4 => syncsafe(&tag, 0).unwrap_or(0) as usize,
+            _ => be_u32(&tag, 0).unwrap_or(0) as usize + 4,
+        };
+    }
+    let Some(frames) = tag.get(frames_start..) else {
+        return Ok(Vec::new());
+    };
+
+    let chapters = id3_frames(frames, version)
+        .into_iter()
+        .filter(|(id, _)| *id == b"CHAP")
+        .filter_map(|(_, body)| {
+            let id_end = body.iter().position(|&b| b == 0)?;
+            let times = id_end + 1;
+            let start = be_u32(body, times)?;
+            let end = be_u32(body, times + 4)?;
+            let title = id3_frames(body.get(times + 16..)?, version)
+                .into_iter()
+                .find(|(id, _)| *id == b"TIT2")
+                .and_then(|(_, text)| id3_text(text));
+            Some((start as f64 / 1000.0, Some(end as f64 / 1000.0).filter(|&e| e > 0.0), title))
+        })
+        .collect();
+    Ok(chapters)
+}
+
+// "HH:MM:SS.mmm", with the hours optional.
+fn parse_timestamp(text: &str) -> Option<f64> {
+    text.trim()
+        .split(':')
+        .try_fold(0.0, |total, part| Some(total * 60.0 + part.parse::<f64>().ok()?))
+        .filter(|t| t.is_finite() && *t >= 0.0)
+}
+
+// CHAPTER001=00:00:00.000 with the title in CHAPTER001NAME.
+fn vorbis_chapters(path: &str) -> Vec<Marker> {
+    let Ok(tagged) = lofty::read_from_path(path) else {
+        return Vec::new();
+    };
+    let Some(tag) = tagged.primary_tag().or_else(|| tagged.first_tag()) else {
+        return Vec::new();
+    };
+
+    let comments: Vec<(String, &str)> = tag
+        .items()
+        .filter_map(|item| match item.key() {
+            lofty::tag::ItemKey::Unknown(key) => Some((key.to_uppercase(), item.value().text()?)),
+            _ => None,
+        })
+        .collect();
+    comment_chapters(&comments)
+}
+
+// The chapters among upper-cased (key, value) comments.
+fn comment_chapters(comments: &[(String, &str)]) -> Vec<Marker> {
+    let name_of = |key: &str| {
+        comments
+            .iter()
+            .find(|(k, _)| k.strip_prefix(key) == Some("NAME"))
+            .map(|(_, v)| v.to_string())
+    };
+
+    comments
+        .iter()
+        .filter(|(key, _)| {
+            key.strip_prefix("CHAPTER")
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .filter_map(|(key, value)| Some((parse_timestamp(value)?, None, name_of(key))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    // A version 0 full box body: version and flags, then 32-bit fields.
+    fn fields(values: &[u32]) -> Vec<u8> {
+        let mut body = vec![0; 4];
+        for value in values {
+            body.extend_from_slice(&value.to_be_bytes());
+        }
+        body
+    }
+
+    fn temp_file(name: &str, data: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("yamplayer-chapters-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn text(title: &str) -> Vec<u8> {
+        let mut sample = (title.len() as u16).to_be_bytes().to_vec();
+        sample.extend_from_slice(title.as_bytes());
+        sample
+    }
+
+    // An M4B with an audio track whose `tref/chap` points at a text track of
+    // three chapter titles, stored in one chunk of `mdat`.
+    fn quicktime_file(titles: &[&str], starts_ms: &[u32]) -> Vec<u8> {
+        let ftyp = mp4_box(b"ftyp", b"M4B \0\0\0\0");
+        let samples: Vec<Vec<u8>> = titles.iter().map(|t| text(t)).collect();
+        let mdat = mp4_box(b"mdat", &samples.concat());
+        let first_sample = (ftyp.len() + 8) as u32;
+
+        let audio = mp4_box(b"trak", &[
+            mp4_box(b"tkhd", &fields(&[0, 0, 1])),
+            mp4_box(b"tref", &mp4_box(b"chap", &2u32.to_be_bytes())),
+        ].concat());
+
+        let mut stts = vec![starts_ms.len() as u32];
+        for pair in starts_ms.windows(2) {
+            stts.extend([1, pair[1] - pair[0]]);
+        }
+        stts.extend([1, 1000]);
+        let mut stsz = vec![0, samples.len() as u32];
+        stsz.extend(samples.iter().map(|s| s.len() as u32));
+        let stbl = [
+            mp4_box(b"stts", &fields(&stts)),
+            mp4_box(b"stsz", &fields(&stsz)),
+            mp4_box(b"stsc", &fields(&[1, 1, samples.len() as u32, 1])),
+            mp4_box(b"stco", &fields(&[1, first_sample])),
+        ]
+        .concat();
+        let chapters = mp4_box(b"trak", &[
+            mp4_box(b"tkhd", &fields(&[0, 0, 2])),
+            mp4_box(b"mdia", &[
+                mp4_box(b"mdhd", &fields(&[0, 0, 1000])),
+                mp4_box(b"minf", &mp4_box(b"stbl", &stbl)),
+            ].concat()),
+        ].concat());
+
+        let moov = mp4_box(b"moov", &[audio, chapters].concat());
+        [ftyp, mdat, moov].concat()
+    }
+
+    fn chpl(chapters: &[(u64, &str)]) -> Vec<u8> {
+        let mut body = vec![1, 0, 0, 0, 0, 0, 0, 0, chapters.len() as u8];
+        for (start, title) in chapters {
+            body.extend_from_slice(&start.to_be_bytes());
+            body.push(title.len() as u8);
+            body.extend_from_slice(title.as_bytes());
+        }
+        mp4_box(b"udta", &mp4_box(b"chpl", &body))
+    }
+
+    fn id3_frame(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn chap(element: &str, start_ms: u32, end_ms: u32, title: Option<&[u8]>) -> Vec<u8> {
+        let mut body = element.as_bytes().to_vec();
+        body.push(0);
+        for value in [start_ms, end_ms, u32::MAX, u32::MAX] {
+            body.extend_from_slice(&value.to_be_bytes());
+        }
+        if let Some(title) = title {
+            body.extend(id3_frame(b"TIT2", title));
+        }
+        id3_frame(b"CHAP", &body)
+    }
+
+    // An ID3v2.3 tag in front of a single MPEG frame header.
+    fn id3_file(frames: &[u8]) -> Vec<u8> {
+        let size = frames.len() as u32;
+        let syncsafe = [(size >> 21) as u8 & 0x7F, (size >> 14) as u8 & 0x7F, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F];
+        let mut data = b"ID3\x03\x00\x00".to_vec();
+        data.extend_from_slice(&syncsafe);
+        data.extend_from_slice(frames);
+        data.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        data
+    }
+
+    fn summary(chapters: &[Chapter]) -> Vec<(f64, Option<f64>, Option<&str>)> {
+        chapters.iter().map(|c| (c.start, c.end, c.title.as_deref())).collect()
+    }
+
+    #[test]
+    fn reads_quicktime_chapter_track() {
+        let path = temp_file("qt.m4b", &quicktime_file(&["Intro", "Part One", "Part Two"], &[0, 1500, 4000]));
+        let chapters = read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(
+            summary(&chapters),
+            [(0.0, Some(1.5), Some("Intro")), (1.5, Some(4.0), Some("Part One")), (4.0, None, Some("Part Two"))]
+        );
+        assert_eq!(chapters.iter().map(|c| c.index).collect::<Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn reads_utf16_text_samples() {
+        let mut sample = vec![0, 8, 0xFE, 0xFF, 0, b'O', 0, b'k', 0, 0];
+        assert_eq!(text_sample(&sample).as_deref(), Some("Ok"));
+        sample[2..4].copy_from_slice(&[0xFF, 0xFE]);
+        sample[4..].copy_from_slice(&[b'O', 0, b'k', 0, 0, 0]);
+        assert_eq!(text_sample(&sample).as_deref(), Some("Ok"));
+        // Length past the end of the sample.
+        assert_eq!(text_sample(&[0, 9, b'a']), None);
+        assert_eq!(text_sample(&[0]), None);
+    }
+
+    #[test]
+    fn falls_back_to_nero_chapters() {
+        let moov = mp4_box(b"moov", &chpl(&[(0, "One"), (25_000_000, "Two")]));
+        let data = [mp4_box(b"ftyp", b"M4A \0\0\0\0"), moov].concat();
+        let path = temp_file("nero.m4a", &data);
+        let chapters = read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(summary(&chapters), [(0.0, Some(2.5), Some("One")), (2.5, None, Some("Two"))]);
+    }
+
+    #[test]
+    fn reads_id3_chap_frames() {
+        let frames = [
+            chap("ch1", 60_000, 0, Some(b"\x00Second")),
+            chap("ch0", 0, 60_000, Some(b"\x01\xFF\xFEF\x00i\x00r\x00s\x00t\x00\x00\x00")),
+            chap("ch2", 90_000, 120_000, None),
+            id3_frame(b"TIT2", b"\x03Book"),
+        ]
+        .concat();
+        let path = temp_file("id3.mp3", &id3_file(&frames));
+        let chapters = read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(
+            summary(&chapters),
+            [(0.0, Some(60.0), Some("First")), (60.0, Some(90.0), Some("Second")), (90.0, Some(120.0), None)]
+        );
+    }
+
+    #[test]
+    fn reads_vorbis_chapter_comments() {
+        let comments = [
+            ("CHAPTER002".to_string(), "00:01:30.500"),
+            ("CHAPTER001".to_string(), "00:00:00.000"),
+            ("CHAPTER001NAME".to_string(), "Opening"),
+            ("CHAPTER002NAME".to_string(), "  "),
+            ("CHAPTER003".to_string(), "not a time"),
+            ("CHAPTERS".to_string(), "00:00:10"),
+            ("CHAPTER004".to_string(), "02:00"),
+        ];
+        let chapters = number(comment_chapters(&comments));
+        assert_eq!(
+            summary(&chapters),
+            [(0.0, Some(90.5), Some("Opening")), (90.5, Some(120.0), None), (120.0, None, None)]
+        );
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("01:02:03.250"), Some(3723.25));
+        assert_eq!(parse_timestamp("2:03"), Some(123.0));
+        assert_eq!(parse_timestamp("45.5"), Some(45.5));
+        for bad in ["", "1::2", "-1:00", "aa:00", "inf", "NaN"] {
+            assert_eq!(parse_timestamp(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn truncated_mp4_data_does_not_panic() {
+        let data = quicktime_file(&["Intro", "Part One"], &[0, 1500]);
+        let moov_at = data.len() - mp4_boxes(&data).last().unwrap().1.len();
+        let moov = &data[moov_at..];
+        let with_nero = [moov, chpl(&[(0, "One"), (10, "Two")]).as_slice()].concat();
+        for end in 0..with_nero.len() {
+            let part = &with_nero[..end];
+            let traks: Vec<&[u8]> = mp4_boxes(part).into_iter().map(|(_, body)| body).collect();
+            for trak in traks {
+                track_id(trak);
+                timescale(trak);
+                chapter_refs(trak);
+                if let Some(stbl) = mp4_child(trak, &[b"mdia", b"minf", b"stbl"]) {
+                    sample_locations(stbl);
+                    sample_times(stbl);
+                }
+            }
+            nero_chapters(part);
+        }
+        for end in 0..data.len() {
+            // An error is fine; a panic isn't.
+            let path = temp_file("cut.m4b", &data[..end]);
+            let _ = read(&path);
+            std::fs::remove_file(&path).ok();
+        }
+    }
+
+    #[test]
+    fn damaged_mp4_sizes_and_counts_are_bounded() {
+        // Box sizes that run past the data, or would overflow.
+        let mut data = mp4_box(b"moov", &[0; 8]);
+        data[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(mp4_boxes(&data).is_empty());
+        let mut large = vec![0, 0, 0, 1];
+        large.extend_from_slice(b"moov");
+        large.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(mp4_boxes(&large).is_empty());
+        assert!(mp4_boxes(&[0, 0, 0, 4, b'f']).is_empty());
+
+        // Tables that claim billions of entries.
+        let stbl = [
+            mp4_box(b"stts", &fields(&[1, u32::MAX, 1])),
+            mp4_box(b"stsz", &fields(&[16, u32::MAX])),
+            mp4_box(b"stsc", &fields(&[1, 1, u32::MAX, 1])),
+            mp4_box(b"stco", &fields(&[2, u32::MAX, u32::MAX])),
+        ]
+        .concat();
+        assert_eq!(sample_times(&stbl).len(), MAX_SAMPLES);
+        let locations = sample_locations(&stbl);
+        assert_eq!(locations.len(), MAX_SAMPLES);
+        assert_eq!(locations[1], (u32::MAX as u64 + 16, 16));
+
+        let wide = [mp4_box(b"co64", &[fields(&[1]), u64::MAX.to_be_bytes().to_vec()].concat())].concat();
+        let stbl = [wide, mp4_box(b"stsz", &fields(&[16, 2])), mp4_box(b"stsc", &fields(&[1, 1, 2, 1]))].concat();
+        assert_eq!(sample_locations(&stbl), [(u64::MAX, 16), (u64::MAX, 16)]);
+    }
+
+    #[test]
+    fn truncated_id3_data_does_not_panic() {
+        let frames = [
+            chap("ch0", 0, 1000, Some(b"\x01\xFE\xFF\x00A")),
+            chap("ch1", 1000, 0, Some(b"\x02\x00B\x00")),
+        ]
+        .concat();
+        for end in 0..frames.len() {
+            for version in [3, 4] {
+                for (_, body) in id3_frames(&frames[..end], version) {
+                    id3_text(body);
+                }
+            }
+        }
+        let data = id3_file(&frames);
+        for end in 0..data.len() {
+            let path = temp_file("cut.mp3", &data[..end]);
+            let _ = read(&path);
+            std::fs::remove_file(&path).ok();
+        }
+        // A tag that claims to be larger than the file.
+        let mut oversized = id3_file(&frames);
+        oversized[6..10].copy_from_slice(&[0x7F; 4]);
+        let path = temp_file("oversized.mp3", &oversized);
+        assert_eq!(read(&path).unwrap().len(), 2);
+        std::fs::remove_file(&path).ok();
+    }
+}
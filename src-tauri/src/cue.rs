@@ -0,0 +1,301 @@
+use std::time::Duration;
+use crate::models::TrackRange;
+
+// CUE times count frames of 1/75 s, the CD sector rate.
+const FRAMES_PER_SEC: u64 = 75;
+
+#[derive(Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+    pub files: Vec<CueFile>,
+}
+
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Default)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    // Start of INDEX 01 in CUE frames. Pregaps (INDEX 00) stay with the
+    // previous track, so consecutive tracks join without a gap.
+    pub start: Option<u64>,
+}
+
+// A part of an audio file, in CUE frames.
+#[derive(Clone, Copy)]
+pub struct Range {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl Range {
+    pub fn start(&self) -> Duration {
+        frames_to_duration(self.start)
+    }
+
+    pub fn length(&self) -> Option<Duration> {
+        self.end.map(|end| frames_to_duration(end.saturating_sub(self.start)))
+    }
+}
+
+impl From<&TrackRange> for Range {
+    fn from(range: &TrackRange) -> Self {
+        Range {
+            start: range.start,
+            end: range.end,
+        }
+    }
+}
+
+fn frames_to_duration(frames: u64) -> Duration {
+    Duration::from_secs(frames / FRAMES_PER_SEC) + Duration::from_nanos(frames % FRAMES_PER_SEC * 1_000_000_000 / FRAMES_PER_SEC)
+}
+
+// CUE sheets are often saved in a legacy code page rather than UTF-8; bytes
+// that aren't valid UTF-8 are read as Latin-1.
+pub fn decode_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+// Splits `"quoted value" rest` or `value rest` into the value and the rest.
+fn take_value(text: &str) -> (String, &str) {
+    let text = text.trim_start();
+    if let Some(quoted) = text.strip_prefix('"') {
+        match quoted.find('"') {
+            Some(end) => (quoted[..end].to_string(), &quoted[end + 1..]),
+            None => (quoted.to_string(), ""),
+        }
+    } else {
+        match text.find(char::is_whitespace) {
+            Some(end) => (text[..end].to_string(), &text[end..]),
+            None => (text.to_string(), ""),
+        }
+    }
+}
+
+fn parse_time(text: &str) -> Option<u64> {
+    let mut parts = text.trim().split(':').map(|p| p.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    minutes.checked_mul(60)?.checked_add(seconds)?.checked_mul(FRAMES_PER_SEC)?.checked_add(frames)
+}
+
+// "-6.50 dB" as written by ReplayGain taggers.
+fn parse_gain(text: &str) -> Option<f32> {
+    text.split_whitespace().next()?.parse().ok()
+}
+
+pub fn parse(text: &str) -> CueSheet {
+    let mut sheet = CueSheet::default();
+
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let track = sheet.files.last_mut().and_then(|f| f.tracks.last_mut());
+
+        match command.to_uppercase().as_str() {
+            "FILE" => sheet.files.push(CueFile {
+                name: take_value(rest).0,
+                tracks: Vec::new(),
+            }),
+            "TRACK" => {
+                let number = take_value(rest).0.parse().unwrap_or(0);
+                // Sheets embedded in the audio file may leave out FILE.
+                if sheet.files.is_empty() {
+                    sheet.files.push(CueFile {
+                        name: String::new(),
+                        tracks: Vec::new(),
+                    });
+                }
+                if let Some(file) = sheet.files.last_mut() {
+                    file.tracks.push(CueTrack {
+                        number,
+                        ..CueTrack::default()
+                    });
+                }
+            }
+            "TITLE" => match track {
+                Some(track) => track.title = Some(take_value(rest).0),
+                None => sheet.title = Some(take_value(rest).0),
+            },
+            "PERFORMER" => match track {
+                Some(track) => track.performer = Some(take_value(rest).0),
+                None => sheet.performer = Some(take_value(rest).0),
+            },
+            "INDEX" => {
+                let (index, time) = take_value(rest);
+                if let (Some(track), Ok(1)) = (track, index.parse::<u32>()) {
+                    track.start = parse_time(time);
+                }
+            }
+            "REM" => {
+                let (key, value) = take_value(rest);
+                match (key.to_uppercase().as_str(), track) {
+                    ("REPLAYGAIN_TRACK_GAIN", Some(track)) => track.track_gain = parse_gain(value),
+                    ("REPLAYGAIN_TRACK_PEAK", Some(track)) => track.track_peak = parse_gain(value),
+                    ("REPLAYGAIN_ALBUM_GAIN", _) => sheet.album_gain = parse_gain(value),
+                    ("REPLAYGAIN_ALBUM_PEAK", _) => sheet.album_peak = parse_gain(value),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for file in &mut sheet.files {
+        file.tracks.retain(|t| t.start.is_some());
+        file.tracks.sort_by_key(|t| t.start);
+    }
+    sheet
+}
+
+// Each track runs up to the start of the next one; the last runs to the end
+// of the file.
+pub fn ranges(tracks: &[CueTrack]) -> Vec<Range> {
+    tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| Range {
+            start: track.start.unwrap_or(0),
+            end: tracks.get(i + 1).and_then(|t| t.start),
+        })
+        .collect()
+}
+
+// The library path of the `index`th track (from 1) cut from `file`. It only
+// identifies the track: the range to play is stored with it. Since `file` is
+// a file, the path can't name a real one.
+pub fn track_path(file: &str, index: usize) -> String {
+    format!("{}/{:02}", file, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE Rock
+REM REPLAYGAIN_ALBUM_GAIN -7.25 dB
+REM REPLAYGAIN_ALBUM_PEAK 0.988
+PERFORMER "The Band"
+TITLE "The Album"
+FILE "The Album.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Opener"
+    REM REPLAYGAIN_TRACK_GAIN -6.50 dB
+    REM REPLAYGAIN_TRACK_PEAK 0.912
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second"
+    PERFORMER "Guest"
+    INDEX 00 03:58:50
+    INDEX 01 04:00:10
+  track 03 audio
+    title Closer
+    index 01 07:30:74
+"#;
+
+    #[test]
+    fn parses_sheet_and_track_fields() {
+        let sheet = parse(SHEET);
+        assert_eq!(sheet.title.as_deref(), Some("The Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+        assert_eq!(sheet.album_gain, Some(-7.25));
+        assert_eq!(sheet.album_peak, Some(0.988));
+        assert_eq!(sheet.files.len(), 1);
+        assert_eq!(sheet.files[0].name, "The Album.flac");
+
+        let tracks = &sheet.files[0].tracks;
+        assert_eq!(tracks.iter().map(|t| t.number).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(tracks[0].title.as_deref(), Some("Opener"));
+        assert_eq!(tracks[0].performer, None);
+        assert_eq!(tracks[0].track_gain, Some(-6.5));
+        assert_eq!(tracks[0].track_peak, Some(0.912));
+        assert_eq!(tracks[1].performer.as_deref(), Some("Guest"));
+        assert_eq!(tracks[1].track_gain, None);
+        // Lower-case commands and unquoted values.
+        assert_eq!(tracks[2].title.as_deref(), Some("Closer"));
+    }
+
+    #[test]
+    fn track_starts_at_index_01_in_frames_of_75th_seconds() {
+        let sheet = parse(SHEET);
+        let starts: Vec<_> = sheet.files[0].tracks.iter().map(|t| t.start).collect();
+        assert_eq!(starts, [Some(0), Some(240 * 75 + 10), Some(450 * 75 + 74)]);
+
+        let ranges = ranges(&sheet.files[0].tracks);
+        // The pregap before track 2 stays with track 1.
+        assert_eq!(ranges[0].end, Some(240 * 75 + 10));
+        assert_eq!(ranges[1].start(), Duration::from_nanos(240_133_333_333));
+        assert_eq!(ranges[1].length(), Some(Duration::from_nanos((210 * 75 + 64) * 1_000_000_000 / 75)));
+        assert_eq!(ranges[2].end, None);
+        assert_eq!(ranges[2].length(), None);
+    }
+
+    #[test]
+    fn tracks_are_grouped_by_file_and_sorted() {
+        let sheet = parse(
+            "FILE \"b.wav\" WAVE\n\
+             TRACK 02 AUDIO\nINDEX 01 01:00:00\n\
+             TRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+             FILE \"c.wav\" WAVE\n\
+             TRACK 03 AUDIO\nINDEX 01 00:00:00\n",
+        );
+        assert_eq!(sheet.files.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), ["b.wav", "c.wav"]);
+        assert_eq!(sheet.files[0].tracks.iter().map(|t| t.number).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(sheet.files[1].tracks.len(), 1);
+    }
+
+    #[test]
+    fn tracks_without_index_01_or_with_bad_times_are_dropped() {
+        let sheet = parse(
+            "FILE a.wav WAVE\n\
+             TRACK 01 AUDIO\nINDEX 00 00:00:00\n\
+             TRACK 02 AUDIO\nINDEX 01 1:xx:00\n\
+             TRACK 03 AUDIO\nINDEX 01 00:02\n\
+             TRACK 04 AUDIO\nINDEX 01 00:02:00\n",
+        );
+        assert_eq!(sheet.files[0].tracks.iter().map(|t| t.number).collect::<Vec<_>>(), [4]);
+    }
+
+    #[test]
+    fn embedded_sheet_without_file_line() {
+        // CUESHEET tags in FLAC and APE files sometimes carry only tracks.
+        let sheet = parse("TITLE Live\r\nTRACK 01 AUDIO\r\nINDEX 01 00:00:00\r\nTRACK 02 AUDIO\r\nINDEX 01 00:10:00\r\n");
+        assert_eq!(sheet.title.as_deref(), Some("Live"));
+        assert_eq!(sheet.files.len(), 1);
+        assert_eq!(sheet.files[0].tracks.len(), 2);
+        assert_eq!(sheet.files[0].tracks[1].start, Some(750));
+    }
+
+    #[test]
+    fn garbage_parses_to_nothing() {
+        for text in ["", "TRACK", "INDEX 01 00:00:00", "FILE", "FILE \"unterminated", "REM", "TRACK 01\nINDEX 01 99999999999999999999:00:00", "TRACK 01\nINDEX 01 18446744073709551615:00:00"] {
+            let sheet = parse(text);
+            assert!(sheet.files.iter().all(|f| f.tracks.is_empty()), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn decodes_utf8_with_bom_and_latin1() {
+        assert_eq!(decode_text(b"\xEF\xBB\xBFTITLE \"Caf\xC3\xA9\""), "TITLE \"Caf\u{e9}\"");
+        assert_eq!(decode_text(b"TITLE \"Caf\xE9\""), "TITLE \"Caf\u{e9}\"");
+    }
+
+    #[test]
+    fn track_paths_are_unique_per_track() {
+        assert_eq!(track_path("/music/a#cue:1-2.flac", 3), "/music/a#cue:1-2.flac/03");
+        assert_ne!(track_path("/music/a.flac", 1), track_path("/music/a.flac", 10));
+    }
+}
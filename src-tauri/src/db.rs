@@ -1,6 +1,9 @@
-use rusqlite::{Connection, Result as SqlResult, params};
+use rusqlite::{Connection, OptionalExtension, Result as SqlResult, params};
 use tauri::{AppHandle, Manager};
-use crate::models::{MusicFile, IndexedFolder};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use crate::models::{Bookmark, LoopRegion, MusicFile, IndexedFolder, Loudness, ReplayGain, EqPreset, Session, Silence, TrackOverrides, TrackRange, Waveform, WaveformChannel};
 
 pub fn get_db_path(app: &AppHandle) -> Result<std::path::PathBuf, String> {
     let app_data_dir = app.path().app_data_dir()
@@ -14,7 +17,11 @@ pub fn init_db(app: &AppHandle) -> Result<Connection, String> {
     let db_path = get_db_path(app)?;
     let conn = Connection::open(&db_path)
         .map_err(|e| format!("Failed to open database: {}", e))?;
+    create_tables(&conn)?;
+    Ok(conn)
+}
 
+pub fn create_tables(conn: &Connection) -> Result<(), String> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS indexed_folders (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -34,6 +41,20 @@ pub fn init_db(app: &AppHandle) -> Result<Connection, String> {
             album TEXT,
             title TEXT,
             thumbnail TEXT,
+            replaygain_track_gain REAL,
+            replaygain_track_peak REAL,
+            replaygain_album_gain REAL,
+            replaygain_album_peak REAL,
+            r128_integrated REAL,
+            r128_range REAL,
+            r128_true_peak REAL,
+            r128_album_integrated REAL,
+            r128_album_range REAL,
+            r128_album_true_peak REAL,
+            r128_scanned INTEGER,
+            source_file TEXT,
+            start_frame INTEGER,
+            end_frame INTEGER,
             FOREIGN KEY (folder_id) REFERENCES indexed_folders(id) ON DELETE CASCADE
         )",
         [],
@@ -59,12 +80,144 @@ pub fn init_db(app: &AppHandle) -> Result<Connection, String> {
         [],
     ).ok();
 
+    for column in [
+        "replaygain_track_gain",
+        "replaygain_track_peak",
+        "replaygain_album_gain",
+        "replaygain_album_peak",
+        "r128_integrated",
+        "r128_range",
+        "r128_true_peak",
+        "r128_album_integrated",
+        "r128_album_range",
+        "r128_album_true_peak",
+    ] {
+        conn.execute(
+            &format!("ALTER TABLE tracks ADD COLUMN {} REAL", column),
+            [],
+        ).ok();
+    }
+
+    conn.execute(
+        "ALTER TABLE tracks ADD COLUMN r128_scanned INTEGER",
+        [],
+    ).ok();
+
+    // Where the audio of a track cut from a single-file rip by a CUE sheet
+    // is: the file, and start and end in CUE frames.
+    for column in ["source_file TEXT", "start_frame INTEGER", "end_frame INTEGER"] {
+        conn.execute(
+            &format!("ALTER TABLE tracks ADD COLUMN {}", column),
+            [],
+        ).ok();
+    }
+
     conn.execute(
         "CREATE INDEX IF NOT EXISTS idx_tracks_folder ON tracks(folder_id)",
         [],
     ).map_err(|e| format!("Failed to create index: {}", e))?;
 
-    Ok(conn)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS eq_presets (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT UNIQUE NOT NULL,
+            settings TEXT NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create eq_presets table: {}", e))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create settings table: {}", e))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS session_queue (
+            position INTEGER PRIMARY KEY,
+            path TEXT NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create session_queue table: {}", e))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS waveforms (
+            path TEXT PRIMARY KEY,
+            modified INTEGER NOT NULL,
+            duration REAL NOT NULL,
+            channels INTEGER NOT NULL,
+            buckets INTEGER NOT NULL,
+            data BLOB NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create waveforms table: {}", e))?;
+
+    // Silence found at the ends of each track, kept for as long as the file
+    // and the threshold it was found with stay the same.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS silence (
+            path TEXT PRIMARY KEY,
+            modified INTEGER NOT NULL,
+            threshold_db REAL NOT NULL,
+            start_secs REAL NOT NULL,
+            end_secs REAL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create silence table: {}", e))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS audiobooks (
+            path TEXT PRIMARY KEY
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create audiobooks table: {}", e))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS resume_positions (
+            path TEXT PRIMARY KEY,
+            position REAL NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create resume_positions table: {}", e))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS bookmarks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            position REAL NOT NULL,
+            note TEXT,
+            created TEXT NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create bookmarks table: {}", e))?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS loop_regions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            name TEXT NOT NULL,
+            start_frame INTEGER NOT NULL,
+            end_frame INTEGER NOT NULL
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create loop_regions table: {}", e))?;
+
+    // Keyed by path rather than track id, since re-indexing recreates the
+    // track rows.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS track_overrides (
+            path TEXT PRIMARY KEY,
+            start_secs REAL,
+            stop_secs REAL,
+            gain_db REAL NOT NULL DEFAULT 0,
+            exclude_from_shuffle INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    ).map_err(|e| format!("Failed to create track_overrides table: {}", e))?;
+
+    Ok(())
 }
 
 pub fn get_db_connection(app: &AppHandle) -> Result<Connection, String> {
@@ -89,16 +242,24 @@ pub fn save_folder(conn: &Connection, path: &str) -> Result<i64, String> {
 }
 
 pub fn save_tracks(conn: &Connection, folder_id: i64, tracks: &[MusicFile]) -> Result<(), String> {
+    // Re-indexing replaces the rows, so keep loudness analysis for files that
+    // are still there instead of forcing a rescan.
+    let previous_loudness = load_loudness(conn, folder_id)?;
+
     conn.execute(
         "DELETE FROM tracks WHERE folder_id = ?1",
         params![folder_id],
     ).map_err(|e| format!("Failed to delete old tracks: {}", e))?;
 
     let mut stmt = conn.prepare(
-        "INSERT INTO tracks (folder_id, path, name, artist, album, title, thumbnail) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+        "INSERT INTO tracks (folder_id, path, name, artist, album, title, thumbnail, replaygain_track_gain, replaygain_track_peak, replaygain_album_gain, replaygain_album_peak, r128_integrated, r128_range, r128_true_peak, r128_album_integrated, r128_album_range, r128_album_true_peak, r128_scanned, source_file, start_frame, end_frame) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)"
     ).map_err(|e| format!("Failed to prepare statement: {}", e))?;
 
     for track in tracks {
+        let loudness = match previous_loudness.get(&track.path) {
+            Some(previous) if !track.loudness.scanned => previous,
+            _ => &track.loudness,
+        };
         stmt.execute(params![
             folder_id,
             track.path,
@@ -107,6 +268,20 @@ pub fn save_tracks(conn: &Connection, folder_id: i64, tracks: &[MusicFile]) -> R
             track.album,
             track.title,
             track.thumbnail,
+            track.replay_gain.track_gain,
+            track.replay_gain.track_peak,
+            track.replay_gain.album_gain,
+            track.replay_gain.album_peak,
+            loudness.integrated,
+            loudness.range,
+            loudness.true_peak,
+            loudness.album_integrated,
+            loudness.album_range,
+            loudness.album_true_peak,
+            loudness.scanned,
+            track.range.as_ref().map(|r| &r.file),
+            track.range.as_ref().map(|r| r.start as i64),
+            track.range.as_ref().and_then(|r| r.end).map(|end| end as i64),
         ])
             .map_err(|e| format!("Failed to insert track: {}", e))?;
     }
@@ -116,10 +291,10 @@ pub fn save_tracks(conn: &Connection, folder_id: i64, tracks: &[MusicFile]) -> R
 
 pub fn load_tracks(conn: &Connection) -> Result<Vec<MusicFile>, String> {
     let mut stmt = conn.prepare(
-        "SELECT path, name, artist, album, title, thumbnail FROM tracks ORDER BY COALESCE(artist, ''), COALESCE(album, ''), COALESCE(title, name)"
+        "SELECT path, name, artist, album, title, thumbnail, replaygain_track_gain, replaygain_track_peak, replaygain_album_gain, replaygain_album_peak, r128_integrated, r128_range, r128_true_peak, r128_album_integrated, r128_album_range, r128_album_true_peak, COALESCE(r128_scanned, r128_integrated IS NOT NULL), (SELECT start_secs FROM silence WHERE silence.path = tracks.path), (SELECT end_secs FROM silence WHERE silence.path = tracks.path), source_file, start_frame, end_frame FROM tracks ORDER BY COALESCE(artist, ''), COALESCE(album, ''), COALESCE(title, name)"
     ).map_err(|e| format!("Failed to prepare statement: {}", e))?;
 
-    let tracks: Vec<MusicFile> = stmt.query_map([], |row| {
+    let mut tracks: Vec<MusicFile> = stmt.query_map([], |row| {
         Ok(MusicFile {
             path: row.get(0)?,
             name: row.get(1)?,
@@ -127,15 +302,154 @@ pub fn load_tracks(conn: &Connection) -> Result<Vec<MusicFile>, String> {
             album: row.get(3)?,
             title: row.get(4)?,
             thumbnail: row.get(5)?,
+            replay_gain: ReplayGain {
+                track_gain: row.get(6)?,
+                track_peak: row.get(7)?,
+                album_gain: row.get(8)?,
+                album_peak: row.get(9)?,
+            },
+            loudness: Loudness {
+                integrated: row.get(10)?,
+                range: row.get(11)?,
+                true_peak: row.get(12)?,
+                album_integrated: row.get(13)?,
+                album_range: row.get(14)?,
+                album_true_peak: row.get(15)?,
+                scanned: row.get(16)?,
+            },
+            overrides: TrackOverrides::default(),
+            silence: Silence {
+                start: row.get::<_, Option<f64>>(17)?.unwrap_or(0.0),
+                end: row.get(18)?,
+            },
+            range: range_from_row(row, 19)?,
         })
     })
     .map_err(|e| format!("Failed to query tracks: {}", e))?
     .collect::<SqlResult<Vec<_>>>()
     .map_err(|e| format!("Failed to collect tracks: {}", e))?;
 
+    apply_overrides(conn, &mut tracks)?;
     Ok(tracks)
 }
 
+// The range stored from column `first` on: file, start and end frame.
+fn range_from_row(row: &rusqlite::Row, first: usize) -> SqlResult<Option<TrackRange>> {
+    let file: Option<String> = row.get(first)?;
+    let start: Option<i64> = row.get(first + 1)?;
+    let end: Option<i64> = row.get(first + 2)?;
+    Ok(file.map(|file| TrackRange {
+        file,
+        start: start.unwrap_or(0).max(0) as u64,
+        end: end.map(|end| end.max(0) as u64),
+    }))
+}
+
+// The range of a track cut from a file by a CUE sheet, or `None` for a track
+// that is a whole file or isn't in the library.
+pub fn load_track_range(conn: &Connection, path: &str) -> Result<Option<TrackRange>, String> {
+    conn.query_row(
+        "SELECT source_file, start_frame, end_frame FROM tracks WHERE path = ?1",
+        params![path],
+        |row| range_from_row(row, 0),
+    )
+    .optional()
+    .map(Option::flatten)
+    .map_err(|e| format!("Failed to query track: {}", e))
+}
+
+fn load_overrides(conn: &Connection) -> Result<HashMap<String, TrackOverrides>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT path, start_secs, stop_secs, gain_db, exclude_from_shuffle FROM track_overrides"
+    ).map_err(|e| format!("Failed to prepare statement: {}", e))?;
+
+    let overrides = stmt.query_map([], |row| {
+        Ok((row.get(0)?, TrackOverrides {
+            start: row.get(1)?,
+            stop: row.get(2)?,
+            gain_db: row.get(3)?,
+            exclude_from_shuffle: row.get(4)?,
+        }))
+    })
+    .map_err(|e| format!("Failed to query track overrides: {}", e))?
+    .collect::<SqlResult<HashMap<_, _>>>()
+    .map_err(|e| format!("Failed to collect track overrides: {}", e))?;
+
+    Ok(overrides)
+}
+
+// Fills in the stored overrides of each track.
+pub fn apply_overrides(conn: &Connection, tracks: &mut [MusicFile]) -> Result<(), String> {
+    let mut overrides = load_overrides(conn)?;
+    for track in tracks {
+        if let Some(stored) = overrides.remove(&track.path) {
+            track.overrides = stored;
+        }
+    }
+    Ok(())
+}
+
+// Overrides left at their defaults are removed rather than stored.
+pub fn save_overrides(conn: &Connection, path: &str, overrides: &TrackOverrides) -> Result<(), String> {
+    if *overrides == TrackOverrides::default() {
+        conn.execute(
+            "DELETE FROM track_overrides WHERE path = ?1",
+            params![path],
+        ).map_err(|e| format!("Failed to save track overrides: {}", e))?;
+        return Ok(());
+    }
+
+    conn.execute(
+        "INSERT INTO track_overrides (path, start_secs, stop_secs, gain_db, exclude_from_shuffle)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(path) DO UPDATE SET
+            start_secs = ?2, stop_secs = ?3, gain_db = ?4, exclude_from_shuffle = ?5",
+        params![path, overrides.start, overrides.stop, overrides.gain_db, overrides.exclude_from_shuffle],
+    ).map_err(|e| format!("Failed to save track overrides: {}", e))?;
+
+    Ok(())
+}
+
+fn load_loudness(conn: &Connection, folder_id: i64) -> Result<HashMap<String, Loudness>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT path, r128_integrated, r128_range, r128_true_peak, r128_album_integrated, r128_album_range, r128_album_true_peak FROM tracks WHERE folder_id = ?1 AND (r128_scanned = 1 OR r128_integrated IS NOT NULL)"
+    ).map_err(|e| format!("Failed to prepare statement: {}", e))?;
+
+    let loudness: HashMap<String, Loudness> = stmt.query_map(params![folder_id], |row| {
+        Ok((row.get(0)?, Loudness {
+            integrated: row.get(1)?,
+            range: row.get(2)?,
+            true_peak: row.get(3)?,
+            album_integrated: row.get(4)?,
+            album_range: row.get(5)?,
+            album_true_peak: row.get(6)?,
+            scanned: true,
+        }))
+    })
+    .map_err(|e| format!("Failed to query loudness: {}", e))?
+    .collect::<SqlResult<HashMap<_, _>>>()
+    .map_err(|e| format!("Failed to collect loudness: {}", e))?;
+
+    Ok(loudness)
+}
+
+pub fn save_loudness(conn: &Connection, path: &str, loudness: &Loudness) -> Result<(), String> {
+    conn.execute(
+        "UPDATE tracks SET r128_integrated = ?2, r128_range = ?3, r128_true_peak = ?4, r128_album_integrated = ?5, r128_album_range = ?6, r128_album_true_peak = ?7, r128_scanned = 1 WHERE path = ?1",
+        params![
+            path,
+            loudness.integrated,
+            loudness.range,
+            loudness.true_peak,
+            loudness.album_integrated,
+            loudness.album_range,
+            loudness.album_true_peak,
+        ],
+    ).map_err(|e| format!("Failed to save loudness: {}", e))?;
+
+    Ok(())
+}
+
 pub fn get_indexed_folders(conn: &Connection) -> Result<Vec<IndexedFolder>, String> {
     let mut stmt = conn.prepare(
         "SELECT id, path, last_indexed FROM indexed_folders ORDER BY last_indexed DESC"
@@ -179,22 +493,14 @@ pub fn get_folder_id(conn: &Connection, folder_path: &str) -> Result<i64, String
     Ok(folder_id)
 }
 
-pub fn get_track_count(conn: &Connection, folder_id: i64) -> Result<i64, String> {
-    let count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM tracks WHERE folder_id = ?1",
-        params![folder_id],
-        |row| row.get(0),
-    ).map_err(|e| format!("Failed to count tracks: {}", e))?;
-
-    Ok(count)
-}
-
-pub fn get_track_paths(conn: &Connection, folder_id: i64) -> Result<Vec<String>, String> {
-    let mut stmt = conn.prepare("SELECT path FROM tracks WHERE folder_id = ?1")
+// The paths of a folder's tracks, each with the file it is cut from if it
+// comes from a CUE sheet.
+pub fn get_track_paths(conn: &Connection, folder_id: i64) -> Result<Vec<(String, Option<String>)>, String> {
+    let mut stmt = conn.prepare("SELECT path, source_file FROM tracks WHERE folder_id = ?1")
         .map_err(|e| format!("Failed to prepare statement: {}", e))?;
 
-    let paths: Vec<String> = stmt.query_map(params![folder_id], |row| {
-        row.get::<_, String>(0)
+    let paths: Vec<(String, Option<String>)> = stmt.query_map(params![folder_id], |row| {
+        Ok((row.get(0)?, row.get(1)?))
     })
     .map_err(|e| format!("Failed to query tracks: {}", e))?
     .collect::<SqlResult<Vec<_>>>()
@@ -204,6 +510,32 @@ pub fn get_track_paths(conn: &Connection, folder_id: i64) -> Result<Vec<String>,
 }
 
 pub fn remove_folder(conn: &Connection, folder_id: i64) -> Result<(), String> {
+    for (table, what) in [
+        ("waveforms", "waveforms"),
+        ("silence", "silence"),
+        ("track_overrides", "track overrides"),
+        ("loop_regions", "loop regions"),
+        ("bookmarks", "bookmarks"),
+        ("resume_positions", "resume positions"),
+    ] {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE path IN (SELECT path FROM tracks WHERE folder_id = ?1)", table),
+            params![folder_id],
+        ).map_err(|e| format!("Failed to remove {}: {}", what, e))?;
+    }
+
+    // Audiobooks are flagged by the file or directory they start at.
+    let folder_path: String = conn.query_row(
+        "SELECT path FROM indexed_folders WHERE id = ?1",
+        params![folder_id],
+        |row| row.get(0),
+    ).map_err(|e| format!("Failed to get folder path: {}", e))?;
+    for root in load_audiobooks(conn)? {
+        if Path::new(&root).starts_with(&folder_path) {
+            set_audiobook(conn, &root, false)?;
+        }
+    }
+
     conn.execute(
         "DELETE FROM indexed_folders WHERE id = ?1",
         params![folder_id],
@@ -211,3 +543,451 @@ pub fn remove_folder(conn: &Connection, folder_id: i64) -> Result<(), String> {
 
     Ok(())
 }
+
+pub fn save_eq_preset(conn: &Connection, preset: &EqPreset) -> Result<(), String> {
+    let settings = serde_json::to_string(&preset.settings)
+        .map_err(|e| format!("Failed to serialize preset: {}", e))?;
+
+    conn.execute(
+        "INSERT INTO eq_presets (name, settings) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET settings = ?2",
+        params![preset.name, settings],
+    ).map_err(|e| format!("Failed to save preset: {}", e))?;
+
+    Ok(())
+}
+
+pub fn load_eq_presets(conn: &Connection) -> Result<Vec<EqPreset>, String> {
+    let mut stmt = conn.prepare("SELECT name, settings FROM eq_presets ORDER BY name")
+        .map_err(|e| format!("Failed to prepare statement: {}", e))?;
+
+    let rows: Vec<(String, String)> = stmt.query_map([], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })
+    .map_err(|e| format!("Failed to query presets: {}", e))?
+    .collect::<SqlResult<Vec<_>>>()
+    .map_err(|e| format!("Failed to collect presets: {}", e))?;
+
+    rows.into_iter()
+        .map(|(name, settings)| {
+            let settings = serde_json::from_str(&settings)
+                .map_err(|e| format!("Failed to parse preset {}: {}", name, e))?;
+            Ok(EqPreset { name, settings })
+        })
+        .collect()
+}
+
+pub fn delete_eq_preset(conn: &Connection, name: &str) -> Result<(), String> {
+    conn.execute(
+        "DELETE FROM eq_presets WHERE name = ?1",
+        params![name],
+    ).map_err(|e| format!("Failed to delete preset: {}", e))?;
+
+    Ok(())
+}
+
+pub fn save_setting(conn: &Connection, key: &str, value: &str) -> Result<(), String> {
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = ?2",
+        params![key, value],
+    ).map_err(|e| format!("Failed to save setting: {}", e))?;
+
+    Ok(())
+}
+
+pub fn load_setting(conn: &Connection, key: &str) -> Result<Option<String>, String> {
+    conn.query_row(
+        "SELECT value FROM settings WHERE key = ?1",
+        params![key],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| format!("Failed to load setting: {}", e))
+}
+
+// Written in a single transaction so a crash mid-save leaves the previous
+// session intact. The queue rows are only rewritten when the queue changed.
+pub fn save_session(conn: &mut Connection, session: &Session, queue_changed: bool) -> Result<(), String> {
+    let state = serde_json::to_string(&session.state)
+        .map_err(|e| format!("Failed to serialize session: {}", e))?;
+
+    let tx = conn.transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+
+    if queue_changed {
+        tx.execute("DELETE FROM session_queue", [])
+            .map_err(|e| format!("Failed to clear session queue: {}", e))?;
+
+        {
+            let mut stmt = tx.prepare("INSERT INTO session_queue (position, path) VALUES (?1, ?2)")
+                .map_err(|e| format!("Failed to prepare statement: {}", e))?;
+            for (position, path) in session.queue.iter().enumerate() {
+                stmt.execute(params![position as i64, path])
+                    .map_err(|e| format!("Failed to save session queue: {}", e))?;
+            }
+        }
+    }
+
+    save_setting(&tx, "session", &state)?;
+
+    tx.commit()
+        .map_err(|e| format!("Failed to commit session: {}", e))?;
+
+    Ok(())
+}
+
+pub fn load_session(conn: &Connection) -> Result<Option<Session>, String> {
+    let state = match load_setting(conn, "session")? {
+        Some(state) => serde_json::from_str(&state)
+            .map_err(|e| format!("Failed to parse session: {}", e))?,
+        None => return Ok(None),
+    };
+
+    let mut stmt = conn.prepare("SELECT path FROM session_queue ORDER BY position")
+        .map_err(|e| format!("Failed to prepare statement: {}", e))?;
+
+    let queue = stmt.query_map([], |row| row.get(0))
+        .map_err(|e| format!("Failed to query session queue: {}", e))?
+        .collect::<SqlResult<Vec<String>>>()
+        .map_err(|e| format!("Failed to collect session queue: {}", e))?;
+
+    Ok(Some(Session { queue, state }))
+}
+
+// When the file at `path` last changed, in seconds since the epoch. Silence
+// and waveforms are cached against it and worked out again once it moves.
+pub fn modified(path: &str) -> Result<i64, String> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64))
+}
+
+pub fn save_silence(conn: &Connection, path: &str, modified: i64, threshold_db: f32, silence: &Silence) -> Result<(), String> {
+    conn.execute(
+        "INSERT INTO silence (path, modified, threshold_db, start_secs, end_secs)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(path) DO UPDATE SET
+            modified = ?2, threshold_db = ?3, start_secs = ?4, end_secs = ?5",
+        params![path, modified, threshold_db as f64, silence.start, silence.end],
+    ).map_err(|e| format!("Failed to save silence: {}", e))?;
+
+    Ok(())
+}
+
+// Returns the stored silence unless the file has changed or it was found
+// with another threshold.
+pub fn load_silence(conn: &Connection, path: &str, modified: i64, threshold_db: f32) -> Result<Option<Silence>, String> {
+    conn.query_row(
+        "SELECT start_secs, end_secs FROM silence WHERE path = ?1 AND modified = ?2 AND threshold_db = ?3",
+        params![path, modified, threshold_db as f64],
+        |row| {
+            Ok(Silence {
+                start: row.get(0)?,
+                end: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+    .map_err(|e| format!("Failed to load silence: {}", e))
+}
+
+// Each channel is stored as its minimums followed by its maximums, one signed
+// byte per bucket.
+pub fn save_waveform(conn: &Connection, path: &str, modified: i64, waveform: &Waveform) -> Result<(), String> {
+    let buckets = waveform.channels.first().map_or(0, |c| c.min.len());
+    let data: Vec<u8> = waveform
+        .channels
+        .iter()
+        .flat_map(|c| c.min.iter().chain(c.max.iter()))
+        .map(|&v| v as u8)
+        .collect();
+
+    conn.execute(
+        "INSERT INTO waveforms (path, modified, duration, channels, buckets, data)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(path) DO UPDATE SET
+            modified = ?2, duration = ?3, channels = ?4, buckets = ?5, data = ?6",
+        params![path, modified, waveform.duration, waveform.channels.len() as i64, buckets as i64, data],
+    ).map_err(|e| format!("Failed to save waveform: {}", e))?;
+
+    Ok(())
+}
+
+// Returns the stored waveform unless the file has changed since it was made.
+pub fn load_waveform(conn: &Connection, path: &str, modified: i64) -> Result<Option<Waveform>, String> {
+    let row = conn.query_row(
+        "SELECT duration, channels, buckets, data FROM waveforms WHERE path = ?1 AND modified = ?2",
+        params![path, modified],
+        |row| {
+            Ok((
+                row.get::<_, f64>(0)?,
+                row.get::<_, i64>(1)? as usize,
+                row.get::<_, i64>(2)? as usize,
+                row.get::<_, Vec<u8>>(3)?,
+            ))
+        },
+    )
+    .optional()
+    .map_err(|e| format!("Failed to load waveform: {}", e))?;
+
+    let Some((duration, channels, buckets, data)) = row else {
+        return Ok(None);
+    };
+    if buckets == 0 || data.len() != channels * buckets * 2 {
+        return Ok(None);
+    }
+
+    let channels = data
+        .chunks(buckets * 2)
+        .map(|chunk| WaveformChannel {
+            min: chunk[..buckets].iter().map(|&v| v as i8).collect(),
+            max: chunk[buckets..].iter().map(|&v| v as i8).collect(),
+        })
+        .collect();
+
+    Ok(Some(Waveform { duration, channels }))
+}
+
+pub fn load_audiobooks(conn: &Connection) -> Result<Vec<String>, String> {
+    let mut stmt = conn.prepare("SELECT path FROM audiobooks ORDER BY path")
+        .map_err(|e| format!("Failed to prepare statement: {}", e))?;
+
+    let paths = stmt.query_map([], |row| row.get(0))
+        .map_err(|e| format!("Failed to query audiobooks: {}", e))?
+        .collect::<SqlResult<Vec<String>>>()
+        .map_err(|e| format!("Failed to collect audiobooks: {}", e))?;
+    Ok(paths)
+}
+
+pub fn set_audiobook(conn: &Connection, path: &str, enabled: bool) -> Result<(), String> {
+    let sql = if enabled {
+        "INSERT OR IGNORE INTO audiobooks (path) VALUES (?1)"
+    } else {
+        "DELETE FROM audiobooks WHERE path = ?1"
+    };
+    conn.execute(sql, params![path])
+        .map_err(|e| format!("Failed to save audiobook: {}", e))?;
+
+    Ok(())
+}
+
+pub fn load_resume_positions(conn: &Connection) -> Result<HashMap<String, f64>, String> {
+    let mut stmt = conn.prepare("SELECT path, position FROM resume_positions")
+        .map_err(|e| format!("Failed to prepare statement: {}", e))?;
+
+    let positions = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| format!("Failed to query resume positions: {}", e))?
+        .collect::<SqlResult<HashMap<String, f64>>>()
+        .map_err(|e| format!("Failed to collect resume positions: {}", e))?;
+    Ok(positions)
+}
+
+// `None` forgets the position, e.g. once the file has been listened to the end.
+pub fn save_resume_position(conn: &Connection, path: &str, position: Option<f64>) -> Result<(), String> {
+    match position {
+        Some(position) => conn.execute(
+            "INSERT INTO resume_positions (path, position) VALUES (?1, ?2)
+             ON CONFLICT(path) DO UPDATE SET position = ?2",
+            params![path, position],
+        ),
+        None => conn.execute(
+            "DELETE FROM resume_positions WHERE path = ?1",
+            params![path],
+        ),
+    }
+    .map_err(|e| format!("Failed to save resume position: {}", e))?;
+
+    Ok(())
+}
+
+fn bookmark_from_row(row: &rusqlite::Row) -> SqlResult<Bookmark> {
+    Ok(Bookmark {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        position: row.get(2)?,
+        note: row.get(3)?,
+        created: row.get(4)?,
+    })
+}
+
+pub fn add_bookmark(conn: &Connection, path: &str, position: f64, note: Option<&str>) -> Result<Bookmark, String> {
+    let now = chrono::Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO bookmarks (path, position, note, created) VALUES (?1, ?2, ?3, ?4)",
+        params![path, position, note, now],
+    ).map_err(|e| format!("Failed to save bookmark: {}", e))?;
+
+    Ok(Bookmark {
+        id: conn.last_insert_rowid(),
+        path: path.to_string(),
+        position,
+        note: note.map(str::to_string),
+        created: now,
+    })
+}
+
+// Bookmarks in listening order, for one file or for all of them.
+pub fn load_bookmarks(conn: &Connection, path: Option<&str>) -> Result<Vec<Bookmark>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT id, path, position, note, created FROM bookmarks
+         WHERE ?1 IS NULL OR path = ?1
+         ORDER BY path, position",
+    ).map_err(|e| format!("Failed to prepare statement: {}", e))?;
+
+    let bookmarks = stmt.query_map(params![path], bookmark_from_row)
+        .map_err(|e| format!("Failed to query bookmarks: {}", e))?
+        .collect::<SqlResult<Vec<_>>>()
+        .map_err(|e| format!("Failed to collect bookmarks: {}", e))?;
+    Ok(bookmarks)
+}
+
+pub fn get_bookmark(conn: &Connection, id: i64) -> Result<Option<Bookmark>, String> {
+    conn.query_row(
+        "SELECT id, path, position, note, created FROM bookmarks WHERE id = ?1",
+        params![id],
+        bookmark_from_row,
+    )
+    .optional()
+    .map_err(|e| format!("Failed to load bookmark: {}", e))
+}
+
+pub fn update_bookmark(conn: &Connection, id: i64, note: Option<&str>) -> Result<(), String> {
+    let updated = conn.execute(
+        "UPDATE bookmarks SET note = ?2 WHERE id = ?1",
+        params![id, note],
+    ).map_err(|e| format!("Failed to update bookmark: {}", e))?;
+
+    if updated == 0 {
+        return Err(format!("Bookmark not found: {}", id));
+    }
+    Ok(())
+}
+
+pub fn delete_bookmark(conn: &Connection, id: i64) -> Result<(), String> {
+    conn.execute(
+        "DELETE FROM bookmarks WHERE id = ?1",
+        params![id],
+    ).map_err(|e| format!("Failed to delete bookmark: {}", e))?;
+
+    Ok(())
+}
+
+fn loop_region_from_row(row: &rusqlite::Row) -> SqlResult<LoopRegion> {
+    Ok(LoopRegion {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        name: row.get(2)?,
+        start: row.get(3)?,
+        end: row.get(4)?,
+    })
+}
+
+pub fn save_loop_region(conn: &Connection, path: &str, name: &str, start: u64, end: u64) -> Result<LoopRegion, String> {
+    conn.execute(
+        "INSERT INTO loop_regions (path, name, start_frame, end_frame) VALUES (?1, ?2, ?3, ?4)",
+        params![path, name, start, end],
+    ).map_err(|e| format!("Failed to save loop region: {}", e))?;
+
+    Ok(LoopRegion {
+        id: conn.last_insert_rowid(),
+        path: path.to_string(),
+        name: name.to_string(),
+        start,
+        end,
+    })
+}
+
+pub fn load_loop_regions(conn: &Connection, path: &str) -> Result<Vec<LoopRegion>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT id, path, name, start_frame, end_frame FROM loop_regions
+         WHERE path = ?1
+         ORDER BY start_frame, end_frame",
+    ).map_err(|e| format!("Failed to prepare statement: {}", e))?;
+
+    let regions = stmt.query_map(params![path], loop_region_from_row)
+        .map_err(|e| format!("Failed to query loop regions: {}", e))?
+        .collect::<SqlResult<Vec<_>>>()
+        .map_err(|e| format!("Failed to collect loop regions: {}", e))?;
+    Ok(regions)
+}
+
+pub fn get_loop_region(conn: &Connection, id: i64) -> Result<Option<LoopRegion>, String> {
+    conn.query_row(
+        "SELECT id, path, name, start_frame, end_frame FROM loop_regions WHERE id = ?1",
+        params![id],
+        loop_region_from_row,
+    )
+    .optional()
+    .map_err(|e| format!("Failed to load loop region: {}", e))
+}
+
+pub fn delete_loop_region(conn: &Connection, id: i64) -> Result<(), String> {
+    conn.execute(
+        "DELETE FROM loop_regions WHERE id = ?1",
+        params![id],
+    ).map_err(|e| format!("Failed to delete loop region: {}", e))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(path: &str) -> MusicFile {
+        MusicFile {
+            path: path.to_string(),
+            name: path.to_string(),
+            artist: None,
+            album: None,
+            title: None,
+            thumbnail: None,
+            replay_gain: ReplayGain::default(),
+            loudness: Loudness::default(),
+            overrides: TrackOverrides::default(),
+            silence: Silence::default(),
+            range: None,
+        }
+    }
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn removing_a_folder_removes_everything_kept_for_its_tracks() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        let gone = save_folder(&conn, "/music/gone").unwrap();
+        let kept = save_folder(&conn, "/music/kept").unwrap();
+        save_tracks(&conn, gone, &[track("/music/gone/a.flac")]).unwrap();
+        save_tracks(&conn, kept, &[track("/music/kept/b.flac")]).unwrap();
+
+        for path in ["/music/gone/a.flac", "/music/kept/b.flac"] {
+            save_silence(&conn, path, 1, -60.0, &Silence::default()).unwrap();
+            save_overrides(&conn, path, &TrackOverrides { gain_db: 1.0, ..TrackOverrides::default() }).unwrap();
+            save_loop_region(&conn, path, "chorus", 0, 100).unwrap();
+            add_bookmark(&conn, path, 1.0, None).unwrap();
+            save_resume_position(&conn, path, Some(2.0)).unwrap();
+        }
+        set_audiobook(&conn, "/music/gone", true).unwrap();
+        set_audiobook(&conn, "/music/kept/b.flac", true).unwrap();
+        // Shares a prefix with the removed folder without being inside it.
+        set_audiobook(&conn, "/music/gone too", true).unwrap();
+
+        remove_folder(&conn, gone).unwrap();
+
+        let paths: Vec<String> = load_tracks(&conn).unwrap().into_iter().map(|t| t.path).collect();
+        assert_eq!(paths, ["/music/kept/b.flac"]);
+        for table in ["silence", "track_overrides", "loop_regions", "bookmarks", "resume_positions"] {
+            assert_eq!(count(&conn, table), 1, "{}", table);
+        }
+        assert_eq!(load_audiobooks(&conn).unwrap(), ["/music/gone too", "/music/kept/b.flac"]);
+        assert_eq!(get_folder_paths(&conn).unwrap(), ["/music/kept"]);
+    }
+}
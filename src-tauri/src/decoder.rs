@@ -0,0 +1,556 @@
+#[cfg(test)]
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::Duration;
+use audiopus::coder::Decoder as OpusCoder;
+use audiopus::packet::Packet;
+use audiopus::{Channels, MutSignals, SampleRate};
+use ogg::{Packet as OggPacket, PacketReader};
+#[cfg(test)]
+use once_cell::sync::Lazy;
+use rodio::source::SeekError;
+use rodio::{Decoder, Source};
+use crate::cue::Range;
+use crate::formats::{self, Format};
+use crate::models::TrackRange;
+#[cfg(feature = "wavpack")]
+use crate::wavpack::WavPackDecoder;
+
+// Opus always decodes at 48 kHz, and granule positions count in it.
+const OPUS_RATE: u32 = 48000;
+// Longest Opus packet: 120 ms at 48 kHz.
+const OPUS_MAX_FRAMES: usize = 5760;
+// Searched from the end of the file for the last page's granule position.
+const OPUS_TAIL_LEN: u64 = 65536;
+// Audio decoded and thrown away before a seek target so the decoder settles;
+// RFC 7845 recommends at least 80 ms.
+const OPUS_PREROLL: u64 = 3840;
+// Seeks closer than this ahead of the current position decode forward
+// rather than search the file.
+const OPUS_SEEK_AHEAD: u64 = OPUS_RATE as u64;
+
+// Opens a library track: the file at `path`, or for a track cut from a file
+// by a CUE sheet, only its part of that file.
+pub fn open_track(path: &str, range: Option<&TrackRange>) -> Result<AudioDecoder, String> {
+    let decoder = match range {
+        Some(range) => {
+            let inner = open(&range.file)?;
+            Segment::new(inner, range.into()).map(|d| AudioDecoder::Segment(Box::new(d)))
+        }
+        None => open(path),
+    }?;
+    #[cfg(test)]
+    if UNSEEKABLE.lock().unwrap().contains(path) {
+        return Ok(AudioDecoder::Unseekable(Box::new(decoder)));
+    }
+    Ok(decoder)
+}
+
+// Tracks opened with a decoder that can't seek, so engine tests can take the
+// paths that deal with one; every format this tree decodes can.
+#[cfg(test)]
+pub static UNSEEKABLE: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Opens `path` with a decoder picked from its content. Everything but Opus
+// and WavPack goes through rodio's decoders, which probe the stream
+// themselves.
+pub fn open(path: &str) -> Result<AudioDecoder, String> {
+    match formats::detect(path) {
+        Some(Format::Opus) => return OpusDecoder::open(path).map(|d| AudioDecoder::Opus(Box::new(d))),
+        #[cfg(feature = "wavpack")]
+        Some(Format::WavPack) => return WavPackDecoder::open(path).map(|d| AudioDecoder::WavPack(Box::new(d))),
+        _ => {}
+    }
+
+    let file = File::open(path)
+        .map_err(|e| format!("Failed to open file: {}", e))?;
+    Decoder::new(BufReader::new(file))
+        .map(|d| AudioDecoder::Rodio(Box::new(d)))
+        .map_err(|e| format!("Failed to decode audio: {}", e))
+}
+
+pub enum AudioDecoder {
+    Rodio(Box<Decoder<BufReader<File>>>),
+    Opus(Box<OpusDecoder>),
+    #[cfg(feature = "wavpack")]
+    WavPack(Box<WavPackDecoder>),
+    Segment(Box<Segment>),
+    #[cfg(test)]
+    Unseekable(Box<AudioDecoder>),
+}
+
+impl Iterator for AudioDecoder {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<i16> {
+        match self {
+            AudioDecoder::Rodio(d) => d.next(),
+            AudioDecoder::Opus(d) => d.next(),
+            #[cfg(feature = "wavpack")]
+            AudioDecoder::WavPack(d) => d.next(),
+            AudioDecoder::Segment(d) => d.next(),
+            #[cfg(test)]
+            AudioDecoder::Unseekable(d) => d.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            AudioDecoder::Rodio(d) => d.size_hint(),
+            AudioDecoder::Opus(d) => d.size_hint(),
+            #[cfg(feature = "wavpack")]
+            AudioDecoder::WavPack(d) => d.size_hint(),
+            AudioDecoder::Segment(d) => d.size_hint(),
+            #[cfg(test)]
+            AudioDecoder::Unseekable(d) => d.size_hint(),
+        }
+    }
+}
+
+impl Source for AudioDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        match self {
+            AudioDecoder::Rodio(d) => d.current_frame_len(),
+            AudioDecoder::Opus(d) => d.current_frame_len(),
+            #[cfg(feature = "wavpack")]
+            AudioDecoder::WavPack(d) => d.current_frame_len(),
+            AudioDecoder::Segment(d) => d.current_frame_len(),
+            #[cfg(test)]
+            AudioDecoder::Unseekable(d) => d.current_frame_len(),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        match self {
+            AudioDecoder::Rodio(d) => d.channels(),
+            AudioDecoder::Opus(d) => d.channels(),
+            #[cfg(feature = "wavpack")]
+            AudioDecoder::WavPack(d) => d.channels(),
+            AudioDecoder::Segment(d) => d.channels(),
+            #[cfg(test)]
+            AudioDecoder::Unseekable(d) => d.channels(),
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match self {
+            AudioDecoder::Rodio(d) => d.sample_rate(),
+            AudioDecoder::Opus(d) => d.sample_rate(),
+            #[cfg(feature = "wavpack")]
+            AudioDecoder::WavPack(d) => d.sample_rate(),
+            AudioDecoder::Segment(d) => d.sample_rate(),
+            #[cfg(test)]
+            AudioDecoder::Unseekable(d) => d.sample_rate(),
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        match self {
+            AudioDecoder::Rodio(d) => d.total_duration(),
+            AudioDecoder::Opus(d) => d.total_duration(),
+            #[cfg(feature = "wavpack")]
+            AudioDecoder::WavPack(d) => d.total_duration(),
+            AudioDecoder::Segment(d) => d.total_duration(),
+            #[cfg(test)]
+            AudioDecoder::Unseekable(d) => d.total_duration(),
+        }
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        match self {
+            AudioDecoder::Rodio(d) => d.try_seek(pos),
+            AudioDecoder::Opus(d) => d.try_seek(pos),
+            #[cfg(feature = "wavpack")]
+            AudioDecoder::WavPack(d) => d.try_seek(pos),
+            AudioDecoder::Segment(d) => d.try_seek(pos),
+            #[cfg(test)]
+            AudioDecoder::Unseekable(_) => Err(SeekError::NotSupported { underlying_source: "test decoder" }),
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+// Granule position of the last page in the file, which is the length of the
+// stream in 48 kHz frames including the pre-skip.
+fn last_granule(file: &mut File) -> Option<u64> {
+    let length = file.seek(SeekFrom::End(0)).ok()?;
+    let start = length.saturating_sub(OPUS_TAIL_LEN);
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+
+    let page = tail.windows(4).rposition(|w| w == b"OggS")?;
+    let granule = tail.get(page + 6..page + 14)?;
+    Some(u64::from_le_bytes(granule.try_into().ok()?))
+}
+
+// Ogg Opus with one or two channels (channel mapping family 0).
+pub struct OpusDecoder {
+    path: String,
+    reader: PacketReader<BufReader<File>>,
+    // Packets read ahead while finding where a seek landed.
+    pending: VecDeque<OggPacket>,
+    coder: OpusCoder,
+    channel_layout: Channels,
+    channels: u16,
+    gain: f32,
+    // Frames at the start of the stream that only prime the decoder.
+    pre_skip: u64,
+    // Playable frames in the stream, when the last page could be read.
+    total_frames: Option<u64>,
+    // Frames decoded so far, counting the pre-skip.
+    decoded: u64,
+    // Frame in the stream, counting the pre-skip, at the start of `buffer`.
+    buffer_start: u64,
+    buffer: Vec<i16>,
+    offset: usize,
+}
+
+impl OpusDecoder {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let mut file = File::open(path)
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+        let last = last_granule(&mut file);
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+        let mut reader = PacketReader::new(BufReader::new(file));
+
+        let head = reader
+            .read_packet()
+            .map_err(|e| format!("Failed to decode audio: {}", e))?
+            .ok_or_else(|| "Failed to decode audio: empty Opus stream".to_string())?;
+        let head = &head.data;
+        if head.len() < 19 || &head[..8] != b"OpusHead" {
+            return Err("Failed to decode audio: missing Opus header".to_string());
+        }
+        let channels = head[9] as u16;
+        let channel_layout = match (head[18], channels) {
+            (0, 1) => Channels::Mono,
+            (0, 2) => Channels::Stereo,
+            _ => return Err(format!("Unsupported Opus channel layout: {} channels", channels)),
+        };
+        let pre_skip = read_u16(head, 10) as u64;
+        // Q7.8 dB applied on top of the decoded signal.
+        let gain_db = i16::from_le_bytes([head[16], head[17]]) as f32 / 256.0;
+
+        // The comment header carries nothing the decoder needs; tags are read
+        // through lofty.
+        reader
+            .read_packet()
+            .map_err(|e| format!("Failed to decode audio: {}", e))?;
+
+        let coder = OpusCoder::new(SampleRate::Hz48000, channel_layout)
+            .map_err(|e| format!("Failed to decode audio: {}", e))?;
+
+        Ok(OpusDecoder {
+            path: path.to_string(),
+            reader,
+            pending: VecDeque::new(),
+            coder,
+            channel_layout,
+            channels,
+            gain: 10f32.powf(gain_db / 20.0),
+            pre_skip,
+            total_frames: last.map(|g| g.saturating_sub(pre_skip)),
+            decoded: 0,
+            buffer_start: 0,
+            buffer: Vec::with_capacity(OPUS_MAX_FRAMES * channels as usize),
+            offset: 0,
+        })
+    }
+
+    // Decodes packets until one yields audio past the pre-skip. Returns false
+    // at the end of the stream.
+    fn refill(&mut self) -> bool {
+        let channels = self.channels as usize;
+        loop {
+            let next = match self.pending.pop_front() {
+                Some(packet) => Some(packet),
+                None => self.reader.read_packet().ok().flatten(),
+            };
+            let packet = match next {
+                Some(packet) => packet,
+                None => {
+                    self.buffer.clear();
+                    self.offset = 0;
+                    return false;
+                }
+            };
+            let Ok(input) = Packet::try_from(&packet.data) else {
+                continue;
+            };
+
+            self.buffer.resize(OPUS_MAX_FRAMES * channels, 0);
+            let Ok(signals) = MutSignals::try_from(&mut self.buffer) else {
+                continue;
+            };
+            let frames = match self.coder.decode(Some(input), signals, false) {
+                Ok(frames) => frames as u64,
+                Err(_) => continue,
+            };
+
+            // Trim the priming frames at the start and the padding the
+            // encoder added to the final packet.
+            let first = self.decoded;
+            self.decoded += frames;
+            let start = self.pre_skip.saturating_sub(first).min(frames);
+            let end = match self.total_frames {
+                Some(total) => (total + self.pre_skip).saturating_sub(first).min(frames),
+                None => frames,
+            };
+            if start >= end {
+                continue;
+            }
+
+            self.buffer.truncate(end as usize * channels);
+            if self.gain != 1.0 {
+                for sample in &mut self.buffer {
+                    *sample = (*sample as f32 * self.gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                }
+            }
+            self.buffer_start = first;
+            self.offset = start as usize * channels;
+            return true;
+        }
+    }
+
+    // Frames handed out so far, not counting the pre-skip.
+    fn played(&self) -> u64 {
+        (self.buffer_start + (self.offset / self.channels as usize) as u64).saturating_sub(self.pre_skip)
+    }
+
+    // Moves the reader to the page holding stream frame `goal` (counting the
+    // pre-skip) by bisecting the file on granule positions. The packets of
+    // the first page are kept back for `refill`, with `decoded` set to where
+    // they start. Returns false if the stream couldn't be searched.
+    fn seek_page(&mut self, goal: u64) -> bool {
+        if !matches!(self.reader.seek_absgp(None, goal), Ok(true)) {
+            return false;
+        }
+        self.pending.clear();
+        loop {
+            match self.reader.read_packet() {
+                Ok(Some(packet)) => {
+                    let end = packet.last_in_page().then(|| packet.absgp_page());
+                    self.pending.push_back(packet);
+                    if let Some(end) = end {
+                        let length: u64 = self.pending.iter().map(|p| opus_packet_frames(&p.data)).sum();
+                        self.decoded = end.saturating_sub(length);
+                        break;
+                    }
+                }
+                _ => return false,
+            }
+        }
+
+        // Decoder state from before the jump would only add noise.
+        match OpusCoder::new(SampleRate::Hz48000, self.channel_layout) {
+            Ok(coder) => self.coder = coder,
+            Err(_) => return false,
+        }
+        self.buffer.clear();
+        self.offset = 0;
+        self.buffer_start = self.decoded;
+        true
+    }
+}
+
+// Length at 48 kHz of an Opus packet, read from its TOC byte (RFC 6716, 3.1).
+fn opus_packet_frames(packet: &[u8]) -> u64 {
+    let Some(&toc) = packet.first() else {
+        return 0;
+    };
+    let config = (toc >> 3) as usize;
+    // Frame sizes in 1/400 s: SILK, hybrid, then CELT configurations.
+    let frame = match config {
+        0..=11 => [4, 8, 16, 24][config % 4],
+        12..=15 => [4, 8][config % 2],
+        _ => [1, 2, 4, 8][config % 4],
+    };
+    let count = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(0, |&b| (b & 0x3F) as u64),
+    };
+    count * frame * (OPUS_RATE as u64 / 400)
+}
+
+impl Iterator for OpusDecoder {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<i16> {
+        if self.offset >= self.buffer.len() && !self.refill() {
+            return None;
+        }
+        let sample = self.buffer[self.offset];
+        self.offset += 1;
+        Some(sample)
+    }
+}
+
+impl Source for OpusDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.buffer.len() - self.offset).filter(|&len| len > 0)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        OPUS_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_frames
+            .map(|frames| Duration::from_secs_f64(frames as f64 / OPUS_RATE as f64))
+    }
+
+    // Jumps to the page a little before the target, then decodes up to it.
+    // Targets just ahead are decoded to directly; if the file can't be
+    // searched, decoding starts over from the beginning.
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let target = (pos.as_secs_f64() * OPUS_RATE as f64) as u64;
+        let played = self.played();
+        if target < played || target - played > OPUS_SEEK_AHEAD {
+            let goal = (target + self.pre_skip).saturating_sub(OPUS_PREROLL);
+            if goal <= self.pre_skip || !self.seek_page(goal) || self.played() > target {
+                *self = OpusDecoder::open(&self.path)
+                    .map_err(|e| SeekError::Other(Box::new(io::Error::other(e))))?;
+            }
+        }
+
+        let channels = self.channels as usize;
+        while self.played() < target {
+            if self.offset >= self.buffer.len() && !self.refill() {
+                break;
+            }
+            let skip = ((target - self.played()) as usize * channels).min(self.buffer.len() - self.offset);
+            self.offset += skip;
+        }
+        Ok(())
+    }
+}
+
+fn samples_in(duration: Duration, channels: u16, sample_rate: u32) -> u64 {
+    (duration.as_secs_f64() * sample_rate as f64).round() as u64 * channels as u64
+}
+
+// One track of a CUE sheet: the file's decoder, started at the track's offset
+// and ended where the next track begins. Positions and lengths it reports are
+// relative to the track.
+pub struct Segment {
+    inner: AudioDecoder,
+    range: Range,
+    // Samples left before the end of the track; `None` runs to the end of
+    // the file.
+    remaining: Option<u64>,
+}
+
+impl Segment {
+    fn new(mut inner: AudioDecoder, range: Range) -> Result<Self, String> {
+        match inner.try_seek(range.start()) {
+            Ok(()) => {}
+            Err(e) if e.source_intact() => {
+                let skip = samples_in(range.start(), inner.channels(), inner.sample_rate());
+                for _ in 0..skip {
+                    inner.next();
+                }
+            }
+            Err(e) => return Err(format!("Failed to seek: {}", e)),
+        }
+
+        let remaining = range
+            .length()
+            .map(|length| samples_in(length, inner.channels(), inner.sample_rate()));
+        Ok(Segment {
+            inner,
+            range,
+            remaining,
+        })
+    }
+}
+
+impl Iterator for Segment {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<i16> {
+        match &mut self.remaining {
+            Some(0) => None,
+            Some(remaining) => {
+                *remaining -= 1;
+                self.inner.next()
+            }
+            None => self.inner.next(),
+        }
+    }
+}
+
+impl Source for Segment {
+    fn current_frame_len(&self) -> Option<usize> {
+        match (self.inner.current_frame_len(), self.remaining) {
+            (Some(len), Some(remaining)) => Some(len.min(remaining as usize)),
+            (None, Some(remaining)) => Some(remaining as usize),
+            (len, None) => len,
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.range.length().or_else(|| {
+            self.inner
+                .total_duration()
+                .map(|total| total.saturating_sub(self.range.start()))
+        })
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(self.range.start() + pos)?;
+        self.remaining = self.range.length().map(|length| {
+            samples_in(length.saturating_sub(pos), self.channels(), self.sample_rate())
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opus_packet_lengths_follow_the_toc_byte() {
+        // SILK 10 ms, one frame.
+        assert_eq!(opus_packet_frames(&[0 << 3]), 480);
+        // SILK 60 ms, two frames (code 1).
+        assert_eq!(opus_packet_frames(&[(3 << 3) | 1]), 5760);
+        // Hybrid 20 ms, two frames of different sizes (code 2).
+        assert_eq!(opus_packet_frames(&[(13 << 3) | 2]), 1920);
+        // CELT 2.5 ms, three frames (code 3 counts them in the next byte).
+        assert_eq!(opus_packet_frames(&[(16 << 3) | 3, 3]), 360);
+        // CELT 20 ms, one frame.
+        assert_eq!(opus_packet_frames(&[31 << 3]), 960);
+    }
+
+    #[test]
+    fn truncated_opus_packets_count_as_empty() {
+        assert_eq!(opus_packet_frames(&[]), 0);
+        assert_eq!(opus_packet_frames(&[(31 << 3) | 3]), 0);
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rodio::source::SeekError;
+use rodio::Source;
+use crate::biquad::Biquad;
+use crate::models::{EqBand, EqFilterKind, EqualizerSettings};
+
+const DEFAULT_SHELF_Q: f32 = 0.707;
+
+// Settings shared with every `Equalizer` source. Sources compare `version`
+// against the one they were built with and retune when it moves on, so edits
+// apply to the track that is already playing.
+pub struct EqControl {
+    settings: Mutex<EqualizerSettings>,
+    version: AtomicU64,
+}
+
+impl EqControl {
+    pub fn new(settings: EqualizerSettings) -> Arc<Self> {
+        Arc::new(EqControl {
+            settings: Mutex::new(settings),
+            version: AtomicU64::new(0),
+        })
+    }
+
+    pub fn settings(&self) -> EqualizerSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn update(&self, settings: EqualizerSettings) {
+        *self.settings.lock().unwrap() = settings;
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn design(band: &EqBand, sample_rate: u32) -> Biquad {
+    let rate = sample_rate as f64;
+    // Keep the centre frequency below Nyquist for low sample rates.
+    let frequency = (band.frequency as f64).clamp(1.0, rate * 0.49);
+    let gain = band.gain_db as f64;
+    let q = (band.q as f64).max(0.01);
+
+    match band.kind {
+        EqFilterKind::Peaking => Biquad::peaking(rate, frequency, gain, q),
+        EqFilterKind::LowShelf => Biquad::low_shelf(rate, frequency, gain, q),
+        EqFilterKind::HighShelf => Biquad::high_shelf(rate, frequency, gain, q),
+    }
+}
+
+pub struct Equalizer<S> {
+    inner: S,
+    control: Arc<EqControl>,
+    version: u64,
+    enabled: bool,
+    preamp: f32,
+    // One filter cascade per channel.
+    filters: Vec<Vec<Biquad>>,
+    sample_rate: u32,
+    channel: usize,
+}
+
+impl<S> Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, control: Arc<EqControl>) -> Self {
+        let mut equalizer = Equalizer {
+            inner,
+            control,
+            version: 0,
+            enabled: false,
+            preamp: 1.0,
+            filters: Vec::new(),
+            sample_rate: 0,
+            channel: 0,
+        };
+        equalizer.retune();
+        equalizer
+    }
+
+    fn retune(&mut self) {
+        self.version = self.control.version.load(Ordering::SeqCst);
+        let settings = self.control.settings();
+        let channels = self.inner.channels().max(1) as usize;
+        let sample_rate = self.inner.sample_rate().max(1);
+
+        let designed: Vec<Biquad> = settings.bands.iter().map(|b| design(b, sample_rate)).collect();
+        let reset = self.filters.len() != channels
+            || self.filters.first().is_none_or(|f| f.len() != designed.len())
+            || self.sample_rate != sample_rate;
+
+        if reset {
+            self.filters = vec![designed; channels];
+        } else {
+            for channel in self.filters.iter_mut() {
+                for (filter, new) in channel.iter_mut().zip(designed.iter()) {
+                    filter.retune(new);
+                }
+            }
+        }
+
+        self.enabled = settings.enabled;
+        self.preamp = 10f32.powf(settings.preamp_db / 20.0);
+        self.sample_rate = sample_rate;
+    }
+}
+
+impl<S> Iterator for Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 && self.control.version.load(Ordering::Relaxed) != self.version {
+            self.retune();
+        }
+
+        let sample = self.inner.next()?;
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.filters.len().max(1);
+
+        if !self.enabled {
+            return Some(sample);
+        }
+
+        let mut x = (sample * self.preamp) as f64;
+        if let Some(filters) = self.filters.get_mut(channel) {
+            for filter in filters.iter_mut() {
+                x = filter.process(x);
+            }
+        }
+        Some(x as f32)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Equalizer<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+fn parse_number(value: Option<&str>) -> Option<f32> {
+    value.and_then(|v| v.parse::<f32>().ok()).filter(|v| v.is_finite())
+}
+
+// Parses the EqualizerAPO `ParametricEQ.txt` format published by AutoEQ:
+//
+//   Preamp: -6.2 dB
+//   Filter 1: ON PK Fc 105 Hz Gain 5.5 dB Q 0.70
+//   Filter 2: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70
+pub fn parse_parametric_eq(contents: &str) -> Result<EqualizerSettings, String> {
+    let mut settings = EqualizerSettings {
+        enabled: true,
+        preamp_db: 0.0,
+        bands: Vec::new(),
+    };
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix("Preamp:") {
+            settings.preamp_db = parse_number(rest.split_whitespace().next())
+                .ok_or_else(|| format!("Invalid preamp line: {}", line))?;
+            continue;
+        }
+
+        let rest = match line.strip_prefix("Filter") {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let rest = match rest.split_once(':') {
+            Some((_, rest)) => rest,
+            None => return Err(format!("Invalid filter line: {}", line)),
+        };
+
+        let tokens: Vec<&str> = rest.split_whitespace().collect();
+        if tokens.first() != Some(&"ON") {
+            continue;
+        }
+
+        let kind = match tokens.get(1).copied() {
+            Some("PK") | Some("PEQ") => EqFilterKind::Peaking,
+            Some("LS") | Some("LSC") => EqFilterKind::LowShelf,
+            Some("HS") | Some("HSC") => EqFilterKind::HighShelf,
+            _ => return Err(format!("Unsupported filter type: {}", line)),
+        };
+
+        let value_after = |key: &str| {
+            tokens
+                .iter()
+                .position(|t| *t == key)
+                .and_then(|i| parse_number(tokens.get(i + 1).copied()))
+        };
+
+        let frequency = value_after("Fc").ok_or_else(|| format!("Missing frequency: {}", line))?;
+        let gain_db = value_after("Gain").unwrap_or(0.0);
+        let q = value_after("Q").unwrap_or(DEFAULT_SHELF_Q);
+
+        settings.bands.push(EqBand {
+            kind,
+            frequency,
+            gain_db,
+            q,
+        });
+    }
+
+    if settings.bands.is_empty() {
+        return Err("No filters found in file".to_string());
+    }
+
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn samples() -> Vec<f32> {
+        (0..44100).map(|i| (i as f32 * 0.05).sin() * 0.9).collect()
+    }
+
+    fn band(kind: EqFilterKind, frequency: f32) -> EqBand {
+        EqBand {
+            kind,
+            frequency,
+            gain_db: 0.0,
+            q: DEFAULT_SHELF_Q,
+        }
+    }
+
+    #[test]
+    fn a_flat_equalizer_is_bit_transparent() {
+        let control = EqControl::new(EqualizerSettings {
+            enabled: true,
+            preamp_db: 0.0,
+            bands: vec![
+                band(EqFilterKind::LowShelf, 105.0),
+                band(EqFilterKind::Peaking, 1000.0),
+                band(EqFilterKind::HighShelf, 8000.0),
+            ],
+        });
+        let input = samples();
+        let output: Vec<f32> = Equalizer::new(SamplesBuffer::new(2, 44100, input.clone()), control).collect();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn boosting_a_band_changes_the_signal() {
+        let mut boosted = band(EqFilterKind::Peaking, 1000.0);
+        boosted.gain_db = 6.0;
+        let control = EqControl::new(EqualizerSettings {
+            enabled: true,
+            preamp_db: 0.0,
+            bands: vec![boosted],
+        });
+        let input = samples();
+        let output: Vec<f32> = Equalizer::new(SamplesBuffer::new(2, 44100, input.clone()), control).collect();
+        assert_eq!(output.len(), input.len());
+        assert!(output != input);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use rodio::source::UniformSourceIterator;
+use rodio::Source;
+use tauri::{AppHandle, Emitter};
+use crate::{audio, decoder};
+use crate::equalizer::{EqControl, Equalizer};
+use crate::flac::FlacWriter;
+use crate::models::{ExportFormat, ExportPlan, ExportRequest, ExportStatus};
+use crate::sources::Gain;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+// Samples rendered between checks for cancellation and progress.
+const CHUNK_SAMPLES: usize = 8192;
+
+static STATUS: Lazy<Mutex<ExportStatus>> = Lazy::new(|| Mutex::new(idle_status()));
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+fn idle_status() -> ExportStatus {
+    ExportStatus {
+        running: false,
+        track_index: 0,
+        track_count: 0,
+        current: None,
+        rendered_secs: 0.0,
+        total_secs: None,
+        error: None,
+    }
+}
+
+enum Encoder {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Flac(FlacWriter<BufWriter<File>>),
+}
+
+struct Output {
+    encoder: Encoder,
+    scale: f32,
+    buffer: Vec<i32>,
+}
+
+impl Output {
+    fn create(path: &str, format: ExportFormat, channels: u16, sample_rate: u32, bits: u16) -> Result<Self, String> {
+        let encoder = match format {
+            ExportFormat::Wav => {
+                let spec = hound::WavSpec {
+                    channels,
+                    sample_rate,
+                    bits_per_sample: bits,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                let writer = hound::WavWriter::create(path, spec)
+                    .map_err(|e| format!("Failed to create output file: {}", e))?;
+                Encoder::Wav(writer)
+            }
+            ExportFormat::Flac => {
+                let file = File::create(path)
+                    .map_err(|e| format!("Failed to create output file: {}", e))?;
+                Encoder::Flac(FlacWriter::new(BufWriter::new(file), channels, sample_rate, bits as u32)?)
+            }
+        };
+
+        Ok(Output {
+            encoder,
+            scale: ((1i32 << (bits - 1)) - 1) as f32,
+            buffer: Vec::with_capacity(CHUNK_SAMPLES),
+        })
+    }
+
+    fn write(&mut self, samples: impl IntoIterator<Item = f32>) -> Result<(), String> {
+        self.buffer.clear();
+        self.buffer
+            .extend(samples.into_iter().map(|s| (s.clamp(-1.0, 1.0) * self.scale).round() as i32));
+
+        match &mut self.encoder {
+            Encoder::Wav(writer) => {
+                for &sample in &self.buffer {
+                    writer
+                        .write_sample(sample)
+                        .map_err(|e| format!("Failed to write audio: {}", e))?;
+                }
+                Ok(())
+            }
+            Encoder::Flac(writer) => writer.write_samples(&self.buffer),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self.encoder {
+            Encoder::Wav(writer) => writer
+                .finalize()
+                .map_err(|e| format!("Failed to finish output file: {}", e)),
+            Encoder::Flac(writer) => writer.finish(),
+        }
+    }
+}
+
+// Sum of the track lengths less the crossfade overlaps, if every track
+// reports its length.
+fn total_secs(plan: &ExportPlan) -> Option<f64> {
+    let overlap = plan.crossfade.unwrap_or_default().as_secs_f64();
+    plan.tracks.iter().try_fold(0.0, |total, track| {
+        let length = decoder::open_track(&track.path, track.range.as_ref()).ok()?.total_duration()?.as_secs_f64();
+        let overlap = if track.crossfade_in { overlap.min(length) } else { 0.0 };
+        Some(total + length - overlap)
+    })
+}
+
+fn emit_progress(app: &AppHandle) {
+    let status = STATUS.lock().unwrap().clone();
+    let _ = app.emit("export-progress", status);
+}
+
+fn cancelled() -> Result<(), String> {
+    if CANCEL.load(Ordering::SeqCst) {
+        Err("Export cancelled".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// Renders the tracks one after another into `output`. The last crossfade's
+// worth of audio is held back in `tail` so the next track can be mixed into
+// it before it is written.
+fn render(app: &AppHandle, plan: &ExportPlan, output_path: &str, format: ExportFormat, bits: u16) -> Result<(), String> {
+    let first = decoder::open_track(&plan.tracks[0].path, plan.tracks[0].range.as_ref())?;
+    let channels = first.channels().max(1);
+    let sample_rate = first.sample_rate().max(1);
+    drop(first);
+
+    let mut output = Output::create(output_path, format, channels, sample_rate, bits)?;
+    let equalizer = EqControl::new(plan.equalizer.clone());
+    let crossfade_frames = plan
+        .crossfade
+        .map_or(0, |d| (d.as_secs_f64() * sample_rate as f64) as usize);
+    let tail_len = crossfade_frames * channels as usize;
+    let mut tail: VecDeque<f32> = VecDeque::with_capacity(tail_len);
+    let mut rendered = 0usize;
+    let mut last_progress = Instant::now();
+
+    for (index, track) in plan.tracks.iter().enumerate() {
+        {
+            let mut status = STATUS.lock().unwrap();
+            status.track_index = index;
+            status.current = Some(track.path.clone());
+        }
+        emit_progress(app);
+
+        let gain = Arc::new(AtomicU32::new(track.gain.to_bits()));
+        let source = decoder::open_track(&track.path, track.range.as_ref())?.convert_samples::<f32>();
+        let source = Gain::new(source, gain);
+        let source = Equalizer::new(source, equalizer.clone());
+        let mut samples = UniformSourceIterator::<_, f32>::new(source, channels, sample_rate);
+
+        // Without a crossfade the held-back audio goes out untouched first.
+        if !track.crossfade_in {
+            output.write(tail.drain(..))?;
+        }
+        let mix_len = tail.len();
+        let mix_frames = (mix_len / channels as usize).max(1);
+
+        let mut position = 0;
+        let mut chunk = Vec::with_capacity(CHUNK_SAMPLES);
+        loop {
+            chunk.clear();
+            chunk.extend(samples.by_ref().take(CHUNK_SAMPLES));
+            if chunk.is_empty() {
+                break;
+            }
+
+            for &sample in &chunk {
+                if position < mix_len {
+                    let t = (position / channels as usize) as f32 / mix_frames as f32;
+                    tail[position] = tail[position] * (1.0 - t) + sample * t;
+                } else {
+                    tail.push_back(sample);
+                    // Mixed samples overlap audio already counted.
+                    rendered += 1;
+                }
+                position += 1;
+            }
+            // While mixing the tail stays at `mix_len`, so nothing shifts
+            // under the positions still to be mixed.
+            let overflow = tail.len().saturating_sub(tail_len);
+            output.write(tail.drain(..overflow))?;
+
+            STATUS.lock().unwrap().rendered_secs =
+                rendered as f64 / channels as f64 / sample_rate as f64;
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                emit_progress(app);
+            }
+            cancelled()?;
+        }
+
+        // A track shorter than the crossfade leaves part of the previous one
+        // unmixed; let it finish fading out on its own.
+        for (i, sample) in tail.iter_mut().enumerate().take(mix_len).skip(position) {
+            let t = (i / channels as usize) as f32 / mix_frames as f32;
+            *sample *= 1.0 - t;
+        }
+    }
+
+    output.write(tail.drain(..))?;
+    output.finish()
+}
+
+fn run(app: &AppHandle, plan: ExportPlan, request: &ExportRequest) -> Result<(), String> {
+    let total_secs = total_secs(&plan);
+    STATUS.lock().unwrap().total_secs = total_secs;
+
+    // Rendered next to the destination and moved into place once complete,
+    // so a failed export never leaves a truncated file under the real name.
+    let partial = format!("{}.part", request.output);
+    let result = render(app, &plan, &partial, request.format, request.bits_per_sample)
+        .and_then(|()| {
+            std::fs::rename(&partial, &request.output)
+                .map_err(|e| format!("Failed to move output file: {}", e))
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+// Starts rendering in the background. `export-progress` is emitted with the
+// status as it goes and once more when it finishes or fails.
+pub fn start(app: AppHandle, request: ExportRequest) -> Result<(), String> {
+    if request.bits_per_sample != 16 && request.bits_per_sample != 24 {
+        return Err(format!("Unsupported bit depth: {}", request.bits_per_sample));
+    }
+    if request.output.trim().is_empty() {
+        return Err("No output file given".to_string());
+    }
+
+    let plan = audio::export_plan(request.paths.clone())?;
+    {
+        let mut status = STATUS.lock().unwrap();
+        if status.running {
+            return Err("An export is already running".to_string());
+        }
+        *status = ExportStatus {
+            running: true,
+            track_count: plan.tracks.len(),
+            ..idle_status()
+        };
+    }
+    CANCEL.store(false, Ordering::SeqCst);
+
+    std::thread::spawn(move || {
+        let result = run(&app, plan, &request);
+        {
+            let mut status = STATUS.lock().unwrap();
+            status.running = false;
+            status.current = None;
+            status.error = result.err();
+        }
+        emit_progress(&app);
+    });
+
+    Ok(())
+}
+
+pub fn status() -> ExportStatus {
+    STATUS.lock().unwrap().clone()
+}
+
+pub fn cancel() {
+    CANCEL.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,91 @@
+use std::f32::consts::PI;
+
+// In-place iterative radix-2 FFT. Both slices hold one half of the complex
+// input and must have the same power-of-two length.
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two() && im.len() == n);
+
+    // Bit-reversal permutation.
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        let (w_im, w_re) = angle.sin_cos();
+        for start in (0..n).step_by(len) {
+            let (mut cur_re, mut cur_im) = (1.0f32, 0.0f32);
+            for k in 0..len / 2 {
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * cur_re - im[b] * cur_im;
+                let t_im = re[b] * cur_im + im[b] * cur_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+
+                let next_re = cur_re * w_re - cur_im * w_im;
+                cur_im = cur_re * w_im + cur_im * w_re;
+                cur_re = next_re;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+pub fn hann_window(n: usize) -> Vec<f32> {
+    (0..n)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_pure_tone_lands_in_its_bin() {
+        let n = 64;
+        let bin = 5;
+        let mut re: Vec<f32> = (0..n).map(|i| (2.0 * PI * bin as f32 * i as f32 / n as f32).cos()).collect();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+
+        // A real tone splits between its bin and the mirrored one.
+        for k in 0..n {
+            let magnitude = (re[k] * re[k] + im[k] * im[k]).sqrt();
+            let expected = if k == bin || k == n - bin { n as f32 / 2.0 } else { 0.0 };
+            assert!((magnitude - expected).abs() < 1e-3, "bin {}: {}", k, magnitude);
+        }
+    }
+
+    #[test]
+    fn a_constant_lands_in_the_first_bin() {
+        let mut re = vec![0.5; 16];
+        let mut im = vec![0.0; 16];
+        fft(&mut re, &mut im);
+        assert!((re[0] - 8.0).abs() < 1e-6);
+        assert!(re[1..].iter().chain(&im).all(|v| v.abs() < 1e-6));
+    }
+
+    #[test]
+    fn hann_window_starts_at_zero_and_peaks_in_the_middle() {
+        let window = hann_window(8);
+        assert_eq!(window[0], 0.0);
+        assert!((window[4] - 1.0).abs() < 1e-6);
+        assert!((window[2] - window[6]).abs() < 1e-6);
+    }
+}
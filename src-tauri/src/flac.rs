@@ -0,0 +1,464 @@
+use std::io::{Seek, SeekFrom, Write};
+
+// A small FLAC encoder: fixed-blocksize frames with independent channels, each
+// subframe coded as a constant, a fixed predictor of order 0-4 with a single
+// Rice partition, or verbatim, whichever is smallest. The MD5 signature in
+// STREAMINFO is left zeroed, which the format allows.
+
+const BLOCK_SIZE: usize = 4096;
+const MAX_FIXED_ORDER: usize = 4;
+// Parameter 15 is the escape code in 4-bit Rice partitions.
+const MAX_RICE_PARAMETER: u32 = 14;
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    // Writes the low `bits` bits of `value`, most significant first.
+    fn write(&mut self, value: u64, bits: u32) {
+        let mut remaining = bits;
+        while remaining > 0 {
+            let take = (8 - self.bits).min(remaining);
+            let chunk = (value >> (remaining - take)) & ((1 << take) - 1);
+            self.buffer = (self.buffer << take) | chunk;
+            self.bits += take;
+            remaining -= take;
+            if self.bits == 8 {
+                self.bytes.push(self.buffer as u8);
+                self.buffer = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & ((1u64 << bits) - 1), bits);
+    }
+
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+// Frame numbers use the same variable-length coding as UTF-8.
+fn write_coded_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+
+    let mut bytes = 2;
+    while value >= 1u64 << (5 * bytes + 1) {
+        bytes += 1;
+    }
+    let lead = (0xFFu64 << (8 - bytes)) & 0xFF;
+    writer.write(lead | (value >> (6 * (bytes - 1))), 8);
+    for i in (0..bytes - 1).rev() {
+        writer.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |k: usize| samples[i - k];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Picks the Rice parameter that codes `residual` in the fewest bits, using
+// the sum of the values to estimate the size of the quotients.
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let count = residual.len() as u64;
+    let sum: u64 = residual.iter().map(|&r| zigzag(r)).sum();
+    (0..=MAX_RICE_PARAMETER)
+        .map(|k| (k, count * (k as u64 + 1) + (sum >> k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i64], bits_per_sample: u32) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        writer.write(0b0000_0000, 8);
+        writer.write_signed(samples[0], bits_per_sample);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * bits_per_sample as u64;
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = residual(samples, order);
+            let (parameter, bits) = rice_parameter(&residual);
+            let total = order as u64 * bits_per_sample as u64 + 2 + 4 + 4 + bits;
+            (order, residual, parameter, total)
+        })
+        .min_by_key(|(_, _, _, total)| *total);
+
+    match best {
+        Some((order, residual, parameter, total)) if total < verbatim_bits => {
+            writer.write(0b0001_0000 | ((order as u64) << 1), 8);
+            for &warmup in &samples[..order] {
+                writer.write_signed(warmup, bits_per_sample);
+            }
+            // Rice coding with 4-bit parameters and a single partition.
+            writer.write(0b00, 2);
+            writer.write(0, 4);
+            writer.write(parameter as u64, 4);
+            for &r in &residual {
+                let value = zigzag(r);
+                writer.write_unary(value >> parameter);
+                writer.write(value, parameter);
+            }
+        }
+        _ => {
+            writer.write(0b0000_0010, 8);
+            for &sample in samples {
+                writer.write_signed(sample, bits_per_sample);
+            }
+        }
+    }
+}
+
+pub struct FlacWriter<W: Write + Seek> {
+    out: W,
+    channels: usize,
+    bits_per_sample: u32,
+    sample_rate: u32,
+    // Interleaved samples waiting for a full block.
+    pending: Vec<i32>,
+    frame_number: u64,
+    total_frames: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    pub fn new(mut out: W, channels: u16, sample_rate: u32, bits_per_sample: u32) -> Result<Self, String> {
+        if !(1..=8).contains(&channels) {
+            return Err(format!("FLAC supports 1 to 8 channels, not {}", channels));
+        }
+        if bits_per_sample != 16 && bits_per_sample != 24 {
+            return Err(format!("Unsupported FLAC bit depth: {}", bits_per_sample));
+        }
+
+        out.write_all(b"fLaC")
+            .map_err(|e| format!("Failed to write FLAC header: {}", e))?;
+        let mut writer = FlacWriter {
+            out,
+            channels: channels as usize,
+            bits_per_sample,
+            sample_rate,
+            pending: Vec::with_capacity(BLOCK_SIZE * channels as usize),
+            frame_number: 0,
+            total_frames: 0,
+            min_frame_size: 0,
+            max_frame_size: 0,
+        };
+        // Written again with the final sizes and length by `finish`.
+        writer.write_stream_info()?;
+        Ok(writer)
+    }
+
+    fn write_stream_info(&mut self) -> Result<(), String> {
+        let mut info = BitWriter::new();
+        // Last-metadata-block flag, STREAMINFO type and its 34-byte length.
+        info.write(1, 1);
+        info.write(0, 7);
+        info.write(34, 24);
+        info.write(BLOCK_SIZE as u64, 16);
+        info.write(BLOCK_SIZE as u64, 16);
+        info.write(self.min_frame_size as u64, 24);
+        info.write(self.max_frame_size as u64, 24);
+        info.write(self.sample_rate as u64, 20);
+        info.write(self.channels as u64 - 1, 3);
+        info.write(self.bits_per_sample as u64 - 1, 5);
+        info.write(self.total_frames, 36);
+        info.write(0, 64);
+        info.write(0, 64);
+
+        self.out
+            .write_all(&info.bytes)
+            .map_err(|e| format!("Failed to write FLAC header: {}", e))
+    }
+
+    // Takes interleaved samples already scaled to the stream's bit depth.
+    pub fn write_samples(&mut self, samples: &[i32]) -> Result<(), String> {
+        for &sample in samples {
+            self.pending.push(sample);
+            if self.pending.len() == BLOCK_SIZE * self.channels {
+                self.write_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self) -> Result<(), String> {
+        let block = self.pending.len() / self.channels;
+        if block == 0 {
+            return Ok(());
+        }
+
+        let mut writer = BitWriter::new();
+        writer.write(0b1111_1111_1111_1000, 16);
+        // Block size as a 16-bit value after the header; sample rate taken
+        // from STREAMINFO.
+        writer.write(0b0111, 4);
+        writer.write(0b0000, 4);
+        writer.write(self.channels as u64 - 1, 4);
+        writer.write(if self.bits_per_sample == 16 { 0b100 } else { 0b110 }, 3);
+        writer.write(0, 1);
+        write_coded_number(&mut writer, self.frame_number);
+        writer.write(block as u64 - 1, 16);
+        let crc = crc8(&writer.bytes);
+        writer.write(crc as u64, 8);
+
+        for channel in 0..self.channels {
+            let samples: Vec<i64> = self
+                .pending
+                .iter()
+                .skip(channel)
+                .step_by(self.channels)
+                .map(|&s| s as i64)
+                .collect();
+            write_subframe(&mut writer, &samples, self.bits_per_sample);
+        }
+        writer.align();
+        let crc = crc16(&writer.bytes);
+        writer.write(crc as u64, 16);
+
+        self.out
+            .write_all(&writer.bytes)
+            .map_err(|e| format!("Failed to write FLAC frame: {}", e))?;
+
+        let size = writer.bytes.len() as u32;
+        self.min_frame_size = if self.frame_number == 0 { size } else { self.min_frame_size.min(size) };
+        self.max_frame_size = self.max_frame_size.max(size);
+        self.frame_number += 1;
+        self.total_frames += block as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        let remainder = self.pending.len() % self.channels;
+        self.pending.truncate(self.pending.len() - remainder);
+        self.write_frame()?;
+
+        self.out
+            .seek(SeekFrom::Start(4))
+            .map_err(|e| format!("Failed to finish FLAC file: {}", e))?;
+        self.write_stream_info()?;
+        self.out
+            .flush()
+            .map_err(|e| format!("Failed to finish FLAC file: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::errors::Error;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    fn encode(samples: &[i32], channels: u16, bits_per_sample: u32) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = FlacWriter::new(&mut out, channels, 44100, bits_per_sample).unwrap();
+        // Uneven pieces, so blocks are filled across several calls.
+        for piece in samples.chunks(1000 * channels as usize + 1) {
+            writer.write_samples(piece).unwrap();
+        }
+        writer.finish().unwrap();
+        out.into_inner()
+    }
+
+    // Decodes with symphonia and returns the interleaved samples at the
+    // stream's own bit depth, along with its channel count and length.
+    fn decode(bytes: Vec<u8>, bits_per_sample: u32) -> (Vec<i32>, usize, Option<u64>) {
+        let stream = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+        let mut hint = Hint::new();
+        hint.with_extension("flac");
+        let probed = symphonia::default::get_probe()
+            .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+            .unwrap();
+        let mut format = probed.format;
+        let params = format.default_track().unwrap().codec_params.clone();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions::default())
+            .unwrap();
+
+        let mut samples = Vec::new();
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => panic!("failed to read packet: {}", e),
+            };
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend(buffer.samples().iter().map(|s| s >> (32 - bits_per_sample)));
+        }
+        (samples, params.channels.unwrap().count(), params.n_frames)
+    }
+
+    fn round_trip(samples: &[i32], channels: u16, bits_per_sample: u32) {
+        let (decoded, decoded_channels, frames) = decode(encode(samples, channels, bits_per_sample), bits_per_sample);
+        assert_eq!(decoded_channels, channels as usize);
+        assert_eq!(frames, Some((samples.len() / channels as usize) as u64));
+        assert_eq!(decoded.len(), samples.len());
+        assert!(decoded == samples, "decoded samples differ from the input");
+    }
+
+    // A tone with some noise on top, which the fixed predictors code well.
+    fn tone(frames: usize, channels: usize, amplitude: f64) -> Vec<i32> {
+        let mut noise = 1u32;
+        (0..frames * channels)
+            .map(|i| {
+                noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let t = (i / channels) as f64 / 44100.0;
+                let phase = (i % channels) as f64;
+                let dither = (noise >> 16) as f64 / 65536.0 - 0.5;
+                (amplitude * (2.0 * std::f64::consts::PI * 440.0 * t + phase).sin() + dither * 64.0) as i32
+            })
+            .collect()
+    }
+
+    // Full-scale noise, which no predictor helps with.
+    fn noise(count: usize, bits_per_sample: u32) -> Vec<i32> {
+        let mut state = 0x2545_F491u64;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                ((state >> 32) as i32) >> (32 - bits_per_sample)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crcs_match_the_check_values() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+
+    #[test]
+    fn frame_numbers_use_utf8_coding() {
+        let coded = |value: u64| {
+            let mut writer = BitWriter::new();
+            write_coded_number(&mut writer, value);
+            writer.bytes
+        };
+        assert_eq!(coded(0x7F), [0x7F]);
+        assert_eq!(coded(0x80), [0xC2, 0x80]);
+        assert_eq!(coded(0x7FF), [0xDF, 0xBF]);
+        assert_eq!(coded(0x800), [0xE0, 0xA0, 0x80]);
+        assert_eq!(coded(0x10000), [0xF0, 0x90, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn stereo_16_bit_round_trips_with_a_short_last_block() {
+        round_trip(&tone(BLOCK_SIZE * 3 + 1234, 2, 20000.0), 2, 16);
+    }
+
+    #[test]
+    fn mono_24_bit_round_trips() {
+        round_trip(&tone(BLOCK_SIZE * 2 + 77, 1, 8_000_000.0), 1, 24);
+    }
+
+    #[test]
+    fn less_than_one_block_round_trips() {
+        round_trip(&tone(1, 2, 1000.0), 2, 16);
+        round_trip(&tone(5, 1, 1000.0), 1, 24);
+        round_trip(&tone(BLOCK_SIZE - 1, 2, 1000.0), 2, 16);
+    }
+
+    #[test]
+    fn silence_and_extremes_round_trip() {
+        // Constant subframes, then the largest swings the bit depth allows.
+        let mut samples = vec![0; BLOCK_SIZE * 2];
+        samples.extend((0..BLOCK_SIZE * 2 + 6).map(|i| if i % 2 == 0 { i16::MAX as i32 } else { i16::MIN as i32 }));
+        round_trip(&samples, 2, 16);
+
+        let mut samples = vec![-(1 << 23); BLOCK_SIZE + 10];
+        samples.extend((0..BLOCK_SIZE).map(|i| if i % 3 == 0 { (1 << 23) - 1 } else { -(1 << 23) }));
+        round_trip(&samples, 1, 24);
+    }
+
+    #[test]
+    fn noise_falls_back_to_verbatim_and_round_trips() {
+        round_trip(&noise(BLOCK_SIZE * 2 + 11, 16), 1, 16);
+        round_trip(&noise((BLOCK_SIZE + 5) * 6, 24), 6, 24);
+    }
+
+    #[test]
+    fn multi_byte_frame_numbers_round_trip() {
+        // Frame numbers from 128 on take two bytes in the frame header.
+        round_trip(&tone(BLOCK_SIZE * 130 + 1, 1, 1000.0), 1, 16);
+    }
+
+    #[test]
+    fn unsupported_formats_are_refused() {
+        let mut out = Cursor::new(Vec::new());
+        assert!(FlacWriter::new(&mut out, 0, 44100, 16).is_err());
+        assert!(FlacWriter::new(&mut out, 2, 44100, 8).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use crate::models::SupportedFormat;
+
+// Enough of the file to tell the containers apart; Ogg pages put the codec
+// id of the first packet at offset 28.
+const HEADER_LEN: usize = 36;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Mp3,
+    Aac,
+    // MP4/M4A, holding AAC or ALAC.
+    Mp4,
+    Flac,
+    Vorbis,
+    Opus,
+    Wav,
+    Aiff,
+    WavPack,
+}
+
+const FORMATS: &[Format] = &[
+    Format::Mp3,
+    Format::Aac,
+    Format::Mp4,
+    Format::Flac,
+    Format::Vorbis,
+    Format::Opus,
+    Format::Wav,
+    Format::Aiff,
+    #[cfg(feature = "wavpack")]
+    Format::WavPack,
+];
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Mp3 => "MP3",
+            Format::Aac => "AAC",
+            Format::Mp4 => "MPEG-4 Audio (AAC, ALAC)",
+            Format::Flac => "FLAC",
+            Format::Vorbis => "Ogg Vorbis",
+            Format::Opus => "Opus",
+            Format::Wav => "WAV",
+            Format::Aiff => "AIFF",
+            Format::WavPack => "WavPack",
+        }
+    }
+
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Format::Mp3 => &["mp3"],
+            Format::Aac => &["aac"],
+            Format::Mp4 => &["m4a", "m4b", "mp4", "alac"],
+            Format::Flac => &["flac"],
+            Format::Vorbis => &["ogg", "oga"],
+            Format::Opus => &["opus"],
+            Format::Wav => &["wav"],
+            Format::Aiff => &["aif", "aiff", "aifc"],
+            Format::WavPack => &["wv"],
+        }
+    }
+}
+
+fn from_extension(path: &Path) -> Option<Format> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    FORMATS.iter().copied().find(|f| f.extensions().contains(&ext.as_str()))
+}
+
+fn from_header(header: &[u8]) -> Option<Format> {
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    if at(0, b"fLaC") {
+        Some(Format::Flac)
+    } else if at(0, b"OggS") {
+        match () {
+            _ if at(28, b"OpusHead") => Some(Format::Opus),
+            _ if at(28, b"\x01vorbis") => Some(Format::Vorbis),
+            _ => None,
+        }
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        Some(Format::Wav)
+    } else if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+        Some(Format::Aiff)
+    } else if at(0, b"wvpk") {
+        Some(Format::WavPack)
+    } else if at(4, b"ftyp") {
+        Some(Format::Mp4)
+    } else if at(0, b"ID3") {
+        // ID3 tags sit in front of AAC streams as well, but far less often.
+        Some(Format::Mp3)
+    } else {
+        match header {
+            // Frame sync; layer bits of zero mark an ADTS header instead of
+            // an MPEG audio frame.
+            [0xFF, second, ..] if second & 0xF6 == 0xF0 => Some(Format::Aac),
+            [0xFF, second, ..] if second & 0xE0 == 0xE0 && second & 0x06 != 0 => Some(Format::Mp3),
+            _ => None,
+        }
+    }
+}
+
+// Works out the format from the start of the file, falling back to the
+// extension only for streams without a recognisable header.
+pub fn detect(path: &str) -> Option<Format> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    let read = File::open(path).and_then(|f| f.take(HEADER_LEN as u64).read_to_end(&mut header));
+    if read.is_err() {
+        return None;
+    }
+
+    // Raw MP3 and AAC streams can start with junk before the first frame.
+    from_header(&header)
+        .filter(|f| FORMATS.contains(f))
+        .or_else(|| from_extension(Path::new(path)).filter(|f| matches!(f, Format::Mp3 | Format::Aac)))
+}
+
+// Files that are skipped without being opened: anything else a music folder
+// typically holds.
+fn is_known_other(path: &Path) -> bool {
+    let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    matches!(
+        ext.as_deref(),
+        Some("jpg" | "jpeg" | "png" | "gif" | "bmp" | "webp" | "txt" | "nfo" | "log" | "cue" | "m3u" | "m3u8" | "pdf")
+    )
+}
+
+// Whether the indexer should pick the file up. Files with an audio extension
+// are taken as named; only the rest are opened, so files without an
+// extension or with a misleading one are found too.
+pub fn is_audio_file(path: &Path) -> bool {
+    if from_extension(path).is_some() {
+        return true;
+    }
+    !is_known_other(path) && path.to_str().is_some_and(|p| detect(p).is_some())
+}
+
+pub fn supported() -> Vec<SupportedFormat> {
+    FORMATS
+        .iter()
+        .map(|f| SupportedFormat {
+            name: f.name().to_string(),
+            extensions: f.extensions().iter().map(|e| e.to_string()).collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A header of HEADER_LEN bytes with `parts` written at their offsets.
+    fn header(parts: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_LEN];
+        for (offset, bytes) in parts {
+            header[*offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        header
+    }
+
+    fn is(header: &[u8], format: Format) -> bool {
+        from_header(header) == Some(format)
+    }
+
+    #[test]
+    fn containers_are_told_apart_by_their_magic() {
+        assert!(is(&header(&[(0, b"fLaC")]), Format::Flac));
+        assert!(is(&header(&[(0, b"OggS"), (28, b"OpusHead")]), Format::Opus));
+        assert!(is(&header(&[(0, b"OggS"), (28, b"\x01vorbis")]), Format::Vorbis));
+        // Ogg FLAC, Speex and the like aren't played.
+        assert!(from_header(&header(&[(0, b"OggS"), (28, b"Speex   ")])).is_none());
+        assert!(is(&header(&[(0, b"RIFF"), (8, b"WAVE")]), Format::Wav));
+        assert!(from_header(&header(&[(0, b"RIFF"), (8, b"AVI ")])).is_none());
+        assert!(is(&header(&[(0, b"FORM"), (8, b"AIFF")]), Format::Aiff));
+        assert!(is(&header(&[(0, b"FORM"), (8, b"AIFC")]), Format::Aiff));
+        assert!(is(&header(&[(0, b"wvpk")]), Format::WavPack));
+        assert!(is(&header(&[(4, b"ftypM4A ")]), Format::Mp4));
+        assert!(from_header(b"").is_none());
+        assert!(from_header(b"OggS").is_none());
+    }
+
+    #[test]
+    fn mpeg_streams_are_told_apart_by_their_sync() {
+        assert!(is(&header(&[(0, b"ID3\x04")]), Format::Mp3));
+        // MPEG-1 Layer III, with and without CRC.
+        assert!(is(&header(&[(0, &[0xFF, 0xFB, 0x90])]), Format::Mp3));
+        assert!(is(&header(&[(0, &[0xFF, 0xFA, 0x90])]), Format::Mp3));
+        // MPEG-2.5 Layer III.
+        assert!(is(&header(&[(0, &[0xFF, 0xE3])]), Format::Mp3));
+        // ADTS, MPEG-4 and MPEG-2.
+        assert!(is(&header(&[(0, &[0xFF, 0xF1])]), Format::Aac));
+        assert!(is(&header(&[(0, &[0xFF, 0xF9])]), Format::Aac));
+        // Sync bits with a reserved layer, or no sync at all.
+        assert!(from_header(&header(&[(0, &[0xFF, 0xE0])])).is_none());
+        assert!(from_header(&header(&[(0, &[0xFE, 0xFB])])).is_none());
+    }
+
+    #[test]
+    fn content_wins_over_the_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, bytes: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, bytes).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+
+        let flac = write("misnamed.mp3", &header(&[(0, b"fLaC")]));
+        assert!(detect(&flac) == Some(Format::Flac));
+        let opus = write("download.bin", &header(&[(0, b"OggS"), (28, b"OpusHead")]));
+        assert!(detect(&opus) == Some(Format::Opus));
+        assert!(is_audio_file(Path::new(&opus)));
+
+        // Raw MPEG streams may start with junk, so only their extensions are
+        // trusted without a header.
+        let junk = [0x55u8; HEADER_LEN];
+        assert!(detect(&write("junk.mp3", &junk)) == Some(Format::Mp3));
+        assert!(detect(&write("junk.aac", &junk)) == Some(Format::Aac));
+        assert!(detect(&write("junk.flac", &junk)).is_none());
+        assert!(!is_audio_file(Path::new(&write("junk.bin", &junk))));
+
+        // Pictures and the like aren't opened at all.
+        let cover = write("cover.jpg", &header(&[(0, b"RIFF"), (8, b"WAVE")]));
+        assert!(!is_audio_file(Path::new(&cover)));
+        assert!(detect(&dir.path().join("missing.flac").to_string_lossy()).is_none());
+    }
+}
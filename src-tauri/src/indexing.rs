@@ -1,62 +1,211 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 use lofty::read_from_path;
 use lofty::file::TaggedFileExt;
-use lofty::tag::Accessor;
-use crate::models::MusicFile;
+use lofty::tag::{Accessor, ItemKey};
+use crate::cue::{self, CueSheet};
+use crate::models::{Loudness, MusicFile, ReplayGain, Silence, TrackOverrides, TrackRange};
 use crate::db;
+use crate::formats;
+use crate::replaygain;
 use lofty::picture::Picture;
 use base64::{engine::general_purpose, Engine};
 
-const SUPPORTED_EXTENSIONS: &[&str] = &["mp3", "wav", "flac", "ogg"];
+struct Metadata {
+    artist: Option<String>,
+    album: Option<String>,
+    title: Option<String>,
+    thumbnail: Option<String>,
+    replay_gain: ReplayGain,
+    // A CUE sheet embedded in the tags of a single-file album rip.
+    cue_sheet: Option<String>,
+}
+
+fn extract_metadata(file_path: &str) -> Metadata {
+    let empty = Metadata {
+        artist: None,
+        album: None,
+        title: None,
+        thumbnail: None,
+        replay_gain: ReplayGain::default(),
+        cue_sheet: None,
+    };
 
-fn extract_metadata(file_path: &str) -> (Option<String>, Option<String>, Option<String>, Option<String>) {
     match read_from_path(file_path) {
         Ok(tagged_file) => {
             let tag = tagged_file.primary_tag();
             let tag = tag.or_else(|| tagged_file.first_tag());
 
             if let Some(tag) = tag {
-                let artist = tag.artist().map(|s| s.to_string());
-                let album = tag.album().map(|s| s.to_string());
-                let title = tag.title().map(|s| s.to_string());
                 let thumbnail = tag.pictures().get(0).map(|p: &Picture| {
                     let b64 = general_purpose::STANDARD.encode(p.data());
                     let mime = p.mime_type().map_or("image/jpeg", |m| m.as_str());
                     format!("data:{};base64,{}", mime, b64)
                 });
-                (artist, album, title, thumbnail)
+                Metadata {
+                    artist: tag.artist().map(|s| s.to_string()),
+                    album: tag.album().map(|s| s.to_string()),
+                    title: tag.title().map(|s| s.to_string()),
+                    thumbnail,
+                    replay_gain: replaygain::read_tags(tag),
+                    cue_sheet: tag
+                        .get_string(&ItemKey::Unknown("CUESHEET".to_string()))
+                        .map(|s| s.to_string()),
+                }
             } else {
-                (None, None, None, None)
+                empty
+            }
+        }
+        Err(_) => empty,
+    }
+}
+
+fn is_cue_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+}
+
+// The audio file a CUE sheet's FILE line names. Sheets often still name the
+// file they were ripped to (say a WAV later converted to FLAC), so a sibling
+// with the same stem is taken when the named file is missing.
+fn resolve_cue_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    let named = dir.join(name);
+    if named.is_file() {
+        return Some(named);
+    }
+
+    let stem = Path::new(name).file_stem()?;
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.file_stem() == Some(stem) && formats::is_audio_file(p))
+}
+
+// External CUE sheets among `files`, keyed by the audio file each one splits.
+// A sheet covering several files is split into one per file.
+fn external_cue_sheets(files: &[PathBuf]) -> HashMap<PathBuf, CueSheet> {
+    let mut sheets = HashMap::new();
+
+    for cue_path in files.iter().filter(|p| is_cue_file(p)) {
+        let (Ok(bytes), Some(dir)) = (std::fs::read(cue_path), cue_path.parent()) else {
+            continue;
+        };
+        let CueSheet {
+            title,
+            performer,
+            album_gain,
+            album_peak,
+            files: cue_files,
+        } = cue::parse(&cue::decode_text(&bytes));
+
+        for cue_file in cue_files {
+            if let Some(audio) = resolve_cue_file(dir, &cue_file.name) {
+                sheets.insert(audio, CueSheet {
+                    title: title.clone(),
+                    performer: performer.clone(),
+                    album_gain,
+                    album_peak,
+                    files: vec![cue_file],
+                });
             }
         }
-        Err(_) => (None, None, None, None),
     }
+
+    sheets
+}
+
+// One track per CUE track of a sheet covering just this file, filling in
+// from the file's own tags whatever the sheet leaves out.
+fn cue_tracks(file_path: &str, file_name: &str, sheet: &CueSheet, metadata: &Metadata) -> Vec<MusicFile> {
+    let [cue_file] = sheet.files.as_slice() else {
+        return Vec::new();
+    };
+    let tracks = &cue_file.tracks;
+
+    tracks
+        .iter()
+        .zip(cue::ranges(tracks))
+        .enumerate()
+        .map(|(index, (track, range))| MusicFile {
+            path: cue::track_path(file_path, index + 1),
+            name: format!("{:02}. {}", track.number, track.title.as_deref().unwrap_or(file_name)),
+            artist: track
+                .performer
+                .clone()
+                .or_else(|| sheet.performer.clone())
+                .or_else(|| metadata.artist.clone()),
+            album: sheet.title.clone().or_else(|| metadata.album.clone()),
+            title: track.title.clone(),
+            thumbnail: metadata.thumbnail.clone(),
+            // The file's own track gain covers the whole album.
+            replay_gain: ReplayGain {
+                track_gain: track.track_gain,
+                track_peak: track.track_peak,
+                album_gain: sheet.album_gain.or(metadata.replay_gain.album_gain),
+                album_peak: sheet.album_peak.or(metadata.replay_gain.album_peak),
+            },
+            loudness: Loudness::default(),
+            overrides: TrackOverrides::default(),
+            silence: Silence::default(),
+            range: Some(TrackRange {
+                file: file_path.to_string(),
+                start: range.start,
+                end: range.end,
+            }),
+        })
+        .collect()
+}
+
+fn list_files(path: &str) -> Vec<PathBuf> {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .collect()
 }
 
 pub fn scan_folder(path: &str) -> Vec<MusicFile> {
     let mut music_files = Vec::new();
+    let files = list_files(path);
+    let mut cue_sheets = external_cue_sheets(&files);
 
-    for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
-        if entry.file_type().is_file() {
-            if let Some(ext) = entry.path().extension() {
-                if let Some(ext_str) = ext.to_str() {
-                    if SUPPORTED_EXTENSIONS.contains(&ext_str.to_lowercase().as_str()) {
-                        let file_path = entry.path().to_string_lossy().to_string();
-                        let file_name = entry.file_name().to_string_lossy().to_string();
-                        let (artist, album, title, thumbnail) = extract_metadata(&file_path);
-                        music_files.push(MusicFile {
-                            path: file_path,
-                            name: file_name,
-                            artist,
-                            album,
-                            title,
-                            thumbnail,
-                        });
-                    }
-                }
-            }
+    for file in &files {
+        if !formats::is_audio_file(file) {
+            continue;
+        }
+
+        let file_path = file.to_string_lossy().to_string();
+        let file_name = file.file_name().map_or(String::new(), |n| n.to_string_lossy().to_string());
+        let metadata = extract_metadata(&file_path);
+
+        // An embedded sheet can only describe the file it sits in; one that
+        // names several files doesn't fit it and is ignored.
+        let sheet = cue_sheets
+            .remove(file)
+            .or_else(|| metadata.cue_sheet.as_deref().map(cue::parse).filter(|s| s.files.len() == 1));
+        let tracks = sheet.map_or_else(Vec::new, |sheet| cue_tracks(&file_path, &file_name, &sheet, &metadata));
+        if !tracks.is_empty() {
+            music_files.extend(tracks);
+            continue;
         }
+
+        music_files.push(MusicFile {
+            path: file_path,
+            name: file_name,
+            artist: metadata.artist,
+            album: metadata.album,
+            title: metadata.title,
+            thumbnail: metadata.thumbnail,
+            replay_gain: metadata.replay_gain,
+            loudness: Loudness::default(),
+            overrides: TrackOverrides::default(),
+            silence: Silence::default(),
+            range: None,
+        });
     }
 
     music_files
@@ -67,34 +216,84 @@ pub fn check_for_changes(app: &tauri::AppHandle) -> Result<bool, String> {
     let folders = db::get_folder_paths(&conn)?;
 
     for folder_path in folders {
-        let mut current_files = HashSet::new();
-        for entry in WalkDir::new(&folder_path).into_iter().filter_map(|e| e.ok()) {
-            if entry.file_type().is_file() {
-                if let Some(ext) = entry.path().extension() {
-                    if let Some(ext_str) = ext.to_str() {
-                        if SUPPORTED_EXTENSIONS.contains(&ext_str.to_lowercase().as_str()) {
-                            current_files.insert(entry.path().to_string_lossy().to_string());
-                        }
-                    }
-                }
-            }
-        }
+        let files = list_files(&folder_path);
+        let current_files: HashSet<String> = files
+            .iter()
+            .filter(|f| formats::is_audio_file(f))
+            .map(|f| f.to_string_lossy().to_string())
+            .collect();
 
         let folder_id = db::get_folder_id(&conn, &folder_path)?;
-        let stored_count = db::get_track_count(&conn, folder_id)?;
+        let stored_paths = db::get_track_paths(&conn, folder_id)?;
+        // CUE tracks count as the file they are cut from.
+        let stored_files: HashSet<String> = stored_paths
+            .iter()
+            .map(|(path, file)| file.clone().unwrap_or_else(|| path.clone()))
+            .collect();
+        let whole_files: HashSet<&String> = stored_paths
+            .iter()
+            .filter(|(_, file)| file.is_none())
+            .map(|(path, _)| path)
+            .collect();
 
-        if current_files.len() as i64 != stored_count {
+        if current_files != stored_files {
             return Ok(true);
         }
 
-        let stored_paths = db::get_track_paths(&conn, folder_id)?;
-
-        for stored_path in stored_paths {
-            if !current_files.contains(&stored_path) {
-                return Ok(true);
-            }
+        // A CUE sheet added next to a file that is still indexed whole.
+        let split_files = external_cue_sheets(&files);
+        if split_files.keys().any(|f| whole_files.contains(&f.to_string_lossy().to_string())) {
+            return Ok(true);
         }
     }
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            artist: Some("Tag Artist".to_string()),
+            album: Some("Tag Album".to_string()),
+            title: None,
+            thumbnail: None,
+            replay_gain: ReplayGain::default(),
+            cue_sheet: None,
+        }
+    }
+
+    #[test]
+    fn cue_tracks_keep_their_range_in_the_file() {
+        let sheet = cue::parse(
+            "PERFORMER Band\nFILE rip.flac WAVE\n\
+             TRACK 01 AUDIO\nTITLE One\nINDEX 01 00:00:00\n\
+             TRACK 02 AUDIO\nINDEX 01 01:00:00\n",
+        );
+        let tracks = cue_tracks("/music/rip.flac", "rip.flac", &sheet, &metadata());
+
+        let paths: Vec<_> = tracks.iter().map(|t| t.path.as_str()).collect();
+        assert_eq!(paths, ["/music/rip.flac/01", "/music/rip.flac/02"]);
+        let ranges: Vec<_> = tracks
+            .iter()
+            .map(|t| t.range.as_ref().map(|r| (r.file.as_str(), r.start, r.end)))
+            .collect();
+        assert_eq!(ranges, [Some(("/music/rip.flac", 0, Some(4500))), Some(("/music/rip.flac", 4500, None))]);
+
+        assert_eq!(tracks[0].name, "01. One");
+        assert_eq!(tracks[1].name, "02. rip.flac");
+        assert_eq!(tracks[0].artist.as_deref(), Some("Band"));
+        assert_eq!(tracks[0].album.as_deref(), Some("Tag Album"));
+    }
+
+    #[test]
+    fn sheets_over_several_files_are_not_mapped_onto_one() {
+        let sheet = cue::parse(
+            "FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+             FILE b.wav WAVE\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n",
+        );
+        assert!(cue_tracks("/music/a.wav", "a.wav", &sheet, &metadata()).is_empty());
+    }
+}
@@ -2,20 +2,60 @@ mod models;
 mod db;
 mod audio;
 mod indexing;
+mod sources;
+mod replaygain;
+mod loudness;
+mod biquad;
+mod equalizer;
+mod timestretch;
+mod supervisor;
+mod backend;
+mod output;
+mod queue;
+mod session;
+mod fft;
+mod visualizer;
+mod waveform;
+mod flac;
+mod formats;
+mod decoder;
+mod cue;
+mod export;
+mod chapters;
+mod audiobook;
+mod silence;
+#[cfg(feature = "wavpack")]
+mod wavpack;
 
-use tauri::AppHandle;
-use crate::models::MusicFile;
+use tauri::{AppHandle, Window, WindowEvent};
+use crate::models::{MusicFile, TrackOverrides};
 use crate::models::IndexedFolder;
+use crate::models::CrossfadeSettings;
+use crate::models::ReplayGainSettings;
+use crate::models::LoudnessScanStatus;
+use crate::models::{EqPreset, EqualizerSettings};
+use crate::models::{PlaybackRate, PlaybackRateMode};
+use crate::models::{OutputDevice, OutputSettings};
+use crate::models::{QueueSnapshot, RepeatMode, ShuffleMode};
+use crate::models::{AlarmSettings, AlarmStatus, SleepTimerSettings, SleepTimerStatus};
+use crate::models::Waveform;
+use crate::models::{ExportRequest, ExportStatus};
+use crate::models::SupportedFormat;
+use crate::models::{Bookmark, Chapter};
+use crate::models::LoopRegion;
+use crate::models::SilenceSettings;
 
 #[tauri::command]
 fn index_folder(path: String, app: AppHandle) -> Result<Vec<MusicFile>, String> {
-    let music_files = indexing::scan_folder(&path);
-
     let conn = db::get_db_connection(&app)?;
+    let mut music_files = indexing::scan_folder(&path);
+
     let folder_id = db::save_folder(&conn, &path)?;
     db::save_tracks(&conn, folder_id, &music_files)?;
+    db::apply_overrides(&conn, &mut music_files)?;
 
     audio::set_tracks(music_files.clone());
+    refresh_silence(&app, &conn, &music_files);
 
     Ok(music_files)
 }
@@ -25,6 +65,7 @@ fn load_from_db(app: AppHandle) -> Result<Vec<MusicFile>, String> {
     let conn = db::get_db_connection(&app)?;
     let tracks = db::load_tracks(&conn)?;
     audio::set_tracks(tracks.clone());
+    refresh_silence(&app, &conn, &tracks);
     Ok(tracks)
 }
 
@@ -42,14 +83,30 @@ fn check_for_changes(app: AppHandle) -> Result<bool, String> {
 #[tauri::command]
 fn remove_folder(folder_id: i64, app: AppHandle) -> Result<(), String> {
     let conn = db::get_db_connection(&app)?;
+    // Positions not yet written would otherwise be lost when the audiobook
+    // state is reloaded below.
+    audiobook::persist(&app)?;
     db::remove_folder(&conn, folder_id)?;
 
     let tracks = db::load_tracks(&conn)?;
     audio::set_tracks(tracks);
+    audiobook::restore(&app)?;
 
     Ok(())
 }
 
+#[tauri::command]
+fn get_track_overrides(path: String) -> Result<TrackOverrides, String> {
+    audio::get_track_overrides(&path)
+}
+
+#[tauri::command]
+fn set_track_overrides(path: String, overrides: TrackOverrides, app: AppHandle) -> Result<(), String> {
+    audio::set_track_overrides(&path, overrides.clone())?;
+    let conn = db::get_db_connection(&app)?;
+    db::save_overrides(&conn, &path, &overrides)
+}
+
 #[tauri::command]
 fn list_music() -> Result<Vec<MusicFile>, String> {
     audio::list_music()
@@ -120,9 +177,482 @@ fn play_previous() -> Result<(), String> {
     audio::play_previous()
 }
 
+#[tauri::command]
+fn get_queue() -> Result<QueueSnapshot, String> {
+    audio::get_queue()
+}
+
+#[tauri::command]
+fn enqueue(paths: Vec<String>) -> Result<(), String> {
+    audio::enqueue(paths)
+}
+
+#[tauri::command]
+fn enqueue_next(paths: Vec<String>) -> Result<(), String> {
+    audio::enqueue_next(paths)
+}
+
+#[tauri::command]
+fn remove_from_queue(id: u64) -> Result<(), String> {
+    audio::remove_from_queue(id)
+}
+
+#[tauri::command]
+fn move_in_queue(id: u64, to: usize) -> Result<(), String> {
+    audio::move_in_queue(id, to)
+}
+
+#[tauri::command]
+fn clear_queue() -> Result<(), String> {
+    audio::clear_queue()
+}
+
+#[tauri::command]
+fn play_queue_entry(id: u64) -> Result<(), String> {
+    audio::play_queue_entry(id)
+}
+
+#[tauri::command]
+fn play_selection(paths: Vec<String>, start: usize) -> Result<(), String> {
+    audio::play_selection(paths, start)
+}
+
+#[tauri::command]
+fn play_album(album: String, artist: Option<String>) -> Result<(), String> {
+    audio::play_album(album, artist)
+}
+
+#[tauri::command]
+fn play_artist(artist: String) -> Result<(), String> {
+    audio::play_artist(artist)
+}
+
+#[tauri::command]
+fn play_folder(path: String) -> Result<(), String> {
+    audio::play_folder(path)
+}
+
+#[tauri::command]
+fn set_repeat(mode: RepeatMode) -> Result<(), String> {
+    audio::set_repeat(mode)
+}
+
+#[tauri::command]
+fn set_shuffle(mode: ShuffleMode) -> Result<(), String> {
+    audio::set_shuffle(mode)
+}
+
+// Reloads the saved session, replacing the queue and whatever is playing.
+#[tauri::command]
+fn restore_session(app: AppHandle) -> Result<(), String> {
+    session::restore(&app)
+}
+
+#[tauri::command]
+fn set_crossfade(settings: CrossfadeSettings) -> Result<(), String> {
+    audio::set_crossfade(settings)
+}
+
+#[tauri::command]
+fn get_crossfade() -> Result<CrossfadeSettings, String> {
+    audio::get_crossfade()
+}
+
+#[tauri::command]
+fn set_replay_gain(settings: ReplayGainSettings) -> Result<(), String> {
+    audio::set_replay_gain(settings)
+}
+
+#[tauri::command]
+fn get_replay_gain() -> Result<ReplayGainSettings, String> {
+    audio::get_replay_gain()
+}
+
+#[tauri::command]
+fn start_loudness_scan(rescan: bool, write_tags: bool, app: AppHandle) -> Result<(), String> {
+    loudness::start_scan(app, rescan, write_tags)
+}
+
+#[tauri::command]
+fn get_loudness_scan_status() -> Result<LoudnessScanStatus, String> {
+    Ok(loudness::scan_status())
+}
+
+#[tauri::command]
+fn set_equalizer(settings: EqualizerSettings) -> Result<(), String> {
+    audio::set_equalizer(settings)
+}
+
+#[tauri::command]
+fn get_equalizer() -> Result<EqualizerSettings, String> {
+    audio::get_equalizer()
+}
+
+#[tauri::command]
+fn list_eq_presets(app: AppHandle) -> Result<Vec<EqPreset>, String> {
+    let conn = db::get_db_connection(&app)?;
+    db::load_eq_presets(&conn)
+}
+
+#[tauri::command]
+fn save_eq_preset(preset: EqPreset, app: AppHandle) -> Result<(), String> {
+    let conn = db::get_db_connection(&app)?;
+    db::save_eq_preset(&conn, &preset)
+}
+
+#[tauri::command]
+fn delete_eq_preset(name: String, app: AppHandle) -> Result<(), String> {
+    let conn = db::get_db_connection(&app)?;
+    db::delete_eq_preset(&conn, &name)
+}
+
+#[tauri::command]
+fn import_eq_preset(path: String, name: String, app: AppHandle) -> Result<EqPreset, String> {
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    let preset = EqPreset {
+        name,
+        settings: equalizer::parse_parametric_eq(&contents)?,
+    };
+
+    let conn = db::get_db_connection(&app)?;
+    db::save_eq_preset(&conn, &preset)?;
+    Ok(preset)
+}
+
+#[tauri::command]
+fn set_playback_rate(rate: f32, mode: PlaybackRateMode) -> Result<(), String> {
+    audio::set_playback_rate(rate, mode)
+}
+
+#[tauri::command]
+fn get_playback_rate() -> Result<PlaybackRate, String> {
+    audio::get_playback_rate()
+}
+
+#[tauri::command]
+fn set_transition_fade(millis: u32, app: AppHandle) -> Result<(), String> {
+    audio::set_transition_fade(millis)?;
+    let conn = db::get_db_connection(&app)?;
+    db::save_setting(&conn, "transition_fade", &millis.to_string())
+}
+
+#[tauri::command]
+fn get_transition_fade() -> Result<u32, String> {
+    audio::get_transition_fade()
+}
+
+#[tauri::command]
+fn set_sleep_timer(settings: SleepTimerSettings) -> Result<(), String> {
+    audio::set_sleep_timer(settings)
+}
+
+#[tauri::command]
+fn cancel_sleep_timer() -> Result<(), String> {
+    audio::cancel_sleep_timer()
+}
+
+#[tauri::command]
+fn get_sleep_timer() -> Result<Option<SleepTimerStatus>, String> {
+    audio::get_sleep_timer()
+}
+
+#[tauri::command]
+fn set_alarm(settings: AlarmSettings) -> Result<AlarmStatus, String> {
+    audio::set_alarm(settings)
+}
+
+#[tauri::command]
+fn cancel_alarm() -> Result<(), String> {
+    audio::cancel_alarm()
+}
+
+#[tauri::command]
+fn get_alarm() -> Result<Option<AlarmStatus>, String> {
+    audio::get_alarm()
+}
+
+#[tauri::command]
+fn subscribe_visualizer(fps: u32, window: Window, app: AppHandle) -> Result<(), String> {
+    visualizer::subscribe(app, window.label(), fps);
+    Ok(())
+}
+
+#[tauri::command]
+fn unsubscribe_visualizer(window: Window) -> Result<(), String> {
+    visualizer::unsubscribe(window.label());
+    Ok(())
+}
+
+#[tauri::command]
+fn get_supported_formats() -> Result<Vec<SupportedFormat>, String> {
+    Ok(formats::supported())
+}
+
+#[tauri::command]
+fn export_audio(request: ExportRequest, app: AppHandle) -> Result<(), String> {
+    export::start(app, request)
+}
+
+#[tauri::command]
+fn get_export_status() -> Result<ExportStatus, String> {
+    Ok(export::status())
+}
+
+#[tauri::command]
+fn cancel_export() -> Result<(), String> {
+    export::cancel();
+    Ok(())
+}
+
+#[tauri::command]
+fn set_audiobook(path: String, enabled: bool, app: AppHandle) -> Result<(), String> {
+    audiobook::set_flag(&app, &path, enabled)
+}
+
+#[tauri::command]
+fn list_audiobooks() -> Result<Vec<String>, String> {
+    Ok(audiobook::flags())
+}
+
+fn current_track_or(track: Option<String>) -> Result<String, String> {
+    match track {
+        Some(track) => Ok(track),
+        None => audio::get_current_track()?.ok_or_else(|| "Nothing is playing".to_string()),
+    }
+}
+
+// A CUE track is already a part of a file; the file's own chapters don't
+// line up with it.
+fn track_chapters(track: &str) -> Result<Vec<Chapter>, String> {
+    if audio::track_range(track).is_some() {
+        return Ok(Vec::new());
+    }
+    chapters::read(track)
+}
+
+#[tauri::command]
+fn list_chapters(track: Option<String>) -> Result<Vec<Chapter>, String> {
+    track_chapters(&current_track_or(track)?)
+}
+
+#[tauri::command]
+fn jump_to_chapter(index: usize) -> Result<(), String> {
+    let chapters = track_chapters(&current_track_or(None)?)?;
+    let chapter = chapters
+        .get(index)
+        .ok_or_else(|| format!("Chapter not found: {}", index))?;
+    audio::seek(chapter.start)
+}
+
+#[tauri::command]
+fn add_bookmark(note: Option<String>, position: Option<f64>, app: AppHandle) -> Result<Bookmark, String> {
+    audiobook::add_bookmark(&app, note, position)
+}
+
+#[tauri::command]
+fn list_bookmarks(track: Option<String>, app: AppHandle) -> Result<Vec<Bookmark>, String> {
+    let conn = db::get_db_connection(&app)?;
+    db::load_bookmarks(&conn, track.as_deref())
+}
+
+#[tauri::command]
+fn update_bookmark(id: i64, note: Option<String>, app: AppHandle) -> Result<(), String> {
+    let conn = db::get_db_connection(&app)?;
+    db::update_bookmark(&conn, id, note.as_deref())
+}
+
+#[tauri::command]
+fn delete_bookmark(id: i64, app: AppHandle) -> Result<(), String> {
+    let conn = db::get_db_connection(&app)?;
+    db::delete_bookmark(&conn, id)
+}
+
+#[tauri::command]
+fn jump_to_bookmark(id: i64, app: AppHandle) -> Result<(), String> {
+    audiobook::jump_to_bookmark(&app, id)
+}
+
+#[tauri::command]
+fn set_loop(start: u64, end: u64) -> Result<(), String> {
+    audio::set_loop(start, end)
+}
+
+#[tauri::command]
+fn clear_loop() -> Result<(), String> {
+    audio::clear_loop()
+}
+
+#[tauri::command]
+fn get_loop() -> Result<Option<(u64, u64)>, String> {
+    audio::get_loop()
+}
+
+// Saves the active loop of the current track under `name`.
+#[tauri::command]
+fn save_loop_region(name: String, app: AppHandle) -> Result<LoopRegion, String> {
+    let path = current_track_or(None)?;
+    let (start, end) = audio::get_loop()?.ok_or_else(|| "No loop is set".to_string())?;
+    let conn = db::get_db_connection(&app)?;
+    db::save_loop_region(&conn, &path, &name, start, end)
+}
+
+#[tauri::command]
+fn list_loop_regions(track: Option<String>, app: AppHandle) -> Result<Vec<LoopRegion>, String> {
+    let path = current_track_or(track)?;
+    let conn = db::get_db_connection(&app)?;
+    db::load_loop_regions(&conn, &path)
+}
+
+#[tauri::command]
+fn delete_loop_region(id: i64, app: AppHandle) -> Result<(), String> {
+    let conn = db::get_db_connection(&app)?;
+    db::delete_loop_region(&conn, id)
+}
+
+// Starts looping a saved region, switching to its track first if needed.
+#[tauri::command]
+fn recall_loop_region(id: i64, app: AppHandle) -> Result<(), String> {
+    let conn = db::get_db_connection(&app)?;
+    let region = db::get_loop_region(&conn, id)?.ok_or_else(|| format!("Loop region not found: {}", id))?;
+
+    // Setting the loop seeks to its start.
+    if audio::get_current_track()?.as_deref() != Some(region.path.as_str()) {
+        audio::play_music_at(region.path, 0.0)?;
+    }
+    audio::set_loop(region.start, region.end)
+}
+
+#[tauri::command]
+fn get_waveform(track: String, app: AppHandle) -> Result<Option<Waveform>, String> {
+    waveform::get(&app, &track)
+}
+
+#[tauri::command]
+fn list_output_devices() -> Result<Vec<OutputDevice>, String> {
+    output::list_devices()
+}
+
+#[tauri::command]
+fn get_output_settings() -> Result<OutputSettings, String> {
+    Ok(output::settings())
+}
+
+fn apply_output_settings(settings: OutputSettings, app: &AppHandle) -> Result<(), String> {
+    output::set_settings(settings.clone())?;
+
+    let value = serde_json::to_string(&settings)
+        .map_err(|e| format!("Failed to serialize output settings: {}", e))?;
+    let conn = db::get_db_connection(app)?;
+    db::save_setting(&conn, "output", &value)
+}
+
+#[tauri::command]
+fn set_output_device(device: Option<String>, app: AppHandle) -> Result<(), String> {
+    let settings = OutputSettings {
+        device,
+        ..output::settings()
+    };
+    apply_output_settings(settings, &app)
+}
+
+#[tauri::command]
+fn set_output_buffer_size(buffer_size: Option<u32>, app: AppHandle) -> Result<(), String> {
+    let settings = OutputSettings {
+        buffer_size,
+        ..output::settings()
+    };
+    apply_output_settings(settings, &app)
+}
+
+fn restore_output_settings(app: &AppHandle) {
+    let stored = db::get_db_connection(app)
+        .and_then(|conn| db::load_setting(&conn, "output"))
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_str::<OutputSettings>(&value).ok());
+
+    if let Some(settings) = stored {
+        output::configure(settings);
+    }
+}
+
+fn load_silence_settings(conn: &rusqlite::Connection) -> SilenceSettings {
+    db::load_setting(conn, "silence")
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or(SilenceSettings {
+            skip: false,
+            threshold_db: -60.0,
+        })
+}
+
+fn refresh_silence(app: &AppHandle, conn: &rusqlite::Connection, tracks: &[MusicFile]) {
+    let paths = tracks.iter().map(|t| t.path.clone()).collect();
+    silence::refresh(app, paths, load_silence_settings(conn).threshold_db);
+}
+
+#[tauri::command]
+fn get_silence_settings(app: AppHandle) -> Result<SilenceSettings, String> {
+    let conn = db::get_db_connection(&app)?;
+    Ok(load_silence_settings(&conn))
+}
+
+// A new threshold has the library checked again in the background.
+#[tauri::command]
+fn set_silence_settings(settings: SilenceSettings, app: AppHandle) -> Result<(), String> {
+    if !settings.threshold_db.is_finite() || settings.threshold_db >= 0.0 {
+        return Err(format!("Invalid silence threshold: {} dB", settings.threshold_db));
+    }
+    audio::set_skip_silence(settings.skip)?;
+
+    let value = serde_json::to_string(&settings)
+        .map_err(|e| format!("Failed to serialize silence settings: {}", e))?;
+    let conn = db::get_db_connection(&app)?;
+    let previous = load_silence_settings(&conn);
+    db::save_setting(&conn, "silence", &value)?;
+    if previous.threshold_db != settings.threshold_db {
+        refresh_silence(&app, &conn, &audio::list_music()?);
+    }
+    Ok(())
+}
+
+fn restore_silence_settings(app: &AppHandle) {
+    if let Ok(conn) = db::get_db_connection(app) {
+        let _ = audio::set_skip_silence(load_silence_settings(&conn).skip);
+    }
+}
+
+fn restore_transition_fade(app: &AppHandle) {
+    let stored = db::get_db_connection(app)
+        .and_then(|conn| db::load_setting(&conn, "transition_fade"))
+        .ok()
+        .flatten()
+        .and_then(|value| value.parse::<u32>().ok());
+
+    if let Some(millis) = stored {
+        let _ = audio::set_transition_fade(millis);
+    }
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
+        .setup(|app| {
+            restore_output_settings(app.handle());
+            restore_transition_fade(app.handle());
+            restore_silence_settings(app.handle());
+            let _ = audiobook::restore(app.handle());
+            let _ = session::restore(app.handle());
+            supervisor::start(app.handle().clone());
+            Ok(())
+        })
+        .on_window_event(|window, event| {
+            if let WindowEvent::Destroyed = event {
+                visualizer::release(window.label());
+            }
+        })
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
         .invoke_handler(tauri::generate_handler![
@@ -132,6 +662,8 @@ pub fn run() {
             get_indexed_folders,
             check_for_changes,
             remove_folder,
+            get_track_overrides,
+            set_track_overrides,
             play_music,
             pause_music,
             resume_music,
@@ -144,7 +676,72 @@ pub fn run() {
             get_playback_position,
             seek,
             play_next,
-            play_previous
+            play_previous,
+            get_queue,
+            enqueue,
+            enqueue_next,
+            remove_from_queue,
+            move_in_queue,
+            clear_queue,
+            play_queue_entry,
+            play_selection,
+            play_album,
+            play_artist,
+            play_folder,
+            set_repeat,
+            set_shuffle,
+            restore_session,
+            set_crossfade,
+            get_crossfade,
+            set_replay_gain,
+            get_replay_gain,
+            start_loudness_scan,
+            get_loudness_scan_status,
+            set_equalizer,
+            get_equalizer,
+            list_eq_presets,
+            save_eq_preset,
+            delete_eq_preset,
+            import_eq_preset,
+            set_playback_rate,
+            get_playback_rate,
+            subscribe_visualizer,
+            unsubscribe_visualizer,
+            get_waveform,
+            get_supported_formats,
+            export_audio,
+            get_export_status,
+            cancel_export,
+            set_audiobook,
+            list_audiobooks,
+            list_chapters,
+            jump_to_chapter,
+            add_bookmark,
+            list_bookmarks,
+            update_bookmark,
+            delete_bookmark,
+            jump_to_bookmark,
+            set_loop,
+            clear_loop,
+            get_loop,
+            save_loop_region,
+            list_loop_regions,
+            delete_loop_region,
+            recall_loop_region,
+            set_transition_fade,
+            get_silence_settings,
+            set_silence_settings,
+            get_transition_fade,
+            set_sleep_timer,
+            cancel_sleep_timer,
+            get_sleep_timer,
+            set_alarm,
+            cancel_alarm,
+            get_alarm,
+            list_output_devices,
+            get_output_settings,
+            set_output_device,
+            set_output_buffer_size
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use lofty::config::WriteOptions;
+use lofty::file::TaggedFileExt;
+use lofty::read_from_path;
+use lofty::tag::{ItemKey, Tag, TagExt};
+use rodio::Source;
+use tauri::AppHandle;
+use crate::biquad::Biquad;
+use crate::models::{Loudness, LoudnessScanStatus, MusicFile, TrackRange};
+use crate::{audio, db, decoder};
+
+// ReplayGain 2.0 reference level.
+pub const REFERENCE_LUFS: f32 = -18.0;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+// Loudness is accumulated in 100 ms steps; momentary blocks span 4 steps and
+// short-term blocks span 30.
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+const TRUE_PEAK_TAPS: usize = 12;
+const OVERSAMPLING: usize = 4;
+
+static SCAN_STATUS: Lazy<Mutex<LoudnessScanStatus>> = Lazy::new(|| {
+    Mutex::new(LoudnessScanStatus {
+        running: false,
+        processed: 0,
+        total: 0,
+        current: None,
+    })
+});
+
+// The two-stage K-weighting filter from ITU-R BS.1770, recomputed for the
+// actual sample rate.
+fn k_weighting(sample_rate: u32) -> (Biquad, Biquad) {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    (shelf, high_pass)
+}
+
+// Windowed-sinc interpolation kernel split into one phase per oversampled
+// position.
+fn true_peak_kernel() -> [[f64; TRUE_PEAK_TAPS]; OVERSAMPLING] {
+    let length = TRUE_PEAK_TAPS * OVERSAMPLING;
+    let center = (length / 2) as f64;
+    let mut phases = [[0.0; TRUE_PEAK_TAPS]; OVERSAMPLING];
+
+    for m in 0..length {
+        let t = (m as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+        let window = 0.5 - 0.5 * (2.0 * PI * m as f64 / length as f64).cos();
+        phases[m % OVERSAMPLING][m / OVERSAMPLING] = sinc * window;
+    }
+
+    phases
+}
+
+fn channel_weight(channels: u16, channel: usize) -> f64 {
+    // 5.1 layouts: the LFE channel is ignored and the surrounds are boosted.
+    if channels == 6 {
+        match channel {
+            3 => 0.0,
+            4 | 5 => 1.41,
+            _ => 1.0,
+        }
+    } else {
+        1.0
+    }
+}
+
+pub struct TrackAnalysis {
+    steps: Vec<f64>,
+    true_peak: f64,
+}
+
+impl TrackAnalysis {
+    fn momentary_blocks(&self) -> Vec<f64> {
+        self.steps
+            .windows(MOMENTARY_STEPS)
+            .map(|w| w.iter().sum::<f64>() / MOMENTARY_STEPS as f64)
+            .collect()
+    }
+
+    fn short_term_blocks(&self) -> Vec<f64> {
+        self.steps
+            .windows(SHORT_TERM_STEPS)
+            .map(|w| w.iter().sum::<f64>() / SHORT_TERM_STEPS as f64)
+            .collect()
+    }
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+    let above_absolute: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|e| *e > 0.0 && energy_to_lufs(*e) > ABSOLUTE_GATE)
+        .collect();
+    if above_absolute.is_empty() {
+        return None;
+    }
+
+    let mean = above_absolute.iter().sum::<f64>() / above_absolute.len() as f64;
+    let relative_gate = energy_to_lufs(mean) + INTEGRATED_RELATIVE_GATE;
+
+    let gated: Vec<f64> = above_absolute
+        .into_iter()
+        .filter(|e| energy_to_lufs(*e) > relative_gate)
+        .collect();
+    if gated.is_empty() {
+        return None;
+    }
+
+    Some(energy_to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+fn loudness_range(blocks: &[f64]) -> Option<f64> {
+    let above_absolute: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|e| *e > 0.0 && energy_to_lufs(*e) > ABSOLUTE_GATE)
+        .collect();
+    if above_absolute.is_empty() {
+        return None;
+    }
+
+    let mean = above_absolute.iter().sum::<f64>() / above_absolute.len() as f64;
+    let relative_gate = energy_to_lufs(mean) + RANGE_RELATIVE_GATE;
+
+    let mut levels: Vec<f64> = above_absolute
+        .into_iter()
+        .map(energy_to_lufs)
+        .filter(|l| *l > relative_gate)
+        .collect();
+    if levels.is_empty() {
+        return None;
+    }
+    levels.sort_by(|a, b| a.total_cmp(b));
+
+    let percentile = |p: f64| levels[((levels.len() - 1) as f64 * p).round() as usize];
+    Some(percentile(0.95) - percentile(0.10))
+}
+
+fn peak_to_dbtp(peak: f64) -> Option<f64> {
+    if peak > 0.0 {
+        Some(20.0 * peak.log10())
+    } else {
+        None
+    }
+}
+
+pub fn analyze_file(path: &str, range: Option<&TrackRange>) -> Result<TrackAnalysis, String> {
+    let source = decoder::open_track(path, range)?;
+
+    let channels = source.channels().max(1);
+    let sample_rate = source.sample_rate().max(1);
+    let step_frames = (sample_rate as usize / 10).max(1);
+    let kernel = true_peak_kernel();
+
+    let mut filters = vec![k_weighting(sample_rate); channels as usize];
+    let mut history = vec![[0.0f64; TRUE_PEAK_TAPS]; channels as usize];
+    let mut step_sum = 0.0;
+    let mut step_frame_count = 0;
+    let mut steps = Vec::new();
+    let mut true_peak: f64 = 0.0;
+    let mut channel = 0usize;
+
+    for sample in source.convert_samples::<f32>() {
+        let x = sample as f64;
+
+        let (shelf, high_pass) = &mut filters[channel];
+        let weighted = high_pass.process(shelf.process(x));
+        step_sum += weighted * weighted * channel_weight(channels, channel);
+
+        let taps = &mut history[channel];
+        taps.copy_within(0..TRUE_PEAK_TAPS - 1, 1);
+        taps[0] = x;
+        for phase in &kernel {
+            let y: f64 = phase.iter().zip(taps.iter()).map(|(h, s)| h * s).sum();
+            true_peak = true_peak.max(y.abs());
+        }
+
+        channel += 1;
+        if channel == channels as usize {
+            channel = 0;
+            step_frame_count += 1;
+            if step_frame_count == step_frames {
+                steps.push(step_sum / step_frames as f64);
+                step_sum = 0.0;
+                step_frame_count = 0;
+            }
+        }
+    }
+
+    Ok(TrackAnalysis { steps, true_peak })
+}
+
+pub fn track_loudness(analysis: &TrackAnalysis) -> Loudness {
+    Loudness {
+        integrated: integrated_loudness(&analysis.momentary_blocks()).map(|v| v as f32),
+        range: loudness_range(&analysis.short_term_blocks()).map(|v| v as f32),
+        true_peak: peak_to_dbtp(analysis.true_peak).map(|v| v as f32),
+        ..Loudness::default()
+    }
+}
+
+// Album values gate the blocks of every track together, as if the album were
+// one continuous programme.
+pub fn album_loudness(analyses: &[&TrackAnalysis]) -> (Option<f32>, Option<f32>, Option<f32>) {
+    let momentary: Vec<f64> = analyses.iter().flat_map(|a| a.momentary_blocks()).collect();
+    let short_term: Vec<f64> = analyses.iter().flat_map(|a| a.short_term_blocks()).collect();
+    let peak = analyses.iter().map(|a| a.true_peak).fold(0.0, f64::max);
+
+    (
+        integrated_loudness(&momentary).map(|v| v as f32),
+        loudness_range(&short_term).map(|v| v as f32),
+        peak_to_dbtp(peak).map(|v| v as f32),
+    )
+}
+
+fn write_replay_gain_tags(path: &str, loudness: &Loudness) -> Result<(), String> {
+    let mut tagged_file = read_from_path(path)
+        .map_err(|e| format!("Failed to read tags: {}", e))?;
+
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file
+        .primary_tag_mut()
+        .ok_or_else(|| "Failed to create tag".to_string())?;
+
+    let gain = |lufs: f32| format!("{:.2} dB", REFERENCE_LUFS - lufs);
+    let peak = |dbtp: f32| format!("{:.6}", 10f32.powf(dbtp / 20.0));
+
+    if let Some(integrated) = loudness.integrated {
+        tag.insert_text(ItemKey::ReplayGainTrackGain, gain(integrated));
+    }
+    if let Some(true_peak) = loudness.true_peak {
+        tag.insert_text(ItemKey::ReplayGainTrackPeak, peak(true_peak));
+    }
+    if let Some(integrated) = loudness.album_integrated {
+        tag.insert_text(ItemKey::ReplayGainAlbumGain, gain(integrated));
+    }
+    if let Some(true_peak) = loudness.album_true_peak {
+        tag.insert_text(ItemKey::ReplayGainAlbumPeak, peak(true_peak));
+    }
+
+    tag.save_to_path(path, WriteOptions::default())
+        .map_err(|e| format!("Failed to write tags: {}", e))
+}
+
+// Groups tracks by album so album values can be computed in one pass. Albums
+// are told apart by artist as well as title, as shuffle does, so albums of the
+// same name don't share a gain. Tracks without an album tag are analysed on
+// their own.
+fn scan_groups(tracks: Vec<MusicFile>, rescan: bool) -> Vec<Vec<MusicFile>> {
+    let mut albums: HashMap<(Option<String>, String), Vec<MusicFile>> = HashMap::new();
+    let mut groups = Vec::new();
+
+    for track in tracks {
+        match track.album.clone() {
+            Some(album) => albums.entry((track.artist.clone(), album)).or_default().push(track),
+            None => groups.push(vec![track]),
+        }
+    }
+    groups.extend(albums.into_values());
+
+    groups
+        .into_iter()
+        .filter(|group| rescan || group.iter().any(|t| !t.loudness.scanned))
+        .collect()
+}
+
+fn run_scan(app: &AppHandle, rescan: bool, write_tags: bool) -> Result<(), String> {
+    let conn = db::get_db_connection(app)?;
+    let groups = scan_groups(db::load_tracks(&conn)?, rescan);
+
+    SCAN_STATUS.lock().unwrap().total = groups.iter().map(|g| g.len()).sum();
+
+    for group in groups {
+        let mut analysed = Vec::new();
+        for track in group {
+            SCAN_STATUS.lock().unwrap().current = Some(track.path.clone());
+            if let Ok(analysis) = analyze_file(&track.path, track.range.as_ref()) {
+                analysed.push((track, analysis));
+            }
+            SCAN_STATUS.lock().unwrap().processed += 1;
+        }
+
+        let is_album = analysed.first().is_some_and(|(t, _)| t.album.is_some());
+        let analyses: Vec<&TrackAnalysis> = analysed.iter().map(|(_, a)| a).collect();
+        let (album_integrated, album_range, album_true_peak) = if is_album {
+            album_loudness(&analyses)
+        } else {
+            (None, None, None)
+        };
+
+        for (track, analysis) in &analysed {
+            let loudness = Loudness {
+                album_integrated,
+                album_range,
+                album_true_peak,
+                scanned: true,
+                ..track_loudness(analysis)
+            };
+            db::save_loudness(&conn, &track.path, &loudness)?;
+            // Tags of a file split by a CUE sheet can't hold per-track values.
+            if write_tags && track.range.is_none() {
+                let _ = write_replay_gain_tags(&track.path, &loudness);
+            }
+        }
+    }
+
+    audio::update_loudness(db::load_tracks(&conn)?);
+    Ok(())
+}
+
+pub fn start_scan(app: AppHandle, rescan: bool, write_tags: bool) -> Result<(), String> {
+    {
+        let mut status = SCAN_STATUS.lock().unwrap();
+        if status.running {
+            return Err("A loudness scan is already running".to_string());
+        }
+        *status = LoudnessScanStatus {
+            running: true,
+            processed: 0,
+            total: 0,
+            current: None,
+        };
+    }
+
+    std::thread::spawn(move || {
+        let _ = run_scan(&app, rescan, write_tags);
+        let mut status = SCAN_STATUS.lock().unwrap();
+        status.running = false;
+        status.current = None;
+    });
+
+    Ok(())
+}
+
+pub fn scan_status() -> LoudnessScanStatus {
+    SCAN_STATUS.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const RATE: u32 = 48000;
+
+    // Writes a stereo file with `left` and `right` at each frame, `secs` long.
+    fn write(dir: &TempDir, name: &str, secs: f64, frame: impl Fn(usize) -> (f64, f64)) -> String {
+        let path = dir.path().join(name);
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..(secs * RATE as f64) as usize {
+            let (left, right) = frame(i);
+            writer.write_sample((left * i16::MAX as f64).round() as i16).unwrap();
+            writer.write_sample((right * i16::MAX as f64).round() as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn sine(dbfs: f64, frequency: f64, phase: f64) -> impl Fn(usize) -> (f64, f64) {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        move |i| {
+            let x = amplitude * (2.0 * PI * frequency * i as f64 / RATE as f64 + phase).sin();
+            (x, x)
+        }
+    }
+
+    // Mean square of a 100 ms step measuring `lufs`.
+    fn step(lufs: f64) -> f64 {
+        10f64.powf((lufs + 0.691) / 10.0)
+    }
+
+    fn analysis(steps: Vec<f64>) -> TrackAnalysis {
+        TrackAnalysis { steps, true_peak: 0.0 }
+    }
+
+    #[test]
+    fn measures_a_stereo_sine_per_bs_1770() {
+        let dir = tempfile::tempdir().unwrap();
+        // EBU Tech 3341 case 1: a 1 kHz sine at -23 dBFS measures -23.0 LUFS.
+        for dbfs in [-23.0, -20.0] {
+            let path = write(&dir, "sine.wav", 10.0, sine(dbfs, 1000.0, 0.0));
+            let loudness = track_loudness(&analyze_file(&path, None).unwrap());
+            let integrated = loudness.integrated.unwrap() as f64;
+            assert!((integrated - dbfs).abs() < 0.1, "{} at {} dBFS", integrated, dbfs);
+            assert!(loudness.range.unwrap() < 0.1);
+        }
+    }
+
+    #[test]
+    fn gates_drop_silence_and_quiet_passages() {
+        let tone = step(-23.0);
+        let silence = |n| vec![0.0; n];
+
+        // Silence falls under the absolute gate, however much of it there is;
+        // only the blocks fading into it count.
+        let steps = [vec![tone; 100], silence(300)].concat();
+        let integrated = integrated_loudness(&analysis(steps).momentary_blocks()).unwrap();
+        assert!((integrated + 23.0).abs() < 0.1, "{}", integrated);
+
+        // A passage 20 LU down falls under the relative gate.
+        let steps = [vec![tone; 100], vec![step(-43.0); 100]].concat();
+        let integrated = integrated_loudness(&analysis(steps).momentary_blocks()).unwrap();
+        assert!((integrated + 23.0).abs() < 0.1, "{}", integrated);
+        // One 5 LU down doesn't.
+        let steps = [vec![tone; 100], vec![step(-28.0); 100]].concat();
+        let integrated = integrated_loudness(&analysis(steps).momentary_blocks()).unwrap();
+        assert!(integrated < -24.0, "{}", integrated);
+
+        assert!(integrated_loudness(&analysis(silence(100)).momentary_blocks()).is_none());
+    }
+
+    #[test]
+    fn range_spans_the_loud_and_quiet_parts() {
+        let steps = [vec![step(-20.0); 600], vec![step(-30.0); 600]].concat();
+        let range = loudness_range(&analysis(steps).short_term_blocks()).unwrap();
+        assert!((range - 10.0).abs() < 0.01, "{}", range);
+
+        // Silence doesn't stretch it.
+        let steps = [vec![step(-20.0); 600], vec![0.0; 600]].concat();
+        let range = loudness_range(&analysis(steps).short_term_blocks()).unwrap();
+        assert!(range < 0.01, "{}", range);
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        // At a quarter of the rate and 45 degrees out, every sample lands 3 dB
+        // under the crest.
+        let path = write(&dir, "quarter.wav", 1.0, sine(-6.0, RATE as f64 / 4.0, PI / 4.0));
+        let loudness = track_loudness(&analyze_file(&path, None).unwrap());
+        let true_peak = loudness.true_peak.unwrap();
+        assert!((true_peak + 6.0).abs() < 0.5, "{}", true_peak);
+
+        assert_eq!(peak_to_dbtp(1.0), Some(0.0));
+        assert_eq!(peak_to_dbtp(0.0), None);
+    }
+}
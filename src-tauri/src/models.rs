@@ -1,5 +1,12 @@
-use rodio::Sink;
+use chrono::{DateTime, Local};
+use rodio::{Sink, Source};
+use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
+use crate::equalizer::EqControl;
+use crate::output::Dedicated;
+use crate::queue::PlayQueue;
+use crate::sources::{Fader, LoopControl};
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct MusicFile {
@@ -9,6 +16,85 @@ pub struct MusicFile {
     pub album: Option<String>,
     pub title: Option<String>,
     pub thumbnail: Option<String>,
+    pub replay_gain: ReplayGain,
+    pub loudness: Loudness,
+    #[serde(default)]
+    pub overrides: TrackOverrides,
+    #[serde(default)]
+    pub silence: Silence,
+    // Set for tracks cut from a single-file rip by a CUE sheet. Their `path`
+    // then only identifies the track; the audio is played from here.
+    #[serde(default)]
+    pub range: Option<TrackRange>,
+}
+
+// The part of `file` a CUE track covers, in CUE frames of 1/75 s. Without an
+// end it runs to the end of the file.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct TrackRange {
+    pub file: String,
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+// Silence found at the ends of a track by the background worker: where the
+// sound starts, and where the trailing silence starts if there is any.
+// Seconds into the track.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Silence {
+    pub start: f64,
+    pub end: Option<f64>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct SilenceSettings {
+    // Cut the silence around tracks that follow on from each other.
+    pub skip: bool,
+    // Level in dBFS below which samples count as silence.
+    pub threshold_db: f32,
+}
+
+// Playback settings the user has set for a single track. Times are seconds
+// into the track; positions stay relative to the file, so chapters, bookmarks
+// and loops still line up.
+#[derive(Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TrackOverrides {
+    pub start: Option<f64>,
+    pub stop: Option<f64>,
+    pub gain_db: f32,
+    pub exclude_from_shuffle: bool,
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+// EBU R128 analysis results: integrated loudness in LUFS, loudness range in LU
+// and true peak in dBTP.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Loudness {
+    pub integrated: Option<f32>,
+    pub range: Option<f32>,
+    pub true_peak: Option<f32>,
+    pub album_integrated: Option<f32>,
+    pub album_range: Option<f32>,
+    pub album_true_peak: Option<f32>,
+    // Set once the scanner has been over the track, including tracks too
+    // short or quiet to measure, so those aren't scanned again.
+    #[serde(default)]
+    pub scanned: bool,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct LoudnessScanStatus {
+    pub running: bool,
+    pub processed: usize,
+    pub total: usize,
+    pub current: Option<String>,
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -18,12 +104,390 @@ pub struct IndexedFolder {
     pub last_indexed: String,
 }
 
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CrossfadeSettings {
+    pub duration_secs: f32,
+    pub on_manual_skip: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ReplayGainSettings {
+    pub mode: ReplayGainMode,
+    pub preamp_db: f32,
+    pub prevent_clipping: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EqFilterKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct EqBand {
+    pub kind: EqFilterKind,
+    pub frequency: f32,
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct EqualizerSettings {
+    pub enabled: bool,
+    pub preamp_db: f32,
+    pub bands: Vec<EqBand>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct EqPreset {
+    pub name: String,
+    pub settings: EqualizerSettings,
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackRateMode {
+    Stretch,
+    Tape,
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PlaybackRate {
+    pub rate: f32,
+    pub mode: PlaybackRateMode,
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct OutputSettings {
+    // Device name as reported by the host; `None` follows the system default.
+    pub device: Option<String>,
+    // Requested buffer size in frames; `None` lets the host decide.
+    pub buffer_size: Option<u32>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct OutputDevice {
+    pub name: String,
+    pub is_default: bool,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct QueueEntry {
+    pub id: u64,
+    pub path: String,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    All,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    Tracks,
+    Albums,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct QueueSnapshot {
+    pub entries: Vec<QueueEntry>,
+    pub current: Option<u64>,
+    pub repeat: RepeatMode,
+    pub shuffle: ShuffleMode,
+    // Entry ids in the order they will play.
+    pub order: Vec<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SleepAfter {
+    Minutes { minutes: f32 },
+    EndOfTrack,
+    // The current track and then `count` more.
+    Tracks { count: u32 },
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct SleepTimerSettings {
+    pub after: SleepAfter,
+    pub fade_secs: f32,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct SleepTimerStatus {
+    pub settings: SleepTimerSettings,
+    pub remaining_secs: Option<f64>,
+    pub remaining_tracks: Option<u32>,
+}
+
+pub struct SleepTimer {
+    pub settings: SleepTimerSettings,
+    // Set for timers that run for a fixed time.
+    pub deadline: Option<Instant>,
+    // Tracks still to finish before the final one starts.
+    pub tracks_left: u32,
+    // Fader of the track currently being faded out.
+    pub fading: Option<Arc<Fader>>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AlarmTarget {
+    Folder { path: String },
+    Tracks { paths: Vec<String> },
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct AlarmSettings {
+    // Local time of day as "HH:MM".
+    pub time: String,
+    pub target: AlarmTarget,
+    pub fade_in_secs: f32,
+    pub repeat_daily: bool,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct AlarmStatus {
+    pub settings: AlarmSettings,
+    pub next: String,
+}
+
+pub struct Alarm {
+    pub settings: AlarmSettings,
+    pub next: DateTime<Local>,
+}
+
+// Everything needed to pick playback up again after a restart. The queue is
+// stored as rows of its own; the rest is kept as one JSON value.
+#[derive(Clone, PartialEq)]
+pub struct Session {
+    pub queue: Vec<String>,
+    pub state: SessionState,
+}
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SessionState {
+    // Index into the queue of the entry that was playing.
+    pub current: Option<usize>,
+    pub position: f64,
+    pub volume: f32,
+    pub repeat: RepeatMode,
+    pub shuffle: ShuffleMode,
+    // Queue indices in shuffled play order.
+    pub shuffle_order: Vec<usize>,
+    pub crossfade: CrossfadeSettings,
+    pub replay_gain: ReplayGainSettings,
+    pub equalizer: EqualizerSettings,
+    pub playback_rate: PlaybackRate,
+}
+
+// Snapshot of the engine taken by the playback supervisor on every tick.
+#[derive(Clone, PartialEq)]
+pub struct PlaybackStatus {
+    pub track: Option<String>,
+    pub queue_version: u64,
+    pub playing: bool,
+    pub position: f64,
+    pub duration: Option<f64>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct TrackChangedEvent {
+    pub path: Option<String>,
+    pub track: Option<MusicFile>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct StateChangedEvent {
+    pub playing: bool,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct PositionEvent {
+    pub position: f64,
+    pub duration: Option<f64>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct PlaybackErrorEvent {
+    pub path: Option<String>,
+    pub message: String,
+}
+
+// One frame of visualizer data: spectrum bands from low to high frequency on
+// a 0-255 scale, linear peak and RMS levels and a downsampled waveform.
+#[derive(Clone, serde::Serialize)]
+pub struct VisualizerFrame {
+    pub bins: Vec<u8>,
+    pub peak: f32,
+    pub rms: f32,
+    pub scope: Vec<i8>,
+}
+
+// Min/max overview of a whole track, one value per bucket on a -127..=127
+// scale. Buckets are spread evenly over `duration`.
+#[derive(Clone, serde::Serialize)]
+pub struct Waveform {
+    pub duration: f64,
+    pub channels: Vec<WaveformChannel>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct WaveformChannel {
+    pub min: Vec<i8>,
+    pub max: Vec<i8>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct WaveformReadyEvent {
+    pub path: String,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct SupportedFormat {
+    pub name: String,
+    pub extensions: Vec<String>,
+}
+
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Wav,
+    Flac,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct ExportRequest {
+    // Tracks to render in order; `None` renders the queue in play order.
+    pub paths: Option<Vec<String>>,
+    pub output: String,
+    pub format: ExportFormat,
+    pub bits_per_sample: u16,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct ExportStatus {
+    pub running: bool,
+    pub track_index: usize,
+    pub track_count: usize,
+    pub current: Option<String>,
+    pub rendered_secs: f64,
+    // Unknown when a track doesn't report its length up front.
+    pub total_secs: Option<f64>,
+    pub error: Option<String>,
+}
+
+// What an export renders: the tracks with the gain and transitions playback
+// would give them, and the processing settings at the time it was started.
+pub struct ExportPlan {
+    pub tracks: Vec<ExportTrack>,
+    pub crossfade: Option<Duration>,
+    pub equalizer: EqualizerSettings,
+}
+
+pub struct ExportTrack {
+    pub path: String,
+    pub range: Option<TrackRange>,
+    pub gain: f32,
+    // Whether this track crossfades out of the one before it.
+    pub crossfade_in: bool,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct Chapter {
+    pub index: usize,
+    pub title: Option<String>,
+    pub start: f64,
+    // Unknown for the last chapter when the file doesn't say where it ends.
+    pub end: Option<f64>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct Bookmark {
+    pub id: i64,
+    pub path: String,
+    pub position: f64,
+    pub note: Option<String>,
+    pub created: String,
+}
+
+// A named A–B loop saved for a track, in sample frames at the track's own
+// sample rate.
+#[derive(Clone, serde::Serialize)]
+pub struct LoopRegion {
+    pub id: i64,
+    pub path: String,
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+pub type TrackSource = Box<dyn Source<Item = f32> + Send>;
+
+// Handles into a track's source chain that stay valid while it plays.
+#[derive(Clone)]
+pub struct TrackControls {
+    pub started: Arc<OnceLock<Instant>>,
+    pub fader: Arc<Fader>,
+    // Short ramp applied around pause, resume, stop, skip and seek, kept
+    // apart from `fader` so it doesn't disturb crossfades and sleep fades.
+    pub ramp: Arc<Fader>,
+    pub gain: Arc<AtomicU32>,
+    // Position within the track in nanoseconds, counted from decoded frames.
+    pub position: Arc<AtomicU64>,
+    pub looping: Arc<LoopControl>,
+}
+
 pub struct AudioState {
     pub sink: Option<Sink>,
+    pub controls: Option<TrackControls>,
+    pub fading_sink: Option<Sink>,
+    pub fading_fader: Option<Arc<Fader>>,
     pub current_track: Option<String>,
+    // The library; the play order lives in `queue`.
     pub tracks: Vec<MusicFile>,
+    pub queue: PlayQueue,
     pub volume: f32,
-    pub playback_start: Option<Instant>,
-    pub paused_elapsed: Duration,
     pub total_duration: Option<Duration>,
+    pub queued_next: Option<QueuedTrack>,
+    pub crossfade: CrossfadeSettings,
+    pub replay_gain: ReplayGainSettings,
+    pub equalizer: Arc<EqControl>,
+    pub playback_rate: PlaybackRate,
+    pub stretch_rate: Arc<AtomicU32>,
+    pub sleep_timer: Option<SleepTimer>,
+    pub alarm: Option<Alarm>,
+    pub transition_fade: Duration,
+    pub skip_silence: bool,
+    // Output of an engine built with a backend of its own; `None` plays
+    // through the app's shared output stream.
+    pub output: Option<Dedicated>,
+}
+
+pub struct QueuedTrack {
+    pub entry: u64,
+    pub path: String,
+    pub total_duration: Option<Duration>,
+    pub controls: TrackControls,
+    pub crossfade: bool,
+    // Held back until the current track nears its end, so queue edits before
+    // then can still swap it out.
+    pub source: Option<TrackSource>,
 }
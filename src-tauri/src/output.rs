@@ -0,0 +1,267 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use crate::audio;
+use crate::backend::{self, Backend, Stream};
+use crate::models::{OutputDevice, OutputSettings};
+
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub use crate::backend::Mixer;
+
+enum Command {
+    Open(OutputSettings, Sender<Result<(), String>>),
+    // Sent from a stream's error callback; carries the generation of the
+    // stream so stale reports from a replaced stream are ignored.
+    Lost(u64),
+}
+
+// cpal streams can't be moved between threads on every platform, so the
+// stream lives on a dedicated thread and everyone else only sees the mixer
+// that feeds it.
+struct Output {
+    backend: Arc<dyn Backend>,
+    settings: OutputSettings,
+    mixer: Option<Mixer>,
+    commands: Option<Sender<Command>>,
+    // Set when the stream came back after the device was lost, until the
+    // supervisor has moved playback over to it.
+    rebuilt: bool,
+}
+
+static OUTPUT: Lazy<Mutex<Output>> = Lazy::new(|| {
+    Mutex::new(Output {
+        backend: backend::from_env(),
+        settings: OutputSettings::default(),
+        mixer: None,
+        commands: None,
+        rebuilt: false,
+    })
+});
+
+fn open_stream(
+    backend: &dyn Backend,
+    settings: &OutputSettings,
+    commands: &Sender<Command>,
+    generation: u64,
+) -> Result<(Stream, Mixer), String> {
+    let commands = commands.clone();
+    backend.open(
+        settings,
+        Box::new(move || {
+            let _ = commands.send(Command::Lost(generation));
+        }),
+    )
+}
+
+fn publish(mixer: Option<Mixer>, settings: Option<OutputSettings>) {
+    let mut output = OUTPUT.lock().unwrap();
+    output.mixer = mixer;
+    if let Some(settings) = settings {
+        output.settings = settings;
+    }
+}
+
+fn run(backend: Arc<dyn Backend>, commands: Receiver<Command>, sender: Sender<Command>) {
+    let backend = backend.as_ref();
+    let mut stream: Option<Stream> = None;
+    let mut generation = 0u64;
+    let mut lost = false;
+
+    loop {
+        // While the device is gone, keep retrying until one comes back.
+        let command = if lost {
+            match commands.recv_timeout(RECOVERY_INTERVAL) {
+                Ok(command) => Some(command),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => break,
+            }
+        };
+
+        match command {
+            Some(Command::Open(settings, reply)) => {
+                generation += 1;
+                let mut result = open_stream(backend, &settings, &sender, generation);
+                if result.is_err() && stream.is_some() {
+                    // Some hosts only allow one stream per device; retry with
+                    // the current one closed.
+                    stream = None;
+                    result = open_stream(backend, &settings, &sender, generation);
+                }
+
+                match result {
+                    Ok((new_stream, mixer)) => {
+                        stream = Some(new_stream);
+                        lost = false;
+                        publish(Some(mixer), Some(settings));
+                        let _ = reply.send(Ok(()));
+                    }
+                    Err(e) => {
+                        if stream.is_none() {
+                            // The previous stream is gone too; bring it back
+                            // with the previous settings.
+                            publish(None, None);
+                            lost = true;
+                        }
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            Some(Command::Lost(lost_generation)) if lost_generation != generation => {}
+            Some(Command::Lost(_)) | None => {
+                stream = None;
+                publish(None, None);
+
+                generation += 1;
+                let settings = OUTPUT.lock().unwrap().settings.clone();
+                match open_stream(backend, &settings, &sender, generation) {
+                    Ok((new_stream, mixer)) => {
+                        stream = Some(new_stream);
+                        lost = false;
+                        publish(Some(mixer), None);
+                        // Playback is moved over from the supervisor: this
+                        // thread never waits on the engine, which may itself
+                        // be waiting on this thread for a stream.
+                        OUTPUT.lock().unwrap().rebuilt = true;
+                    }
+                    Err(_) => lost = true,
+                }
+            }
+        }
+    }
+
+    drop(stream);
+}
+
+fn command_sender(output: &mut Output) -> Sender<Command> {
+    if let Some(commands) = &output.commands {
+        return commands.clone();
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let thread_sender = sender.clone();
+    let backend = output.backend.clone();
+    thread::spawn(move || run(backend, receiver, thread_sender));
+    output.commands = Some(sender.clone());
+    sender
+}
+
+fn request_open(commands: &Sender<Command>, settings: OutputSettings) -> Result<(), String> {
+    let (reply, response) = mpsc::channel();
+    commands
+        .send(Command::Open(settings, reply))
+        .map_err(|_| "Output thread has stopped".to_string())?;
+    response
+        .recv()
+        .map_err(|_| "Output thread has stopped".to_string())?
+}
+
+// Returns the mixer of the live output stream, opening the stream on first use.
+pub fn mixer() -> Result<Mixer, String> {
+    let (settings, commands) = {
+        let mut output = OUTPUT.lock().unwrap();
+        if let Some(mixer) = &output.mixer {
+            return Ok(mixer.clone());
+        }
+        (output.settings.clone(), command_sender(&mut output))
+    };
+
+    request_open(&commands, settings)?;
+    OUTPUT
+        .lock()
+        .unwrap()
+        .mixer
+        .clone()
+        .ok_or_else(|| "Output stream is not available".to_string())
+}
+
+// The mixer of the live output stream, without opening one.
+pub fn current() -> Option<Mixer> {
+    OUTPUT.lock().unwrap().mixer.clone()
+}
+
+pub fn is_open() -> bool {
+    OUTPUT.lock().unwrap().mixer.is_some()
+}
+
+// Whether the stream has been rebuilt since the last call, so playback needs
+// moving over to it.
+pub fn take_rebuilt() -> bool {
+    std::mem::take(&mut OUTPUT.lock().unwrap().rebuilt)
+}
+
+// Applies remembered settings at startup, before any stream is opened.
+pub fn configure(settings: OutputSettings) {
+    OUTPUT.lock().unwrap().settings = settings;
+}
+
+pub fn settings() -> OutputSettings {
+    OUTPUT.lock().unwrap().settings.clone()
+}
+
+// Switches to new settings, rebuilding the stream if one is open and moving
+// playback over to it.
+pub fn set_settings(settings: OutputSettings) -> Result<(), String> {
+    let backend = OUTPUT.lock().unwrap().backend.clone();
+    backend.check_device(settings.device.as_deref())?;
+
+    let commands = {
+        let mut output = OUTPUT.lock().unwrap();
+        if output.mixer.is_none() {
+            output.settings = settings;
+            return Ok(());
+        }
+        command_sender(&mut output)
+    };
+
+    request_open(&commands, settings)?;
+    audio::reattach_output()
+}
+
+// An output stream of its own on a given backend, apart from the app's shared
+// one; used by engines built with their own backend, e.g. in tests. Like the
+// shared stream it lives on a thread of its own, and it closes when dropped.
+pub struct Dedicated {
+    mixer: Mixer,
+    _close: Sender<()>,
+}
+
+impl Dedicated {
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn open(backend: Arc<dyn Backend>, settings: OutputSettings) -> Result<Self, String> {
+        let (reply, response) = mpsc::channel();
+        let (close, closed) = mpsc::channel::<()>();
+        thread::spawn(move || match backend.open(&settings, Box::new(|| {})) {
+            Ok((stream, mixer)) => {
+                let _ = reply.send(Ok(mixer));
+                // Returns once the sender is dropped.
+                let _ = closed.recv();
+                drop(stream);
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e));
+            }
+        });
+
+        let mixer = response
+            .recv()
+            .map_err(|_| "Output thread has stopped".to_string())??;
+        Ok(Dedicated { mixer, _close: close })
+    }
+
+    pub fn mixer(&self) -> Mixer {
+        self.mixer.clone()
+    }
+}
+
+pub fn list_devices() -> Result<Vec<OutputDevice>, String> {
+    let backend = OUTPUT.lock().unwrap().backend.clone();
+    backend.devices()
+}
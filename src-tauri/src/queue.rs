@@ -0,0 +1,766 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::models::{MusicFile, QueueEntry, QueueSnapshot, RepeatMode, ShuffleMode};
+
+const MAX_HISTORY: usize = 500;
+// Random spots tried when slotting a new entry into the shuffle order.
+const INSERT_ATTEMPTS: usize = 8;
+
+// Small xorshift generator; shuffling doesn't need anything stronger.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+fn same_artist(a: Option<&str>, b: Option<&str>) -> bool {
+    matches!((a, b), (Some(a), Some(b)) if a == b)
+}
+
+// Where the mix allows it, keeps neighbours from sharing an artist: each clash
+// is swapped with the first later item that fits. The first item stays put.
+fn spread_artists<'a, T>(items: &mut [T], artist: impl Fn(&T) -> Option<&'a str>) {
+    for i in 1..items.len() {
+        let previous = artist(&items[i - 1]);
+        if !same_artist(previous, artist(&items[i])) {
+            continue;
+        }
+        if let Some(j) = (i + 1..items.len()).find(|&j| !same_artist(previous, artist(&items[j]))) {
+            items.swap(i, j);
+        }
+    }
+}
+
+// The play order, kept apart from the library so rescans and folder removals
+// leave it alone. Entries carry an id so the same file can be queued twice and
+// the current entry survives edits around it.
+#[derive(Default)]
+pub struct PlayQueue {
+    entries: Vec<QueueEntry>,
+    current: Option<u64>,
+    next_id: u64,
+    version: u64,
+    repeat: RepeatMode,
+    shuffle: ShuffleMode,
+    // Entry ids in shuffled play order. Independent of `entries`, so edits to
+    // the queue don't reshuffle what is coming up.
+    order: Vec<u64>,
+    // Entries that played before the current one, most recent last.
+    history: Vec<u64>,
+}
+
+impl PlayQueue {
+    fn changed(&mut self) {
+        self.version += 1;
+    }
+
+    fn make_entries(&mut self, paths: Vec<String>) -> Vec<QueueEntry> {
+        paths
+            .into_iter()
+            .map(|path| {
+                let id = self.next_id;
+                self.next_id += 1;
+                QueueEntry { id, path }
+            })
+            .collect()
+    }
+
+    fn shuffling(&self) -> bool {
+        self.shuffle != ShuffleMode::Off
+    }
+
+    // Artist of every queued entry, looked up in the library.
+    fn artists<'a>(&self, library: &'a [MusicFile]) -> HashMap<u64, Option<&'a str>> {
+        let by_path: HashMap<&str, &'a MusicFile> = library.iter().map(|t| (t.path.as_str(), t)).collect();
+        self.entries
+            .iter()
+            .map(|e| (e.id, by_path.get(e.path.as_str()).and_then(|t| t.artist.as_deref())))
+            .collect()
+    }
+
+    // Queued entries whose track is set to be left out of shuffle.
+    fn excluded(&self, library: &[MusicFile]) -> HashSet<u64> {
+        let paths: HashSet<&str> = library
+            .iter()
+            .filter(|t| t.overrides.exclude_from_shuffle)
+            .map(|t| t.path.as_str())
+            .collect();
+        self.entries
+            .iter()
+            .filter(|e| paths.contains(e.path.as_str()))
+            .map(|e| e.id)
+            .collect()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            entries: self.entries.clone(),
+            current: self.current,
+            repeat: self.repeat,
+            shuffle: self.shuffle,
+            order: self.sequence(),
+        }
+    }
+
+    // Every entry in the order it will play.
+    pub fn play_order(&self) -> Vec<QueueEntry> {
+        self.sequence().into_iter().filter_map(|id| self.entry(id)).collect()
+    }
+
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.entries.iter().position(|e| e.id == id)
+    }
+
+    pub fn entry(&self, id: u64) -> Option<QueueEntry> {
+        self.entries.iter().find(|e| e.id == id).cloned()
+    }
+
+    pub fn current(&self) -> Option<QueueEntry> {
+        self.current.and_then(|id| self.entry(id))
+    }
+
+    // Moving from the end of the shuffle order back to its start under repeat
+    // deals a fresh order for the next time round, starting from `id`.
+    pub fn set_current(&mut self, id: Option<u64>, library: &[MusicFile]) {
+        if self.current != id {
+            let wrapped = self.shuffling()
+                && self.repeat != RepeatMode::Off
+                && self.current.is_some()
+                && self.current == self.order.last().copied()
+                && id == self.order.first().copied();
+            if let Some(previous) = self.current {
+                self.history.push(previous);
+                if self.history.len() > MAX_HISTORY {
+                    self.history.remove(0);
+                }
+            }
+            self.current = id;
+            if wrapped && self.order.len() > 1 {
+                self.reshuffle(id, library);
+            }
+            self.changed();
+        }
+    }
+
+    // Entry ids in the order they will play.
+    fn sequence(&self) -> Vec<u64> {
+        if self.shuffling() {
+            self.order.clone()
+        } else {
+            self.entries.iter().map(|e| e.id).collect()
+        }
+    }
+
+    fn following(&self, manual: bool) -> Option<QueueEntry> {
+        let current = self.current?;
+        if self.repeat == RepeatMode::One && !manual {
+            return self.entry(current);
+        }
+
+        let sequence = self.sequence();
+        // An entry left out of the shuffle order that was picked by hand; the
+        // shuffle carries on after the last entry that played from it.
+        let index = sequence.iter().position(|&id| id == current).or_else(|| {
+            self.history
+                .iter()
+                .rev()
+                .find_map(|&played| sequence.iter().position(|&id| id == played))
+        });
+        let next = match index {
+            Some(index) => match sequence.get(index + 1) {
+                Some(id) => *id,
+                None if self.repeat != RepeatMode::Off => *sequence.first()?,
+                None => return None,
+            },
+            None => *sequence.first()?,
+        };
+        self.entry(next)
+    }
+
+    // The entry to play when the current one finishes on its own. Repeat-one
+    // plays the same entry again.
+    pub fn upcoming(&self) -> Option<QueueEntry> {
+        self.following(false)
+    }
+
+    // The entry a manual skip moves to; repeat-one doesn't hold it back.
+    pub fn next_entry(&self) -> Option<QueueEntry> {
+        self.following(true)
+    }
+
+    // While shuffling, "previous" is whatever actually played last.
+    pub fn previous_entry(&self) -> Option<QueueEntry> {
+        if self.shuffling() {
+            return self.history.iter().rev().find_map(|&id| self.entry(id));
+        }
+
+        let index = self.current.and_then(|id| self.position(id))?;
+        match index.checked_sub(1) {
+            Some(i) => self.entries.get(i).cloned(),
+            None if self.repeat != RepeatMode::Off => self.entries.last().cloned(),
+            None => None,
+        }
+    }
+
+    // Makes the previous entry current without recording the one being left in
+    // the history, so stepping back repeatedly walks further into the past.
+    pub fn step_back(&mut self) -> Option<QueueEntry> {
+        let entry = self.previous_entry()?;
+        if let Some(index) = self.history.iter().rposition(|&id| id == entry.id) {
+            self.history.truncate(index);
+        }
+        self.current = Some(entry.id);
+        self.changed();
+        Some(entry)
+    }
+
+    // Tracks excluded from shuffle are left out of the order, unless one is
+    // the entry to start with.
+    fn reshuffle(&mut self, first: Option<u64>, library: &[MusicFile]) {
+        let mut rng = Rng::new();
+        let artists = self.artists(library);
+        let excluded = self.excluded(library);
+        let included = |id: &u64| Some(*id) == first || !excluded.contains(id);
+        let artist = |id: &u64| artists.get(id).copied().flatten();
+
+        let mut order: Vec<u64> = match self.shuffle {
+            ShuffleMode::Off => {
+                self.order.clear();
+                return;
+            }
+            ShuffleMode::Tracks => {
+                let mut ids: Vec<u64> = self
+                    .entries
+                    .iter()
+                    .map(|e| e.id)
+                    .filter(|id| Some(*id) != first && !excluded.contains(id))
+                    .collect();
+                rng.shuffle(&mut ids);
+                ids
+            }
+            ShuffleMode::Albums => {
+                // Albums keep their track order; only the albums are shuffled.
+                let by_path: HashMap<&str, &MusicFile> = library.iter().map(|t| (t.path.as_str(), t)).collect();
+                let mut groups: Vec<Vec<u64>> = Vec::new();
+                let mut album_groups: HashMap<(Option<&str>, &str), usize> = HashMap::new();
+                for entry in self.entries.iter().filter(|e| included(&e.id)) {
+                    let track = by_path.get(entry.path.as_str());
+                    match track.and_then(|t| t.album.as_deref()) {
+                        Some(album) => {
+                            let key = (track.and_then(|t| t.artist.as_deref()), album);
+                            let index = *album_groups.entry(key).or_insert_with(|| {
+                                groups.push(Vec::new());
+                                groups.len() - 1
+                            });
+                            groups[index].push(entry.id);
+                        }
+                        None => groups.push(vec![entry.id]),
+                    }
+                }
+
+                // The album that is playing goes first.
+                let current_group = first.and_then(|id| groups.iter().position(|g| g.contains(&id)));
+                let leading = current_group.map(|index| groups.remove(index));
+                rng.shuffle(&mut groups);
+                if let Some(group) = leading {
+                    groups.insert(0, group);
+                }
+                spread_artists(&mut groups, |group| group.first().and_then(&artist));
+                groups.into_iter().flatten().collect()
+            }
+        };
+
+        if self.shuffle == ShuffleMode::Tracks {
+            if let Some(first) = first {
+                order.insert(0, first);
+            }
+            spread_artists(&mut order, artist);
+        }
+        self.order = order;
+    }
+
+    // Puts a new entry somewhere among the entries still to come, preferring a
+    // spot where it doesn't land next to the same artist.
+    fn insert_shuffled(&mut self, id: u64, library: &[MusicFile]) {
+        let mut rng = Rng::new();
+        let artists = self.artists(library);
+        let artist = |id: Option<&u64>| id.and_then(|id| artists.get(id).copied().flatten());
+
+        let from = self
+            .current
+            .and_then(|current| self.order.iter().position(|&o| o == current))
+            .map_or(0, |i| i + 1);
+        let own = artist(Some(&id));
+
+        let mut index = self.order.len();
+        for _ in 0..INSERT_ATTEMPTS {
+            index = from + rng.below(self.order.len() - from + 1);
+            let before = index.checked_sub(1).and_then(|i| self.order.get(i));
+            let after = self.order.get(index);
+            if !same_artist(own, artist(before)) && !same_artist(own, artist(after)) {
+                break;
+            }
+        }
+        self.order.insert(index, id);
+    }
+
+    // Replaces the whole queue and returns the entry to start with: the one at
+    // `start`, or when none is given the first in play order.
+    pub fn replace(&mut self, paths: Vec<String>, start: Option<usize>, library: &[MusicFile]) -> Option<QueueEntry> {
+        self.entries = self.make_entries(paths);
+        self.current = None;
+        self.history.clear();
+
+        let first = start.and_then(|i| self.entries.get(i)).map(|e| e.id);
+        self.reshuffle(first, library);
+        self.changed();
+
+        let first = first.or_else(|| self.sequence().first().copied())?;
+        self.entry(first)
+    }
+
+    pub fn append(&mut self, paths: Vec<String>, library: &[MusicFile]) {
+        let entries = self.make_entries(paths);
+        let ids: Vec<u64> = entries.iter().map(|e| e.id).collect();
+        self.entries.extend(entries);
+        if self.shuffling() {
+            let excluded = self.excluded(library);
+            for id in ids.into_iter().filter(|id| !excluded.contains(id)) {
+                self.insert_shuffled(id, library);
+            }
+        }
+        self.changed();
+    }
+
+    // Inserts right after the current entry, or at the end when nothing is
+    // playing. While shuffling the entries also play next, in the given order.
+    pub fn insert_next(&mut self, paths: Vec<String>) {
+        let entries = self.make_entries(paths);
+        let ids: Vec<u64> = entries.iter().map(|e| e.id).collect();
+
+        let index = self
+            .current
+            .and_then(|id| self.position(id))
+            .map_or(self.entries.len(), |i| i + 1);
+        self.entries.splice(index..index, entries);
+
+        if self.shuffling() {
+            let index = self
+                .current
+                .and_then(|id| self.order.iter().position(|&o| o == id))
+                .map_or(self.order.len(), |i| i + 1);
+            self.order.splice(index..index, ids);
+        }
+        self.changed();
+    }
+
+    pub fn remove(&mut self, id: u64) -> Result<(), String> {
+        let index = self.position(id).ok_or_else(|| format!("Queue entry not found: {}", id))?;
+        self.entries.remove(index);
+        self.order.retain(|&o| o != id);
+        self.history.retain(|&h| h != id);
+        if self.current == Some(id) {
+            self.current = None;
+        }
+        self.changed();
+        Ok(())
+    }
+
+    pub fn move_to(&mut self, id: u64, to: usize) -> Result<(), String> {
+        let index = self.position(id).ok_or_else(|| format!("Queue entry not found: {}", id))?;
+        let entry = self.entries.remove(index);
+        let to = to.min(self.entries.len());
+        self.entries.insert(to, entry);
+        self.changed();
+        Ok(())
+    }
+
+    // Drops everything except the entry that is playing.
+    pub fn clear(&mut self) {
+        let current = self.current();
+        self.entries = current.into_iter().collect();
+        self.order.retain(|&o| Some(o) == self.current);
+        self.history.clear();
+        self.changed();
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn shuffle(&self) -> ShuffleMode {
+        self.shuffle
+    }
+
+    // Paths in queue order, the index of the current entry and the shuffle
+    // order as queue indices; the form a session is saved in.
+    pub fn export(&self) -> (Vec<String>, Option<usize>, Vec<usize>) {
+        let paths = self.entries.iter().map(|e| e.path.clone()).collect();
+        let current = self.current.and_then(|id| self.position(id));
+        let order = self.order.iter().filter_map(|&id| self.position(id)).collect();
+        (paths, current, order)
+    }
+
+    pub fn restore(
+        &mut self,
+        paths: Vec<String>,
+        current: Option<usize>,
+        repeat: RepeatMode,
+        shuffle: ShuffleMode,
+        order: Vec<usize>,
+    ) -> Option<QueueEntry> {
+        self.entries = self.make_entries(paths);
+        self.current = current.and_then(|i| self.entries.get(i)).map(|e| e.id);
+        self.history.clear();
+        self.repeat = repeat;
+        self.shuffle = shuffle;
+        self.order = order
+            .into_iter()
+            .filter_map(|i| self.entries.get(i))
+            .map(|e| e.id)
+            .collect();
+        if self.shuffling() && self.order.is_empty() {
+            // No saved order, e.g. from before shuffling; fall back to queue
+            // order. It can be shorter than the queue when tracks are
+            // excluded from shuffle.
+            self.order = self.entries.iter().map(|e| e.id).collect();
+        }
+        self.changed();
+        self.current()
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+        self.changed();
+    }
+
+    // Brings the shuffle order in line with tracks that were excluded from
+    // shuffle or let back in since it was dealt. The current entry stays where
+    // it is.
+    pub fn update_exclusions(&mut self, library: &[MusicFile]) {
+        if !self.shuffling() {
+            return;
+        }
+        let excluded = self.excluded(library);
+        let before = self.order.len();
+        self.order.retain(|id| Some(*id) == self.current || !excluded.contains(id));
+
+        let ordered: HashSet<u64> = self.order.iter().copied().collect();
+        let missing: Vec<u64> = self
+            .entries
+            .iter()
+            .map(|e| e.id)
+            .filter(|id| !excluded.contains(id) && !ordered.contains(id))
+            .collect();
+        let changed = self.order.len() != before || !missing.is_empty();
+        for id in missing {
+            self.insert_shuffled(id, library);
+        }
+        if changed {
+            self.changed();
+        }
+    }
+
+    // Turning shuffle on (or switching its kind) deals a fresh order that
+    // starts from the current entry.
+    pub fn set_shuffle(&mut self, shuffle: ShuffleMode, library: &[MusicFile]) {
+        if self.shuffle == shuffle {
+            return;
+        }
+        self.shuffle = shuffle;
+        self.reshuffle(self.current, library);
+        self.changed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Loudness, ReplayGain, Silence, TrackOverrides};
+
+    fn track(path: &str, artist: Option<&str>, album: Option<&str>) -> MusicFile {
+        MusicFile {
+            path: path.to_string(),
+            name: path.to_string(),
+            artist: artist.map(str::to_string),
+            album: album.map(str::to_string),
+            title: None,
+            thumbnail: None,
+            replay_gain: ReplayGain::default(),
+            loudness: Loudness::default(),
+            overrides: TrackOverrides::default(),
+            silence: Silence::default(),
+            range: None,
+        }
+    }
+
+    fn library(count: usize) -> Vec<MusicFile> {
+        (0..count).map(|i| track(&format!("{:02}.mp3", i), None, None)).collect()
+    }
+
+    fn paths(library: &[MusicFile]) -> Vec<String> {
+        library.iter().map(|t| t.path.clone()).collect()
+    }
+
+    fn play_order(queue: &PlayQueue) -> Vec<String> {
+        queue.play_order().into_iter().map(|e| e.path).collect()
+    }
+
+    fn sorted(mut paths: Vec<String>) -> Vec<String> {
+        paths.sort();
+        paths
+    }
+
+    // Moves on the way a track finishing on its own does.
+    fn advance(queue: &mut PlayQueue, library: &[MusicFile]) -> Option<QueueEntry> {
+        let next = queue.upcoming()?;
+        queue.set_current(Some(next.id), library);
+        Some(next)
+    }
+
+    #[test]
+    fn shuffle_starts_from_the_chosen_entry_and_keeps_every_entry() {
+        let library = library(12);
+        let mut queue = PlayQueue::default();
+        queue.set_shuffle(ShuffleMode::Tracks, &library);
+
+        let first = queue.replace(paths(&library), Some(5), &library).unwrap();
+        assert_eq!(first.path, "05.mp3");
+        let order = play_order(&queue);
+        assert_eq!(order[0], "05.mp3");
+        assert_eq!(sorted(order), paths(&library));
+    }
+
+    #[test]
+    fn turning_shuffle_off_goes_back_to_queue_order() {
+        let library = library(6);
+        let mut queue = PlayQueue::default();
+        let first = queue.replace(paths(&library), Some(2), &library).unwrap();
+        queue.set_current(Some(first.id), &library);
+
+        queue.set_shuffle(ShuffleMode::Tracks, &library);
+        assert_eq!(play_order(&queue)[0], "02.mp3");
+        queue.set_shuffle(ShuffleMode::Off, &library);
+        assert_eq!(play_order(&queue), paths(&library));
+        assert_eq!(queue.upcoming().unwrap().path, "03.mp3");
+    }
+
+    #[test]
+    fn excluded_tracks_stay_out_of_the_shuffle_unless_started_from() {
+        let mut library = library(8);
+        library[3].overrides.exclude_from_shuffle = true;
+        let mut queue = PlayQueue::default();
+        queue.set_shuffle(ShuffleMode::Tracks, &library);
+
+        queue.replace(paths(&library), None, &library);
+        let order = play_order(&queue);
+        assert_eq!(order.len(), 7);
+        assert!(!order.contains(&"03.mp3".to_string()));
+
+        queue.replace(paths(&library), Some(3), &library);
+        assert_eq!(play_order(&queue)[0], "03.mp3");
+        assert_eq!(play_order(&queue).len(), 8);
+    }
+
+    #[test]
+    fn exclusions_changed_while_shuffling_update_the_order() {
+        let mut library = library(6);
+        let mut queue = PlayQueue::default();
+        queue.set_shuffle(ShuffleMode::Tracks, &library);
+        let first = queue.replace(paths(&library), Some(0), &library).unwrap();
+        queue.set_current(Some(first.id), &library);
+
+        library[4].overrides.exclude_from_shuffle = true;
+        queue.update_exclusions(&library);
+        assert!(!play_order(&queue).contains(&"04.mp3".to_string()));
+
+        library[4].overrides.exclude_from_shuffle = false;
+        queue.update_exclusions(&library);
+        assert_eq!(sorted(play_order(&queue)), paths(&library));
+        assert_eq!(play_order(&queue)[0], "00.mp3");
+    }
+
+    #[test]
+    fn neighbours_are_kept_apart_by_artist() {
+        let mut artists = vec![Some("A"), Some("A"), Some("B"), Some("B")];
+        spread_artists(&mut artists, |a| *a);
+        assert_eq!(artists, vec![Some("A"), Some("B"), Some("A"), Some("B")]);
+
+        // Where there aren't enough others to go round, the clashes are left
+        // at the end and the first item stays put.
+        let mut artists = vec![Some("A"), Some("A"), Some("A"), Some("B")];
+        spread_artists(&mut artists, |a| *a);
+        assert_eq!(artists, vec![Some("A"), Some("B"), Some("A"), Some("A")]);
+
+        // Unknown artists never clash.
+        let mut artists = vec![None, None, Some("A")];
+        spread_artists(&mut artists, |a| *a);
+        assert_eq!(artists, vec![None, None, Some("A")]);
+    }
+
+    #[test]
+    fn album_shuffle_keeps_albums_together() {
+        let library: Vec<MusicFile> = (0..9)
+            .map(|i| {
+                let album = format!("Album {}", i / 3);
+                track(&format!("{:02}.mp3", i), Some("Artist"), Some(album.as_str()))
+            })
+            .collect();
+        let mut queue = PlayQueue::default();
+        queue.set_shuffle(ShuffleMode::Albums, &library);
+        queue.replace(paths(&library), Some(4), &library);
+
+        let order = play_order(&queue);
+        assert_eq!(&order[..3], ["03.mp3", "04.mp3", "05.mp3"]);
+        for album in order.chunks(3) {
+            let first: usize = album[0][..2].parse().unwrap();
+            assert_eq!(first % 3, 0);
+            let expected: Vec<String> = (first..first + 3).map(|i| format!("{:02}.mp3", i)).collect();
+            assert_eq!(album, expected.as_slice());
+        }
+    }
+
+    #[test]
+    fn same_named_albums_by_different_artists_are_separate() {
+        let library = vec![
+            track("a1.mp3", Some("A"), Some("Greatest Hits")),
+            track("b1.mp3", Some("B"), Some("Greatest Hits")),
+            track("a2.mp3", Some("A"), Some("Greatest Hits")),
+        ];
+        let mut queue = PlayQueue::default();
+        queue.set_shuffle(ShuffleMode::Albums, &library);
+        queue.replace(paths(&library), Some(0), &library);
+
+        assert_eq!(&play_order(&queue)[..2], ["a1.mp3", "a2.mp3"]);
+    }
+
+    #[test]
+    fn stepping_back_walks_the_history_while_shuffling() {
+        let library = library(10);
+        let mut queue = PlayQueue::default();
+        queue.set_shuffle(ShuffleMode::Tracks, &library);
+        let first = queue.replace(paths(&library), None, &library).unwrap();
+        queue.set_current(Some(first.id), &library);
+
+        let mut played = vec![first.path];
+        for _ in 0..3 {
+            played.push(advance(&mut queue, &library).unwrap().path);
+        }
+
+        for expected in played.iter().rev().skip(1) {
+            assert_eq!(&queue.step_back().unwrap().path, expected);
+        }
+        assert!(queue.step_back().is_none());
+        assert_eq!(queue.current().unwrap().path, played[0]);
+    }
+
+    #[test]
+    fn previous_follows_queue_order_without_shuffle() {
+        let library = library(3);
+        let mut queue = PlayQueue::default();
+        let first = queue.replace(paths(&library), Some(0), &library).unwrap();
+        queue.set_current(Some(first.id), &library);
+        assert!(queue.previous_entry().is_none());
+
+        queue.set_repeat(RepeatMode::All);
+        assert_eq!(queue.previous_entry().unwrap().path, "02.mp3");
+
+        advance(&mut queue, &library);
+        assert_eq!(queue.step_back().unwrap().path, "00.mp3");
+    }
+
+    #[test]
+    fn repeat_one_replays_until_skipped() {
+        let library = library(3);
+        let mut queue = PlayQueue::default();
+        let first = queue.replace(paths(&library), Some(1), &library).unwrap();
+        queue.set_current(Some(first.id), &library);
+        queue.set_repeat(RepeatMode::One);
+
+        assert_eq!(queue.upcoming().unwrap().path, "01.mp3");
+        assert_eq!(queue.next_entry().unwrap().path, "02.mp3");
+    }
+
+    #[test]
+    fn the_queue_ends_without_repeat() {
+        let library = library(2);
+        let mut queue = PlayQueue::default();
+        let first = queue.replace(paths(&library), Some(0), &library).unwrap();
+        queue.set_current(Some(first.id), &library);
+
+        assert_eq!(advance(&mut queue, &library).unwrap().path, "01.mp3");
+        assert!(queue.upcoming().is_none());
+    }
+
+    #[test]
+    fn repeat_all_deals_a_new_shuffle_on_each_pass() {
+        let library = library(20);
+        let mut queue = PlayQueue::default();
+        queue.set_shuffle(ShuffleMode::Tracks, &library);
+        queue.set_repeat(RepeatMode::All);
+        let first = queue.replace(paths(&library), None, &library).unwrap();
+        queue.set_current(Some(first.id), &library);
+
+        let first_pass = play_order(&queue);
+        for _ in 1..first_pass.len() {
+            advance(&mut queue, &library);
+        }
+        assert_eq!(queue.current().unwrap().path, first_pass[19]);
+
+        // Round again: the first entry of the last pass plays, and what
+        // follows it is dealt afresh.
+        let wrapped = advance(&mut queue, &library).unwrap();
+        assert_eq!(wrapped.path, first_pass[0]);
+        let second_pass = play_order(&queue);
+        assert_eq!(second_pass[0], first_pass[0]);
+        assert_eq!(sorted(second_pass.clone()), paths(&library));
+        assert_ne!(second_pass, first_pass);
+    }
+
+    #[test]
+    fn the_shuffle_carries_on_after_an_excluded_entry_picked_by_hand() {
+        let mut library = library(6);
+        library[3].overrides.exclude_from_shuffle = true;
+        let mut queue = PlayQueue::default();
+        queue.set_shuffle(ShuffleMode::Tracks, &library);
+        let first = queue.replace(paths(&library), None, &library).unwrap();
+        queue.set_current(Some(first.id), &library);
+        advance(&mut queue, &library);
+        let order = play_order(&queue);
+
+        let excluded = queue.entries[3].clone();
+        queue.set_current(Some(excluded.id), &library);
+        assert_eq!(queue.upcoming().unwrap().path, order[2]);
+
+        // Still in place once the entry played before it is removed.
+        let played = queue.history.last().copied().unwrap();
+        queue.remove(played).unwrap();
+        assert_eq!(queue.upcoming().unwrap().path, order[2]);
+    }
+}
@@ -0,0 +1,137 @@
+use lofty::tag::{ItemKey, Tag};
+use crate::loudness::REFERENCE_LUFS;
+use crate::models::{Loudness, ReplayGain, ReplayGainMode, ReplayGainSettings};
+
+// Tag values look like "-6.54 dB" for gains and "0.988547" for peaks.
+fn parse_value(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .or_else(|| value.strip_suffix("DB"))
+        .unwrap_or(value);
+    number.trim().parse::<f32>().ok().filter(|v| v.is_finite())
+}
+
+pub fn read_tags(tag: &Tag) -> ReplayGain {
+    let read = |key: ItemKey| tag.get_string(&key).and_then(parse_value);
+
+    ReplayGain {
+        track_gain: read(ItemKey::ReplayGainTrackGain),
+        track_peak: read(ItemKey::ReplayGainTrackPeak),
+        album_gain: read(ItemKey::ReplayGainAlbumGain),
+        album_peak: read(ItemKey::ReplayGainAlbumPeak),
+    }
+}
+
+// Values measured by the loudness scanner stand in for missing tags.
+fn analysed(loudness: &Loudness) -> ReplayGain {
+    let gain = |lufs: Option<f32>| lufs.map(|l| REFERENCE_LUFS - l);
+    let peak = |dbtp: Option<f32>| dbtp.map(|p| 10f32.powf(p / 20.0));
+
+    ReplayGain {
+        track_gain: gain(loudness.integrated),
+        track_peak: peak(loudness.true_peak),
+        album_gain: gain(loudness.album_integrated),
+        album_peak: peak(loudness.album_true_peak),
+    }
+}
+
+pub fn gain_factor(replay_gain: &ReplayGain, loudness: &Loudness, settings: &ReplayGainSettings) -> f32 {
+    let measured = analysed(loudness);
+    let track_gain = replay_gain.track_gain.or(measured.track_gain);
+    let track_peak = replay_gain.track_peak.or(measured.track_peak);
+
+    let (gain, peak) = match settings.mode {
+        ReplayGainMode::Off => return 1.0,
+        ReplayGainMode::Track => (track_gain, track_peak),
+        ReplayGainMode::Album => (
+            replay_gain.album_gain.or(measured.album_gain).or(track_gain),
+            replay_gain.album_peak.or(measured.album_peak).or(track_peak),
+        ),
+    };
+
+    let gain = match gain {
+        Some(gain) => gain,
+        None => return 1.0,
+    };
+
+    let mut factor = 10f32.powf((gain + settings.preamp_db) / 20.0);
+    if settings.prevent_clipping {
+        if let Some(peak) = peak.filter(|p| *p > 0.0) {
+            factor = factor.min(1.0 / peak);
+        }
+    }
+    factor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: ReplayGainMode, prevent_clipping: bool) -> ReplayGainSettings {
+        ReplayGainSettings {
+            mode,
+            preamp_db: 0.0,
+            prevent_clipping,
+        }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn parses_tag_values() {
+        assert_eq!(parse_value("-6.5 dB"), Some(-6.5));
+        assert_eq!(parse_value(" -6.54db "), Some(-6.54));
+        assert_eq!(parse_value("+1.2"), Some(1.2));
+        assert_eq!(parse_value("0.988547"), Some(0.988547));
+        assert_eq!(parse_value("loud"), None);
+        assert_eq!(parse_value("dB"), None);
+        assert_eq!(parse_value("inf"), None);
+        assert_eq!(parse_value(""), None);
+    }
+
+    #[test]
+    fn gain_is_held_back_by_the_peak() {
+        let tags = ReplayGain {
+            track_gain: Some(6.0),
+            track_peak: Some(0.8),
+            album_gain: Some(-6.0),
+            album_peak: Some(0.9),
+        };
+        let loudness = Loudness::default();
+
+        // +6 dB would take a 0.8 peak over full scale.
+        assert!(close(gain_factor(&tags, &loudness, &settings(ReplayGainMode::Track, true)), 1.25));
+        assert!(close(gain_factor(&tags, &loudness, &settings(ReplayGainMode::Track, false)), 1.99526));
+        // Cutting is never held back.
+        assert!(close(gain_factor(&tags, &loudness, &settings(ReplayGainMode::Album, true)), 0.50119));
+        assert_eq!(gain_factor(&tags, &loudness, &settings(ReplayGainMode::Off, true)), 1.0);
+
+        let mut boosted = settings(ReplayGainMode::Album, true);
+        boosted.preamp_db = 12.0;
+        assert!(close(gain_factor(&tags, &loudness, &boosted), 1.0 / 0.9));
+    }
+
+    #[test]
+    fn measured_loudness_stands_in_for_missing_tags() {
+        let loudness = Loudness {
+            integrated: Some(-12.0),
+            true_peak: Some(-1.0),
+            ..Loudness::default()
+        };
+        let factor = gain_factor(&ReplayGain::default(), &loudness, &settings(ReplayGainMode::Album, true));
+        assert!(close(factor, 0.50119), "{}", factor);
+
+        let quiet = Loudness {
+            integrated: Some(-30.0),
+            true_peak: Some(-6.0),
+            ..Loudness::default()
+        };
+        let factor = gain_factor(&ReplayGain::default(), &quiet, &settings(ReplayGainMode::Track, true));
+        assert!(close(factor, 10f32.powf(6.0 / 20.0)), "{}", factor);
+        assert_eq!(gain_factor(&ReplayGain::default(), &Loudness::default(), &settings(ReplayGainMode::Track, true)), 1.0);
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tauri::AppHandle;
+use crate::audio;
+use crate::db;
+use crate::models::Session;
+
+// Nothing is saved until the stored session has been restored, so a fresh
+// launch can't overwrite it with an empty one.
+static READY: AtomicBool = AtomicBool::new(false);
+
+pub fn restore(app: &AppHandle) -> Result<(), String> {
+    let result = db::get_db_connection(app)
+        .and_then(|conn| db::load_session(&conn))
+        .and_then(|session| match session {
+            Some(session) => audio::restore_session(session),
+            None => Ok(()),
+        });
+    READY.store(true, Ordering::SeqCst);
+    result
+}
+
+// Saves the session if it differs from the last one saved. Called
+// periodically by the playback supervisor.
+pub fn persist(app: &AppHandle, last: &mut Option<Session>) -> Result<(), String> {
+    if !READY.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let session = audio::session();
+    if last.as_ref() == Some(&session) {
+        return Ok(());
+    }
+
+    let queue_changed = last.as_ref().is_none_or(|l| l.queue != session.queue);
+    let mut conn = db::get_db_connection(app)?;
+    db::save_session(&mut conn, &session, queue_changed)?;
+    *last = Some(session);
+    Ok(())
+}
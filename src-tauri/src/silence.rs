@@ -0,0 +1,232 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use rodio::Source;
+use tauri::AppHandle;
+use crate::{audio, db, decoder};
+use crate::models::{Silence, TrackRange};
+
+// How far into each end of a track silence is looked for. Padding beyond this
+// is taken to be part of the track, e.g. a hidden track after a long gap.
+const MAX_SILENCE: Duration = Duration::from_secs(60);
+// Trailing silence shorter than this isn't worth cutting.
+const MIN_TRAILING: f64 = 0.05;
+
+struct Worker {
+    pending: VecDeque<String>,
+    // The same paths as `pending`, for checking what is already waiting.
+    queued: HashSet<String>,
+    // The track being checked right now.
+    active: Option<String>,
+    running: bool,
+    // Set by the first refresh, before anything is queued.
+    threshold_db: Option<f32>,
+}
+
+static WORKER: Lazy<Mutex<Worker>> = Lazy::new(|| {
+    Mutex::new(Worker {
+        pending: VecDeque::new(),
+        queued: HashSet::new(),
+        active: None,
+        running: false,
+        threshold_db: None,
+    })
+});
+
+// Finds the silence at the start and end of a track: the samples quieter than
+// `threshold_db` (dBFS). Only the first and last minute are decoded. A track
+// that is silent throughout, or can't be decoded, has no silence marked.
+fn detect(path: &str, range: Option<&TrackRange>, threshold_db: f32) -> Silence {
+    let Ok(mut source) = decoder::open_track(path, range) else {
+        return Silence::default();
+    };
+    let channels = source.channels().max(1) as u64;
+    let sample_rate = source.sample_rate().max(1) as u64;
+    let threshold = (10f32.powf(threshold_db / 20.0) * i16::MAX as f32) as i32;
+    let loud = |sample: i16| (sample as i32).abs() > threshold;
+    let max_samples = MAX_SILENCE.as_secs() * sample_rate * channels;
+
+    let secs = |sample: u64| (sample / channels) as f64 / sample_rate as f64;
+
+    let mut position = 0u64;
+    let mut first_loud = None;
+    while first_loud.is_none() && position < max_samples {
+        match source.next() {
+            Some(sample) => {
+                if loud(sample) {
+                    first_loud = Some(position);
+                }
+                position += 1;
+            }
+            None => return Silence::default(),
+        }
+    }
+
+    // Jump to the last minute when the length is known; otherwise read on.
+    // After a jump, a last minute without sound is all cut, but no more.
+    let mut last_loud = first_loud;
+    if let Some(total) = source.total_duration() {
+        let tail = total.saturating_sub(MAX_SILENCE);
+        let tail_samples = (tail.as_secs_f64() * sample_rate as f64) as u64 * channels;
+        if tail_samples > position && source.try_seek(tail).is_ok() {
+            position = tail_samples;
+            last_loud = tail_samples.checked_sub(channels);
+        }
+    }
+
+    for sample in source.by_ref() {
+        if loud(sample) {
+            last_loud = Some(position);
+        }
+        position += 1;
+    }
+
+    let length = secs(position);
+    Silence {
+        start: first_loud.map_or(0.0, secs),
+        end: last_loud
+            .map(|last| secs(last) + 1.0 / sample_rate as f64)
+            .filter(|&end| length - end >= MIN_TRAILING),
+    }
+}
+
+// Silence for `path` from the cache, or detected and stored when the file or
+// threshold has changed since it was last looked at.
+fn lookup(conn: &rusqlite::Connection, path: &str, threshold_db: f32) -> Result<Silence, String> {
+    let range = db::load_track_range(conn, path)?;
+    let modified = db::modified(range.as_ref().map_or(path, |r| &r.file))?;
+    if let Some(silence) = db::load_silence(conn, path, modified, threshold_db)? {
+        return Ok(silence);
+    }
+    let silence = detect(path, range.as_ref(), threshold_db);
+    db::save_silence(conn, path, modified, threshold_db, &silence)?;
+    Ok(silence)
+}
+
+fn run(app: AppHandle) {
+    let conn = db::get_db_connection(&app);
+    loop {
+        let (path, threshold_db) = {
+            let mut worker = WORKER.lock().unwrap();
+            let next = worker.pending.pop_front().zip(worker.threshold_db);
+            worker.active = next.as_ref().map(|(path, _)| path.clone());
+            match next {
+                Some((path, threshold_db)) => {
+                    worker.queued.remove(&path);
+                    (path, threshold_db)
+                }
+                None => {
+                    worker.running = false;
+                    return;
+                }
+            }
+        };
+
+        // Files that have gone or can't be read keep whatever they had.
+        let Ok(conn) = &conn else {
+            continue;
+        };
+        if let Ok(silence) = lookup(conn, &path, threshold_db) {
+            audio::set_silence(&path, silence);
+        }
+    }
+}
+
+// Brings the silence of `paths` up to date on the background worker, so
+// indexing and loading the library don't wait for tracks to be decoded. A
+// new threshold also goes over the track being checked again.
+pub fn refresh(app: &AppHandle, mut paths: Vec<String>, threshold_db: f32) {
+    let mut worker = WORKER.lock().unwrap();
+    if worker.threshold_db != Some(threshold_db) {
+        worker.threshold_db = Some(threshold_db);
+        paths.extend(worker.active.clone());
+    }
+    for path in paths {
+        if worker.queued.insert(path.clone()) {
+            worker.pending.push_back(path);
+        }
+    }
+
+    if !worker.running && !worker.pending.is_empty() {
+        worker.running = true;
+        let app = app.clone();
+        std::thread::spawn(move || run(app));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+    use tempfile::TempDir;
+
+    const RATE: u32 = 44100;
+
+    // Writes a mono file made of `parts`: a sample level each, held for that
+    // many seconds. Levels are a square wave's height, so every sample of a
+    // part is as loud as the next.
+    fn write(dir: &TempDir, name: &str, parts: &[(i16, f64)]) -> String {
+        let path = dir.path().join(name);
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for &(level, secs) in parts {
+            for i in 0..(secs * RATE as f64).round() as usize {
+                writer.write_sample(if i % 2 == 0 { level } else { -level }).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn finds_the_silence_at_both_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(&dir, "padded.wav", &[(0, 0.5), (8000, 1.0), (0, 0.25)]);
+        let silence = detect(&path, None, -60.0);
+        assert_eq!((silence.start, silence.end), (0.5, Some(1.5)));
+
+        // A track that ends on sound, or on too short a gap, keeps its end.
+        let path = write(&dir, "open.wav", &[(0, 0.1), (8000, 0.5), (0, 0.02)]);
+        let silence = detect(&path, None, -60.0);
+        assert_eq!((silence.start, silence.end), (0.1, None));
+
+        let path = write(&dir, "silent.wav", &[(0, 1.0)]);
+        let silence = detect(&path, None, -60.0);
+        assert_eq!((silence.start, silence.end), (0.0, None));
+    }
+
+    #[test]
+    fn the_threshold_decides_what_counts_as_silence() {
+        let dir = tempfile::tempdir().unwrap();
+        // A lead-in at about -50 dBFS.
+        let path = write(&dir, "hiss.wav", &[(100, 0.5), (8000, 0.5), (100, 0.5)]);
+        let silence = detect(&path, None, -60.0);
+        assert_eq!((silence.start, silence.end), (0.0, None));
+        let silence = detect(&path, None, -40.0);
+        assert_eq!((silence.start, silence.end), (0.5, Some(1.0)));
+    }
+
+    #[test]
+    fn cached_silence_is_kept_per_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(&dir, "hiss.wav", &[(100, 0.5), (8000, 0.5)]);
+        let conn = Connection::open_in_memory().unwrap();
+        db::create_tables(&conn).unwrap();
+        let modified = db::modified(&path).unwrap();
+
+        assert_eq!(lookup(&conn, &path, -60.0).unwrap().start, 0.0);
+        assert_eq!(db::load_silence(&conn, &path, modified, -60.0).unwrap().map(|s| s.start), Some(0.0));
+        assert!(db::load_silence(&conn, &path, modified, -40.0).unwrap().is_none());
+
+        // Another threshold isn't answered from the cache.
+        assert_eq!(lookup(&conn, &path, -40.0).unwrap().start, 0.5);
+        assert_eq!(db::load_silence(&conn, &path, modified, -40.0).unwrap().map(|s| s.start), Some(0.5));
+        assert!(db::load_silence(&conn, &path, modified + 1, -40.0).unwrap().is_none());
+    }
+}
@@ -0,0 +1,882 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use rodio::source::SeekError;
+use rodio::{Sample, Source};
+
+// Records the instant the first sample of a queued track is pulled by the sink,
+// which is the exact point where the previous track ended.
+pub struct TrackStart<S> {
+    inner: S,
+    started: Arc<OnceLock<Instant>>,
+    signalled: bool,
+}
+
+impl<S> TrackStart<S> {
+    pub fn new(inner: S, started: Arc<OnceLock<Instant>>) -> Self {
+        TrackStart {
+            inner,
+            started,
+            signalled: false,
+        }
+    }
+}
+
+impl<S> Iterator for TrackStart<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<S::Item> {
+        if !self.signalled {
+            let _ = self.started.set(Instant::now());
+            self.signalled = true;
+        }
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for TrackStart<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+// Gain ramp shared between the control side and a `Fade` source on the audio
+// thread. Requests are picked up on the next sample the source produces.
+pub struct Fader {
+    request: Mutex<Option<(Option<f32>, f32, Duration)>>,
+    pending: AtomicBool,
+    gain: AtomicU32,
+    target: AtomicU32,
+}
+
+impl Fader {
+    pub fn new(initial: f32) -> Arc<Self> {
+        Arc::new(Fader {
+            request: Mutex::new(None),
+            pending: AtomicBool::new(false),
+            gain: AtomicU32::new(initial.to_bits()),
+            target: AtomicU32::new(initial.to_bits()),
+        })
+    }
+
+    pub fn ramp_to(&self, target: f32, over: Duration) {
+        self.request(None, target, over);
+    }
+
+    // Jumps to `start` before ramping; meant for sources that are paused or
+    // not yet playing, where the jump can't be heard.
+    pub fn ramp_from(&self, start: f32, target: f32, over: Duration) {
+        self.request(Some(start), target, over);
+    }
+
+    fn request(&self, start: Option<f32>, target: f32, over: Duration) {
+        *self.request.lock().unwrap() = Some((start, target, over));
+        self.target.store(target.to_bits(), Ordering::SeqCst);
+        self.pending.store(true, Ordering::SeqCst);
+    }
+
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::SeqCst))
+    }
+
+    pub fn is_settled(&self) -> bool {
+        !self.pending.load(Ordering::SeqCst)
+            && self.gain.load(Ordering::SeqCst) == self.target.load(Ordering::SeqCst)
+    }
+}
+
+pub struct Fade<S> {
+    inner: S,
+    fader: Arc<Fader>,
+    gain: f32,
+    target: f32,
+    step: f32,
+    channel: u16,
+}
+
+impl<S> Fade<S> {
+    pub fn new(inner: S, fader: Arc<Fader>) -> Self {
+        let gain = fader.gain();
+        Fade {
+            inner,
+            fader,
+            gain,
+            target: gain,
+            step: 0.0,
+            channel: 0,
+        }
+    }
+}
+
+impl<S> Iterator for Fade<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<S::Item> {
+        if self.fader.pending.swap(false, Ordering::SeqCst) {
+            if let Some((start, target, over)) = self.fader.request.lock().unwrap().take() {
+                if let Some(start) = start {
+                    self.gain = start;
+                    self.fader.gain.store(start.to_bits(), Ordering::SeqCst);
+                }
+                let frames = over.as_secs_f32() * self.inner.sample_rate() as f32;
+                self.target = target;
+                self.step = if frames >= 1.0 {
+                    (target - self.gain) / frames
+                } else {
+                    target - self.gain
+                };
+            }
+        }
+
+        let sample = self.inner.next()?.amplify(self.gain);
+
+        self.channel += 1;
+        if self.channel >= self.inner.channels().max(1) {
+            self.channel = 0;
+            if self.gain != self.target {
+                self.gain += self.step;
+                if (self.step > 0.0 && self.gain >= self.target)
+                    || (self.step <= 0.0 && self.gain <= self.target)
+                {
+                    self.gain = self.target;
+                }
+                self.fader.gain.store(self.gain.to_bits(), Ordering::SeqCst);
+            }
+        }
+
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Fade<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+// Applies a gain factor that can be changed from outside while the track plays.
+pub struct Gain<S> {
+    inner: S,
+    factor: Arc<AtomicU32>,
+}
+
+impl<S> Gain<S> {
+    pub fn new(inner: S, factor: Arc<AtomicU32>) -> Self {
+        Gain { inner, factor }
+    }
+}
+
+impl<S> Iterator for Gain<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<S::Item> {
+        let factor = f32::from_bits(self.factor.load(Ordering::Relaxed));
+        self.inner.next().map(|sample| sample.amplify(factor))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Gain<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+// Ends a track early at `end`, or passes it through unchanged without one.
+// Frames are counted as they pass and seeks set the count, so the end holds
+// wherever playback started.
+pub struct StopAt<S> {
+    inner: S,
+    end: Option<Duration>,
+    end_frame: u64,
+    frame: u64,
+    channel: u16,
+}
+
+impl<S> StopAt<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(inner: S, end: Option<Duration>) -> Self {
+        let end_frame = end.map_or(u64::MAX, |end| {
+            (end.as_secs_f64() * inner.sample_rate().max(1) as f64).round() as u64
+        });
+        StopAt {
+            inner,
+            end,
+            end_frame,
+            frame: 0,
+            channel: 0,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        let frames = self.end_frame.saturating_sub(self.frame);
+        let samples = frames.saturating_mul(self.inner.channels() as u64) - self.channel as u64;
+        samples.min(usize::MAX as u64) as usize
+    }
+}
+
+impl<S> Iterator for StopAt<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<S::Item> {
+        if self.channel == 0 && self.frame >= self.end_frame {
+            return None;
+        }
+
+        let sample = self.inner.next()?;
+
+        self.channel += 1;
+        if self.channel >= self.inner.channels().max(1) {
+            self.channel = 0;
+            self.frame += 1;
+        }
+
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.inner.size_hint();
+        let remaining = self.remaining();
+        (lower.min(remaining), Some(upper.map_or(remaining, |u| u.min(remaining))))
+    }
+}
+
+impl<S> Source for StopAt<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        match (self.inner.current_frame_len(), self.end) {
+            (Some(len), Some(_)) => Some(len.min(self.remaining())),
+            (None, Some(_)) => Some(self.remaining()),
+            (len, None) => len,
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        match (self.inner.total_duration(), self.end) {
+            (Some(total), Some(end)) => Some(total.min(end)),
+            (total, end) => total.or(end),
+        }
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.frame = (pos.as_secs_f64() * self.inner.sample_rate().max(1) as f64).round() as u64;
+        self.channel = 0;
+        Ok(())
+    }
+}
+
+// Counts the frames pulled from the decoder and publishes the resulting track
+// position, so the reported position follows the audio actually consumed
+// rather than the wall clock.
+pub struct Position<S> {
+    inner: S,
+    position: Arc<AtomicU64>,
+    base: Duration,
+    frames: u64,
+    sample_rate: u32,
+    channel: u16,
+}
+
+impl<S> Position<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(inner: S, position: Arc<AtomicU64>) -> Self {
+        let sample_rate = inner.sample_rate().max(1);
+        position.store(0, Ordering::Relaxed);
+        Position {
+            inner,
+            position,
+            base: Duration::ZERO,
+            frames: 0,
+            sample_rate,
+            channel: 0,
+        }
+    }
+
+    fn current(&self) -> Duration {
+        self.base + Duration::from_nanos(self.frames * 1_000_000_000 / self.sample_rate as u64)
+    }
+
+    // Seeks to `pos`, decoding and discarding up to it when the format can't
+    // seek. Only meant for sources that aren't playing yet, since decoding a
+    // long stretch would hold up the output.
+    pub fn seek_or_skip(&mut self, pos: Duration) -> Result<(), SeekError> {
+        match self.try_seek(pos) {
+            Err(e) if e.source_intact() && pos >= self.current() => {
+                while self.current() < pos {
+                    if self.next().is_none() {
+                        return Err(SeekError::Other(Box::new(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "position is past the end of the track",
+                        ))));
+                    }
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+impl<S> Iterator for Position<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<S::Item> {
+        if self.channel == 0 {
+            // Decoders may switch sample rate between packets; fold what has
+            // been counted so far before counting at the new rate.
+            let sample_rate = self.inner.sample_rate().max(1);
+            if sample_rate != self.sample_rate {
+                self.base = self.current();
+                self.frames = 0;
+                self.sample_rate = sample_rate;
+            }
+        }
+
+        let sample = self.inner.next()?;
+
+        self.channel += 1;
+        if self.channel >= self.inner.channels().max(1) {
+            self.channel = 0;
+            self.frames += 1;
+            self.position.store(self.current().as_nanos() as u64, Ordering::Relaxed);
+        }
+
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Position<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.base = pos;
+        self.frames = 0;
+        self.channel = 0;
+        self.position.store(pos.as_nanos() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// A–B loop region shared between the control side and a `Loop` source, in
+// frames at the track's own sample rate.
+pub struct LoopControl {
+    region: Mutex<Option<(u64, u64)>>,
+    version: AtomicU64,
+    sample_rate: AtomicU32,
+    // Set when the source couldn't get back to the loop start. It plays
+    // silence until the track is reopened there or the loop is cleared.
+    stalled: AtomicBool,
+}
+
+impl LoopControl {
+    pub fn new() -> Arc<Self> {
+        Arc::new(LoopControl {
+            region: Mutex::new(None),
+            version: AtomicU64::new(0),
+            sample_rate: AtomicU32::new(0),
+            stalled: AtomicBool::new(false),
+        })
+    }
+
+    pub fn set(&self, region: Option<(u64, u64)>) {
+        *self.region.lock().unwrap() = region;
+        self.stalled.store(false, Ordering::SeqCst);
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn region(&self) -> Option<(u64, u64)> {
+        *self.region.lock().unwrap()
+    }
+
+    // The rate loop frames count at; 0 until the source has been built.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn to_time(&self, frames: u64) -> Duration {
+        Duration::from_secs_f64(frames as f64 / self.sample_rate().max(1) as f64)
+    }
+
+    // The loop region as times into the track.
+    pub fn times(&self) -> Option<(Duration, Duration)> {
+        self.region().map(|(start, end)| (self.to_time(start), self.to_time(end)))
+    }
+
+    pub fn stalled(&self) -> bool {
+        self.stalled.load(Ordering::SeqCst)
+    }
+}
+
+// Loops a stretch of longer than this by seeking back on every pass instead of
+// holding it in memory.
+const MAX_LOOP_BUFFER: Duration = Duration::from_secs(120);
+
+// Plays the loop region of `control` over and over. A pass that starts at the
+// loop start comes from the decoder and is recorded; later passes replay the
+// recording, so the loop joins without a gap whether or not the format seeks
+// accurately. Sits right above `Position` and keeps the published position
+// running inside the loop.
+pub struct Loop<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    inner: Position<S>,
+    control: Arc<LoopControl>,
+    version: u64,
+    // Loop start and end in frames.
+    region: Option<(u64, u64)>,
+    // The next frame to be played.
+    frame: u64,
+    channel: u16,
+    recording: bool,
+    buffer: Vec<S::Item>,
+    // Index into `buffer` while replaying.
+    replay: Option<usize>,
+}
+
+impl<S> Loop<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(inner: Position<S>, control: Arc<LoopControl>) -> Self {
+        let frame = (inner.current().as_secs_f64() * inner.sample_rate().max(1) as f64).round() as u64;
+        control.sample_rate.store(inner.sample_rate(), Ordering::Relaxed);
+        Loop {
+            inner,
+            control,
+            version: 0,
+            region: None,
+            frame,
+            channel: 0,
+            recording: false,
+            buffer: Vec::new(),
+            replay: None,
+        }
+    }
+
+    fn to_frames(&self, time: Duration) -> u64 {
+        (time.as_secs_f64() * self.inner.sample_rate().max(1) as f64).round() as u64
+    }
+
+    fn to_time(&self, frames: u64) -> Duration {
+        Duration::from_secs_f64(frames as f64 / self.inner.sample_rate().max(1) as f64)
+    }
+
+    // Leaves the recording and puts the decoder where playback had got to.
+    fn stop_replay(&mut self) {
+        if self.replay.take().is_some() {
+            let at = self.to_time(self.frame);
+            let _ = self.inner.try_seek(at);
+        }
+        self.recording = false;
+        self.buffer.clear();
+    }
+
+    fn refresh(&mut self) {
+        let version = self.control.version.load(Ordering::Relaxed);
+        if version == self.version {
+            return;
+        }
+        self.version = version;
+        self.stop_replay();
+        self.region = self.control.region().filter(|(start, end)| start < end);
+    }
+
+    // Goes back to the start of the loop: into the recording if the whole
+    // region has been recorded, otherwise by seeking the decoder. If the
+    // decoder can't seek, the loop stalls until the track is reopened at the
+    // start.
+    fn wrap(&mut self, start: u64, end: u64) -> bool {
+        if self.recording && !self.buffer.is_empty() {
+            self.recording = false;
+            self.replay = Some(0);
+            self.frame = start;
+            return true;
+        }
+
+        self.buffer.clear();
+        match self.inner.try_seek(self.to_time(start)) {
+            Ok(()) => {
+                self.frame = start;
+                self.recording = end - start <= self.to_frames(MAX_LOOP_BUFFER);
+                true
+            }
+            Err(_) => {
+                self.recording = false;
+                self.control.stalled.store(true, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    fn pull(&mut self) -> Option<S::Item> {
+        match self.replay {
+            Some(index) => {
+                let sample = self.buffer[index];
+                let next = index + 1;
+                self.replay = Some(if next < self.buffer.len() { next } else { 0 });
+                Some(sample)
+            }
+            None => self.inner.next(),
+        }
+    }
+}
+
+impl<S> Iterator for Loop<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<S::Item> {
+        if self.channel == 0 {
+            self.refresh();
+            if self.control.stalled() {
+                return Some(S::Item::zero_value());
+            }
+            if let (Some((start, end)), None) = (self.region, self.replay) {
+                if self.frame >= end && !self.wrap(start, end) {
+                    return Some(S::Item::zero_value());
+                }
+                // Playing into the loop start records the pass.
+                if self.replay.is_none() && self.frame == start && !self.recording {
+                    self.buffer.clear();
+                    self.recording = end - start <= self.to_frames(MAX_LOOP_BUFFER);
+                }
+            }
+        }
+
+        let sample = match self.pull() {
+            Some(sample) => sample,
+            // A loop end past the end of the track loops at the end.
+            None => {
+                let (start, end) = self.region.filter(|_| self.channel == 0)?;
+                if !self.wrap(start, end) {
+                    return Some(S::Item::zero_value());
+                }
+                self.pull()?
+            }
+        };
+        if self.recording {
+            self.buffer.push(sample);
+        }
+
+        self.channel += 1;
+        if self.channel >= self.inner.channels().max(1) {
+            self.channel = 0;
+            self.frame += 1;
+            if self.replay == Some(0) {
+                self.frame = self.region.map_or(self.frame, |(start, _)| start);
+            }
+            if self.replay.is_some() {
+                let position = self.to_time(self.frame);
+                self.inner.position.store(position.as_nanos() as u64, Ordering::Relaxed);
+            }
+        }
+
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Loop<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        match self.replay {
+            Some(_) => None,
+            None => self.inner.current_frame_len(),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.refresh();
+        self.inner.try_seek(pos)?;
+        self.replay = None;
+        self.buffer.clear();
+        self.frame = self.to_frames(pos);
+        self.channel = 0;
+        // Landing on the loop start records the next pass.
+        self.recording = self.region.is_some_and(|(start, end)| {
+            self.frame == start && end - start <= self.to_frames(MAX_LOOP_BUFFER)
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 10;
+
+    // Mono samples 0, 1, 2, ... at 10 Hz, optionally seekable.
+    struct Tape {
+        at: usize,
+        len: usize,
+        seekable: bool,
+    }
+
+    impl Iterator for Tape {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            let sample = (self.at < self.len).then_some(self.at as f32)?;
+            self.at += 1;
+            Some(sample)
+        }
+    }
+
+    impl Source for Tape {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+
+        fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+            if !self.seekable {
+                return Err(SeekError::NotSupported { underlying_source: "tape" });
+            }
+            self.at = (pos.as_secs_f64() * RATE as f64).round() as usize;
+            Ok(())
+        }
+    }
+
+    fn looped(len: usize, seekable: bool) -> (Loop<Tape>, Arc<LoopControl>) {
+        let control = LoopControl::new();
+        let tape = Tape { at: 0, len, seekable };
+        let source = Loop::new(Position::new(tape, Arc::new(AtomicU64::new(0))), control.clone());
+        (source, control)
+    }
+
+    fn take(source: &mut Loop<Tape>, count: usize) -> Vec<f32> {
+        source.by_ref().take(count).collect()
+    }
+
+    #[test]
+    fn loop_regions_count_in_frames_at_the_track_rate() {
+        let (_source, control) = looped(10, true);
+        assert_eq!(control.sample_rate(), RATE);
+        control.set(Some((3, 15)));
+        assert_eq!(control.times(), Some((Duration::from_millis(300), Duration::from_millis(1500))));
+    }
+
+    #[test]
+    fn loop_played_into_from_before_replays_without_seeking() {
+        let (mut source, control) = looped(10, false);
+        control.set(Some((2, 5)));
+        assert_eq!(take(&mut source, 11), [0.0, 1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0]);
+        assert!(!control.stalled());
+    }
+
+    #[test]
+    fn loop_joined_inside_seeks_back_to_its_start() {
+        let (mut source, control) = looped(10, true);
+        assert_eq!(take(&mut source, 3), [0.0, 1.0, 2.0]);
+        control.set(Some((1, 5)));
+        assert_eq!(take(&mut source, 10), [3.0, 4.0, 1.0, 2.0, 3.0, 4.0, 1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn loop_end_past_the_track_wraps_at_the_end() {
+        let (mut source, control) = looped(4, true);
+        control.set(Some((2, 100)));
+        assert_eq!(take(&mut source, 8), [0.0, 1.0, 2.0, 3.0, 2.0, 3.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn loop_that_cannot_seek_back_stalls_in_silence() {
+        let (mut source, control) = looped(10, false);
+        assert_eq!(take(&mut source, 3), [0.0, 1.0, 2.0]);
+        control.set(Some((1, 5)));
+        // Frame 1 has gone by and can't be sought back to.
+        assert_eq!(take(&mut source, 5), [3.0, 4.0, 0.0, 0.0, 0.0]);
+        assert!(control.stalled());
+
+        // Clearing the loop plays on from where the decoder is.
+        control.set(None);
+        assert!(!control.stalled());
+        assert_eq!(take(&mut source, 3), [5.0, 6.0, 7.0]);
+    }
+
+    #[test]
+    fn loop_stalls_rather_than_ending_the_track() {
+        let (mut source, control) = looped(4, false);
+        assert_eq!(take(&mut source, 3), [0.0, 1.0, 2.0]);
+        control.set(Some((1, 100)));
+        assert_eq!(take(&mut source, 4), [3.0, 0.0, 0.0, 0.0]);
+        assert!(control.stalled());
+        control.set(None);
+        assert_eq!(source.next(), None);
+    }
+}
@@ -0,0 +1,105 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter};
+use crate::audio;
+use crate::audiobook;
+use crate::output;
+use crate::session;
+use crate::waveform;
+use crate::models::{PlaybackErrorEvent, PlaybackStatus, PositionEvent, StateChangedEvent, TrackChangedEvent};
+
+const TICK: Duration = Duration::from_millis(50);
+const POSITION_INTERVAL: Duration = Duration::from_millis(250);
+const SESSION_INTERVAL: Duration = Duration::from_secs(2);
+
+fn emit_track(app: &AppHandle, status: &PlaybackStatus) {
+    let track = audio::get_current_track_info().ok().flatten();
+    let _ = app.emit(
+        "track-changed",
+        TrackChangedEvent {
+            path: status.track.clone(),
+            track,
+        },
+    );
+}
+
+fn emit_position(app: &AppHandle, status: &PlaybackStatus) {
+    let _ = app.emit(
+        "position",
+        PositionEvent {
+            position: status.position,
+            duration: status.duration,
+        },
+    );
+}
+
+// Watches the engine on its own thread so tracks keep advancing whether or not
+// a window is listening, and pushes changes to the frontend as events.
+pub fn start(app: AppHandle) {
+    thread::spawn(move || {
+        let mut last: Option<PlaybackStatus> = None;
+        let mut last_position = Instant::now();
+        let mut saved_session = None;
+        let mut last_save = Instant::now();
+
+        loop {
+            if output::take_rebuilt() {
+                if let Err(message) = audio::reattach_output() {
+                    let _ = app.emit("playback-error", PlaybackErrorEvent { path: None, message });
+                }
+            }
+
+            let (status, errors) = audio::poll_playback();
+
+            for error in errors {
+                let _ = app.emit("playback-error", error);
+            }
+
+            let track_changed = last.as_ref().is_none_or(|l| l.track != status.track);
+            if track_changed {
+                emit_track(&app, &status);
+                // Waveforms are made the first time a track plays.
+                if let Some(path) = &status.track {
+                    let _ = waveform::get(&app, path);
+                }
+            }
+
+            if last.as_ref().is_none_or(|l| l.playing != status.playing) {
+                let _ = app.emit(
+                    "state-changed",
+                    StateChangedEvent {
+                        playing: status.playing,
+                    },
+                );
+            }
+
+            // While playing the position is sent at a fixed rate; otherwise
+            // only when it moved, e.g. after a seek while paused.
+            let moved = last
+                .as_ref()
+                .is_none_or(|l| l.position != status.position || l.duration != status.duration);
+            let due = status.playing && last_position.elapsed() >= POSITION_INTERVAL;
+            if track_changed || due || (!status.playing && moved) {
+                emit_position(&app, &status);
+                last_position = Instant::now();
+            }
+
+            if last.as_ref().is_none_or(|l| l.queue_version != status.queue_version) {
+                if let Ok(queue) = audio::get_queue() {
+                    let _ = app.emit("queue-changed", queue);
+                }
+            }
+
+            audiobook::track_progress(&status);
+
+            if last_save.elapsed() >= SESSION_INTERVAL {
+                let _ = session::persist(&app, &mut saved_session);
+                let _ = audiobook::persist(&app);
+                last_save = Instant::now();
+            }
+
+            last = Some(status);
+            thread::sleep(TICK);
+        }
+    });
+}
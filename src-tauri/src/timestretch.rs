@@ -0,0 +1,366 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use rodio::source::SeekError;
+use rodio::Source;
+
+const FRAME_SECS: f32 = 0.04;
+const TOLERANCE_SECS: f32 = 0.01;
+// Correlation only looks at every Nth frame of the overlap; plenty for
+// finding the best alignment and much cheaper.
+const CORRELATION_STRIDE: usize = 4;
+
+fn is_unity(rate: f32) -> bool {
+    (rate - 1.0).abs() < 0.001
+}
+
+// Pitch-preserving time stretch using WSOLA: windowed segments are read from
+// the input at `rate` times the output hop, each nudged within a small
+// tolerance to the offset that best lines up with the previous segment, and
+// overlap-added at a fixed output hop.
+pub struct TimeStretch<S> {
+    inner: S,
+    rate: Arc<AtomicU32>,
+    channels: usize,
+    frame_len: usize,
+    hop: usize,
+    tolerance: usize,
+    window: Vec<f32>,
+    stretching: bool,
+    input: Vec<f32>,
+    inner_done: bool,
+    analysis_pos: f64,
+    prev_pos: usize,
+    fresh: bool,
+    overlap: Vec<f32>,
+    output: Vec<f32>,
+    output_pos: usize,
+    channel: usize,
+}
+
+impl<S> TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, rate: Arc<AtomicU32>) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate().max(1) as f32;
+        let hop = ((sample_rate * FRAME_SECS) as usize / 2).max(1);
+        let frame_len = hop * 2;
+        let window = (0..frame_len)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / frame_len as f32).cos())
+            .collect();
+
+        TimeStretch {
+            inner,
+            rate,
+            channels,
+            frame_len,
+            hop,
+            tolerance: (sample_rate * TOLERANCE_SECS) as usize,
+            window,
+            stretching: false,
+            input: Vec::new(),
+            inner_done: false,
+            analysis_pos: 0.0,
+            prev_pos: 0,
+            fresh: true,
+            overlap: vec![0.0; hop * channels],
+            output: Vec::new(),
+            output_pos: 0,
+            channel: 0,
+        }
+    }
+
+    fn rate(&self) -> f32 {
+        f32::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+
+    fn reset(&mut self) {
+        self.input.clear();
+        self.inner_done = false;
+        self.analysis_pos = 0.0;
+        self.prev_pos = 0;
+        self.fresh = true;
+        self.overlap.iter_mut().for_each(|s| *s = 0.0);
+        self.output.clear();
+        self.output_pos = 0;
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    fn fill_to(&mut self, frames: usize) {
+        while !self.inner_done && self.buffered_frames() < frames {
+            match self.inner.next() {
+                Some(sample) => self.input.push(sample),
+                None => self.inner_done = true,
+            }
+        }
+    }
+
+    fn mono(&self, frame: usize) -> f32 {
+        let start = frame * self.channels;
+        self.input[start..start + self.channels].iter().sum()
+    }
+
+    fn best_offset(&self, template: usize, center: usize) -> usize {
+        let low = center.saturating_sub(self.tolerance);
+        let high = center + self.tolerance;
+        let available = self.buffered_frames().saturating_sub(self.frame_len);
+        let high = high.min(available);
+        if low >= high {
+            return center.min(available);
+        }
+
+        let mut best = center.min(available);
+        let mut best_score = f32::MIN;
+        for candidate in low..=high {
+            let score: f32 = (0..self.hop)
+                .step_by(CORRELATION_STRIDE)
+                .map(|i| self.mono(template + i) * self.mono(candidate + i))
+                .sum();
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+        }
+        best
+    }
+
+    // Produces the next `hop` frames of output. Returns false once the inner
+    // source has run dry.
+    fn process_hop(&mut self) -> bool {
+        let rate = self.rate() as f64;
+        let center = self.analysis_pos.round() as usize;
+        let template = self.prev_pos + self.hop;
+        let needed = (center + self.tolerance).max(template) + self.frame_len;
+        self.fill_to(needed);
+
+        if self.buffered_frames() < (center + self.frame_len).max(template + self.hop) {
+            return self.flush();
+        }
+
+        let channels = self.channels;
+        let start = if self.fresh {
+            // The first segment starts on the unstretched signal: pre-load the
+            // overlap with the complementary half window so it sums to the
+            // input instead of fading in from silence.
+            for i in 0..self.hop {
+                for c in 0..channels {
+                    self.overlap[i * channels + c] = self.input[i * channels + c] * (1.0 - self.window[i]);
+                }
+            }
+            self.fresh = false;
+            0
+        } else {
+            self.best_offset(template, center)
+        };
+        self.output.clear();
+        self.output_pos = 0;
+
+        for i in 0..self.frame_len {
+            let w = self.window[i];
+            for c in 0..channels {
+                let x = self.input.get((start + i) * channels + c).copied().unwrap_or(0.0) * w;
+                if i < self.hop {
+                    self.output.push(self.overlap[i * channels + c] + x);
+                } else {
+                    self.overlap[(i - self.hop) * channels + c] = x;
+                }
+            }
+        }
+
+        self.prev_pos = start;
+        self.analysis_pos += self.hop as f64 * rate;
+
+        // Drop input that no future segment can reach.
+        let keep_from = self.prev_pos.min((self.analysis_pos as usize).saturating_sub(self.tolerance));
+        if keep_from > self.frame_len {
+            self.input.drain(..keep_from * channels);
+            self.prev_pos -= keep_from;
+            self.analysis_pos -= keep_from as f64;
+        }
+
+        true
+    }
+
+    // Out of input: the rest of it is played as it is, carrying on from where
+    // the last segment would have, so the end of the track isn't cut short.
+    // Faded in over what is left in the overlap buffer, it sums back to the
+    // input. Returns false once there is nothing left to play.
+    fn flush(&mut self) -> bool {
+        let channels = self.channels;
+        let start = if self.fresh { 0 } else { self.prev_pos + self.hop };
+        let rest = self.buffered_frames().saturating_sub(start);
+        let frames = if self.fresh { rest } else { rest.max(self.hop) };
+
+        self.output.clear();
+        self.output_pos = 0;
+        for i in 0..frames {
+            for c in 0..channels {
+                let x = if i < rest { self.input[(start + i) * channels + c] } else { 0.0 };
+                let sample = if !self.fresh && i < self.hop {
+                    self.overlap[i * channels + c] + x * self.window[i]
+                } else {
+                    x
+                };
+                self.output.push(sample);
+            }
+        }
+
+        self.input.clear();
+        self.overlap.iter_mut().for_each(|s| *s = 0.0);
+        self.fresh = true;
+        !self.output.is_empty()
+    }
+
+    // Leaves stretch mode by continuing exactly where the last segment would
+    // have carried on, so the switch back to plain playback is seamless.
+    fn stop_stretching(&mut self) {
+        let from = ((self.prev_pos + self.hop) * self.channels).min(self.input.len());
+        self.output = self.input.split_off(from);
+        self.output_pos = 0;
+        self.input.clear();
+        self.stretching = false;
+    }
+}
+
+impl<S> Iterator for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.output_pos < self.output.len() {
+            let sample = self.output[self.output_pos];
+            self.output_pos += 1;
+            return Some(sample);
+        }
+
+        if !self.stretching {
+            if self.inner_done {
+                return None;
+            }
+            if self.channel == 0 && !is_unity(self.rate()) {
+                self.reset();
+                self.stretching = true;
+            } else {
+                let sample = self.inner.next();
+                self.channel = (self.channel + 1) % self.channels;
+                return sample;
+            }
+        }
+
+        if is_unity(self.rate()) {
+            self.stop_stretching();
+            return self.next();
+        }
+
+        if !self.process_hop() {
+            self.stretching = false;
+            self.inner_done = true;
+            return None;
+        }
+        self.next()
+    }
+}
+
+impl<S> Source for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.reset();
+        self.stretching = false;
+        self.channel = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    const RATE: u32 = 44100;
+
+    fn stretch(samples: Vec<f32>, rate: f32) -> TimeStretch<SamplesBuffer<f32>> {
+        TimeStretch::new(SamplesBuffer::new(2, RATE, samples), Arc::new(AtomicU32::new(rate.to_bits())))
+    }
+
+    // A stereo tone `frames` long.
+    fn tone(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let x = (i as f32 * 440.0 * std::f32::consts::TAU / RATE as f32).sin() * 0.5;
+                [x, -x]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unity_rate_passes_samples_through_untouched() {
+        let samples = tone(RATE as usize);
+        assert_eq!(stretch(samples.clone(), 1.0).collect::<Vec<_>>(), samples);
+    }
+
+    #[test]
+    fn output_length_follows_the_rate() {
+        let frames = RATE as usize;
+        let hop = (RATE as f32 * FRAME_SECS) as usize / 2;
+        for rate in [2.0, 1.5, 0.5] {
+            let out = stretch(tone(frames), rate).count() / 2;
+            let expected = (frames as f32 / rate) as usize;
+            // Up to the last segment's worth at the end is played unstretched.
+            assert!(out.abs_diff(expected) <= hop * 2, "{} frames at {}x", out, rate);
+        }
+    }
+
+    #[test]
+    fn the_end_of_the_input_is_played_out() {
+        // A steady level right to the end, which a windowed tail would fade.
+        let samples = vec![0.5; RATE as usize];
+        let out: Vec<f32> = stretch(samples, 1.5).collect();
+        assert!(out.len().is_multiple_of(2));
+        let tail = &out[out.len() - 200..];
+        assert!(tail.iter().all(|s| (s - 0.5).abs() < 1e-4), "{:?}", &tail[tail.len() - 4..]);
+    }
+
+    #[test]
+    fn inputs_shorter_than_a_segment_come_out_whole() {
+        let samples = tone(100);
+        assert_eq!(stretch(samples.clone(), 2.0).collect::<Vec<_>>(), samples);
+    }
+
+    #[test]
+    fn seeking_starts_the_stretch_over() {
+        let samples = tone(RATE as usize);
+        let fresh: Vec<f32> = stretch(samples.clone(), 2.0).take(4000).collect();
+
+        let mut seeked = stretch(samples, 2.0);
+        assert_eq!(seeked.by_ref().take(12345).count(), 12345);
+        seeked.try_seek(Duration::ZERO).unwrap();
+        assert_eq!(seeked.take(4000).collect::<Vec<_>>(), fresh);
+    }
+}
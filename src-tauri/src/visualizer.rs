@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use rodio::source::SeekError;
+use rodio::Source;
+use tauri::{AppHandle, Emitter};
+use crate::fft;
+use crate::models::VisualizerFrame;
+
+const FFT_SIZE: usize = 2048;
+const BANDS: usize = 64;
+const SCOPE_POINTS: usize = 256;
+const MIN_FREQUENCY: f32 = 20.0;
+// Spectrum levels are mapped onto 0..=255 over this range in dBFS.
+const FLOOR_DB: f32 = -90.0;
+// Frames are handed from the audio thread to the analysis in batches so the
+// shared buffer is only locked once in a while.
+const BATCH: usize = 256;
+const MIN_FPS: u32 = 1;
+const MAX_FPS: u32 = 60;
+
+// Checked by the tap on every frame, so nothing is collected while no one is
+// watching.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct History {
+    samples: Vec<f32>,
+    write: usize,
+    // Frames written since the analysis last looked.
+    fresh: usize,
+    sample_rate: u32,
+}
+
+impl History {
+    fn push(&mut self, frames: &[f32], sample_rate: u32) {
+        if sample_rate != self.sample_rate {
+            self.samples.iter_mut().for_each(|s| *s = 0.0);
+            self.sample_rate = sample_rate;
+        }
+        for &frame in frames {
+            self.samples[self.write] = frame;
+            self.write = (self.write + 1) % FFT_SIZE;
+        }
+        self.fresh = (self.fresh + frames.len()).min(FFT_SIZE);
+    }
+
+    // The buffered frames in the order they were played.
+    fn ordered(&self) -> Vec<f32> {
+        let mut ordered = Vec::with_capacity(FFT_SIZE);
+        ordered.extend_from_slice(&self.samples[self.write..]);
+        ordered.extend_from_slice(&self.samples[..self.write]);
+        ordered
+    }
+}
+
+static HISTORY: Lazy<Mutex<History>> = Lazy::new(|| {
+    Mutex::new(History {
+        samples: vec![0.0; FFT_SIZE],
+        write: 0,
+        fresh: 0,
+        sample_rate: 44100,
+    })
+});
+
+struct Subscription {
+    // Subscriptions per window, so those of a window that closes without
+    // unsubscribing can be dropped with it.
+    subscribers: BTreeMap<String, usize>,
+    fps: u32,
+    // Bumped whenever the analysis thread should stop, so a thread left over
+    // from an earlier subscription exits instead of running alongside.
+    generation: u64,
+}
+
+impl Subscription {
+    // Returns whether this is the first subscription.
+    fn add(&mut self, label: &str) -> bool {
+        *self.subscribers.entry(label.to_string()).or_default() += 1;
+        self.subscribers.values().sum::<usize>() == 1
+    }
+
+    // Drops one of `label`'s subscriptions, or all of them. Returns whether
+    // that was the last of any window's.
+    fn remove(&mut self, label: &str, all: bool) -> bool {
+        let Some(count) = self.subscribers.get_mut(label) else {
+            return false;
+        };
+        *count = if all { 0 } else { *count - 1 };
+        if *count == 0 {
+            self.subscribers.remove(label);
+        }
+        self.subscribers.is_empty()
+    }
+}
+
+static SUBSCRIPTION: Mutex<Subscription> = Mutex::new(Subscription {
+    subscribers: BTreeMap::new(),
+    fps: 30,
+    generation: 0,
+});
+
+// Passes the output through unchanged while copying a mono mixdown of it to
+// the visualizer history.
+pub struct Tap<S> {
+    inner: S,
+    batch: Vec<f32>,
+    sum: f32,
+    channel: u16,
+}
+
+impl<S> Tap<S> {
+    pub fn new(inner: S) -> Self {
+        Tap {
+            inner,
+            batch: Vec::with_capacity(BATCH),
+            sum: 0.0,
+            channel: 0,
+        }
+    }
+}
+
+impl<S> Iterator for Tap<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.inner.next()?;
+        let channels = self.inner.channels().max(1);
+
+        self.sum += sample;
+        self.channel += 1;
+        if self.channel >= channels {
+            if ENABLED.load(Ordering::Relaxed) {
+                self.batch.push(self.sum / channels as f32);
+                if self.batch.len() >= BATCH {
+                    // Never wait on the analysis; drop the batch instead.
+                    if let Ok(mut history) = HISTORY.try_lock() {
+                        history.push(&self.batch, self.inner.sample_rate());
+                    }
+                    self.batch.clear();
+                }
+            }
+            self.sum = 0.0;
+            self.channel = 0;
+        }
+
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S> Source for Tap<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+fn level(magnitude: f32) -> u8 {
+    let db = 20.0 * magnitude.max(1e-9).log10();
+    ((db - FLOOR_DB) / -FLOOR_DB * 255.0).clamp(0.0, 255.0) as u8
+}
+
+fn analyse(samples: &[f32], fresh: usize, sample_rate: u32, window: &[f32]) -> VisualizerFrame {
+    let recent = &samples[FFT_SIZE - fresh.max(1)..];
+    let peak = recent.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    let rms = (recent.iter().map(|s| s * s).sum::<f32>() / recent.len() as f32).sqrt();
+
+    let mut re: Vec<f32> = samples.iter().zip(window).map(|(s, w)| s * w).collect();
+    let mut im = vec![0.0; FFT_SIZE];
+    fft::fft(&mut re, &mut im);
+
+    // Scale so a full-scale sine reads 0 dB.
+    let scale = 2.0 / window.iter().sum::<f32>();
+    let nyquist = sample_rate as f32 / 2.0;
+    let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+    let ratio = (nyquist / MIN_FREQUENCY).ln();
+
+    // Bands are spaced logarithmically; each takes the loudest bin it covers.
+    let bins = (0..BANDS)
+        .map(|band| {
+            let low = MIN_FREQUENCY * (ratio * band as f32 / BANDS as f32).exp();
+            let high = MIN_FREQUENCY * (ratio * (band + 1) as f32 / BANDS as f32).exp();
+            let first = ((low / bin_width) as usize).clamp(1, FFT_SIZE / 2 - 1);
+            let last = ((high / bin_width) as usize).clamp(first, FFT_SIZE / 2 - 1);
+            let magnitude = (first..=last)
+                .map(|k| (re[k] * re[k] + im[k] * im[k]).sqrt() * scale)
+                .fold(0.0f32, f32::max);
+            level(magnitude)
+        })
+        .collect();
+
+    let step = FFT_SIZE / SCOPE_POINTS;
+    let scope = samples
+        .iter()
+        .step_by(step)
+        .map(|s| (s.clamp(-1.0, 1.0) * 127.0) as i8)
+        .collect();
+
+    VisualizerFrame {
+        bins,
+        peak,
+        rms,
+        scope,
+    }
+}
+
+fn run(app: AppHandle, generation: u64) {
+    let window = fft::hann_window(FFT_SIZE);
+
+    loop {
+        let fps = {
+            let subscription = SUBSCRIPTION.lock().unwrap();
+            if subscription.generation != generation {
+                break;
+            }
+            subscription.fps
+        };
+
+        let (samples, fresh, sample_rate) = {
+            let mut history = HISTORY.lock().unwrap();
+            let fresh = history.fresh;
+            history.fresh = 0;
+            (history.ordered(), fresh, history.sample_rate)
+        };
+
+        // Nothing new means the output isn't running; the last frame stays.
+        if fresh > 0 {
+            let frame = analyse(&samples, fresh, sample_rate, &window);
+            let _ = app.emit("visualizer-frame", frame);
+        }
+
+        thread::sleep(Duration::from_secs_f32(1.0 / fps as f32));
+    }
+}
+
+// Frames are sent while any window is subscribed; `label` is the window
+// asking.
+pub fn subscribe(app: AppHandle, label: &str, fps: u32) {
+    let mut subscription = SUBSCRIPTION.lock().unwrap();
+    subscription.fps = fps.clamp(MIN_FPS, MAX_FPS);
+
+    if subscription.add(label) {
+        subscription.generation += 1;
+        let generation = subscription.generation;
+        ENABLED.store(true, Ordering::SeqCst);
+        thread::spawn(move || run(app, generation));
+    }
+}
+
+fn stop(subscription: &mut Subscription) {
+    subscription.generation += 1;
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn unsubscribe(label: &str) {
+    let mut subscription = SUBSCRIPTION.lock().unwrap();
+    if subscription.remove(label, false) {
+        stop(&mut subscription);
+    }
+}
+
+// Drops whatever a window that has gone still had subscribed.
+pub fn release(label: &str) {
+    let mut subscription = SUBSCRIPTION.lock().unwrap();
+    if subscription.remove(label, true) {
+        stop(&mut subscription);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn subscription() -> Subscription {
+        Subscription {
+            subscribers: BTreeMap::new(),
+            fps: 30,
+            generation: 0,
+        }
+    }
+
+    #[test]
+    fn subscriptions_are_counted_per_window() {
+        let mut subscription = subscription();
+        assert!(subscription.add("main"));
+        assert!(!subscription.add("main"));
+        assert!(!subscription.add("mini"));
+
+        assert!(!subscription.remove("main", false));
+        // Windows that never subscribed change nothing.
+        assert!(!subscription.remove("settings", false));
+        assert!(!subscription.remove("settings", true));
+        // A window that goes takes all of its subscriptions with it.
+        assert!(!subscription.remove("mini", true));
+        assert!(subscription.remove("main", false));
+
+        // Nothing left to go below zero.
+        assert!(!subscription.remove("main", false));
+        assert!(subscription.add("main"));
+    }
+
+    #[test]
+    fn the_tap_passes_samples_through_unchanged() {
+        let samples: Vec<f32> = (0..BATCH * 5 + 3).map(|i| (i as f32 * 0.37).sin()).collect();
+        let tap = Tap::new(SamplesBuffer::new(2, 44100, samples.clone()));
+        assert_eq!((tap.channels(), tap.sample_rate()), (2, 44100));
+        assert_eq!(tap.collect::<Vec<_>>(), samples);
+    }
+
+    #[test]
+    fn a_full_scale_tone_peaks_in_its_band() {
+        let sample_rate = 48000;
+        // Exactly on a bin, so none of it leaks into the neighbours.
+        let frequency = 64.0 * sample_rate as f32 / FFT_SIZE as f32;
+        let samples: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect();
+        let frame = analyse(&samples, FFT_SIZE, sample_rate, &fft::hann_window(FFT_SIZE));
+
+        let loudest = (0..BANDS).max_by_key(|&band| frame.bins[band]).unwrap();
+        assert!(frame.bins[loudest] >= 253, "{}", frame.bins[loudest]);
+        // Band edges are rounded down to whole bins.
+        let ratio = (sample_rate as f32 / 2.0 / MIN_FREQUENCY).ln();
+        let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+        let low = MIN_FREQUENCY * (ratio * loudest as f32 / BANDS as f32).exp();
+        let high = MIN_FREQUENCY * (ratio * (loudest + 1) as f32 / BANDS as f32).exp();
+        assert!((low - bin_width..high + bin_width).contains(&frequency), "{} to {}", low, high);
+        // Far from the tone only the window's sidelobes are left.
+        assert!(frame.bins[0] < 40, "{}", frame.bins[0]);
+        assert!(frame.bins[BANDS - 1] < 40, "{}", frame.bins[BANDS - 1]);
+
+        assert!((frame.peak - 1.0).abs() < 1e-3);
+        assert!((frame.rms - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+    }
+}
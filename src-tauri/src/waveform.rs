@@ -0,0 +1,284 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use rodio::Source;
+use tauri::{AppHandle, Emitter};
+use crate::{db, decoder};
+use crate::models::{TrackRange, Waveform, WaveformChannel, WaveformReadyEvent};
+
+const BUCKETS: usize = 1000;
+// Samples are first reduced to min/max per chunk of this many frames, since
+// the number of buckets they end up in is only known once decoding is done.
+const CHUNK_FRAMES: u64 = 256;
+
+struct Worker {
+    pending: VecDeque<String>,
+    // The track being decoded right now.
+    active: Option<String>,
+    running: bool,
+    // Tracks that couldn't be decoded, so they aren't retried on every request.
+    failed: HashMap<String, String>,
+}
+
+static WORKER: Lazy<Mutex<Worker>> = Lazy::new(|| {
+    Mutex::new(Worker {
+        pending: VecDeque::new(),
+        active: None,
+        running: false,
+        failed: HashMap::new(),
+    })
+});
+
+fn to_byte(value: f32) -> i8 {
+    (value.clamp(-1.0, 1.0) * 127.0).round() as i8
+}
+
+pub fn compute(path: &str, range: Option<&TrackRange>) -> Result<Waveform, String> {
+    let mut source = decoder::open_track(path, range)?.convert_samples::<f32>();
+
+    let channels = source.channels().max(1) as usize;
+    let mut chunks: Vec<Vec<(f32, f32)>> = vec![Vec::new(); channels];
+    let mut current = vec![(f32::MAX, f32::MIN); channels];
+    let mut frames_in_chunk = 0;
+    let mut duration = 0.0;
+    let mut channel = 0;
+
+    loop {
+        // Decoders may switch sample rate between packets.
+        let sample_rate = source.sample_rate().max(1) as f64;
+        let Some(sample) = source.next() else {
+            break;
+        };
+
+        let (min, max) = &mut current[channel];
+        *min = min.min(sample);
+        *max = max.max(sample);
+
+        channel += 1;
+        if channel == channels {
+            channel = 0;
+            duration += 1.0 / sample_rate;
+            frames_in_chunk += 1;
+            if frames_in_chunk == CHUNK_FRAMES {
+                frames_in_chunk = 0;
+                for (chunks, current) in chunks.iter_mut().zip(current.iter_mut()) {
+                    chunks.push(*current);
+                    *current = (f32::MAX, f32::MIN);
+                }
+            }
+        }
+    }
+    if frames_in_chunk > 0 {
+        for (chunks, current) in chunks.iter_mut().zip(current.iter()) {
+            chunks.push(*current);
+        }
+    }
+
+    let count = chunks[0].len();
+    if count == 0 {
+        return Err("Track contains no audio".to_string());
+    }
+    let buckets = BUCKETS.min(count);
+
+    let channels = chunks
+        .iter()
+        .map(|chunks| {
+            let (min, max) = (0..buckets)
+                .map(|bucket| {
+                    let first = bucket * count / buckets;
+                    let last = ((bucket + 1) * count / buckets).max(first + 1);
+                    chunks[first..last]
+                        .iter()
+                        .fold((f32::MAX, f32::MIN), |(lo, hi), &(min, max)| (lo.min(min), hi.max(max)))
+                })
+                .map(|(min, max)| (to_byte(min), to_byte(max)))
+                .unzip();
+            WaveformChannel { min, max }
+        })
+        .collect();
+
+    Ok(Waveform { duration, channels })
+}
+
+// The stored waveform for `path`, or `None` when there is none or the file
+// has changed since it was made.
+fn cached(conn: &rusqlite::Connection, path: &str) -> Result<Option<Waveform>, String> {
+    let range = db::load_track_range(conn, path)?;
+    let modified = db::modified(range.as_ref().map_or(path, |r| &r.file))?;
+    db::load_waveform(conn, path, modified)
+}
+
+fn store(conn: &rusqlite::Connection, path: &str) -> Result<(), String> {
+    let range = db::load_track_range(conn, path)?;
+    let modified = db::modified(range.as_ref().map_or(path, |r| &r.file))?;
+    let waveform = compute(path, range.as_ref())?;
+    db::save_waveform(conn, path, modified, &waveform)
+}
+
+fn run(app: AppHandle) {
+    loop {
+        let path = {
+            let mut worker = WORKER.lock().unwrap();
+            worker.active = worker.pending.pop_front();
+            match worker.active.clone() {
+                Some(path) => path,
+                None => {
+                    worker.running = false;
+                    return;
+                }
+            }
+        };
+
+        let result = db::get_db_connection(&app).and_then(|conn| store(&conn, &path));
+
+        match result {
+            Ok(()) => {
+                let _ = app.emit("waveform-ready", WaveformReadyEvent { path });
+            }
+            Err(e) => {
+                WORKER.lock().unwrap().failed.insert(path, e);
+            }
+        }
+    }
+}
+
+// Queues `path` for decoding on the background worker unless it is already
+// waiting or being decoded.
+fn request(app: &AppHandle, path: &str) {
+    let mut worker = WORKER.lock().unwrap();
+    if worker.active.as_deref() == Some(path) || worker.pending.iter().any(|p| p == path) {
+        return;
+    }
+    worker.pending.push_back(path.to_string());
+
+    if !worker.running {
+        worker.running = true;
+        let app = app.clone();
+        std::thread::spawn(move || run(app));
+    }
+}
+
+// Returns the cached waveform for `path`. When there is none yet it is
+// computed in the background and `waveform-ready` is emitted once it is
+// stored; until then this returns `None`.
+pub fn get(app: &AppHandle, path: &str) -> Result<Option<Waveform>, String> {
+    if let Some(e) = WORKER.lock().unwrap().failed.get(path) {
+        return Err(e.clone());
+    }
+
+    let conn = db::get_db_connection(app)?;
+    match cached(&conn, path)? {
+        Some(waveform) => Ok(Some(waveform)),
+        None => {
+            request(app, path);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use rusqlite::Connection;
+    use tempfile::TempDir;
+
+    const SAMPLE_RATE: u32 = 44100;
+    // Two chunks to a bucket.
+    const FRAMES: usize = BUCKETS * CHUNK_FRAMES as usize * 2;
+
+    // Ramps up from full scale negative to positive over the file.
+    fn ramp(i: usize) -> i16 {
+        (i as i64 * u16::MAX as i64 / (FRAMES as i64 - 1) + i16::MIN as i64) as i16
+    }
+
+    // The same ramp going down, for the right channel.
+    fn mirrored(sample: i16) -> i16 {
+        -1 - sample
+    }
+
+    fn ramp_file(dir: &TempDir) -> String {
+        let path = dir.path().join("ramp.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..FRAMES {
+            writer.write_sample(ramp(i)).unwrap();
+            writer.write_sample(mirrored(ramp(i))).unwrap();
+        }
+        writer.finalize().unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn byte(sample: i16) -> i8 {
+        to_byte(sample as f32 / 32768.0)
+    }
+
+    #[test]
+    fn buckets_hold_the_range_of_each_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let waveform = compute(&ramp_file(&dir), None).unwrap();
+        assert!((waveform.duration - FRAMES as f64 / SAMPLE_RATE as f64).abs() < 1e-6);
+        assert_eq!(waveform.channels.len(), 2);
+
+        let frames = FRAMES / BUCKETS;
+        let (left, right) = (&waveform.channels[0], &waveform.channels[1]);
+        for channel in [left, right] {
+            assert_eq!((channel.min.len(), channel.max.len()), (BUCKETS, BUCKETS));
+        }
+        for bucket in 0..BUCKETS {
+            let (first, last) = (ramp(bucket * frames), ramp((bucket + 1) * frames - 1));
+            assert_eq!((left.min[bucket], left.max[bucket]), (byte(first), byte(last)), "bucket {}", bucket);
+            assert_eq!((right.min[bucket], right.max[bucket]), (byte(mirrored(last)), byte(mirrored(first))), "bucket {}", bucket);
+        }
+    }
+
+    #[test]
+    fn short_tracks_get_a_bucket_per_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("short.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..CHUNK_FRAMES * 10 {
+            let x = (i as f32 * 440.0 * std::f32::consts::TAU / SAMPLE_RATE as f32).sin();
+            writer.write_sample((x * 16384.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let waveform = compute(&path.to_string_lossy(), None).unwrap();
+        let [channel] = waveform.channels.as_slice() else {
+            panic!("{} channels", waveform.channels.len());
+        };
+        assert_eq!(channel.min.len(), 10);
+        // Every chunk holds a whole cycle of the tone, peaking at half scale.
+        assert!(channel.min.iter().all(|v| (-64..=-63).contains(v)), "{:?}", channel.min);
+        assert!(channel.max.iter().all(|v| (63..=64).contains(v)), "{:?}", channel.max);
+    }
+
+    #[test]
+    fn a_changed_file_is_computed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = ramp_file(&dir);
+        let conn = Connection::open_in_memory().unwrap();
+        db::create_tables(&conn).unwrap();
+
+        assert!(cached(&conn, &path).unwrap().is_none());
+        store(&conn, &path).unwrap();
+        assert!(cached(&conn, &path).unwrap().is_some());
+
+        let later = SystemTime::now() + Duration::from_secs(60);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(cached(&conn, &path).unwrap().is_none());
+        store(&conn, &path).unwrap();
+        assert!(cached(&conn, &path).unwrap().is_some());
+    }
+}
@@ -0,0 +1,303 @@
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io;
+use std::time::Duration;
+use rodio::source::SeekError;
+use rodio::Source;
+
+// Flags for WavpackOpenFileInput: use a .wvc correction file when there is
+// one, return floats normalised to +/-1.0 and take the path as UTF-8.
+const OPEN_WVC: c_int = 0x1;
+const OPEN_NORMALIZE: c_int = 0x10;
+const OPEN_FILE_UTF8: c_int = 0x80;
+const MODE_FLOAT: c_int = 0x8;
+// Frames unpacked per call.
+const BLOCK_FRAMES: u32 = 4096;
+
+// Linked by build.rs, which finds libwavpack through pkg-config.
+extern "C" {
+    fn WavpackOpenFileInput(path: *const c_char, error: *mut c_char, flags: c_int, norm_offset: c_int) -> *mut c_void;
+    fn WavpackCloseFile(context: *mut c_void) -> *mut c_void;
+    fn WavpackGetNumChannels(context: *mut c_void) -> c_int;
+    fn WavpackGetSampleRate(context: *mut c_void) -> u32;
+    fn WavpackGetBytesPerSample(context: *mut c_void) -> c_int;
+    fn WavpackGetMode(context: *mut c_void) -> c_int;
+    fn WavpackGetNumSamples64(context: *mut c_void) -> i64;
+    fn WavpackUnpackSamples(context: *mut c_void, buffer: *mut i32, frames: u32) -> u32;
+    fn WavpackSeekSample64(context: *mut c_void, frame: i64) -> c_int;
+}
+
+// What the decoder needs from an open stream.
+trait Stream: Send {
+    // Fills `buffer` with up to `frames` interleaved frames and returns how
+    // many it wrote; zero at the end of the stream.
+    fn unpack(&mut self, buffer: &mut [i32], frames: u32) -> u32;
+    fn seek(&mut self, frame: u64) -> bool;
+}
+
+struct Context(*mut c_void);
+
+// The context is only ever touched through `&mut self`.
+unsafe impl Send for Context {}
+
+impl Stream for Context {
+    fn unpack(&mut self, buffer: &mut [i32], frames: u32) -> u32 {
+        unsafe { WavpackUnpackSamples(self.0, buffer.as_mut_ptr(), frames) }
+    }
+
+    fn seek(&mut self, frame: u64) -> bool {
+        i64::try_from(frame).is_ok_and(|frame| unsafe { WavpackSeekSample64(self.0, frame) } != 0)
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { WavpackCloseFile(self.0) };
+    }
+}
+
+// Shift that brings an unpacked integer sample down to 16 bits, or `None`
+// for float streams.
+fn sample_shift(bytes_per_sample: u32, mode: c_int) -> Option<u32> {
+    (mode & MODE_FLOAT == 0).then(|| 32 - 8 * bytes_per_sample)
+}
+
+fn to_i16(sample: i32, shift: Option<u32>) -> i16 {
+    match shift {
+        // Samples come right-justified; move the top bits into place.
+        Some(shift) => ((sample << shift) >> 16) as i16,
+        None => (f32::from_bits(sample as u32) * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16,
+    }
+}
+
+// WavPack through libwavpack, which neither rodio nor symphonia can decode.
+pub struct WavPackDecoder {
+    path: String,
+    stream: Box<dyn Stream>,
+    channels: u16,
+    sample_rate: u32,
+    shift: Option<u32>,
+    total_frames: Option<u64>,
+    buffer: Vec<i32>,
+    offset: usize,
+}
+
+impl WavPackDecoder {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let c_path = CString::new(path).map_err(|e| format!("Failed to open file: {}", e))?;
+        let mut error = [0 as c_char; 80];
+        let context = unsafe {
+            WavpackOpenFileInput(c_path.as_ptr(), error.as_mut_ptr(), OPEN_WVC | OPEN_NORMALIZE | OPEN_FILE_UTF8, 0)
+        };
+        if context.is_null() {
+            let message = unsafe { CStr::from_ptr(error.as_ptr()) }.to_string_lossy();
+            return Err(format!("Failed to decode audio: {}", message));
+        }
+
+        let (channels, sample_rate, bytes, mode, frames) = unsafe {
+            (
+                WavpackGetNumChannels(context),
+                WavpackGetSampleRate(context),
+                WavpackGetBytesPerSample(context),
+                WavpackGetMode(context),
+                WavpackGetNumSamples64(context),
+            )
+        };
+        WavPackDecoder::new(path, Box::new(Context(context)), channels, sample_rate, bytes, mode, frames)
+    }
+
+    fn new(
+        path: &str,
+        stream: Box<dyn Stream>,
+        channels: c_int,
+        sample_rate: u32,
+        bytes_per_sample: c_int,
+        mode: c_int,
+        frames: i64,
+    ) -> Result<Self, String> {
+        if !(1..=u16::MAX as c_int).contains(&channels) || sample_rate == 0 || !(1..=4).contains(&bytes_per_sample) {
+            return Err(format!(
+                "Unsupported WavPack stream: {} channels, {} bytes per sample",
+                channels, bytes_per_sample
+            ));
+        }
+
+        Ok(WavPackDecoder {
+            path: path.to_string(),
+            stream,
+            channels: channels as u16,
+            sample_rate,
+            shift: sample_shift(bytes_per_sample as u32, mode),
+            // -1 when the length isn't known.
+            total_frames: u64::try_from(frames).ok(),
+            buffer: Vec::with_capacity(BLOCK_FRAMES as usize * channels as usize),
+            offset: 0,
+        })
+    }
+
+    fn refill(&mut self) -> bool {
+        let channels = self.channels as usize;
+        self.buffer.resize(BLOCK_FRAMES as usize * channels, 0);
+        let frames = self.stream.unpack(&mut self.buffer, BLOCK_FRAMES).min(BLOCK_FRAMES);
+        self.buffer.truncate(frames as usize * channels);
+        self.offset = 0;
+        frames > 0
+    }
+}
+
+impl Iterator for WavPackDecoder {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<i16> {
+        if self.offset >= self.buffer.len() && !self.refill() {
+            return None;
+        }
+        let sample = self.buffer[self.offset];
+        self.offset += 1;
+        Some(to_i16(sample, self.shift))
+    }
+}
+
+impl Source for WavPackDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.buffer.len() - self.offset).filter(|&len| len > 0)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_frames
+            .map(|frames| Duration::from_secs_f64(frames as f64 / self.sample_rate as f64))
+    }
+
+    // A failed seek leaves the context unusable, so the file is opened again
+    // and playback carries on from the start.
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let frame = (pos.as_secs_f64() * self.sample_rate as f64) as u64;
+        self.buffer.clear();
+        self.offset = 0;
+        if self.stream.seek(frame) {
+            return Ok(());
+        }
+        *self = WavPackDecoder::open(&self.path)
+            .map_err(|e| SeekError::Other(Box::new(io::Error::other(e))))?;
+        Err(SeekError::Other(Box::new(io::Error::other(format!("Failed to seek to {:?}", pos)))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Interleaved samples standing in for libwavpack's output.
+    struct Samples {
+        data: Vec<i32>,
+        channels: usize,
+        frame: usize,
+        seekable: bool,
+    }
+
+    impl Stream for Samples {
+        fn unpack(&mut self, buffer: &mut [i32], frames: u32) -> u32 {
+            let start = (self.frame * self.channels).min(self.data.len());
+            let end = (start + frames as usize * self.channels).min(self.data.len());
+            buffer[..end - start].copy_from_slice(&self.data[start..end]);
+            let read = (end - start) / self.channels;
+            self.frame += read;
+            read as u32
+        }
+
+        fn seek(&mut self, frame: u64) -> bool {
+            if !self.seekable || frame as usize * self.channels > self.data.len() {
+                return false;
+            }
+            self.frame = frame as usize;
+            true
+        }
+    }
+
+    fn decoder(data: Vec<i32>, channels: c_int, bytes: c_int, mode: c_int, seekable: bool) -> WavPackDecoder {
+        let frames = (data.len() / channels as usize) as i64;
+        let stream = Samples { data, channels: channels as usize, frame: 0, seekable };
+        WavPackDecoder::new("missing.wv", Box::new(stream), channels, 1000, bytes, mode, frames).unwrap()
+    }
+
+    #[test]
+    fn integer_samples_shift_down_to_16_bits() {
+        assert_eq!(sample_shift(1, 0), Some(24));
+        assert_eq!(sample_shift(2, 0), Some(16));
+        assert_eq!(sample_shift(3, 0), Some(8));
+        assert_eq!(sample_shift(4, 0), Some(0));
+        assert_eq!(sample_shift(4, MODE_FLOAT), None);
+
+        // 8-bit samples scale up, 24- and 32-bit ones keep their top bits.
+        assert_eq!(to_i16(127, Some(24)), 127 << 8);
+        assert_eq!(to_i16(-128, Some(24)), i16::MIN);
+        assert_eq!(to_i16(-12345, Some(16)), -12345);
+        assert_eq!(to_i16(0x7F_FFFF, Some(8)), i16::MAX);
+        assert_eq!(to_i16(-0x80_0000, Some(8)), i16::MIN);
+        assert_eq!(to_i16(-1, Some(8)), -1);
+        assert_eq!(to_i16(i32::MAX, Some(0)), i16::MAX);
+        assert_eq!(to_i16(0x1234_5678, Some(0)), 0x1234);
+    }
+
+    #[test]
+    fn float_samples_scale_and_clamp() {
+        let float = |value: f32| value.to_bits() as i32;
+        assert_eq!(to_i16(float(0.0), None), 0);
+        assert_eq!(to_i16(float(0.5), None), 16383);
+        assert_eq!(to_i16(float(-1.0), None), -i16::MAX);
+        assert_eq!(to_i16(float(1.5), None), i16::MAX);
+        assert_eq!(to_i16(float(-3.0), None), i16::MIN);
+    }
+
+    #[test]
+    fn rejects_unsupported_streams() {
+        let open = |channels, rate, bytes| {
+            let stream = Samples { data: Vec::new(), channels: 1, frame: 0, seekable: true };
+            WavPackDecoder::new("missing.wv", Box::new(stream), channels, rate, bytes, 0, 0).is_ok()
+        };
+        assert!(open(2, 44100, 2));
+        assert!(!open(0, 44100, 2));
+        assert!(!open(2, 0, 2));
+        assert!(!open(2, 44100, 5));
+    }
+
+    #[test]
+    fn decodes_across_blocks() {
+        let frames = BLOCK_FRAMES as i32 * 2 + 10;
+        let data: Vec<i32> = (0..frames).flat_map(|i| [i, -i]).collect();
+        let mut decoder = decoder(data.clone(), 2, 2, 0, true);
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.total_duration(), Some(Duration::from_secs_f64(frames as f64 / 1000.0)));
+
+        let decoded: Vec<i16> = decoder.by_ref().collect();
+        assert_eq!(decoded, data.iter().map(|&s| s as i16).collect::<Vec<_>>());
+        assert_eq!(decoder.next(), None);
+    }
+
+    #[test]
+    fn seeks_to_the_frame_at_the_position() {
+        let data: Vec<i32> = (0..3000).flat_map(|i| [i, i + 10000]).collect();
+        let mut decoder = decoder(data, 2, 2, 0, true);
+        assert_eq!(decoder.by_ref().take(7).count(), 7);
+
+        decoder.try_seek(Duration::from_millis(1500)).unwrap();
+        assert_eq!(decoder.by_ref().take(4).collect::<Vec<_>>(), [1500, 11500, 1501, 11501]);
+
+        decoder.try_seek(Duration::from_millis(250)).unwrap();
+        assert_eq!(decoder.next(), Some(250));
+    }
+
+    #[test]
+    fn failed_seek_reports_an_error() {
+        let mut decoder = decoder((0..100).collect(), 1, 2, 0, false);
+        assert!(decoder.try_seek(Duration::from_millis(50)).is_err());
+    }
+}
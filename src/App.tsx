@@ -7,11 +7,16 @@ import { useLocation } from "react-router";
 
 function App() {
   const location = useLocation();
-  const { loadCurrentTrack, loadTracksFromDb } = useMusicStore();
+  const { loadCurrentTrack, loadTracksFromDb, subscribeToPlayback } =
+    useMusicStore();
 
   useEffect(() => {
     loadCurrentTrack();
     loadTracksFromDb();
+    const unsubscribe = subscribeToPlayback();
+    return () => {
+      unsubscribe.then((unlisten) => unlisten());
+    };
   }, []);
 
   const isSettingsRoute = location.pathname === "/settings";
@@ -20,18 +20,11 @@ interface MusicListProps {
 export function MusicList({ onPlay }: MusicListProps) {
   const [tracks, setTracks] = useState<MusicFile[]>([]);
   const [loading, setLoading] = useState<boolean>(false);
-  const { isPlaying, checkPlaying, currentTrack, loadCurrentTrack } =
-    useMusicStore();
+  const { isPlaying, currentTrack } = useMusicStore();
 
   useEffect(() => {
     loadMusic();
-    const interval = setInterval(checkPlaying, 500);
-    const trackInterval = setInterval(loadCurrentTrack, 1000);
-    return () => {
-      clearInterval(interval);
-      clearInterval(trackInterval);
-    };
-  }, [checkPlaying, loadCurrentTrack]);
+  }, []);
 
   const loadMusic = async () => {
     try {
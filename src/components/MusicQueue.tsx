@@ -20,18 +20,11 @@ interface MusicQueueProps {
 export function MusicQueue({ onPlay }: MusicQueueProps) {
   const [tracks, setTracks] = useState<MusicFile[]>([]);
   const [loading, setLoading] = useState<boolean>(false);
-  const { isPlaying, checkPlaying, currentTrack, loadCurrentTrack } =
-    useMusicStore();
+  const { isPlaying, currentTrack } = useMusicStore();
 
   useEffect(() => {
     loadMusic();
-    const interval = setInterval(checkPlaying, 500);
-    const trackInterval = setInterval(loadCurrentTrack, 1000);
-    return () => {
-      clearInterval(interval);
-      clearInterval(trackInterval);
-    };
-  }, [checkPlaying, loadCurrentTrack]);
+  }, []);
 
   const loadMusic = async () => {
     try {
@@ -1,4 +1,5 @@
 import { MusicQueue } from "@/components/MusicQueue";
+import { Visualizer } from "@/components/Visualizer";
 import { Button } from "@/components/ui/button";
 import { ScrollArea } from "@/components/ui/scroll-area";
 import { ChevronLeft } from "lucide-react";
@@ -30,6 +31,9 @@ export function NowPlayingView() {
               </div>
             )}
           </div>
+          <div className="mt-4 text-primary">
+            <Visualizer />
+          </div>
         </div>
         <div className="flex-1 flex flex-col overflow-hidden">
           <h2 className="text-2xl font-bold mb-4 shrink-0">Up Next</h2>
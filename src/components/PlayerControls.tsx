@@ -2,6 +2,7 @@ import { useEffect, useState } from "react";
 import { invoke } from "@tauri-apps/api/core";
 import { Button } from "@/components/ui/button";
 import { Slider } from "@/components/ui/slider";
+import { WaveformBar } from "@/components/WaveformBar";
 import { Play, Pause, SkipBack, SkipForward, Volume2 } from "lucide-react";
 import { useMusicStore } from "@/store/musicStore";
 
@@ -15,15 +16,14 @@ export function PlayerControls() {
   const {
     isPlaying,
     togglePlayback,
-    loadTrackInfo,
     currentTrack,
     trackInfo,
-    loadCurrentTrack,
+    position,
+    duration: totalDuration,
   } = useMusicStore();
   const [volume, setVolume] = useState<number[]>([50]);
-  const [currentTime, setCurrentTime] = useState<number>(0);
-  const [totalDuration, setTotalDuration] = useState<number | null>(null);
-  const [isSeeking, setIsSeeking] = useState(false);
+  const [seekTime, setSeekTime] = useState<number | null>(null);
+  const currentTime = seekTime ?? position;
 
   useEffect(() => {
     const loadVolume = async () => {
@@ -37,48 +37,6 @@ export function PlayerControls() {
     loadVolume();
   }, []);
 
-  useEffect(() => {
-    if (currentTrack) {
-      loadTrackInfo();
-    }
-  }, [currentTrack, loadTrackInfo]);
-
-  useEffect(() => {
-    const trackInterval = setInterval(loadCurrentTrack, 1000);
-    return () => clearInterval(trackInterval);
-  }, [loadCurrentTrack]);
-
-  useEffect(() => {
-    const checkPlaying = async () => {
-      try {
-        await invoke<boolean>("is_playing");
-      } catch (error) {
-        console.error("Failed to check playing state:", error);
-      }
-    };
-
-    const updatePosition = async () => {
-      if (isSeeking) return;
-      try {
-        const [elapsed, total] = await invoke<[number, number | null]>(
-          "get_playback_position",
-        );
-        setCurrentTime(elapsed);
-        if (total !== null) {
-          setTotalDuration(total);
-        }
-      } catch (error) {
-        console.error("Failed to get playback position:", error);
-      }
-    };
-
-    const interval = setInterval(() => {
-      checkPlaying();
-      updatePosition();
-    }, 500);
-    return () => clearInterval(interval);
-  }, [currentTrack, isSeeking]);
-
   const handlePlayPause = async () => {
     try {
       await togglePlayback();
@@ -97,18 +55,17 @@ export function PlayerControls() {
   };
 
   const handleSeek = (value: number[]) => {
-    setCurrentTime(value[0]);
+    setSeekTime(value[0]);
   };
 
   const handleSeekCommit = (value: number[]) => {
-    setIsSeeking(false);
+    setSeekTime(null);
     invoke("seek", { positionSecs: value[0] }).catch(console.error);
   };
 
   const handleSkipBack = async () => {
     try {
       await invoke("play_previous");
-      loadCurrentTrack();
     } catch (error) {
       console.error("Failed to skip back:", error);
     }
@@ -117,7 +74,6 @@ export function PlayerControls() {
   const handleSkipForward = async () => {
     try {
       await invoke("play_next");
-      loadCurrentTrack();
     } catch (error) {
       console.error("Failed to skip forward:", error);
     }
@@ -139,16 +95,23 @@ export function PlayerControls() {
         <span className="text-xs text-muted-foreground w-10 text-right">
           {formatTime(currentTime)}
         </span>
-        <Slider
-          value={[currentTime]}
-          onValueChange={handleSeek}
-          onValueCommit={handleSeekCommit}
-          onPointerDown={() => setIsSeeking(true)}
-          max={totalDuration ?? 0}
-          step={1}
-          disabled={!currentTrack}
-          className="flex-1"
-        />
+        <div className="flex-1 relative">
+          <WaveformBar
+            track={currentTrack}
+            progress={totalDuration ? currentTime / totalDuration : 0}
+            className="absolute inset-x-0 -top-4 h-8 w-full pointer-events-none text-primary"
+          />
+          <Slider
+            value={[currentTime]}
+            onValueChange={handleSeek}
+            onValueCommit={handleSeekCommit}
+            onPointerDown={() => setSeekTime(currentTime)}
+            max={totalDuration ?? 0}
+            step={1}
+            disabled={!currentTrack}
+            className="w-full"
+          />
+        </div>
         <span className="text-xs text-muted-foreground w-10">
           {totalDuration !== null ? formatTime(totalDuration) : "0:00"}
         </span>
@@ -0,0 +1,68 @@
+import { useEffect, useRef } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+interface VisualizerFrame {
+  bins: number[];
+  peak: number;
+  rms: number;
+  scope: number[];
+}
+
+const FPS = 30;
+
+export function Visualizer() {
+  const canvasRef = useRef<HTMLCanvasElement>(null);
+
+  useEffect(() => {
+    let active = true;
+    const unlisten = listen<VisualizerFrame>("visualizer-frame", (event) => {
+      if (active) draw(canvasRef.current, event.payload);
+    });
+    invoke("subscribe_visualizer", { fps: FPS }).catch(console.error);
+
+    return () => {
+      active = false;
+      unlisten.then((fn) => fn());
+      invoke("unsubscribe_visualizer").catch(console.error);
+    };
+  }, []);
+
+  return <canvas ref={canvasRef} width={320} height={96} className="w-80 h-24" />;
+}
+
+function draw(canvas: HTMLCanvasElement | null, frame: VisualizerFrame) {
+  const ctx = canvas?.getContext("2d");
+  if (!canvas || !ctx) return;
+
+  const { width, height } = canvas;
+  const color = getComputedStyle(canvas).color;
+  ctx.clearRect(0, 0, width, height);
+
+  // Spectrum bars.
+  ctx.fillStyle = color;
+  ctx.globalAlpha = 0.6;
+  const barWidth = width / frame.bins.length;
+  frame.bins.forEach((value, i) => {
+    const barHeight = (value / 255) * height;
+    ctx.fillRect(i * barWidth, height - barHeight, barWidth - 1, barHeight);
+  });
+
+  // Oscilloscope trace over the bars.
+  ctx.globalAlpha = 1;
+  ctx.strokeStyle = color;
+  ctx.beginPath();
+  frame.scope.forEach((value, i) => {
+    const x = (i / (frame.scope.length - 1)) * width;
+    const y = height / 2 - (value / 127) * (height / 2);
+    if (i === 0) ctx.moveTo(x, y);
+    else ctx.lineTo(x, y);
+  });
+  ctx.stroke();
+
+  // Peak and RMS meters along the right edge.
+  ctx.globalAlpha = 0.4;
+  ctx.fillRect(width - 6, height - frame.peak * height, 2, frame.peak * height);
+  ctx.globalAlpha = 0.8;
+  ctx.fillRect(width - 3, height - frame.rms * height, 2, frame.rms * height);
+}
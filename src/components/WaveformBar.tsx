@@ -0,0 +1,78 @@
+import { useEffect, useRef, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+interface WaveformChannel {
+  min: number[];
+  max: number[];
+}
+
+interface Waveform {
+  duration: number;
+  channels: WaveformChannel[];
+}
+
+interface WaveformBarProps {
+  track: string | null;
+  progress: number;
+  className?: string;
+}
+
+// Draws the min/max overview of the track, with the played part highlighted.
+export function WaveformBar({ track, progress, className }: WaveformBarProps) {
+  const canvasRef = useRef<HTMLCanvasElement>(null);
+  const [waveform, setWaveform] = useState<Waveform | null>(null);
+
+  useEffect(() => {
+    setWaveform(null);
+    if (!track) return;
+
+    let active = true;
+    const load = () =>
+      invoke<Waveform | null>("get_waveform", { track })
+        .then((w) => {
+          if (active && w) setWaveform(w);
+        })
+        .catch(console.error);
+
+    const unlisten = listen<{ path: string }>("waveform-ready", (event) => {
+      if (event.payload.path === track) load();
+    });
+    load();
+
+    return () => {
+      active = false;
+      unlisten.then((fn) => fn());
+    };
+  }, [track]);
+
+  useEffect(() => {
+    const canvas = canvasRef.current;
+    const ctx = canvas?.getContext("2d");
+    if (!canvas || !ctx) return;
+
+    const { width, height } = canvas;
+    ctx.clearRect(0, 0, width, height);
+    if (!waveform || waveform.channels.length === 0) return;
+
+    // Channels are merged into one envelope.
+    const buckets = waveform.channels[0].min.length;
+    const color = getComputedStyle(canvas).color;
+    const played = progress * width;
+    const barWidth = width / buckets;
+
+    for (let i = 0; i < buckets; i++) {
+      const min = Math.min(...waveform.channels.map((c) => c.min[i]));
+      const max = Math.max(...waveform.channels.map((c) => c.max[i]));
+      const top = height / 2 - (max / 127) * (height / 2);
+      const bottom = height / 2 - (min / 127) * (height / 2);
+      const x = i * barWidth;
+
+      ctx.fillStyle = color;
+      ctx.globalAlpha = x < played ? 0.9 : 0.3;
+      ctx.fillRect(x, top, Math.max(barWidth, 1), Math.max(bottom - top, 1));
+    }
+  }, [waveform, progress]);
+
+  return <canvas ref={canvasRef} width={1000} height={32} className={className} />;
+}
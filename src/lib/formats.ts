@@ -0,0 +1,24 @@
+import { invoke } from "@tauri-apps/api/core";
+import type { DialogFilter } from "@tauri-apps/plugin-dialog";
+
+export interface SupportedFormat {
+  name: string;
+  extensions: string[];
+}
+
+let formats: Promise<SupportedFormat[]> | null = null;
+
+// The formats the backend can decode; the same list the indexer uses.
+export function getSupportedFormats(): Promise<SupportedFormat[]> {
+  formats ??= invoke<SupportedFormat[]>("get_supported_formats");
+  return formats;
+}
+
+// File dialog filters: one entry for all audio files, then one per format.
+export async function audioFileFilters(): Promise<DialogFilter[]> {
+  const supported = await getSupportedFormats();
+  return [
+    { name: "Audio Files", extensions: supported.flatMap((f) => f.extensions) },
+    ...supported,
+  ];
+}
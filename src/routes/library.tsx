@@ -1,5 +1,10 @@
 import { useEffect } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { open } from "@tauri-apps/plugin-dialog";
+import { Button } from "@/components/ui/button";
+import { FileAudio } from "lucide-react";
 import { MusicList } from "@/components/MusicList";
+import { audioFileFilters } from "@/lib/formats";
 import { useMusicStore } from "@/store/musicStore";
 import { useNavigate } from "react-router";
 
@@ -21,8 +26,33 @@ export function Library() {
     }
   };
 
+  // Plays files picked from disk, whether or not they are in the library.
+  const handleOpenFiles = async () => {
+    try {
+      const selected = await open({
+        multiple: true,
+        title: "Open Audio Files",
+        filters: await audioFileFilters(),
+      });
+
+      if (selected && selected.length > 0) {
+        await invoke("play_selection", { paths: selected, start: 0 });
+        await loadCurrentTrack();
+        navigate("/now-playing");
+      }
+    } catch (error) {
+      console.error("Failed to open files:", error);
+    }
+  };
+
   return (
     <div className="h-full p-6">
+      <div className="flex justify-end mb-4">
+        <Button variant="outline" className="gap-2" onClick={handleOpenFiles}>
+          <FileAudio className="w-4 h-4" />
+          Open Files
+        </Button>
+      </div>
       <MusicList
         key={refreshKey}
         onPlay={handlePlay}
@@ -1,5 +1,6 @@
 import { create } from "zustand";
 import { invoke } from "@tauri-apps/api/core";
+import { listen, type UnlistenFn } from "@tauri-apps/api/event";
 
 export interface TrackInfo {
   path: string;
@@ -10,10 +11,27 @@ export interface TrackInfo {
   thumbnail: string | null;
 }
 
+interface TrackChangedEvent {
+  path: string | null;
+  track: TrackInfo | null;
+}
+
+interface PositionEvent {
+  position: number;
+  duration: number | null;
+}
+
+interface PlaybackErrorEvent {
+  path: string | null;
+  message: string;
+}
+
 interface MusicPlayerState {
   currentTrack: string | null;
   trackInfo: TrackInfo | null;
   isPlaying: boolean;
+  position: number;
+  duration: number | null;
   refreshKey: number;
 
   // Actions
@@ -29,12 +47,15 @@ interface MusicPlayerState {
   togglePlayback: () => Promise<void>;
   checkPlaying: () => Promise<void>;
   loadTracksFromDb: () => Promise<void>;
+  subscribeToPlayback: () => Promise<UnlistenFn>;
 }
 
 export const useMusicStore = create<MusicPlayerState>((set, get) => ({
   currentTrack: null,
   trackInfo: null,
   isPlaying: false,
+  position: 0,
+  duration: null,
   refreshKey: 0,
 
   setCurrentTrack: (track) => {
@@ -130,4 +151,28 @@ export const useMusicStore = create<MusicPlayerState>((set, get) => ({
       console.error("Failed to load tracks from database:", error);
     }
   },
+
+  subscribeToPlayback: async () => {
+    const unlisteners = await Promise.all([
+      listen<TrackChangedEvent>("track-changed", (event) => {
+        set({ currentTrack: event.payload.path, trackInfo: event.payload.track });
+      }),
+      listen<{ playing: boolean }>("state-changed", (event) => {
+        set({ isPlaying: event.payload.playing });
+      }),
+      listen<PositionEvent>("position", (event) => {
+        set({
+          position: event.payload.position,
+          duration: event.payload.duration,
+        });
+      }),
+      listen<PlaybackErrorEvent>("playback-error", (event) => {
+        console.error(
+          `Playback error${event.payload.path ? ` (${event.payload.path})` : ""}:`,
+          event.payload.message,
+        );
+      }),
+    ]);
+    return () => unlisteners.forEach((unlisten) => unlisten());
+  },
 }));